@@ -17,7 +17,13 @@ fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x")).unwrap().write_all(include_bytes!("memory.x")).unwrap();
+    // FUOTA builds give the upper half of the application flash to staging an update
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_FUOTA").is_some() {
+        include_bytes!("memory-fuota.x")
+    } else {
+        include_bytes!("memory.x")
+    };
+    File::create(out.join("memory.x")).unwrap().write_all(memory).unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
@@ -25,8 +31,18 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-fuota.x");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    // EU868 is built if selected or unless another region is
+    println!("cargo:rustc-check-cfg=cfg(eu868)");
+    let regions = ["AU915", "AS923", "IN865", "KR920", "RU864", "EU433"];
+    if env::var_os("CARGO_FEATURE_EU868").is_some()
+        || !regions.iter().any(|region| env::var_os(format!("CARGO_FEATURE_{region}")).is_some())
+    {
+        println!("cargo:rustc-cfg=eu868");
+    }
 }
@@ -0,0 +1,18 @@
+MEMORY
+{
+    FLASH : ORIGIN = 0x8000000, LENGTH = 118K
+    STAGING : ORIGIN = 0x801D800, LENGTH = 118K
+    DEV_NONCE : ORIGIN = 0x803B000, LENGTH = 4K
+    SPOOL : ORIGIN = 0x803C000, LENGTH = 8K
+    PROVISIONING : ORIGIN = 0x803E000, LENGTH = 2K
+    STORAGE : ORIGIN = 0x803E800, LENGTH = 6K
+    RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
+__provisioning = ORIGIN(PROVISIONING);
+__storage = ORIGIN(STORAGE);
+__staging = ORIGIN(STAGING);
+__staging_end = ORIGIN(STAGING) + LENGTH(STAGING);
+__dev_nonce = ORIGIN(DEV_NONCE);
+__dev_nonce_end = ORIGIN(DEV_NONCE) + LENGTH(DEV_NONCE);
+__spool = ORIGIN(SPOOL);
+__spool_end = ORIGIN(SPOOL) + LENGTH(SPOOL);
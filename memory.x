@@ -1,7 +1,15 @@
 MEMORY
 {
-    FLASH : ORIGIN = 0x8000000, LENGTH = 252K
-    STORAGE : ORIGIN = 0x803F000, LENGTH = 4K
+    FLASH : ORIGIN = 0x8000000, LENGTH = 236K
+    DEV_NONCE : ORIGIN = 0x803B000, LENGTH = 4K
+    SPOOL : ORIGIN = 0x803C000, LENGTH = 8K
+    PROVISIONING : ORIGIN = 0x803E000, LENGTH = 2K
+    STORAGE : ORIGIN = 0x803E800, LENGTH = 6K
     RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
-__storage = ORIGIN(STORAGE);
\ No newline at end of file
+__provisioning = ORIGIN(PROVISIONING);
+__storage = ORIGIN(STORAGE);
+__dev_nonce = ORIGIN(DEV_NONCE);
+__dev_nonce_end = ORIGIN(DEV_NONCE) + LENGTH(DEV_NONCE);
+__spool = ORIGIN(SPOOL);
+__spool_end = ORIGIN(SPOOL) + LENGTH(SPOOL);
@@ -0,0 +1,125 @@
+//! LIS2DH12 accelerometer on I2C2 with both of its interrupt generators routed to INT1:
+//! a high-pass filtered one for movement and an unfiltered high threshold one for shocks.
+//! Boards without one carry on without motion events.
+
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::i2c::I2c;
+use embassy_stm32::mode::Blocking;
+
+use crate::payload;
+use crate::schema;
+
+pub const MOTION_PORT: u8 = schema::MOTION.port;
+pub const MOTION_PAYLOAD_SIZE: usize = schema::MOTION.header_size();
+
+const ADDRESSES: [u8; 2] = [0x19, 0x18];
+const WHO_AM_I: u8 = 0x0F;
+const LIS2DH12_ID: u8 = 0x33;
+const CTRL_REG1: u8 = 0x20;
+const CTRL_REG2: u8 = 0x21;
+const CTRL_REG3: u8 = 0x22;
+const CTRL_REG4: u8 = 0x23;
+const CTRL_REG5: u8 = 0x24;
+const REFERENCE: u8 = 0x26;
+const INT1_CFG: u8 = 0x30;
+const INT1_SRC: u8 = 0x31;
+const INT1_THS: u8 = 0x32;
+const INT2_CFG: u8 = 0x34;
+const INT2_SRC: u8 = 0x35;
+const INT2_THS: u8 = 0x36;
+/// Interrupt active bit of INTx_SRC
+const IA: u8 = 1 << 6;
+/// OR of the high events of all three axes
+const XYZ_HIGH: u8 = 0b0010_1010;
+/// Threshold step at ±8 g
+const THRESHOLD_MG: u16 = 62;
+const MOTION_THRESHOLD_MG: u16 = 250;
+const SHOCK_THRESHOLD_MG: u16 = 4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MotionEvent {
+    Motion,
+    Shock,
+}
+impl MotionEvent {
+    pub fn encode(&self, timestamp: u32) -> [u8; MOTION_PAYLOAD_SIZE] {
+        payload::motion(*self as u8, timestamp)
+    }
+}
+
+pub struct Accelerometer<'d> {
+    i2c: I2c<'d, Blocking>,
+    int1: ExtiInput<'d>,
+    address: u8,
+}
+impl<'d> Accelerometer<'d> {
+    /// `None` if no LIS2DH12 answers.
+    pub fn new(i2c: I2c<'d, Blocking>, int1: ExtiInput<'d>) -> Option<Self> {
+        let mut accelerometer = Self { i2c, int1, address: ADDRESSES[0] };
+        let found = ADDRESSES.iter().any(|address| {
+            accelerometer.address = *address;
+            accelerometer.read(WHO_AM_I) == Some(LIS2DH12_ID)
+        });
+        if !found {
+            defmt::info!("no accelerometer");
+            return None;
+        }
+        let configured = [
+            // 10 Hz, all axes
+            (CTRL_REG1, 0x27),
+            // high-pass filter on the movement interrupt only
+            (CTRL_REG2, 0x01),
+            (CTRL_REG3, 0x60),
+            (CTRL_REG4, 0x20),
+            // latched until the source register is read
+            (CTRL_REG5, 0x0A),
+            (INT1_THS, (MOTION_THRESHOLD_MG / THRESHOLD_MG) as u8),
+            (INT1_CFG, XYZ_HIGH),
+            (INT2_THS, (SHOCK_THRESHOLD_MG / THRESHOLD_MG) as u8),
+            (INT2_CFG, XYZ_HIGH),
+        ]
+        .iter()
+        .all(|(register, value)| accelerometer.write(*register, *value));
+        // settles the high-pass filter on the current orientation
+        accelerometer.read(REFERENCE);
+        if !configured {
+            defmt::error!("accelerometer not configured");
+            return None;
+        }
+        defmt::info!("LIS2DH12 at {=u8:02X}", accelerometer.address);
+        Some(accelerometer)
+    }
+
+    fn read(&mut self, register: u8) -> Option<u8> {
+        let mut value = [0];
+        self.i2c.blocking_write_read(self.address, &[register], &mut value).ok()?;
+        Some(value[0])
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> bool {
+        self.i2c.blocking_write(self.address, &[register, value]).is_ok()
+    }
+
+    pub async fn wait(&mut self) -> MotionEvent {
+        loop {
+            self.int1.wait_for_high().await;
+            // reading both sources releases the latched line
+            let shock = self.read(INT2_SRC).is_some_and(|src| src & IA != 0);
+            let motion = self.read(INT1_SRC).is_some_and(|src| src & IA != 0);
+            if shock {
+                return MotionEvent::Shock;
+            }
+            if motion {
+                return MotionEvent::Motion;
+            }
+        }
+    }
+}
+
+/// Waits for the next event, forever without an accelerometer.
+pub async fn next_event(accelerometer: Option<&mut Accelerometer<'_>>) -> MotionEvent {
+    match accelerometer {
+        Some(accelerometer) => accelerometer.wait().await,
+        None => core::future::pending().await,
+    }
+}
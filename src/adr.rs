@@ -0,0 +1,157 @@
+//! Runtime control of ADR for application tasks, e.g. to pin the spreading factor of a
+//! battery powered device where the network would otherwise keep it on a fast data rate
+//! on the edge of its coverage. [`set`] takes effect before the next uplink and is
+//! persisted in the journal, the `adr` of the profile is used until then.
+//!
+//! With ADR off the device sends at [`AdrControl::data_rate`], with ADR on it starts there
+//! after every join and the network may go up to [`AdrControl::max_data_rate`].
+//!
+//! The lorawan MAC has ADR on with its own backoff counters and takes no settings, so the
+//! ADR bits of each uplink are put in by [`crate::uplink_edit`] and the backoff runs here
+//! on [`AdrControl::ack_limit`] and [`AdrControl::ack_delay`]: a data rate the MAC lowered
+//! without a downlink is put back after every uplink.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::compat::CompatProfile;
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::RecordKey;
+use crate::preset::DataRatePolicy;
+use crate::region::{self, RegionMac};
+use crate::uplink_edit;
+use lorawan::mac::types::DR;
+
+const CONTROL_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AdrControl {
+    pub enabled: bool,
+    /// ADR_ACK_LIMIT, uplinks without a downlink before ADRACKReq is set.
+    pub ack_limit: u16,
+    /// ADR_ACK_DELAY, further uplinks before the data rate is lowered.
+    pub ack_delay: u16,
+    /// Initial data rate, or the one pinned with ADR off.
+    pub data_rate: u8,
+    pub max_data_rate: u8,
+}
+impl AdrControl {
+    fn to_bytes(self) -> [u8; CONTROL_SIZE] {
+        let mut buf = [0; CONTROL_SIZE];
+        buf[0] = self.enabled as u8;
+        buf[1..3].copy_from_slice(&self.ack_limit.to_le_bytes());
+        buf[3..5].copy_from_slice(&self.ack_delay.to_le_bytes());
+        buf[5] = self.data_rate;
+        buf[6] = self.max_data_rate;
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; CONTROL_SIZE]) -> Self {
+        Self {
+            enabled: buf[0] != 0,
+            ack_limit: u16::from_le_bytes([buf[1], buf[2]]),
+            ack_delay: u16::from_le_bytes([buf[3], buf[4]]),
+            data_rate: buf[5],
+            max_data_rate: buf[6],
+        }
+    }
+
+    /// Narrows the data rate policy of the profile.
+    pub fn data_rate_policy(&self, profile: DataRatePolicy) -> DataRatePolicy {
+        match profile {
+            DataRatePolicy::Adaptive { min, max } if self.enabled => {
+                DataRatePolicy::Adaptive { min, max: max.min(self.max_data_rate).max(min) }
+            }
+            DataRatePolicy::Adaptive { .. } => DataRatePolicy::Fixed(self.data_rate),
+            fixed => fixed,
+        }
+    }
+}
+
+static CONTROL: Mutex<CriticalSectionRawMutex, Cell<Option<AdrControl>>> =
+    Mutex::new(Cell::new(None));
+/// Set by [`set`], until persisted.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+/// ADR_ACK_CNT, uplinks since the last downlink.
+static ACK_CNT: AtomicU32 = AtomicU32::new(0);
+
+/// The control persisted, or `default` if none was set.
+pub fn load(store: &mut DeviceNonVolatileStore<'_>, default: AdrControl) {
+    let mut buf = [0; CONTROL_SIZE];
+    let control = match store.read_record(RecordKey::Adr, &mut buf) {
+        Ok(CONTROL_SIZE) => AdrControl::from_bytes(&buf),
+        Ok(_) | Err(_) => default,
+    };
+    defmt::info!("{:?}", control);
+    CONTROL.lock(|current| current.set(Some(control)));
+}
+
+pub fn set(control: AdrControl) {
+    CONTROL.lock(|current| current.set(Some(control)));
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// The control in effect, the `ADR` constant before [`load`].
+pub fn control() -> Option<AdrControl> {
+    CONTROL.lock(Cell::get)
+}
+
+/// ADR_ACK_LIMIT and ADR_ACK_DELAY of `control`, or of `compat` if it has its own.
+fn ack(control: &AdrControl, compat: CompatProfile) -> (u32, u32) {
+    let (ack_limit, ack_delay) = compat.adr_ack().unwrap_or((control.ack_limit, control.ack_delay));
+    (ack_limit as u32, ack_delay.max(1) as u32)
+}
+
+/// Persists a control [`set`] since the last call and sets the ADR bits of the next uplink.
+pub fn update(store: &mut DeviceNonVolatileStore<'_>, compat: CompatProfile) {
+    let Some(control) = control() else {
+        return;
+    };
+    if CHANGED.swap(false, Ordering::Relaxed) {
+        defmt::info!("{:?}", control);
+        if let Err(e) = store.write_record(RecordKey::Adr, &control.to_bytes()) {
+            defmt::error!("ADR control not saved {:?}", e);
+        }
+    }
+    let (ack_limit, _) = ack(&control, compat);
+    let adr_ack_req = control.enabled && ACK_CNT.load(Ordering::Relaxed) >= ack_limit;
+    uplink_edit::set_adr(control.enabled, adr_ack_req);
+}
+
+/// After an uplink sent on `data_rate`, `answered` if a downlink came back: lowers the data
+/// rate once ADR_ACK_DELAY uplinks with ADRACKReq went unanswered, and every ADR_ACK_DELAY
+/// after.
+pub fn sent(mac: &mut RegionMac, compat: CompatProfile, data_rate: Option<DR>, answered: bool) {
+    let ack_cnt = if answered {
+        0
+    } else {
+        ACK_CNT.load(Ordering::Relaxed).saturating_add(1)
+    };
+    ACK_CNT.store(ack_cnt, Ordering::Relaxed);
+    if !answered && mac.configuration().tx_data_rate != data_rate {
+        // the backoff of the MAC, only the network moves it otherwise
+        mac.configuration_mut().tx_data_rate = data_rate;
+    }
+    let Some(control) = control().filter(|control| control.enabled) else {
+        return;
+    };
+    let (ack_limit, ack_delay) = ack(&control, compat);
+    if ack_cnt >= ack_limit + ack_delay && (ack_cnt - ack_limit) % ack_delay == 0 {
+        let current = data_rate.map_or(0, |dr| dr as u8);
+        if current > region::min_data_rate() {
+            defmt::info!("ADR backoff to DR{}", current - 1);
+            mac.configuration_mut().tx_data_rate = region::data_rate(current - 1);
+        }
+    }
+}
+
+/// Starts a new session at the initial data rate.
+pub fn joined(mac: &mut RegionMac) {
+    ACK_CNT.store(0, Ordering::Relaxed);
+    if let Some(control) = control() {
+        mac.configuration_mut().tx_data_rate = region::data_rate(control.data_rate);
+    }
+}
@@ -0,0 +1,152 @@
+//! Airtime report for every uplink, measured on the radio from SetTx to TxDone and
+//! covering every transmission of the uplink: repetitions and confirmed retries. The
+//! application gets it back from the send, e.g. to stretch its reporting interval when
+//! the duty cycle budget runs low.
+//!
+//! The budget is the 1% EU868 duty cycle over a fixed window of an hour, summed over all
+//! channels, which is stricter than the per sub-band limit.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::duty_cycle;
+
+const WINDOW: Duration = Duration::from_secs(3600);
+const BUDGET: Duration = Duration::from_millis(36_000);
+const TX_DONE: u16 = 1 << 0;
+/// MHDR, FHDR without FOpts, FPort and MIC.
+pub const FRAME_OVERHEAD: usize = 1 + 7 + 1 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct UplinkReport {
+    pub time_on_air: Duration,
+    /// Channel of the last transmission in Hz.
+    pub frequency: u32,
+    pub tx_power: i8,
+    pub transmissions: u8,
+    /// Airtime left in the duty cycle window.
+    pub budget_left: Duration,
+}
+
+struct Airtime {
+    report: UplinkReport,
+    tx_started: Option<Instant>,
+    window_start: Instant,
+    window_used: Duration,
+}
+
+impl Airtime {
+    fn budget_left(&self, now: Instant) -> Duration {
+        if now.saturating_duration_since(self.window_start) >= WINDOW {
+            return BUDGET;
+        }
+        BUDGET.checked_sub(self.window_used).unwrap_or_default()
+    }
+}
+
+static AIRTIME: Mutex<CriticalSectionRawMutex, RefCell<Airtime>> =
+    Mutex::new(RefCell::new(Airtime {
+        report: UplinkReport {
+            time_on_air: Duration::from_ticks(0),
+            frequency: 0,
+            tx_power: 0,
+            transmissions: 0,
+            budget_left: BUDGET,
+        },
+        tx_started: None,
+        window_start: Instant::from_ticks(0),
+        window_used: Duration::from_ticks(0),
+    }));
+
+fn with_airtime<R>(f: impl FnOnce(&mut Airtime) -> R) -> R {
+    AIRTIME.lock(|airtime| f(&mut airtime.borrow_mut()))
+}
+
+/// Starts a new report, before the uplink is handed to the MAC.
+pub fn uplink_started() {
+    with_airtime(|airtime| {
+        airtime.report.time_on_air = Duration::from_ticks(0);
+        airtime.report.transmissions = 0;
+    })
+}
+
+/// From a SetTxParams command, with the power as sent to the radio.
+pub fn set_tx_power(tx_power: i8) {
+    with_airtime(|airtime| airtime.report.tx_power = tx_power);
+}
+
+/// From a SetTx command.
+pub fn tx_started(frequency: u32) {
+    with_airtime(|airtime| {
+        airtime.tx_started = Some(Instant::now());
+        airtime.report.frequency = frequency;
+    })
+}
+
+/// From a GetIrqStatus response.
+pub fn irq_status(status: u16) {
+    if status & TX_DONE == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let transmission = with_airtime(|airtime| {
+        let started = airtime.tx_started.take()?;
+        let time_on_air = now.saturating_duration_since(started);
+        if now.saturating_duration_since(airtime.window_start) >= WINDOW {
+            airtime.window_start = now;
+            airtime.window_used = Duration::from_ticks(0);
+        }
+        airtime.window_used += time_on_air;
+        airtime.report.time_on_air += time_on_air;
+        airtime.report.transmissions = airtime.report.transmissions.saturating_add(1);
+        Some((airtime.report.frequency, time_on_air))
+    });
+    if let Some((frequency, time_on_air)) = transmission {
+        duty_cycle::record(frequency, time_on_air);
+    }
+}
+
+/// The report of the uplink since [`uplink_started`].
+pub fn report() -> UplinkReport {
+    with_airtime(|airtime| {
+        let mut report = airtime.report;
+        report.budget_left = airtime.budget_left(Instant::now());
+        report
+    })
+}
+
+/// Airtime left in the duty cycle window now.
+pub fn budget_left() -> Duration {
+    with_airtime(|airtime| airtime.budget_left(Instant::now()))
+}
+
+/// Time on air of a PHY payload of `size` bytes, with the LoRa header, CRC and 4/5 coding
+/// rate the MAC uses and 8 preamble symbols, or 5 preamble bytes for FSK.
+pub fn time_on_air(data_rate: u8, size: usize) -> Duration {
+    match data_rate {
+        0..=5 => lora_time_on_air(12 - data_rate, 125_000, size),
+        6 => lora_time_on_air(7, 250_000, size),
+        _ => fsk_time_on_air(size),
+    }
+}
+
+/// Time on air of a LoRa PHY payload of `size` bytes at `bandwidth` Hz.
+pub fn lora_time_on_air(spreading_factor: u8, bandwidth: i64, size: usize) -> Duration {
+    let sf = spreading_factor as i64;
+    let symbol = (1 << sf) * 1_000_000 / bandwidth;
+    let low_data_rate_optimize = (sf >= 11 && bandwidth == 125_000) as i64;
+    let bits = 8 * size as i64 - 4 * sf + 28 + 16;
+    let per_block = 4 * (sf - 2 * low_data_rate_optimize);
+    let symbols = 8 + ((bits + per_block - 1) / per_block).max(0) * 5;
+    // 8 preamble symbols and 4.25 for the sync word
+    Duration::from_micros(((8 * 4 + 17) * symbol / 4 + symbols * symbol) as u64)
+}
+
+/// Time on air of an FSK PHY payload of `size` bytes at 50 kbps: preamble, sync word,
+/// length, payload and CRC.
+pub fn fsk_time_on_air(size: usize) -> Duration {
+    Duration::from_micros((5 + 3 + 1 + size as u64 + 2) * 8 * 20)
+}
@@ -0,0 +1,109 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::payload;
+use crate::schema;
+use crate::sensor::Measurement;
+
+pub const ALARM_PORT: u8 = schema::ALARM.port;
+pub const ALARM_PAYLOAD_SIZE: usize = schema::ALARM.header_size();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct AlarmConfig {
+    pub id: u8,
+    pub measurement: Measurement,
+    pub direction: Direction,
+    pub threshold: i32,
+    /// distance the value has to move back past the threshold before the alarm clears
+    pub hysteresis: i32,
+    /// minimum time between two uplinks for this alarm
+    pub min_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AlarmEvent {
+    Raised { id: u8, measurement: Measurement, value: i32 },
+    Cleared { id: u8, measurement: Measurement, value: i32 },
+}
+impl AlarmEvent {
+    pub fn encode(&self) -> [u8; ALARM_PAYLOAD_SIZE] {
+        let (id, raised, measurement, value) = match *self {
+            AlarmEvent::Raised { id, measurement, value } => (id, true, measurement, value),
+            AlarmEvent::Cleared { id, measurement, value } => (id, false, measurement, value),
+        };
+        payload::alarm(id, raised, measurement as u8, value)
+    }
+}
+
+struct Alarm {
+    config: AlarmConfig,
+    active: bool,
+    reported: bool,
+    value: i32,
+    last_sent: Option<Instant>,
+}
+impl Alarm {
+    fn update(&mut self, value: i32) {
+        let config = &self.config;
+        self.value = value;
+        self.active = match (config.direction, self.active) {
+            (Direction::Above, false) => value > config.threshold,
+            (Direction::Above, true) => value > config.threshold - config.hysteresis,
+            (Direction::Below, false) => value < config.threshold,
+            (Direction::Below, true) => value < config.threshold + config.hysteresis,
+        };
+    }
+
+    fn may_send(&self, now: Instant) -> bool {
+        self.active != self.reported
+            && self.last_sent.map_or(true, |last| now >= last + self.config.min_interval)
+    }
+}
+
+/// Evaluates measurements against configured thresholds.
+///
+/// State changes that happen while an alarm is rate limited are not lost, the latest
+/// state is reported once the alarm's `min_interval` has passed.
+pub struct AlarmEngine<const N: usize> {
+    alarms: Vec<Alarm, N>,
+}
+impl<const N: usize> AlarmEngine<N> {
+    pub const fn new() -> Self {
+        Self { alarms: Vec::new() }
+    }
+
+    pub fn add(&mut self, config: AlarmConfig) -> Result<(), AlarmConfig> {
+        let alarm = Alarm { config, active: false, reported: false, value: 0, last_sent: None };
+        self.alarms.push(alarm).map_err(|alarm| alarm.config)
+    }
+
+    pub fn update(&mut self, measurement: Measurement, value: i32) {
+        for alarm in self.alarms.iter_mut().filter(|a| a.config.measurement == measurement) {
+            alarm.update(value);
+        }
+    }
+
+    /// Returns the next alarm state change that should be sent and marks it as reported.
+    pub fn next_event(&mut self, now: Instant) -> Option<AlarmEvent> {
+        let alarm = self.alarms.iter_mut().find(|alarm| alarm.may_send(now))?;
+        alarm.reported = alarm.active;
+        alarm.last_sent = Some(now);
+        let (id, measurement, value) = (alarm.config.id, alarm.config.measurement, alarm.value);
+        Some(if alarm.active {
+            AlarmEvent::Raised { id, measurement, value }
+        } else {
+            AlarmEvent::Cleared { id, measurement, value }
+        })
+    }
+}
+impl<const N: usize> Default for AlarmEngine<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
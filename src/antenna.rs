@@ -0,0 +1,130 @@
+//! Spots a disconnected or damaged antenna, which otherwise goes unnoticed until someone
+//! wonders why a device went quiet. The radio has no reflected power measurement, so the
+//! proxies are the PA failing to ramp up into the mismatch, the downlink RSSI falling
+//! well below what the device used to hear, and confirmed uplinks going unanswered after
+//! the link was known to work. Each is reported once as suspected on [`ANTENNA_PORT`], and
+//! as cleared once the downlink RSSI is back near its baseline.
+
+use crate::payload;
+use crate::radio_telemetry::PA_RAMP_ERROR;
+use crate::schema;
+
+pub const ANTENNA_PORT: u8 = schema::ANTENNA.port;
+pub const ANTENNA_PAYLOAD_SIZE: usize = schema::ANTENNA.header_size();
+/// dB below the baseline for a downlink to count as low.
+const RSSI_DROP: i16 = 20;
+/// Consecutive low downlinks before a fault is suspected.
+const LOW_DOWNLINKS: u8 = 3;
+/// Downlinks averaged into the baseline before it is trusted.
+const BASELINE_SAMPLES: u8 = 8;
+const UNANSWERED_UPLINKS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Reason {
+    PaRamp = 0,
+    RssiDrop = 1,
+    NoDownlink = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AntennaEvent {
+    pub suspected: bool,
+    pub reason: Reason,
+    /// dBm, 0 before a baseline was established
+    pub baseline_rssi: i16,
+    pub rssi: i16,
+}
+impl AntennaEvent {
+    pub fn encode(&self) -> [u8; ANTENNA_PAYLOAD_SIZE] {
+        payload::antenna(self.suspected, self.reason as u8, self.baseline_rssi, self.rssi)
+    }
+}
+
+#[derive(Default)]
+pub struct AntennaMonitor {
+    /// Moving average of the downlink RSSI in 1/8 dB
+    baseline: i32,
+    samples: u8,
+    low: u8,
+    unanswered: u32,
+    last_rssi: i16,
+    fault: Option<Reason>,
+    pa_ramp_reported: bool,
+}
+impl AntennaMonitor {
+    fn baseline_rssi(&self) -> i16 {
+        if self.samples >= BASELINE_SAMPLES {
+            (self.baseline / 8) as i16
+        } else {
+            0
+        }
+    }
+
+    fn event(&self, suspected: bool, reason: Reason) -> AntennaEvent {
+        AntennaEvent {
+            suspected,
+            reason,
+            baseline_rssi: self.baseline_rssi(),
+            rssi: self.last_rssi,
+        }
+    }
+
+    fn suspect(&mut self, reason: Reason) -> Option<AntennaEvent> {
+        if self.fault.is_some() {
+            return None;
+        }
+        self.fault = Some(reason);
+        Some(self.event(true, reason))
+    }
+
+    pub fn downlink(&mut self, rssi: i16) -> Option<AntennaEvent> {
+        self.last_rssi = rssi;
+        self.unanswered = 0;
+        if self.samples < BASELINE_SAMPLES {
+            self.samples += 1;
+            self.baseline = match self.samples {
+                1 => rssi as i32 * 8,
+                _ => self.baseline + (rssi as i32 * 8 - self.baseline) / 8,
+            };
+            return None;
+        }
+        let baseline = self.baseline_rssi();
+        if rssi <= baseline - RSSI_DROP {
+            self.low = self.low.saturating_add(1);
+            return (self.low >= LOW_DOWNLINKS).then(|| self.suspect(Reason::RssiDrop)).flatten();
+        }
+        self.low = 0;
+        if rssi > baseline - RSSI_DROP / 2 {
+            match self.fault {
+                Some(reason @ (Reason::RssiDrop | Reason::NoDownlink)) => {
+                    self.fault = None;
+                    return Some(self.event(false, reason));
+                }
+                Some(Reason::PaRamp) => {}
+                None => self.baseline += (rssi as i32 * 8 - self.baseline) / 8,
+            }
+        }
+        None
+    }
+
+    /// After a confirmed uplink that got no answer.
+    pub fn unanswered(&mut self) -> Option<AntennaEvent> {
+        self.unanswered += 1;
+        if self.samples >= BASELINE_SAMPLES && self.unanswered >= UNANSWERED_UPLINKS {
+            self.suspect(Reason::NoDownlink)
+        } else {
+            None
+        }
+    }
+
+    /// With the radio's errors after an uplink, a PA ramp failure is reported once.
+    pub fn radio_errors(&mut self, errors: u16) -> Option<AntennaEvent> {
+        if errors & PA_RAMP_ERROR == 0 || self.pa_ramp_reported {
+            return None;
+        }
+        self.pa_ramp_reported = true;
+        self.fault = Some(Reason::PaRamp);
+        Some(self.event(true, Reason::PaRamp))
+    }
+}
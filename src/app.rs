@@ -0,0 +1,173 @@
+//! The example application, a task next to the main loop using what the firmware offers
+//! application code, as a starting point for a real one. Every round it asks the server
+//! for its settings with [`request`](crate::request::request) on [`SETTINGS_PORT`] and
+//! takes the interval answered, then sends a report on [`REPORT_PORT`] through the packet
+//! queue, on the data rate of the [`AppConfig`], with what the queue dropped and the
+//! answer to a [`link_check`] before it, stamped with the time of the network it asks for
+//! with [`rtc::sync`] until it has one. The next round is twice the interval away while a
+//! band has less than a tenth of its [`duty_cycle`] left. Until then it takes the
+//! downlinks for the application, settings pushed by the server on [`SETTINGS_PORT`]
+//! included.
+//!
+//! After [`LINK_LOST_CHECKS`] unanswered link checks in a row it turns ADR off on the
+//! slowest data rate with [`adr::set`], and restores the ADR control once a check is
+//! answered again.
+//!
+//! Spawned when the profile has an [`AppConfig`].
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::adr::{self, AdrControl};
+use crate::duty_cycle;
+use crate::link_check::{self, LinkCheckAns};
+use crate::packet_queue::{self, DataRateOverride};
+use crate::request::{self, Request};
+use crate::rtc::{self, NetworkTime};
+use crate::schema;
+
+pub const SETTINGS_PORT: u8 = schema::APP_SETTINGS.port;
+const SETTINGS_VERSION: u8 = 1;
+pub const REPORT_PORT: u8 = schema::APP_REPORT.port;
+const REPORT_SIZE: usize = schema::APP_REPORT.header_size();
+/// Unanswered link checks in a row before ADR is turned off on the slowest data rate.
+const LINK_LOST_CHECKS: u8 = 3;
+
+/// Encoded as described by [`schema::APP_REPORT`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct Report {
+    round: u16,
+    /// Uplinks of the packet queue dropped since boot, saturating.
+    dropped: u16,
+    /// Downlinks dropped since boot, saturating.
+    downlinks_dropped: u16,
+    /// Of the link check before the report, 0 gateways if it went unanswered.
+    link: LinkCheckAns,
+    /// 0 until the clock was set.
+    gps_seconds: u32,
+    /// Transmissions refused for the duty cycle since boot, saturating.
+    duty_cycle_refused: u16,
+}
+impl Report {
+    fn new(round: u16, link: Option<LinkCheckAns>) -> Self {
+        let stats = packet_queue::stats();
+        Self {
+            round,
+            dropped: stats.dropped.try_into().unwrap_or(u16::MAX),
+            downlinks_dropped: stats.downlinks_dropped.try_into().unwrap_or(u16::MAX),
+            link: link.unwrap_or(LinkCheckAns { margin: 0, gateway_count: 0 }),
+            gps_seconds: NetworkTime::now().map_or(0, |time| time.gps_seconds),
+            duty_cycle_refused: duty_cycle::refused().try_into().unwrap_or(u16::MAX),
+        }
+    }
+
+    fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut buf = [0; REPORT_SIZE];
+        buf[0..2].copy_from_slice(&self.round.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dropped.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.downlinks_dropped.to_be_bytes());
+        buf[6] = self.link.margin;
+        buf[7] = self.link.gateway_count;
+        buf[8..12].copy_from_slice(&self.gps_seconds.to_be_bytes());
+        buf[12..14].copy_from_slice(&self.duty_cycle_refused.to_be_bytes());
+        buf
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AppConfig {
+    /// Between two rounds, until the server answers with its own.
+    pub interval: Duration,
+    /// For the answer to the settings request, before it is sent again.
+    pub settings_timeout: Duration,
+    /// Data rate of the reports, e.g. `Fixed(0)` for range tests. `None` for the one ADR
+    /// or the profile picked.
+    pub report_data_rate: Option<DataRateOverride>,
+}
+
+#[embassy_executor::task]
+pub async fn run(config: AppConfig) {
+    let mut interval = config.interval;
+    let uplinks = packet_queue::uplinks();
+    let mut round: u16 = 0;
+    let mut unanswered: u8 = 0;
+    // The ADR control in effect before the link was lost, restored once it is back.
+    let mut before_lost: Option<AdrControl> = None;
+    loop {
+        let settings = Request {
+            port: SETTINGS_PORT,
+            payload: &[SETTINGS_VERSION],
+            response_port: SETTINGS_PORT,
+            timeout: config.settings_timeout,
+            retries: 2,
+            poll_interval: config.settings_timeout / 4,
+        };
+        match request::request(&settings).await {
+            Ok(answer) => interval = apply_settings(&answer).unwrap_or(interval),
+            Err(e) => defmt::warn!("app settings not fetched {:?}", e),
+        }
+        if !rtc::synchronized() {
+            match rtc::sync().await {
+                Some(time) => defmt::info!("app clock set to {:?}", time),
+                None => defmt::warn!("app clock not set"),
+            }
+        }
+        let link = link_check::check().await;
+        if link.is_some() {
+            unanswered = 0;
+            if let Some(control) = before_lost.take() {
+                defmt::info!("app link back, restoring {:?}", control);
+                adr::set(control);
+            }
+        } else {
+            unanswered = unanswered.saturating_add(1);
+            if unanswered == LINK_LOST_CHECKS {
+                if let Some(control) = adr::control() {
+                    defmt::warn!("app link lost, ADR off on the slowest data rate");
+                    adr::set(AdrControl { enabled: false, data_rate: 0, ..control });
+                    before_lost = Some(control);
+                }
+            }
+        }
+        let report = Report::new(round, link);
+        match uplinks.send_at(REPORT_PORT, &report.encode(), false, config.report_data_rate).await {
+            Ok(token) => defmt::info!("{:?} {:?}", report, token.outcome().await),
+            Err(e) => defmt::warn!("{:?} not queued {:?}", report, e),
+        }
+        round = round.wrapping_add(1);
+        let low = duty_cycle::budgets().find(|(band, left)| *left < band.budget() / 10);
+        let next_round = match low {
+            Some((band, left)) => {
+                defmt::info!("app slowing down, {} ms left in {:?}", left.as_millis(), band);
+                Instant::now() + interval * 2
+            }
+            None => Instant::now() + interval,
+        };
+        while let Either::Second(downlink) =
+            select(Timer::at(next_round), packet_queue::receive()).await
+        {
+            defmt::info!(
+                "port {} downlink FCnt {} RSSI {} SNR {} in {:?}",
+                downlink.fport,
+                downlink.fcnt,
+                downlink.rssi,
+                downlink.snr,
+                downlink.window
+            );
+            if downlink.fport == SETTINGS_PORT {
+                interval = apply_settings(&downlink.payload).unwrap_or(interval);
+            }
+        }
+    }
+}
+
+/// The interval of settings answered or pushed by the server.
+fn apply_settings(settings: &[u8]) -> Option<Duration> {
+    let [minutes_hi, minutes_lo, ..] = *settings else {
+        defmt::warn!("app settings {=[u8]:02X} not understood", settings);
+        return None;
+    };
+    let minutes = u16::from_be_bytes([minutes_hi, minutes_lo]);
+    defmt::info!("app interval set to {} min", minutes);
+    Some(Duration::from_secs(minutes.max(1) as u64 * 60))
+}
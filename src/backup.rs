@@ -0,0 +1,32 @@
+//! Snapshot of the [`Settings`] uplinked now and then, so that the application server can
+//! send it back to a replacement device. Keys are never part of it, and like every other
+//! payload it is encrypted with the AppSKey up to the application server.
+
+use crate::journal::crc16;
+use crate::schema;
+use crate::settings::{Settings, SETTINGS_SIZE};
+
+pub const BACKUP_PORT: u8 = schema::BACKUP.port;
+pub const BACKUP_PAYLOAD_SIZE: usize = schema::BACKUP.header_size();
+const VERSION: u8 = 1;
+
+pub fn encode(settings: &Settings) -> [u8; BACKUP_PAYLOAD_SIZE] {
+    let mut buf = [0; BACKUP_PAYLOAD_SIZE];
+    buf[0] = VERSION;
+    buf[1..1 + SETTINGS_SIZE].copy_from_slice(&settings.to_bytes());
+    let crc = crc16(&buf[..1 + SETTINGS_SIZE]);
+    buf[1 + SETTINGS_SIZE..].copy_from_slice(&crc.to_be_bytes());
+    buf
+}
+
+/// Validates a snapshot sent back in a downlink.
+pub fn decode(data: &[u8]) -> Option<Settings> {
+    if data.len() != BACKUP_PAYLOAD_SIZE || data[0] != VERSION {
+        return None;
+    }
+    let (body, crc) = data.split_at(1 + SETTINGS_SIZE);
+    if crc16(body).to_be_bytes() != crc {
+        return None;
+    }
+    Settings::from_bytes(&body[1..])
+}
@@ -0,0 +1,88 @@
+use heapless::Deque;
+
+use crate::payload;
+use crate::schema;
+use crate::sensor::Measurement;
+
+pub const BATCH_PORT: u8 = schema::BATCH.port;
+const HEADER_SIZE: usize = schema::BATCH.header_size();
+const SAMPLE_SIZE: usize = schema::BATCH.item_size();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Sample {
+    pub measurement: Measurement,
+    /// [`crate::clock_sync::timestamp`] of when it was taken
+    pub timestamp: u32,
+    pub value: i32,
+}
+
+/// Accumulates timestamped samples so that several of them can share one uplink.
+///
+/// Encoded as described by [`schema::BATCH`], sample timestamps are stored as offsets
+/// from the oldest sample in the uplink. Offsets only go forward, a sample stamped before
+/// the one ahead of it, after the clock was corrected backwards, starts the next uplink.
+pub struct Batch<const N: usize> {
+    samples: Deque<Sample, N>,
+}
+impl<const N: usize> Batch<N> {
+    pub const fn new() -> Self {
+        Self { samples: Deque::new() }
+    }
+
+    /// Number of samples that fit in a payload of `max_payload_size` bytes.
+    pub fn capacity(max_payload_size: usize) -> usize {
+        (max_payload_size.saturating_sub(HEADER_SIZE) / SAMPLE_SIZE).min(N).min(u8::MAX as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Adds a sample, dropping and returning the oldest one if the batch is full.
+    pub fn push(&mut self, sample: Sample) -> Option<Sample> {
+        let dropped = if self.samples.is_full() {
+            self.samples.pop_front()
+        } else {
+            None
+        };
+        let _ = self.samples.push_back(sample);
+        dropped
+    }
+
+    /// Moves as many of the oldest samples as fit into `buf`, returning the encoded length.
+    pub fn encode(&mut self, buf: &mut [u8]) -> usize {
+        let Some(base) = self.samples.front().map(|sample| sample.timestamp) else {
+            return 0;
+        };
+        let mut previous = base;
+        let mut count = 0;
+        let mut len = HEADER_SIZE;
+        while count < Self::capacity(buf.len()) {
+            let Some(sample) = self.samples.front() else {
+                break;
+            };
+            if sample.timestamp < previous {
+                break;
+            }
+            let Ok(offset) = u16::try_from(sample.timestamp - base) else {
+                break;
+            };
+            previous = sample.timestamp;
+            payload::batch_item(&mut buf[len..], sample.measurement as u8, offset, sample.value);
+            self.samples.pop_front();
+            count += 1;
+            len += SAMPLE_SIZE;
+        }
+        payload::batch_header(buf, base, count as u8);
+        len
+    }
+}
+impl<const N: usize> Default for Batch<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,239 @@
+//! Class B beacon acquisition and tracking. Without a beacon to go by the device listens
+//! for a whole beacon period, or only around the next one when [`crate::rtc`] has the
+//! time of the network; once one is heard, [`LoraTimer`] is reset on it and the
+//! radio only opens a short window around each following beacon, widened with every
+//! one missed for the clock drift. After [`MAX_MISSED`] the beacon counts as lost and the
+//! search starts over.
+//!
+//! Windows are run with the radio lent from the MAC, in between its operations, and the
+//! application learns about changes of the lock from [`STATUS`].
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Instant};
+use lora_phy::mod_params::{Bandwidth, RadioError, RxMode, SpreadingFactor};
+use lorawan::device::timer::Timer;
+
+use crate::coding_rate;
+use crate::exclusive::ExclusiveRadio;
+use crate::region::{self, BeaconParams};
+use crate::rtc::NetworkTime;
+use crate::timer::LoraTimer;
+
+pub const BEACON_PERIOD: Duration = Duration::from_secs(128);
+const PREAMBLE_SYMBOLS: u16 = 10;
+/// Beacon-less operation lasts 120 minutes.
+pub const MAX_MISSED: u32 = 56;
+/// Half the window around a beacon that was just heard.
+const WINDOW_MARGIN: Duration = Duration::from_millis(10);
+/// Added to the margin for every beacon missed, the drift over a period at 40 ppm plus
+/// the beacon's own jitter.
+const DRIFT_PER_PERIOD: Duration = Duration::from_millis(6);
+/// Half the window of a search timed by the wall clock, which may be a second off.
+const SEARCH_MARGIN: Duration = Duration::from_secs(2);
+/// Pause before searching again after a search heard nothing.
+const SEARCH_BACKOFF: Duration = Duration::from_secs(15 * 60);
+const MAX_BEACON_SIZE: usize = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BeaconStatus {
+    Locked {
+        gps_time: u32,
+        rssi: i16,
+        snr: i16,
+    },
+    /// A beacon expected wasn't heard, the lock is kept for now.
+    Missed {
+        missed: u32,
+    },
+    Lost,
+}
+
+/// Changes of the beacon lock, for the application to pick up.
+pub static STATUS: Channel<CriticalSectionRawMutex, BeaconStatus, 4> = Channel::new();
+
+fn publish(status: BeaconStatus) {
+    if STATUS.try_send(status).is_err() {
+        defmt::warn!("beacon status dropped {:?}", status);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum State {
+    Off,
+    /// Searching from `from` on, around the next beacon if `timed` by the wall clock or
+    /// for a whole period if not.
+    Searching {
+        from: Instant,
+        timed: bool,
+    },
+    /// Beacons missed since the last one heard, which reset the timer.
+    Tracking {
+        missed: u32,
+        gps_time: u32,
+    },
+}
+
+pub struct BeaconTracker {
+    timer: LoraTimer,
+    state: State,
+}
+impl BeaconTracker {
+    pub fn new() -> Self {
+        Self { timer: LoraTimer::new(), state: State::Off }
+    }
+
+    /// Starts looking for beacons, where the region has them.
+    pub fn start(&mut self) {
+        if region::current().beacon().is_none() {
+            defmt::warn!("no beacons in this region");
+            return;
+        }
+        if self.state == State::Off {
+            self.state = search(Instant::now());
+        }
+    }
+
+    /// GPS time of the last beacon heard and when it started, while locked.
+    pub fn last_beacon(&self) -> Option<(u32, Instant)> {
+        match (self.state, region::current().beacon()) {
+            (State::Tracking { gps_time, .. }, Some(beacon)) => {
+                Some((gps_time, self.timer.started() - beacon.airtime))
+            }
+            _ => None,
+        }
+    }
+
+    fn margin(missed: u32) -> Duration {
+        WINDOW_MARGIN + DRIFT_PER_PERIOD * (missed + 1)
+    }
+
+    /// Completes when the next window is to be opened, never while off.
+    pub async fn window_due(&self) {
+        match (self.state, region::current().beacon()) {
+            (State::Searching { from, .. }, _) => embassy_time::Timer::at(from).await,
+            (State::Tracking { missed, .. }, Some(beacon)) => {
+                // the timer was reset at the end of the last beacon heard
+                let due = BEACON_PERIOD * (missed + 1) - beacon.airtime - Self::margin(missed);
+                match self.timer.at(due.as_millis()) {
+                    Ok(at) => at.await,
+                    Err(e) => match e {},
+                }
+            }
+            _ => core::future::pending().await,
+        }
+    }
+
+    /// How long the next window stays open.
+    pub fn window(&self) -> Duration {
+        match (self.state, region::current().beacon()) {
+            (State::Searching { timed: true, .. }, Some(beacon)) => {
+                SEARCH_MARGIN * 2 + beacon.airtime
+            }
+            (State::Searching { timed: false, .. }, Some(beacon)) => BEACON_PERIOD + beacon.airtime,
+            (State::Tracking { missed, .. }, Some(beacon)) => {
+                Self::margin(missed) * 2 + beacon.airtime
+            }
+            _ => Duration::from_ticks(0),
+        }
+    }
+
+    /// Listens for the beacon in the window that is due.
+    pub async fn receive(&mut self, radio: &mut ExclusiveRadio<'_, '_>) -> Result<(), RadioError> {
+        let Some(beacon) = region::current().beacon() else {
+            return Ok(());
+        };
+        let window = self.window();
+        let radio = radio.radio();
+        let params = radio.create_modulation_params(
+            SpreadingFactor::_9,
+            Bandwidth::_125KHz,
+            coding_rate::LORAWAN,
+            beacon.frequency,
+        )?;
+        let packet = radio.create_rx_packet_params(
+            PREAMBLE_SYMBOLS,
+            true,
+            beacon.size as u8,
+            false,
+            false,
+            &params,
+        )?;
+        radio.prepare_for_rx(RxMode::Continuous, &params, &packet).await?;
+        let mut buf = [0; MAX_BEACON_SIZE];
+        let received = with_timeout(window, radio.rx(&packet, &mut buf[..beacon.size])).await;
+        radio.enter_standby().await?;
+        let heard = match received {
+            Ok(Ok((len, status))) => {
+                parse(&beacon, &buf[..len as usize]).map(|gps_time| (gps_time, status))
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => None,
+        };
+        match (heard, self.state) {
+            (Some((gps_time, status)), _) => {
+                self.timer.reset();
+                self.state = State::Tracking { missed: 0, gps_time };
+                publish(BeaconStatus::Locked { gps_time, rssi: status.rssi, snr: status.snr });
+            }
+            (None, State::Searching { .. }) => {
+                defmt::info!("no beacon heard, searching again in {}", SEARCH_BACKOFF);
+                self.state = search(Instant::now() + SEARCH_BACKOFF);
+            }
+            (None, State::Tracking { missed, gps_time }) if missed + 1 < MAX_MISSED => {
+                self.state = State::Tracking { missed: missed + 1, gps_time };
+                publish(BeaconStatus::Missed { missed: missed + 1 });
+            }
+            (None, _) => {
+                self.state = search(Instant::now());
+                publish(BeaconStatus::Lost);
+            }
+        }
+        Ok(())
+    }
+}
+impl Default for BeaconTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A search from `from` on, starting just before the next beacon when the time is known.
+fn search(from: Instant) -> State {
+    let Some(time) = NetworkTime::at(from) else {
+        return State::Searching { from, timed: false };
+    };
+    let period = BEACON_PERIOD.as_millis() as i64;
+    let until_beacon = Duration::from_millis((period - time.as_millis() % period) as u64);
+    let from = (from + until_beacon).checked_sub(SEARCH_MARGIN).unwrap_or(from);
+    State::Searching { from, timed: true }
+}
+
+/// GPS time from a beacon with a valid first CRC.
+fn parse(beacon: &BeaconParams, payload: &[u8]) -> Option<u32> {
+    if payload.len() != beacon.size {
+        return None;
+    }
+    let (common, rest) = payload.split_at(beacon.rfu + 4);
+    if crc16(common).to_le_bytes() != rest[..2] {
+        return None;
+    }
+    let time = &common[beacon.rfu..];
+    Some(u32::from_le_bytes([time[0], time[1], time[2], time[3]]))
+}
+
+/// CRC-16/XMODEM, which beacons use unlike the CRC in [`crate::journal`].
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
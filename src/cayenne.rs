@@ -0,0 +1,65 @@
+//! CayenneLPP payloads, which most network and application servers decode without a
+//! payload formatter: each value is `[channel][type][data]`, big endian and scaled as the
+//! type says. [`Cayenne`] appends values to a payload until it runs out of room, taking
+//! them in the units the rest of the firmware uses.
+
+use heapless::Vec;
+
+use crate::gnss::Position;
+
+const ANALOG_INPUT: u8 = 2;
+const TEMPERATURE: u8 = 103;
+const GPS: u8 = 136;
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub struct PayloadFull;
+
+pub struct Cayenne<'p, const N: usize> {
+    payload: &'p mut Vec<u8, N>,
+    max_size: usize,
+}
+impl<'p, const N: usize> Cayenne<'p, N> {
+    /// Appends to `payload` for as long as it stays within `max_size`.
+    pub fn new(payload: &'p mut Vec<u8, N>, max_size: usize) -> Self {
+        Self { payload, max_size: max_size.min(N) }
+    }
+
+    fn value(&mut self, channel: u8, kind: u8, data: &[u8]) -> Result<&mut Self, PayloadFull> {
+        if self.payload.len() + 2 + data.len() > self.max_size {
+            return Err(PayloadFull);
+        }
+        let _ = self.payload.extend_from_slice(&[channel, kind]);
+        let _ = self.payload.extend_from_slice(data);
+        Ok(self)
+    }
+
+    /// Sent in hundredths, saturating at ±327.67.
+    pub fn analog_input(&mut self, channel: u8, hundredths: i32) -> Result<&mut Self, PayloadFull> {
+        let value = hundredths.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.value(channel, ANALOG_INPUT, &value.to_be_bytes())
+    }
+
+    /// From centidegrees Celsius, sent in tenths.
+    pub fn temperature(
+        &mut self,
+        channel: u8,
+        centidegrees: i32,
+    ) -> Result<&mut Self, PayloadFull> {
+        let value = (centidegrees / 10).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.value(channel, TEMPERATURE, &value.to_be_bytes())
+    }
+
+    /// With the altitude in centimeters, coordinates are sent in 10^-4 degrees.
+    pub fn gps(
+        &mut self,
+        channel: u8,
+        position: Position,
+        altitude: i32,
+    ) -> Result<&mut Self, PayloadFull> {
+        let mut data = [0; 9];
+        data[..3].copy_from_slice(&(position.latitude / 1000).to_be_bytes()[1..]);
+        data[3..6].copy_from_slice(&(position.longitude / 1000).to_be_bytes()[1..]);
+        data[6..].copy_from_slice(&altitude.to_be_bytes()[1..]);
+        self.value(channel, GPS, &data)
+    }
+}
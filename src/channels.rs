@@ -0,0 +1,71 @@
+//! Channels from the CFList of the JoinAccept, which the MAC only learns while joining.
+//! They are persisted next to the session so that a session restored from flash keeps
+//! using all the channels the network gave it instead of the three default ones.
+//!
+//! The JoinAccept is taken from the radio buffer as it was received and decrypted here
+//! with the AppKey, the MAC has checked its MIC by the time the join succeeded.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+use crate::region::RegionMac;
+
+pub const CF_LIST_CHANNELS: usize = 5;
+/// MHDR, encrypted AppNonce, NetID, DevAddr, DLSettings, RxDelay, CFList and MIC.
+pub const JOIN_ACCEPT_SIZE: usize = 1 + 12 + 16 + 4;
+/// The CFList channels follow the three default ones.
+const FIRST_INDEX: usize = 3;
+const CF_LIST_TYPE_FREQUENCIES: u8 = 0;
+
+/// Channel frequencies in Hz, 0 for a channel left disabled.
+pub type Channels = [u32; CF_LIST_CHANNELS];
+
+/// Persists the channels of the JoinAccept that completed the join, returning them.
+pub fn save(
+    store: &mut DeviceNonVolatileStore<'_>,
+    join_accept: &[u8],
+    app_key: &[u8; 16],
+) -> Result<Option<Channels>, NonVolatileStoreError> {
+    let cf_list = match join_accept {
+        [_, encrypted @ ..] if join_accept.len() == JOIN_ACCEPT_SIZE => {
+            // the network encrypts JoinAccepts with AES decrypt, so they are read with encrypt
+            let aes = Aes128::new(GenericArray::from_slice(app_key));
+            let mut payload = [0; JOIN_ACCEPT_SIZE - 1];
+            payload.copy_from_slice(encrypted);
+            for block in payload.as_chunks_mut::<16>().0 {
+                aes.encrypt_block(GenericArray::from_mut_slice(block));
+            }
+            let cf_list: [u8; 16] = payload[12..28].try_into().unwrap();
+            (cf_list[15] == CF_LIST_TYPE_FREQUENCIES).then_some(cf_list)
+        }
+        _ => None,
+    };
+    store.write_record(RecordKey::CfList, cf_list.as_ref().map_or(&[], |cf_list| &cf_list[..]))?;
+    Ok(cf_list.map(|cf_list| decode(&cf_list)))
+}
+
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) -> Option<Channels> {
+    let mut cf_list = [0; 16];
+    match store.read_record(RecordKey::CfList, &mut cf_list) {
+        Ok(16) => Some(decode(&cf_list)),
+        _ => None,
+    }
+}
+
+fn decode(cf_list: &[u8; 16]) -> Channels {
+    core::array::from_fn(|i| {
+        let f = &cf_list[3 * i..3 * i + 3];
+        u32::from_le_bytes([f[0], f[1], f[2], 0]) * 100
+    })
+}
+
+/// Gives a MAC with a restored session the channels it joined with.
+pub fn restore(mac: &mut RegionMac, channels: &Channels) {
+    for (i, frequency) in channels.iter().enumerate().filter(|(_, f)| **f != 0) {
+        mac.add_channel(FIRST_INDEX + i, *frequency);
+    }
+    defmt::info!("channels restored {:?}", channels);
+}
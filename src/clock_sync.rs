@@ -0,0 +1,137 @@
+//! Application layer clock synchronization (TS003) on [`CLOCK_SYNC_PORT`]. The wall clock
+//! of [`crate::rtc`] starts at uptime and is corrected by the AppTimeAns of the network,
+//! and the device asks for a correction again every period, as set by the network with
+//! DeviceAppTimePeriodicityReq, or when forced with ForceDeviceResyncReq.
+//!
+//! The DeviceTime of an AppTimeReq is taken as the uplink is built rather than when it
+//! goes on air, which is well within the second resolution of the package.
+//!
+//! Data is stamped with [`timestamp`] as it is taken rather than with [`NetworkTime`],
+//! which is `None` until the first correction. A timestamp is the clock as it was at that
+//! instant: counted from boot before the first correction, and a later correction doesn't
+//! change it, backwards or forwards.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::rtc::{self, synchronized, NetworkTime};
+
+pub const CLOCK_SYNC_PORT: u8 = 202;
+/// Largest uplink of requests and answers.
+pub const MAX_UPLINK_SIZE: usize = 16;
+const PACKAGE_IDENTIFIER: u8 = 1;
+const PACKAGE_VERSION: u8 = 1;
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const APP_TIME: u8 = 0x01;
+const PERIODICITY_REQ: u8 = 0x02;
+const FORCE_RESYNC_REQ: u8 = 0x03;
+const APP_TIME_ANS_SIZE: usize = 5;
+/// Param bit of an AppTimeReq asking for an answer even when the clock is right.
+const ANS_REQUIRED: u8 = 1 << 4;
+/// Until the first correction requests are repeated at least this often.
+const UNSYNCED_RETRY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum ClockSyncError {
+    Invalid,
+}
+
+/// Seconds on the network clock at `instant`, for stamping data as it is taken. Only goes
+/// back when the clock is corrected backwards.
+pub fn timestamp(instant: Instant) -> u32 {
+    device_time(instant)
+}
+
+/// Device time sent in an AppTimeReq, counted from boot before the first correction.
+fn device_time(now: Instant) -> u32 {
+    (rtc::millis_at(now) / 1000) as u32
+}
+
+pub struct ClockSync {
+    period: Duration,
+    next_request: Instant,
+    /// AppTimeReq still to send for a ForceDeviceResyncReq.
+    forced: u8,
+    /// TokenReq of the next request, an AppTimeAns only counts with the same token.
+    token: u8,
+    answers: Vec<u8, MAX_UPLINK_SIZE>,
+}
+impl ClockSync {
+    /// Asks for the time right away and then every `period`.
+    pub fn new(period: Duration) -> Self {
+        Self { period, next_request: Instant::now(), forced: 0, token: 0, answers: Vec::new() }
+    }
+
+    /// An uplink for [`CLOCK_SYNC_PORT`] when answers are pending or a request is due.
+    pub fn take_uplink(&mut self, now: Instant) -> Option<Vec<u8, MAX_UPLINK_SIZE>> {
+        let request = self.forced > 0 || now >= self.next_request;
+        if !request && self.answers.is_empty() {
+            return None;
+        }
+        let mut uplink = core::mem::take(&mut self.answers);
+        if request {
+            self.forced = self.forced.saturating_sub(1);
+            let retry = if synchronized() {
+                self.period
+            } else {
+                self.period.min(UNSYNCED_RETRY)
+            };
+            self.next_request = now + retry;
+            let param = if synchronized() {
+                self.token
+            } else {
+                self.token | ANS_REQUIRED
+            };
+            let _ = uplink.push(APP_TIME);
+            let _ = uplink.extend_from_slice(&device_time(now).to_le_bytes());
+            let _ = uplink.push(param);
+        }
+        Some(uplink)
+    }
+
+    /// Handles the commands of a downlink on [`CLOCK_SYNC_PORT`], their answers go out
+    /// with the next [`ClockSync::take_uplink`].
+    pub fn command(&mut self, downlink: &[u8]) -> Result<(), ClockSyncError> {
+        let mut rest = downlink;
+        while let Some((&cid, args)) = rest.split_first() {
+            let used = match cid {
+                PACKAGE_VERSION_REQ => {
+                    let _ =
+                        self.answers.extend_from_slice(&[cid, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                    0
+                }
+                APP_TIME if args.len() >= APP_TIME_ANS_SIZE => {
+                    let correction = i32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                    if args[4] & 0x0F == self.token {
+                        rtc::correct(correction);
+                        self.token = (self.token + 1) & 0x0F;
+                        defmt::info!(
+                            "clock corrected by {} s to {:?}",
+                            correction,
+                            NetworkTime::now()
+                        );
+                    } else {
+                        defmt::warn!("AppTimeAns for another request ignored");
+                    }
+                    APP_TIME_ANS_SIZE
+                }
+                PERIODICITY_REQ if !args.is_empty() => {
+                    self.period = Duration::from_secs(128 << (args[0] & 0x0F));
+                    self.next_request = Instant::now() + self.period;
+                    defmt::info!("clock synchronized every {}", self.period);
+                    let _ = self.answers.extend_from_slice(&[cid, 0]);
+                    let _ =
+                        self.answers.extend_from_slice(&device_time(Instant::now()).to_le_bytes());
+                    1
+                }
+                FORCE_RESYNC_REQ if !args.is_empty() => {
+                    self.forced = args[0] & 0x07;
+                    1
+                }
+                _ => return Err(ClockSyncError::Invalid),
+            };
+            rest = &args[used..];
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Serialization of the MAC's [`Storable`] in the session page. Postcard is the default,
+//! the `cbor` feature switches to CBOR for products that back the state up to the cloud,
+//! and products with their own flash layout implement [`StorableCodec`] themselves.
+
+use lorawan::mac::types::Storable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CodecError;
+
+pub trait StorableCodec {
+    /// Returns the number of bytes written to `buf`.
+    fn encode(storable: &Storable, buf: &mut [u8]) -> Result<usize, CodecError>;
+    fn decode(buf: &mut [u8]) -> Result<Storable, CodecError>;
+}
+
+pub struct Postcard;
+impl StorableCodec for Postcard {
+    fn encode(storable: &Storable, buf: &mut [u8]) -> Result<usize, CodecError> {
+        postcard::to_slice(storable, buf).map(|used| used.len()).map_err(|_| CodecError)
+    }
+
+    fn decode(buf: &mut [u8]) -> Result<Storable, CodecError> {
+        postcard::from_bytes(buf).map_err(|_| CodecError)
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+#[cfg(feature = "cbor")]
+impl StorableCodec for Cbor {
+    fn encode(storable: &Storable, buf: &mut [u8]) -> Result<usize, CodecError> {
+        let writer = serde_cbor::ser::SliceWrite::new(buf);
+        let mut serializer = serde_cbor::Serializer::new(writer).packed_format();
+        serde::Serialize::serialize(storable, &mut serializer).map_err(|_| CodecError)?;
+        Ok(serializer.into_inner().bytes_written())
+    }
+
+    fn decode(buf: &mut [u8]) -> Result<Storable, CodecError> {
+        // not checking for trailing data, the rest of the page is erased flash
+        let mut deserializer = serde_cbor::Deserializer::from_mut_slice(buf);
+        serde::Deserialize::deserialize(&mut deserializer).map_err(|_| CodecError)
+    }
+}
+
+#[cfg(not(feature = "cbor"))]
+pub type DefaultCodec = Postcard;
+#[cfg(feature = "cbor")]
+pub type DefaultCodec = Cbor;
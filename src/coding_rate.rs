@@ -0,0 +1,57 @@
+//! The coding rate of LoRa transmissions in one place. Every region has LoRaWAN frames
+//! sent at 4/5, so while the MAC has the radio [`crate::iv::SubghzSpiDevice`] holds its
+//! modulation to [`LORAWAN`] whatever the driver asked for. The rate set with
+//! [`configure`] is only used by radio jobs outside of LoRaWAN, P2P links and lab tests,
+//! run with the MAC suspended.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use lora_phy::mod_params::CodingRate;
+
+pub const LORAWAN: CodingRate = CodingRate::_4_5;
+/// SetModulationParams value of [`LORAWAN`].
+const LORAWAN_PARAM: u8 = 0x01;
+
+static CONFIGURED: AtomicU8 = AtomicU8::new(LORAWAN_PARAM);
+static MAC_SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+fn param(coding_rate: CodingRate) -> u8 {
+    match coding_rate {
+        CodingRate::_4_5 => 0x01,
+        CodingRate::_4_6 => 0x02,
+        CodingRate::_4_7 => 0x03,
+        CodingRate::_4_8 => 0x04,
+    }
+}
+
+pub fn configure(coding_rate: CodingRate) {
+    if param(coding_rate) != LORAWAN_PARAM {
+        defmt::info!("coding rate {:?} used outside of LoRaWAN only", coding_rate);
+    }
+    CONFIGURED.store(param(coding_rate), Ordering::Relaxed);
+}
+
+/// For radio jobs outside of LoRaWAN.
+pub fn configured() -> CodingRate {
+    match CONFIGURED.load(Ordering::Relaxed) {
+        0x02 => CodingRate::_4_6,
+        0x03 => CodingRate::_4_7,
+        0x04 => CodingRate::_4_8,
+        _ => CodingRate::_4_5,
+    }
+}
+
+/// From [`crate::exclusive::ExclusiveRadio`] taking the radio from the MAC or handing it
+/// back.
+pub fn set_mac_suspended(suspended: bool) {
+    MAC_SUSPENDED.store(suspended, Ordering::Relaxed);
+}
+
+/// The coding rate of a SetModulationParams command to send, [`LORAWAN`] for the MAC.
+pub fn held(coding_rate: u8) -> u8 {
+    if MAC_SUSPENDED.load(Ordering::Relaxed) {
+        coding_rate
+    } else {
+        LORAWAN_PARAM
+    }
+}
@@ -0,0 +1,98 @@
+//! Time-boxed commissioning after installation, so that an installer can see a device
+//! working before walking away: for a while after its first join, the device reports at
+//! a short interval with every report confirmed as a link check, and flashes its LED with
+//! the outcome. Once the time is up the normal schedule takes over and that is recorded
+//! in the journal, later boots and rejoins leave it off.
+
+use embassy_stm32::gpio::Output;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::RecordKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CommissioningConfig {
+    /// How long commissioning lasts from the first join.
+    pub duration: Duration,
+    pub report_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum State {
+    /// Waiting for the first join.
+    Pending(CommissioningConfig),
+    Active {
+        until: Instant,
+        report_interval: Duration,
+    },
+    Done,
+}
+
+pub struct Commissioning {
+    state: State,
+}
+impl Commissioning {
+    pub fn load(
+        store: &mut DeviceNonVolatileStore<'_>,
+        config: Option<CommissioningConfig>,
+    ) -> Self {
+        let mut buf = [0];
+        let done = matches!(store.read_record(RecordKey::Commissioned, &mut buf), Ok(1));
+        let state = match config {
+            Some(config) if !done => State::Pending(config),
+            _ => State::Done,
+        };
+        Self { state }
+    }
+
+    /// Starts commissioning with the first session, joined or restored.
+    pub fn joined(&mut self) {
+        if let State::Pending(config) = self.state {
+            defmt::info!("commissioning for {}", config.duration);
+            self.state = State::Active {
+                until: Instant::now() + config.duration,
+                report_interval: config.report_interval,
+            };
+        }
+    }
+
+    /// Whether commissioning is still on, ending it once the time is up.
+    pub fn active(&mut self, store: &mut DeviceNonVolatileStore<'_>) -> bool {
+        match self.state {
+            State::Active { until, .. } if Instant::now() >= until => {
+                defmt::info!("commissioning over, back to the normal schedule");
+                self.state = State::Done;
+                if let Err(e) = store.write_record(RecordKey::Commissioned, &[1]) {
+                    defmt::error!("commissioning end not saved {:?}", e);
+                }
+                false
+            }
+            State::Active { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The report interval to use, `normal` outside of commissioning.
+    pub fn report_interval(&self, normal: Duration) -> Duration {
+        match self.state {
+            State::Active { report_interval, .. } => report_interval.min(normal),
+            _ => normal,
+        }
+    }
+}
+
+/// Shows the outcome of a link check: one long flash when acknowledged, three short ones
+/// when not.
+pub async fn signal(led: &mut Output<'_>, acked: bool) {
+    let (flashes, length) = if acked {
+        (1, Duration::from_millis(1000))
+    } else {
+        (3, Duration::from_millis(150))
+    };
+    for _ in 0..flashes {
+        led.set_high();
+        Timer::after(length).await;
+        led.set_low();
+        Timer::after(Duration::from_millis(150)).await;
+    }
+}
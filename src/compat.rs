@@ -0,0 +1,30 @@
+use embassy_time::Duration;
+
+/// Behaviour profile of the stack, selectable at runtime to compare against fleets
+/// running Semtech's LoRaMac-node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub enum CompatProfile {
+    #[default]
+    Standard,
+    /// Opens RX1 and RX2 early by LoRaMac-node's default `SystemMaxRxError` and uses its
+    /// ADR backoff counters, whatever the ADR control asks for.
+    LoRaMacNode,
+}
+impl CompatProfile {
+    /// How much earlier than the nominal RX1/RX2 delay the receive window is opened.
+    pub fn rx_window_margin(&self) -> Duration {
+        match self {
+            CompatProfile::Standard => Duration::from_millis(0),
+            CompatProfile::LoRaMacNode => Duration::from_millis(10),
+        }
+    }
+
+    /// ADR_ACK_LIMIT and ADR_ACK_DELAY in place of the ones of [`crate::adr`], LoRaMac-node's
+    /// `REGION_COMMON_DEFAULT_ADR_ACK_LIMIT` and `_DELAY`.
+    pub fn adr_ack(&self) -> Option<(u16, u16)> {
+        match self {
+            CompatProfile::Standard => None,
+            CompatProfile::LoRaMacNode => Some((64, 32)),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Keeps the application from acting twice on the same downlink, as happens when the
+//! network repeats a frame or one slips through from several gateways. Recent downlinks
+//! are remembered by FCnt, FPort and a hash of their payload.
+
+use crate::frames::FrameId;
+
+const ENTRIES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    fcnt: u16,
+    fport: u8,
+    hash: u32,
+}
+
+#[derive(Default)]
+pub struct Dedup {
+    entries: [Option<Entry>; ENTRIES],
+    next: usize,
+}
+impl Dedup {
+    /// Remembers the downlink, returning whether it was seen before.
+    pub fn is_duplicate(&mut self, id: FrameId, data: &[u8]) -> bool {
+        let FrameId::Data { fcnt, fport: Some(fport), .. } = id else {
+            return false;
+        };
+        let entry = Entry { fcnt, fport, hash: fnv1a(data) };
+        if self.entries.contains(&Some(entry)) {
+            return true;
+        }
+        self.entries[self.next] = Some(entry);
+        self.next = (self.next + 1) % ENTRIES;
+        false
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
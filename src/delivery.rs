@@ -0,0 +1,141 @@
+//! Delivery semantics declared per FPort. At most once uplinks are never confirmed. At
+//! least once uplinks are confirmed and sent again until the network acknowledges them
+//! or the retries run out, ahead of anything new. Persistent ones are also kept in the
+//! journal until then, so that a reboot doesn't lose them; that takes a payload that
+//! fits in a record, larger ones are only delivered at least once.
+//!
+//! Ports without a declaration are sent confirmed or not as the uplink was built.
+
+use heapless::Vec;
+
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::{RecordKey, MAX_VALUE_SIZE};
+use crate::region::MAX_PAYLOAD_SIZE;
+
+/// FPort and retries left ahead of the payload in the record.
+const RECORD_HEADER_SIZE: usize = 2;
+const MAX_PERSISTENT_SIZE: usize = MAX_VALUE_SIZE - RECORD_HEADER_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Delivery {
+    AtMostOnce,
+    AtLeastOnce { retries: u8 },
+    Persistent { retries: u8 },
+}
+
+struct Outstanding {
+    fport: u8,
+    payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+    retries: u8,
+    persistent: bool,
+}
+
+pub struct Deliveries {
+    policy: &'static [(u8, Delivery)],
+    outstanding: Option<Outstanding>,
+}
+impl Deliveries {
+    /// Picks up a persistent uplink that wasn't acknowledged before the reboot.
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>, policy: &'static [(u8, Delivery)]) -> Self {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        let outstanding = match store.read_record(RecordKey::PendingUplink, &mut buf) {
+            Ok(len) if len > RECORD_HEADER_SIZE => {
+                defmt::info!("port {} uplink still unacknowledged", buf[0]);
+                Some(Outstanding {
+                    fport: buf[0],
+                    retries: buf[1],
+                    payload: Vec::from_slice(&buf[RECORD_HEADER_SIZE..len]).unwrap(),
+                    persistent: true,
+                })
+            }
+            _ => None,
+        };
+        Self { policy, outstanding }
+    }
+
+    fn declared(&self, fport: u8) -> Option<Delivery> {
+        self.policy.iter().find(|(port, _)| *port == fport).map(|(_, delivery)| *delivery)
+    }
+
+    /// The port of an unacknowledged uplink to send again, with its payload copied in.
+    pub fn retry(&self, payload: &mut Vec<u8, MAX_PAYLOAD_SIZE>) -> Option<u8> {
+        let outstanding = self.outstanding.as_ref()?;
+        payload.clone_from(&outstanding.payload);
+        Some(outstanding.fport)
+    }
+
+    /// Whether an uplink waits to be sent again.
+    pub fn is_outstanding(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    /// Whether the uplink about to be sent is confirmed, holding on to it until it is
+    /// acknowledged where the port asks for that.
+    pub fn sending(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        fport: Option<u8>,
+        payload: &[u8],
+        confirmed: bool,
+    ) -> bool {
+        let Some(fport) = fport else {
+            return confirmed;
+        };
+        if self.outstanding.as_ref().is_some_and(|outstanding| outstanding.fport == fport) {
+            return true;
+        }
+        let (retries, persistent) = match self.declared(fport) {
+            None => return confirmed,
+            Some(Delivery::AtMostOnce) => return false,
+            Some(Delivery::AtLeastOnce { retries }) => (retries, false),
+            Some(Delivery::Persistent { retries }) => (retries, true),
+        };
+        let persistent = persistent && {
+            let fits = payload.len() <= MAX_PERSISTENT_SIZE;
+            if !fits {
+                defmt::warn!("port {} payload too large to persist", fport);
+            }
+            fits
+        };
+        let outstanding =
+            Outstanding { fport, payload: Vec::from_slice(payload).unwrap(), retries, persistent };
+        if persistent {
+            save(store, &outstanding);
+        }
+        self.outstanding = Some(outstanding);
+        true
+    }
+
+    /// After an uplink went out, acknowledged or not.
+    pub fn sent(&mut self, store: &mut DeviceNonVolatileStore<'_>, acked: bool) {
+        let Some(outstanding) = self.outstanding.as_mut() else {
+            return;
+        };
+        if !acked && outstanding.retries > 0 {
+            outstanding.retries -= 1;
+            if outstanding.persistent {
+                save(store, outstanding);
+            }
+            return;
+        }
+        if !acked {
+            defmt::warn!("port {} uplink never acknowledged, dropped", outstanding.fport);
+        }
+        if outstanding.persistent {
+            // an empty record removes it
+            if let Err(e) = store.write_record(RecordKey::PendingUplink, &[]) {
+                defmt::error!("pending uplink not removed {:?}", e);
+            }
+        }
+        self.outstanding = None;
+    }
+}
+
+fn save(store: &mut DeviceNonVolatileStore<'_>, outstanding: &Outstanding) {
+    let mut buf: Vec<u8, MAX_VALUE_SIZE> = Vec::new();
+    buf.extend_from_slice(&[outstanding.fport, outstanding.retries]).unwrap();
+    buf.extend_from_slice(&outstanding.payload).unwrap();
+    if let Err(e) = store.write_record(RecordKey::PendingUplink, &buf) {
+        defmt::error!("pending uplink not saved {:?}", e);
+    }
+}
@@ -0,0 +1,52 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::region::MAX_TX_POWER_ANYWHERE;
+
+/// Caps TX power while the die temperature is high, protecting the PA and the
+/// battery in hot enclosures.
+pub struct DeratingPolicy {
+    /// centidegrees Celsius above which derating starts
+    pub threshold: i32,
+    pub hysteresis: i32,
+    /// dBm
+    pub max_tx_power: i8,
+}
+
+pub const DERATING_POLICY: DeratingPolicy = DeratingPolicy {
+    threshold: 7000,
+    hysteresis: 500,
+    max_tx_power: if MAX_TX_POWER_ANYWHERE < 14 {
+        MAX_TX_POWER_ANYWHERE
+    } else {
+        14
+    },
+};
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Feeds a new temperature reading, returning the new state if derating toggled.
+pub fn update(temperature: i32) -> Option<bool> {
+    let active = ACTIVE.load(Ordering::Relaxed);
+    let policy = &DERATING_POLICY;
+    let new = if active {
+        temperature > policy.threshold - policy.hysteresis
+    } else {
+        temperature > policy.threshold
+    };
+    (new != active).then(|| {
+        ACTIVE.store(new, Ordering::Relaxed);
+        new
+    })
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn limit(tx_power: i8) -> i8 {
+    if is_active() {
+        tx_power.min(DERATING_POLICY.max_tx_power)
+    } else {
+        tx_power
+    }
+}
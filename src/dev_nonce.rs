@@ -0,0 +1,152 @@
+//! The DevNonces of past join requests in two flash pages of their own, apart from the
+//! session and the journal, so that wiping or losing either never lets the device send a
+//! DevNonce again that the join server has seen, which it would reject.
+//!
+//! The lorawan MAC draws its DevNonces at random and can't be given one, so a join request
+//! whose DevNonce is in the log is refused when the radio is to switch to TX, like a frame
+//! beyond the duty cycle, and the join is tried again at once with a new one. The nonce of
+//! every join request sent is logged after its join attempt, a reset during the attempt
+//! loses it.
+//!
+//! Each nonce takes the next double word, with its complement. Once a page is full the
+//! other one is erased and taken over, so the log holds at least a page of the latest.
+
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Deque;
+
+use crate::frames::FrameId;
+
+/// Flash is programmed a double word at a time.
+const WORD: u32 = 8;
+const ERASED: u8 = 0xFF;
+const MARK: u8 = 0xD7;
+const PAGES: u32 = 2;
+/// Nonces the two pages of the 4K region hold.
+const MAX_LOGGED: usize = 512;
+
+/// The nonces in the log, oldest first.
+static LOGGED: Mutex<CriticalSectionRawMutex, RefCell<Deque<u16, MAX_LOGGED>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+/// Of the join request in the radio buffer, until it is logged or refused.
+static PENDING: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+static REFUSED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+fn logged(nonce: u16) {
+    LOGGED.lock(|logged| {
+        let mut logged = logged.borrow_mut();
+        if logged.is_full() {
+            logged.pop_front();
+        }
+        let _ = logged.push_back(nonce);
+    });
+}
+
+/// From the radio buffer written for an uplink.
+pub fn uplink(phy: &[u8]) {
+    if let Some(FrameId::JoinRequest { dev_nonce }) = FrameId::parse(phy) {
+        PENDING.lock(|pending| pending.set(Some(dev_nonce)));
+    }
+}
+
+/// Whether the frame the radio is set up for may go out, not a join request with a
+/// DevNonce used before.
+pub fn permit_tx() -> bool {
+    let Some(nonce) = PENDING.lock(Cell::get) else {
+        return true;
+    };
+    if !LOGGED.lock(|logged| logged.borrow().iter().any(|logged| *logged == nonce)) {
+        return true;
+    }
+    defmt::warn!("refusing join request with DevNonce {} used before", nonce);
+    PENDING.lock(|pending| pending.set(None));
+    REFUSED.lock(|refused| refused.set(true));
+    false
+}
+
+/// The nonce of the join request sent since the last call, to log.
+pub fn take_sent() -> Option<u16> {
+    PENDING.lock(|pending| pending.take())
+}
+
+/// Whether the last join request was refused for its DevNonce since the last call.
+pub fn take_refused() -> bool {
+    REFUSED.lock(|refused| refused.replace(false))
+}
+
+pub struct DevNonces {
+    base: u32,
+    page_size: u32,
+    mounted: bool,
+    page: u32,
+    next: u32,
+}
+impl DevNonces {
+    pub const fn new(base: u32, page_size: u32) -> Self {
+        Self { base, page_size, mounted: false, page: 0, next: 0 }
+    }
+
+    /// Logs the DevNonce of a join request sent.
+    pub fn record<F: NorFlash>(&mut self, flash: &mut F, nonce: u16) -> Result<(), F::Error> {
+        self.mount(flash)?;
+        if self.next + WORD > self.page_size {
+            let page = (self.page + 1) % PAGES;
+            let start = self.page_offset(page);
+            flash.erase(start, start + self.page_size)?;
+            self.page = page;
+            self.next = 0;
+        }
+        let [low, high] = nonce.to_le_bytes();
+        let entry = [MARK, ERASED, low, high, !low, !high, ERASED, ERASED];
+        let offset = self.page_offset(self.page) + self.next;
+        // a torn entry still takes its word and is skipped after a reset
+        self.next += WORD;
+        logged(nonce);
+        flash.write(offset, &entry)
+    }
+
+    fn page_offset(&self, page: u32) -> u32 {
+        self.base + page * self.page_size
+    }
+
+    /// Reads the log, the page that isn't appended to first.
+    pub fn mount<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        if self.mounted {
+            return Ok(());
+        }
+        let mut ends = [0; PAGES as usize];
+        for (page, end) in ends.iter_mut().enumerate() {
+            while *end + WORD <= self.page_size {
+                let mut entry = [0; WORD as usize];
+                flash.read(self.page_offset(page as u32) + *end, &mut entry)?;
+                if entry.iter().all(|b| *b == ERASED) {
+                    break;
+                }
+                *end += WORD;
+            }
+        }
+        // appended to after whatever it holds, torn entries included, unless it is full
+        self.page = match ends {
+            [full, end] if full + WORD > self.page_size && end + WORD <= self.page_size => 1,
+            [end, _] if end + WORD <= self.page_size => 0,
+            _ => 1,
+        };
+        self.next = ends[self.page as usize];
+        for page in [(self.page + 1) % PAGES, self.page] {
+            let mut offset = 0;
+            while offset < ends[page as usize] {
+                let mut entry = [0; WORD as usize];
+                flash.read(self.page_offset(page) + offset, &mut entry)?;
+                offset += WORD;
+                if entry[0] == MARK && [!entry[2], !entry[3]] == entry[4..6] {
+                    logged(u16::from_le_bytes([entry[2], entry[3]]));
+                }
+            }
+        }
+        self.mounted = true;
+        Ok(())
+    }
+}
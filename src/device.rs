@@ -1,41 +1,107 @@
 use core::convert::Infallible;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use embassy_stm32::exti::ExtiInput;
 use embassy_stm32::flash::{Bank1Region, Blocking, Flash, MAX_ERASE_SIZE};
-use embassy_stm32::gpio::{Level, Output, Pin, Speed};
+use embassy_stm32::gpio::{Input, Level, Output, Pin, Pull, Speed};
+use embassy_stm32::i2c::{self, I2c};
 use embassy_stm32::pac;
-use embassy_stm32::peripherals::RNG;
+use embassy_stm32::peripherals::{RNG, USART1};
 use embassy_stm32::rng::Rng;
 use embassy_stm32::spi::Spi;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::usart::{self, UartRx};
 use embassy_stm32::{bind_interrupts, Peripherals};
 use embassy_time::Delay;
-use lora_phy::sx126x::{self, Stm32wl, Sx126x, TcxoCtrlVoltage};
+use heapless::Vec;
+use lora_phy::mod_params::RadioError;
+use lora_phy::sx126x::Sx126x;
 use lora_phy::LoRa;
 use lorawan::device::non_volatile_store::NonVolatileStore;
 use lorawan::device::{Device, DeviceSpecs};
-use lorawan::mac::types::Storable;
-use postcard::{from_bytes, to_slice};
+use lorawan::mac::types::{Configuration, Credentials, Storable};
+use lorawan::mac::Mac;
 
-use crate::iv::{InterruptHandler, Stm32wlInterfaceVariant, SubghzSpiDevice};
+use crate::accelerometer::Accelerometer;
+use crate::codec::{CodecError, DefaultCodec, StorableCodec};
+use crate::compat::CompatProfile;
+use crate::dev_nonce::DevNonces;
+use crate::exclusive::ExclusiveRadio;
+use crate::fcnt::FcntPolicy;
+use crate::gnss::Gnss;
+use crate::iv::{self, InterruptHandler, Stm32wlInterfaceVariant, SubghzSpiDevice};
+use crate::journal::{Journal, JournalError, RecordKey};
+use crate::link_check::{self, LinkCheckAns};
 use crate::lora_radio::{LoraRadioKind, LoraType};
+use crate::migration::{self, Header, HEADER_SIZE};
+use crate::pin_map::{PinFunction, PinMap, SLOTS};
+use crate::radio_config::{self, Selection};
+use crate::region::{self, RegionChoice, RegionMac};
+use crate::rtc::{self, NetworkTime};
+use crate::sensor::Sensors;
+use crate::spool::{Spool, SpoolError};
 use crate::timer::LoraTimer;
+use crate::uplink_edit;
 use rand_core::RngCore;
 
 bind_interrupts!(struct Irqs{
     SUBGHZ_RADIO => InterruptHandler;
     RNG => embassy_stm32::rng::InterruptHandler<RNG>;
+    USART1 => usart::InterruptHandler<USART1>;
 });
 
 extern "C" {
     static __storage: u8;
+    static __spool: u8;
+    static __spool_end: u8;
+    static __dev_nonce: u8;
+    static __dev_nonce_end: u8;
+}
+#[cfg(feature = "fuota")]
+extern "C" {
+    static __staging: u8;
+    static __staging_end: u8;
 }
 pub struct LoraDevice<'d> {
     rng: DeviceRng<'d>,
     radio: LoraType<'d>,
     timer: LoraTimer,
     non_volatile_store: DeviceNonVolatileStore<'d>,
+    sensors: Sensors<'d>,
+    accelerometer: Option<Accelerometer<'d>>,
+    gnss: Gnss<'d>,
+    pulse_inputs: Vec<(usize, ExtiInput<'d>), SLOTS>,
+    wake_inputs: Vec<(usize, ExtiInput<'d>), SLOTS>,
+    /// Held low, nothing switches the relays yet.
+    _relays: [Option<Output<'d>>; SLOTS],
+    led: Output<'d>,
+    radio_selection: Selection,
+    hardware_revision: u8,
 }
 impl<'a> LoraDevice<'a> {
-    pub async fn new(peripherals: Peripherals) -> LoraDevice<'a> {
+    pub async fn new(peripherals: Peripherals, fcnt_policy: FcntPolicy) -> LoraDevice<'a> {
+        #[cfg(feature = "markers")]
+        crate::markers::init(crate::markers::Markers {
+            sleep: Output::new(peripherals.PB8, Level::Low, Speed::Low),
+            tx: Output::new(peripherals.PB9, Level::Low, Speed::Low),
+            rx: Output::new(peripherals.PA8, Level::Low, Speed::Low),
+        });
+        let mut non_volatile_store = DeviceNonVolatileStore::new(
+            Flash::new_blocking(peripherals.FLASH).into_blocking_regions().bank1_region,
+            fcnt_policy,
+        );
+        // straps to ground, read once and left floating again
+        let hardware_revision = {
+            let straps =
+                [Input::new(peripherals.PB13, Pull::Up), Input::new(peripherals.PB14, Pull::Up)];
+            straps
+                .iter()
+                .enumerate()
+                .fold(0, |revision, (i, strap)| revision | (strap.is_low() as u8) << i)
+        };
+        let radio_selection = radio_config::select(&mut non_volatile_store);
+        defmt::info!("radio {:?} {:?}", radio_selection, radio_selection.profile());
         let lora: LoraType<'a> = {
             let spi =
                 Spi::new_subghz(peripherals.SUBGHZSPI, peripherals.DMA1_CH2, peripherals.DMA1_CH3);
@@ -46,25 +112,112 @@ impl<'a> LoraDevice<'a> {
                 Some(Output::new(peripherals.PC4.degrade(), Level::Low, Speed::High)),
             )
             .unwrap();
-            let config = sx126x::Config {
-                chip: Stm32wl { use_high_power_pa: true },
-                tcxo_ctrl: Some(TcxoCtrlVoltage::Ctrl1V7),
-                use_dcdc: true,
-                rx_boost: false,
-            };
+            let config = radio_selection.profile().config();
             LoRa::new(Sx126x::new(spi, iv, config), true, Delay).await.unwrap()
         };
-        let non_volatile_store = DeviceNonVolatileStore::new(
-            Flash::new_blocking(peripherals.FLASH).into_blocking_regions().bank1_region,
+        let accelerometer = Accelerometer::new(
+            I2c::new_blocking(
+                peripherals.I2C2,
+                peripherals.PA12,
+                peripherals.PA11,
+                Hertz(100_000),
+                i2c::Config::default(),
+            ),
+            ExtiInput::new(peripherals.PB3, peripherals.EXTI3, Pull::None),
         );
+        let mut gnss_config = usart::Config::default();
+        gnss_config.baudrate = 9600;
+        let gnss = Gnss::new(
+            UartRx::new(
+                peripherals.USART1,
+                Irqs,
+                peripherals.PB7,
+                peripherals.DMA1_CH4,
+                gnss_config,
+            )
+            .unwrap(),
+        );
+        let pin_map = PinMap::load(&mut non_volatile_store);
+        defmt::info!("{:?}", pin_map);
+        let mut pulse_inputs = Vec::new();
+        let mut wake_inputs = Vec::new();
+        let mut relays = [const { None }; SLOTS];
+        macro_rules! assign {
+            ($slot:expr, $pin:expr, $exti:expr) => {
+                match pin_map.0[$slot] {
+                    PinFunction::PulseInput => {
+                        let _ = pulse_inputs.push(($slot, ExtiInput::new($pin, $exti, Pull::Up)));
+                    }
+                    PinFunction::WakeInput => {
+                        let _ = wake_inputs.push(($slot, ExtiInput::new($pin, $exti, Pull::Up)));
+                    }
+                    PinFunction::Relay => {
+                        relays[$slot] = Some(Output::new($pin.degrade(), Level::Low, Speed::Low))
+                    }
+                    PinFunction::Unused => {}
+                }
+            };
+        }
+        assign!(0, peripherals.PA0, peripherals.EXTI0);
+        assign!(1, peripherals.PA1, peripherals.EXTI1);
+        assign!(2, peripherals.PB4, peripherals.EXTI4);
+        assign!(3, peripherals.PB5, peripherals.EXTI5);
         let ret = Self {
             rng: DeviceRng(Rng::new(peripherals.RNG, Irqs)),
             radio: lora,
             timer: LoraTimer::new(),
             non_volatile_store,
+            sensors: Sensors::new(peripherals.ADC),
+            accelerometer,
+            gnss,
+            pulse_inputs,
+            wake_inputs,
+            _relays: relays,
+            led: Output::new(peripherals.PB10, Level::Low, Speed::Low),
+            radio_selection,
+            hardware_revision,
         };
         ret
     }
+    pub fn sensors(&mut self) -> &mut Sensors<'a> {
+        &mut self.sensors
+    }
+    /// Peripherals reporting asynchronously, borrowed together to be waited on at once.
+    pub fn event_sources(&mut self) -> (Option<&mut Accelerometer<'a>>, &mut Gnss<'a>) {
+        (self.accelerometer.as_mut(), &mut self.gnss)
+    }
+    /// Pulse inputs of the pin map with their slots, to be counted by tasks of their own.
+    pub fn take_pulse_inputs(&mut self) -> Vec<(usize, ExtiInput<'a>), SLOTS> {
+        core::mem::take(&mut self.pulse_inputs)
+    }
+    /// Wake inputs of the pin map with their slots, watched by tasks of their own.
+    pub fn take_wake_inputs(&mut self) -> Vec<(usize, ExtiInput<'a>), SLOTS> {
+        core::mem::take(&mut self.wake_inputs)
+    }
+    pub fn led(&mut self) -> &mut Output<'a> {
+        &mut self.led
+    }
+    /// Keeps the MAC off the radio until [`ExclusiveRadio::resume`].
+    pub fn suspend_mac(&mut self) -> ExclusiveRadio<'_, 'a> {
+        ExclusiveRadio::new(self)
+    }
+    /// Leaves whatever the radio was doing and puts it in standby with its IRQs cleared,
+    /// for when an exchange was cut short.
+    pub async fn abort_rx(&mut self) -> Result<(), RadioError> {
+        iv::abandon();
+        self.radio.enter_standby().await
+    }
+    pub fn radio_selection(&self) -> Selection {
+        self.radio_selection
+    }
+    /// Board revision strapped on PB13 and PB14, 0 when neither is fitted.
+    pub fn hardware_revision(&self) -> u8 {
+        self.hardware_revision
+    }
+    pub fn set_compat_profile(&mut self, profile: CompatProfile) {
+        defmt::info!("compat profile {:?}", profile);
+        self.timer.set_margin(profile.rx_window_margin());
+    }
 }
 impl defmt::Format for LoraDevice<'_> {
     fn format(&self, fmt: defmt::Formatter<'_>) {
@@ -73,43 +226,347 @@ impl defmt::Format for LoraDevice<'_> {
 }
 pub struct DeviceRng<'a>(Rng<'a, RNG>);
 
-pub struct DeviceNonVolatileStore<'a> {
+/// Consecutive failed writes before the flash is considered worn out.
+const MAX_WRITE_FAILURES: u8 = 3;
+static STORAGE_DEGRADED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the flash stopped taking writes, see [`DeviceNonVolatileStore`].
+pub fn storage_degraded() -> bool {
+    STORAGE_DEGRADED.load(Ordering::Relaxed)
+}
+
+/// The storage region holds the session in its middle page, where firmware without a
+/// journal kept it at the start of a smaller region, between the two pages used by the
+/// [`Journal`] for everything the firmware persists on its own.
+///
+/// Uplinks kept while the network can't be reached go to the [`Spool`] in a region of
+/// their own, and so do the [`DevNonces`] of the join requests sent.
+///
+/// Saves that only move FCntUp are skipped as the [`FcntPolicy`] allows.
+///
+/// After [`MAX_WRITE_FAILURES`] the flash is left alone until the next boot: the session
+/// is kept in RAM and records are no longer written.
+pub struct DeviceNonVolatileStore<'a, C = DefaultCodec> {
     flash: Bank1Region<'a, Blocking>,
     buf: [u8; 256],
+    journal: Journal,
+    spool: Spool,
+    dev_nonces: DevNonces,
+    write_failures: u8,
+    fcnt_policy: FcntPolicy,
+    /// FCntUp of the session in `buf`
+    saved_fcnt_up: Option<u32>,
+    /// Of the session loaded, or the next one saved
+    header: Header,
+    codec: PhantomData<C>,
 }
-impl<'a> DeviceNonVolatileStore<'a> {
-    pub fn new(flash: Bank1Region<'a, Blocking>) -> Self {
-        Self { flash, buf: [0xFF; 256] }
+impl<'a, C: StorableCodec> DeviceNonVolatileStore<'a, C> {
+    pub fn new(flash: Bank1Region<'a, Blocking>, fcnt_policy: FcntPolicy) -> Self {
+        let page = MAX_ERASE_SIZE as u32;
+        let journal = Journal::new([Self::offset(), Self::offset() + 2 * page], page);
+        let (spool_start, spool_size) = Self::spool_region();
+        let spool =
+            Spool::new(spool_start, MAX_ERASE_SIZE as u32, spool_size / MAX_ERASE_SIZE as u32);
+        let (dev_nonce_start, dev_nonce_size) = Self::dev_nonce_region();
+        let dev_nonces = DevNonces::new(dev_nonce_start, dev_nonce_size / 2);
+        Self {
+            flash,
+            buf: [0xFF; 256],
+            journal,
+            spool,
+            dev_nonces,
+            write_failures: 0,
+            fcnt_policy,
+            saved_fcnt_up: None,
+            header: Header::EMPTY,
+            codec: PhantomData,
+        }
+    }
+    /// AU915 sub-band persisted with the session, `None` before one was selected.
+    #[cfg(feature = "au915")]
+    pub fn sub_band(&self) -> Option<u8> {
+        Some(self.header.sub_band).filter(|sub_band| (1..=8).contains(sub_band))
+    }
+    /// Saved along with the session, at once if there is one.
+    #[cfg(feature = "au915")]
+    pub fn set_sub_band(&mut self, sub_band: u8) -> Result<(), NonVolatileStoreError> {
+        if self.header.sub_band == sub_band {
+            return Ok(());
+        }
+        self.header.sub_band = sub_band;
+        if self.buf.iter().all(|b| *b == 0xFF) {
+            return Ok(());
+        }
+        migration::write_header(&mut self.buf, &self.header);
+        self.write_session()
+    }
+    /// Region of the session loaded, `None` before one was persisted with it.
+    pub fn region_choice(&self) -> Option<RegionChoice> {
+        RegionChoice::from_id(self.header.region)
+    }
+    /// Saved with the next session, the one in flash stays with the region it was saved in.
+    pub fn set_region_choice(&mut self, region: RegionChoice) {
+        self.header.region = region as u8;
+    }
+    /// Writes the page in `buf` to flash.
+    fn write_session(&mut self) -> Result<(), NonVolatileStoreError> {
+        if storage_degraded() {
+            return Ok(());
+        }
+        let start = Self::session_offset();
+        let res = self
+            .flash
+            .blocking_erase(start, start + MAX_ERASE_SIZE as u32)
+            .and_then(|()| self.flash.blocking_write(start, &self.buf))
+            .map_err(NonVolatileStoreError::Flash);
+        // the session lives on in `buf` if the flash gave up
+        self.track_write(res).or_else(|e| {
+            if storage_degraded() {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+    }
+    fn track_write<T>(
+        &mut self,
+        res: Result<T, NonVolatileStoreError>,
+    ) -> Result<T, NonVolatileStoreError> {
+        match res {
+            Err(NonVolatileStoreError::Flash(e)) => {
+                self.write_failures += 1;
+                if self.write_failures >= MAX_WRITE_FAILURES
+                    && !STORAGE_DEGRADED.swap(true, Ordering::Relaxed)
+                {
+                    defmt::error!("flash worn out, no longer written");
+                }
+            }
+            Ok(_) => self.write_failures = 0,
+            Err(_) => {}
+        }
+        res
     }
     pub fn offset() -> u32 {
         (unsafe { &__storage as *const u8 as u32 }) - pac::FLASH_BASE as u32
     }
+    fn session_offset() -> u32 {
+        Self::offset() + MAX_ERASE_SIZE as u32
+    }
+    fn spool_region() -> (u32, u32) {
+        let start = unsafe { &__spool as *const u8 as u32 };
+        let end = unsafe { &__spool_end as *const u8 as u32 };
+        (start - pac::FLASH_BASE as u32, end - start)
+    }
+    fn dev_nonce_region() -> (u32, u32) {
+        let start = unsafe { &__dev_nonce as *const u8 as u32 };
+        let end = unsafe { &__dev_nonce_end as *const u8 as u32 };
+        (start - pac::FLASH_BASE as u32, end - start)
+    }
+    /// Flash offset and size of the partition updates are staged in, apart from the storage.
+    #[cfg(feature = "fuota")]
+    pub fn staging() -> (u32, u32) {
+        let start = unsafe { &__staging as *const u8 as u32 };
+        let end = unsafe { &__staging_end as *const u8 as u32 };
+        (start - pac::FLASH_BASE as u32, end - start)
+    }
+    #[cfg(feature = "fuota")]
+    pub fn erase_staging(&mut self) -> Result<(), NonVolatileStoreError> {
+        let (start, size) = Self::staging();
+        self.flash.blocking_erase(start, start + size).map_err(NonVolatileStoreError::Flash)
+    }
+    /// Writes part of an update at `offset` into the staging partition, which must have
+    /// been erased. Offsets and lengths are multiples of 8 bytes.
+    #[cfg(feature = "fuota")]
+    pub fn write_staging(&mut self, offset: u32, data: &[u8]) -> Result<(), NonVolatileStoreError> {
+        let (start, size) = Self::staging();
+        if offset as usize + data.len() > size as usize {
+            return Err(NonVolatileStoreError::Flash(embassy_stm32::flash::Error::Size));
+        }
+        self.flash.blocking_write(start + offset, data).map_err(NonVolatileStoreError::Flash)
+    }
+    pub fn read_record(
+        &mut self,
+        key: RecordKey,
+        buf: &mut [u8],
+    ) -> Result<usize, NonVolatileStoreError> {
+        self.journal.read(&mut self.flash, key, buf).map_err(Into::into)
+    }
+    pub fn write_record(
+        &mut self,
+        key: RecordKey,
+        data: &[u8],
+    ) -> Result<(), NonVolatileStoreError> {
+        if storage_degraded() {
+            return Err(NonVolatileStoreError::Degraded);
+        }
+        let res = self.journal.write(&mut self.flash, key, data).map_err(Into::into);
+        self.track_write(res)
+    }
+    /// Keeps an uplink until the network can be reached, see [`Spool`].
+    pub fn spool_push(&mut self, fport: u8, data: &[u8]) -> Result<(), NonVolatileStoreError> {
+        if storage_degraded() {
+            return Err(NonVolatileStoreError::Degraded);
+        }
+        let res = self.spool.push(&mut self.flash, fport, data).map_err(Into::into);
+        self.track_write(res)
+    }
+    /// Copies the oldest spooled uplink into `buf`, returning its FPort and length.
+    pub fn spool_peek(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<Option<(u8, usize)>, NonVolatileStoreError> {
+        self.spool.peek(&mut self.flash, buf).map_err(Into::into)
+    }
+    /// Drops the uplink [`DeviceNonVolatileStore::spool_peek`] returned once it was delivered.
+    pub fn spool_pop(&mut self) -> Result<(), NonVolatileStoreError> {
+        if storage_degraded() {
+            return Err(NonVolatileStoreError::Degraded);
+        }
+        let res = self.spool.pop(&mut self.flash).map_err(Into::into);
+        self.track_write(res)
+    }
+    pub fn spool_pending(&mut self) -> Result<usize, NonVolatileStoreError> {
+        self.spool.pending(&mut self.flash).map_err(Into::into)
+    }
+    /// Reads the DevNonces used before, for [`crate::dev_nonce::permit_tx`].
+    pub fn load_dev_nonces(&mut self) -> Result<(), NonVolatileStoreError> {
+        self.dev_nonces.mount(&mut self.flash).map_err(NonVolatileStoreError::Flash)
+    }
+    /// Logs the DevNonce of a join request sent, see [`DevNonces`].
+    pub fn record_dev_nonce(&mut self, nonce: u16) -> Result<(), NonVolatileStoreError> {
+        if storage_degraded() {
+            return Err(NonVolatileStoreError::Degraded);
+        }
+        let res =
+            self.dev_nonces.record(&mut self.flash, nonce).map_err(NonVolatileStoreError::Flash);
+        self.track_write(res)
+    }
 }
 #[derive(Debug, PartialEq, defmt::Format)]
 pub enum NonVolatileStoreError {
     Flash(embassy_stm32::flash::Error),
     Encoding,
+    NotFound,
+    /// The flash failed too often and is no longer written.
+    Degraded,
+}
+impl From<CodecError> for NonVolatileStoreError {
+    fn from(_: CodecError) -> Self {
+        NonVolatileStoreError::Encoding
+    }
+}
+impl From<JournalError<embassy_stm32::flash::Error>> for NonVolatileStoreError {
+    fn from(e: JournalError<embassy_stm32::flash::Error>) -> Self {
+        match e {
+            JournalError::Flash(e) => NonVolatileStoreError::Flash(e),
+            JournalError::NotFound => NonVolatileStoreError::NotFound,
+            JournalError::TooLarge => NonVolatileStoreError::Encoding,
+        }
+    }
+}
+impl From<SpoolError<embassy_stm32::flash::Error>> for NonVolatileStoreError {
+    fn from(e: SpoolError<embassy_stm32::flash::Error>) -> Self {
+        match e {
+            SpoolError::Flash(e) => NonVolatileStoreError::Flash(e),
+            SpoolError::TooLarge => NonVolatileStoreError::Encoding,
+        }
+    }
 }
-impl NonVolatileStore for DeviceNonVolatileStore<'_> {
+impl<C: StorableCodec> NonVolatileStore for DeviceNonVolatileStore<'_, C> {
     type Error = NonVolatileStoreError;
 
-    fn save(&mut self, storable: Storable) -> Result<(), Self::Error> {
-        self.flash
-            .blocking_erase(Self::offset(), Self::offset() + MAX_ERASE_SIZE as u32)
-            .map_err(NonVolatileStoreError::Flash)?;
-        to_slice(&storable, self.buf.as_mut_slice())
-            .map_err(|_| NonVolatileStoreError::Encoding)?;
-        self.flash.blocking_write(Self::offset(), &self.buf).map_err(NonVolatileStoreError::Flash)
+    fn save(&mut self, mut storable: Storable) -> Result<(), Self::Error> {
+        let fcnt_up = storable.session.as_ref().map(|session| session.fcnt_up);
+        if let Some(fcnt_up) = fcnt_up {
+            uplink_edit::set_fcnt_up(fcnt_up);
+        }
+        if let (Some(fcnt_up), Some(saved)) = (fcnt_up, self.saved_fcnt_up) {
+            let set_fcnt_up = |storable: &mut Storable, fcnt_up| {
+                if let Some(session) = storable.session.as_mut() {
+                    session.fcnt_up = fcnt_up;
+                }
+            };
+            if !self.fcnt_policy.due(saved, fcnt_up) {
+                // skipped if nothing else changed, a restore goes past this FCntUp anyway
+                set_fcnt_up(&mut storable, saved);
+                let mut page = [0xFF; 256];
+                migration::write_header(&mut page, &self.header);
+                C::encode(&storable, &mut page[HEADER_SIZE..])?;
+                if page == self.buf {
+                    return Ok(());
+                }
+                set_fcnt_up(&mut storable, fcnt_up);
+            }
+        }
+        self.buf.fill(0xFF);
+        migration::write_header(&mut self.buf, &self.header);
+        C::encode(&storable, &mut self.buf[HEADER_SIZE..])?;
+        self.saved_fcnt_up = fcnt_up;
+        self.write_session()
     }
 
     fn load(&mut self) -> Result<Storable, Self::Error> {
-        self.flash
-            .blocking_read(Self::offset(), self.buf.as_mut_slice())
-            .map_err(NonVolatileStoreError::Flash)?;
-        from_bytes(self.buf.as_mut_slice()).map_err(|_| NonVolatileStoreError::Encoding)
+        if !storage_degraded() {
+            self.flash
+                .blocking_read(Self::session_offset(), self.buf.as_mut_slice())
+                .map_err(NonVolatileStoreError::Flash)?;
+            // saved in the current schema along with the next session change
+            if migration::migrate(&mut self.buf)? {
+                defmt::info!("session migrated to schema {}", migration::SCHEMA_VERSION);
+            }
+        }
+        let mut storable = C::decode(&mut self.buf[HEADER_SIZE..])?;
+        self.header = migration::header(&self.buf);
+        if let Some(session) = storable.session.as_mut() {
+            self.saved_fcnt_up = Some(session.fcnt_up);
+            session.fcnt_up = self.fcnt_policy.restore(session.fcnt_up);
+            uplink_edit::set_fcnt_up(session.fcnt_up);
+        }
+        Ok(storable)
     }
 }
 
+#[derive(Debug, PartialEq, defmt::Format)]
+pub struct RegionUnavailable(pub RegionChoice);
+
+/// Instantiates the MAC for the region a session was persisted for, which becomes the
+/// [`region::current`] one. Only the regions of [`region::BUILT`] have a MAC in the binary.
+pub fn new_mac(
+    choice: RegionChoice,
+    configuration: Configuration,
+    credentials: Credentials,
+) -> Result<RegionMac, RegionUnavailable> {
+    #[allow(unreachable_patterns)]
+    let mac = match choice {
+        #[cfg(eu868)]
+        RegionChoice::Eu868 => RegionMac::Eu868(Mac::new(configuration, credentials)),
+        #[cfg(feature = "au915")]
+        RegionChoice::Au915 => RegionMac::Au915(Mac::new(configuration, credentials)),
+        #[cfg(feature = "as923-1")]
+        RegionChoice::As923_1 => RegionMac::As923_1(Mac::new(configuration, credentials)),
+        #[cfg(feature = "as923-2")]
+        RegionChoice::As923_2 => RegionMac::As923_2(Mac::new(configuration, credentials)),
+        #[cfg(feature = "as923-3")]
+        RegionChoice::As923_3 => RegionMac::As923_3(Mac::new(configuration, credentials)),
+        #[cfg(feature = "as923-4")]
+        RegionChoice::As923_4 => RegionMac::As923_4(Mac::new(configuration, credentials)),
+        #[cfg(feature = "in865")]
+        RegionChoice::In865 => RegionMac::In865(Mac::new(configuration, credentials)),
+        #[cfg(feature = "kr920")]
+        RegionChoice::Kr920 => RegionMac::Kr920(Mac::new(configuration, credentials)),
+        #[cfg(feature = "ru864")]
+        RegionChoice::Ru864 => RegionMac::Ru864(Mac::new(configuration, credentials)),
+        #[cfg(feature = "eu433")]
+        RegionChoice::Eu433 => RegionMac::Eu433(Mac::new(configuration, credentials)),
+        other => return Err(RegionUnavailable(other)),
+    };
+    if choice != region::current() {
+        iv::recalibrate_image();
+    }
+    region::set_current(choice);
+    Ok(mac)
+}
+
 impl lorawan::device::rng::Rng for DeviceRng<'_> {
     type Error = Infallible;
 
@@ -144,4 +601,14 @@ impl<'a> Device for LoraDevice<'a> {
     fn radio(&mut self) -> &mut LoraType<'a> {
         &mut self.radio
     }
+
+    fn handle_link_check(&mut self, gateway_count: u8, margin: u8) {
+        link_check::answered(LinkCheckAns { margin, gateway_count });
+    }
+
+    fn handle_device_time(&mut self, seconds: u32, fractional: u8) {
+        // as of the end of the uplink, when the RX timer was started
+        let time = NetworkTime { gps_seconds: seconds, millis: (fractional as u16 * 1000) >> 8 };
+        rtc::set(time, self.timer.started());
+    }
 }
@@ -0,0 +1,216 @@
+use core::cell::Cell;
+
+use embassy_time::Instant;
+
+use crate::derating;
+use crate::device::{self, DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::energy;
+use crate::journal::RecordKey;
+use crate::link;
+use crate::mobility;
+use crate::payload;
+use crate::radio_telemetry;
+use crate::regulatory;
+use crate::rx_schedule;
+use crate::schema;
+use crate::storm;
+
+pub const STATUS_PORT: u8 = schema::STATUS.port;
+pub const STATUS_PAYLOAD_SIZE: usize = schema::STATUS.header_size();
+const BOOT_RECORD_SIZE: usize = 12;
+const LINK_RECORD_SIZE: usize = 8;
+/// Link times of something that never happened.
+const NEVER: u32 = u32::MAX;
+
+/// What is persisted at every checkpoint, `uptime` includes `session`.
+struct BootRecord {
+    boot_count: u32,
+    uptime: u32,
+    session: u32,
+}
+impl BootRecord {
+    fn to_bytes(&self) -> [u8; BOOT_RECORD_SIZE] {
+        let mut buf = [0; BOOT_RECORD_SIZE];
+        buf[..4].copy_from_slice(&self.boot_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.uptime.to_le_bytes());
+        buf[8..].copy_from_slice(&self.session.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; BOOT_RECORD_SIZE]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Self { boot_count: word(0), uptime: word(4), session: word(8) }
+    }
+}
+
+/// Counters surviving power cycles, used for battery life modelling in the field, and the
+/// times the link was last known to work, on the uptime's clock.
+///
+/// Uptime is only as accurate as the last [`Diagnostics::checkpoint`] before power was lost.
+pub struct Diagnostics {
+    boot_count: u32,
+    uptime_at_boot: u32,
+    last_session: u32,
+    last_ack: Cell<u32>,
+    last_downlink: Cell<u32>,
+}
+impl Diagnostics {
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>) -> Self {
+        let mut buf = [0; BOOT_RECORD_SIZE];
+        let record = match store.read_record(RecordKey::BootStats, &mut buf) {
+            Ok(BOOT_RECORD_SIZE) => BootRecord::from_bytes(&buf),
+            Ok(_) | Err(_) => BootRecord { boot_count: 0, uptime: 0, session: 0 },
+        };
+        let mut buf = [0; LINK_RECORD_SIZE];
+        let (last_ack, last_downlink) = match store.read_record(RecordKey::LinkTimes, &mut buf) {
+            Ok(LINK_RECORD_SIZE) => {
+                let word =
+                    |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+                (word(0), word(4))
+            }
+            Ok(_) | Err(_) => (NEVER, NEVER),
+        };
+        let diagnostics = Self {
+            boot_count: record.boot_count.wrapping_add(1),
+            uptime_at_boot: record.uptime,
+            last_session: record.session,
+            last_ack: Cell::new(last_ack),
+            last_downlink: Cell::new(last_downlink),
+        };
+        if let Err(e) = diagnostics.checkpoint(store) {
+            defmt::error!("boot stats not saved {:?}", e);
+        }
+        defmt::info!("{:?}", diagnostics);
+        diagnostics
+    }
+
+    pub fn boot_count(&self) -> u32 {
+        self.boot_count
+    }
+
+    /// Seconds since this boot.
+    pub fn session(&self) -> u32 {
+        Instant::now().as_secs() as u32
+    }
+
+    /// Seconds powered over the lifetime of the device.
+    pub fn uptime(&self) -> u32 {
+        self.uptime_at_boot.wrapping_add(self.session())
+    }
+
+    /// Length of the previous session as of its last checkpoint.
+    pub fn last_session(&self) -> u32 {
+        self.last_session
+    }
+
+    pub fn uplink_acked(&self) {
+        self.last_ack.set(self.uptime());
+    }
+
+    pub fn downlink_received(&self) {
+        self.last_downlink.set(self.uptime());
+    }
+
+    /// Seconds since the network last acknowledged an uplink, across reboots.
+    pub fn since_last_ack(&self) -> Option<u32> {
+        self.since(self.last_ack.get())
+    }
+
+    /// Seconds since the last downlink, across reboots.
+    pub fn since_last_downlink(&self) -> Option<u32> {
+        self.since(self.last_downlink.get())
+    }
+
+    fn since(&self, at: u32) -> Option<u32> {
+        (at != NEVER).then(|| self.uptime().saturating_sub(at))
+    }
+
+    /// Saves the counters and the link times.
+    pub fn checkpoint(
+        &self,
+        store: &mut DeviceNonVolatileStore<'_>,
+    ) -> Result<(), NonVolatileStoreError> {
+        let record = BootRecord {
+            boot_count: self.boot_count,
+            uptime: self.uptime(),
+            session: self.session(),
+        };
+        store.write_record(RecordKey::BootStats, &record.to_bytes())?;
+        let mut link = [0; LINK_RECORD_SIZE];
+        link[..4].copy_from_slice(&self.last_ack.get().to_le_bytes());
+        link[4..].copy_from_slice(&self.last_downlink.get().to_le_bytes());
+        store.write_record(RecordKey::LinkTimes, &link)
+    }
+
+    pub fn encode_status(&self) -> [u8; STATUS_PAYLOAD_SIZE] {
+        let (permille, life) =
+            energy::with_meter(|meter| (meter.remaining_permille(), meter.remaining_life()));
+        let days =
+            life.map_or(u16::MAX, |life| (life.as_secs() / 86400).min(u16::MAX as u64) as u16);
+        let radio = radio_telemetry::telemetry();
+        payload::Status {
+            boot_count: self.boot_count(),
+            uptime: self.uptime(),
+            last_session: self.last_session(),
+            battery_permille: permille as u16,
+            battery_days_left: days,
+            flags: status_flags(),
+            radio_temperature: radio.temperature,
+            radio_supply_mv: radio.supply_mv,
+            radio_errors: radio.errors,
+            since_last_ack: self.since_last_ack().unwrap_or(NEVER),
+            since_last_downlink: self.since_last_downlink().unwrap_or(NEVER),
+        }
+        .encode()
+    }
+}
+pub const FLAG_TX_DERATED: u8 = 1 << 0;
+pub const FLAG_REGULATORY_VIOLATION: u8 = 1 << 1;
+pub const FLAG_ADR_ACK_REQ: u8 = 1 << 2;
+pub const FLAG_ADR_BACKOFF: u8 = 1 << 3;
+pub const FLAG_MOBILE: u8 = 1 << 4;
+pub const FLAG_DOWNLINK_STORM: u8 = 1 << 5;
+pub const FLAG_STORAGE_DEGRADED: u8 = 1 << 6;
+pub const FLAG_RX_LATE: u8 = 1 << 7;
+
+fn status_flags() -> u8 {
+    let mut flags = 0;
+    if derating::is_active() {
+        flags |= FLAG_TX_DERATED;
+    }
+    if regulatory::violations() > 0 {
+        flags |= FLAG_REGULATORY_VIOLATION;
+    }
+    if link::adr_ack_req() {
+        flags |= FLAG_ADR_ACK_REQ;
+    }
+    if link::backoff_active() {
+        flags |= FLAG_ADR_BACKOFF;
+    }
+    if mobility::is_mobile() {
+        flags |= FLAG_MOBILE;
+    }
+    if storm::is_active() {
+        flags |= FLAG_DOWNLINK_STORM;
+    }
+    if device::storage_degraded() {
+        flags |= FLAG_STORAGE_DEGRADED;
+    }
+    if rx_schedule::is_drifting() {
+        flags |= FLAG_RX_LATE;
+    }
+    flags
+}
+
+impl defmt::Format for Diagnostics {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "boot count: {} uptime: {}s session: {}s last session: {}s",
+            self.boot_count(),
+            self.uptime(),
+            self.session(),
+            self.last_session()
+        )
+    }
+}
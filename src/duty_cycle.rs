@@ -0,0 +1,240 @@
+//! Duty cycle of each EU868 sub-band: the time on air of every transmission is counted
+//! against the band it went out on, over a window of the last hour. The radio refuses a
+//! TX whose frame doesn't fit in what is left in its band, and the main loop holds back or
+//! drops a queued uplink that would not fit in every band the MAC may pick a channel from.
+//!
+//! The frame is the one the radio is set up for, from the SetPacketType,
+//! SetModulationParams and SetPacketParams commands on their way to it.
+//!
+//! [`crate::airtime`] keeps the stricter budget summed over all channels, this is the
+//! legal limit. Other regions have no bands here and are never held back.
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+use crate::airtime;
+use crate::region::{self, RegionChoice};
+use crate::regulatory;
+
+const WINDOW: Duration = Duration::from_secs(3600);
+/// Transmissions kept per band. With more in the window the oldest is merged into the next,
+/// its airtime then leaves the window later than it would have.
+const HISTORY: usize = 16;
+const PACKET_TYPE_LORA: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Band {
+    /// Hz, inclusive.
+    pub low: u32,
+    /// Hz, exclusive.
+    pub high: u32,
+    /// 100 for 1%, 1000 for 0.1%.
+    pub divisor: u32,
+}
+impl Band {
+    pub const fn budget(&self) -> Duration {
+        Duration::from_ticks(WINDOW.as_ticks() / self.divisor as u64)
+    }
+
+    pub const fn contains(&self, frequency: u32) -> bool {
+        frequency >= self.low && frequency < self.high
+    }
+}
+
+/// ETSI EN 300 220 sub-bands as in RP002, the channels between them can't be used.
+const EU868_BANDS: [Band; 6] = [
+    Band { low: 863_000_000, high: 865_000_000, divisor: 1000 },
+    Band { low: 865_000_000, high: 868_000_000, divisor: 100 },
+    Band { low: 868_000_000, high: 868_600_000, divisor: 100 },
+    Band { low: 868_700_000, high: 869_200_000, divisor: 1000 },
+    Band { low: 869_400_000, high: 869_650_000, divisor: 10 },
+    Band { low: 869_700_000, high: 870_000_000, divisor: 100 },
+];
+
+fn bands() -> &'static [Band] {
+    match region::current() {
+        RegionChoice::Eu868 => &EU868_BANDS,
+        _ => &[],
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Transmission {
+    /// When it ended.
+    at: Instant,
+    time_on_air: Duration,
+}
+impl Transmission {
+    fn in_window(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.at) < WINDOW
+    }
+}
+
+/// The transmissions of a band, oldest first.
+struct Usage(Deque<Transmission, HISTORY>);
+impl Usage {
+    fn used(&self, now: Instant) -> Duration {
+        self.0
+            .iter()
+            .filter(|transmission| transmission.in_window(now))
+            .fold(Duration::from_ticks(0), |used, transmission| used + transmission.time_on_air)
+    }
+
+    /// When `time_on_air` more fits in `budget`, as the transmissions leave the window.
+    fn fits_at(&self, now: Instant, time_on_air: Duration, budget: Duration) -> Instant {
+        let mut used = self.used(now);
+        let mut at = now;
+        for transmission in self.0.iter().filter(|transmission| transmission.in_window(now)) {
+            if used + time_on_air <= budget {
+                break;
+            }
+            used = used.checked_sub(transmission.time_on_air).unwrap_or_default();
+            at = transmission.at + WINDOW;
+        }
+        at
+    }
+
+    fn record(&mut self, now: Instant, time_on_air: Duration) {
+        while self.0.front().is_some_and(|oldest| !oldest.in_window(now)) {
+            self.0.pop_front();
+        }
+        if self.0.is_full() {
+            let oldest = self.0.pop_front().unwrap();
+            if let Some(next) = self.0.front_mut() {
+                next.time_on_air += oldest.time_on_air;
+            }
+        }
+        let _ = self.0.push_back(Transmission { at: now, time_on_air });
+    }
+}
+
+/// The frame the radio is set up to send.
+#[derive(Clone, Copy)]
+struct Frame {
+    lora: bool,
+    spreading_factor: u8,
+    /// Hz.
+    bandwidth: i64,
+    size: u8,
+}
+impl Frame {
+    fn time_on_air(&self) -> Duration {
+        if self.lora {
+            airtime::lora_time_on_air(self.spreading_factor, self.bandwidth, self.size as usize)
+        } else {
+            airtime::fsk_time_on_air(self.size as usize)
+        }
+    }
+}
+
+static USAGE: Mutex<CriticalSectionRawMutex, RefCell<[Usage; EU868_BANDS.len()]>> =
+    Mutex::new(RefCell::new([const { Usage(Deque::new()) }; EU868_BANDS.len()]));
+static FRAME: Mutex<CriticalSectionRawMutex, Cell<Frame>> = Mutex::new(Cell::new(Frame {
+    lora: true,
+    spreading_factor: 12,
+    bandwidth: 125_000,
+    size: u8::MAX,
+}));
+static REFUSED: AtomicU32 = AtomicU32::new(0);
+
+fn band_of(frequency: u32) -> Option<usize> {
+    bands().iter().position(|band| band.contains(frequency))
+}
+
+/// From a SetPacketType command.
+pub fn set_packet_type(packet_type: u8) {
+    FRAME.lock(|frame| frame.set(Frame { lora: packet_type == PACKET_TYPE_LORA, ..frame.get() }));
+}
+
+/// From a SetModulationParams command, only used in LoRa mode.
+pub fn set_modulation(spreading_factor: u8, bandwidth: u8) {
+    // unknown bandwidths count as the slowest
+    let bandwidth = match bandwidth {
+        0x05 => 250_000,
+        0x06 => 500_000,
+        _ => 125_000,
+    };
+    FRAME.lock(|frame| frame.set(Frame { spreading_factor, bandwidth, ..frame.get() }));
+}
+
+/// From a SetPacketParams command, the payload length is where the packet type puts it.
+pub fn set_packet(command: &[u8]) {
+    FRAME.lock(|frame| {
+        let index = if frame.get().lora {
+            4
+        } else {
+            7
+        };
+        if let Some(size) = command.get(index) {
+            frame.set(Frame { size: *size, ..frame.get() });
+        }
+    });
+}
+
+/// Counts a transmission on `frequency` against its band.
+pub fn record(frequency: u32, time_on_air: Duration) {
+    let Some(i) = band_of(frequency) else {
+        return;
+    };
+    USAGE.lock(|usage| usage.borrow_mut()[i].record(Instant::now(), time_on_air));
+}
+
+/// Airtime left now in the band of `frequency`, `None` outside of any band.
+pub fn budget_left(frequency: u32) -> Option<Duration> {
+    let i = band_of(frequency)?;
+    let used = USAGE.lock(|usage| usage.borrow()[i].used(Instant::now()));
+    Some(bands()[i].budget().checked_sub(used).unwrap_or_default())
+}
+
+/// Airtime left now in each band, for the application to adapt its send rate to.
+pub fn budgets() -> impl Iterator<Item = (Band, Duration)> {
+    bands().iter().map(|band| (*band, budget_left(band.low).unwrap_or_default()))
+}
+
+/// When an uplink of `time_on_air` fits in every band it may go out on: the bands of the
+/// default channels and any transmitted in this window. `None` if it never does.
+pub fn available_at(time_on_air: Duration) -> Option<Instant> {
+    let now = Instant::now();
+    USAGE.lock(|usage| {
+        let usage = usage.borrow();
+        let mut at = now;
+        let default_channels = region::current().default_channels();
+        for (i, band) in bands().iter().enumerate() {
+            let default = default_channels.iter().any(|channel| band.contains(*channel));
+            if !default && usage[i].used(now) == Duration::from_ticks(0) {
+                continue;
+            }
+            if time_on_air > band.budget() {
+                return None;
+            }
+            at = at.max(usage[i].fits_at(now, time_on_air, band.budget()));
+        }
+        Some(at)
+    })
+}
+
+/// Whether the frame the radio is set up for may go out on the frequency it is tuned to.
+pub fn permit_tx() -> bool {
+    let frequency = regulatory::frequency();
+    let time_on_air = FRAME.lock(|frame| frame.get().time_on_air());
+    if budget_left(frequency).is_some_and(|left| left < time_on_air) {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+        defmt::warn!(
+            "refusing TX of {} ms on {} Hz, no duty cycle left in the band",
+            time_on_air.as_millis(),
+            frequency
+        );
+        return false;
+    }
+    true
+}
+
+/// Transmissions refused for the duty cycle since boot.
+pub fn refused() -> u32 {
+    REFUSED.load(Ordering::Relaxed)
+}
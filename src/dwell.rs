@@ -0,0 +1,46 @@
+//! AS923 uplink dwell time: where it applies, no transmission may last longer than
+//! 400 ms, which rules out DR0 and DR1 and shrinks the payloads of DR2 to DR4. Payloads
+//! are sized for it with [`crate::region::max_payload_size`], and every uplink is checked
+//! again before it is handed to the MAC, moving it to a faster data rate if it would
+//! still be too long, e.g. an alarm on DR2.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::Duration;
+
+use crate::airtime::{time_on_air, FRAME_OVERHEAD};
+
+pub const MAX_DWELL_TIME: Duration = Duration::from_millis(400);
+const MAX_DATA_RATE: u8 = 7;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_enabled(enabled: bool) {
+    defmt::info!(
+        "uplink dwell time {}",
+        if enabled {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub struct DwellTimeExceeded;
+
+/// The data rate to send `payload_size` bytes on, `data_rate` itself unless it would take
+/// longer than the dwell time.
+pub fn data_rate_for(data_rate: u8, payload_size: usize) -> Result<u8, DwellTimeExceeded> {
+    if !enabled() {
+        return Ok(data_rate);
+    }
+    (data_rate..=MAX_DATA_RATE)
+        .find(|data_rate| time_on_air(*data_rate, FRAME_OVERHEAD + payload_size) <= MAX_DWELL_TIME)
+        .ok_or(DwellTimeExceeded)
+}
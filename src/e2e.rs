@@ -0,0 +1,140 @@
+//! Application layer encryption, keeping payloads confidential from the network operator
+//! who holds the LoRaWAN session keys. Uplinks are sealed with ChaCha20-Poly1305 as
+//! `[counter u32][ciphertext][tag]` under a key of their own, with the DevEUI and the
+//! counter as nonce and the FPort as associated data.
+//!
+//! The key is sent by downlink on [`E2E_KEY_PORT`] as the two AES-128 blocks of the key
+//! encrypted with the AppKey, followed by the first 4 bytes of an AES-CMAC over
+//! `"E2EKEY" | DevEUI | encrypted key`. An empty downlink removes the key.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use cmac::{Cmac, Mac};
+use heapless::Vec;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+use crate::region::MAX_PAYLOAD_SIZE;
+use crate::schema;
+
+pub const E2E_KEY_PORT: u8 = schema::E2E_KEY.port;
+/// Counter and tag added to every payload.
+pub const OVERHEAD: usize = 4 + 16;
+const KEY_SIZE: usize = 32;
+const DOMAIN: &[u8] = b"E2EKEY";
+const MIC_SIZE: usize = 4;
+/// Counters reserved in flash at a time, so that none is used twice across a reset.
+const COUNTER_BLOCK: u32 = 256;
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum E2eError {
+    InvalidMic,
+    Invalid,
+    TooLarge,
+    CounterExhausted,
+    Store(NonVolatileStoreError),
+}
+
+pub struct E2e {
+    cipher: ChaCha20Poly1305,
+    dev_eui: [u8; 8],
+    counter: u32,
+    /// Counters below this are persisted as possibly used.
+    reserved: u32,
+}
+impl E2e {
+    /// `None` until a key was provisioned.
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>, dev_eui: &[u8; 8]) -> Option<Self> {
+        let mut key = [0; KEY_SIZE];
+        let (low, high) = key.split_at_mut(KEY_SIZE / 2);
+        let low = store.read_record(RecordKey::E2eKeyLow, low).ok()?;
+        let high = store.read_record(RecordKey::E2eKeyHigh, high).ok()?;
+        if low + high != KEY_SIZE {
+            return None;
+        }
+        let mut counter = [0; 4];
+        let counter = match store.read_record(RecordKey::E2eCounter, &mut counter) {
+            Ok(4) => u32::from_le_bytes(counter),
+            _ => 0,
+        };
+        defmt::info!("payloads encrypted from counter {}", counter);
+        Some(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            dev_eui: *dev_eui,
+            counter,
+            reserved: counter,
+        })
+    }
+
+    pub fn seal(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        fport: u8,
+        payload: &mut Vec<u8, MAX_PAYLOAD_SIZE>,
+    ) -> Result<(), E2eError> {
+        if payload.len() + OVERHEAD > payload.capacity() {
+            return Err(E2eError::TooLarge);
+        }
+        if self.counter == self.reserved {
+            let reserved =
+                self.counter.checked_add(COUNTER_BLOCK).ok_or(E2eError::CounterExhausted)?;
+            store
+                .write_record(RecordKey::E2eCounter, &reserved.to_le_bytes())
+                .map_err(E2eError::Store)?;
+            self.reserved = reserved;
+        }
+        let counter = self.counter.to_be_bytes();
+        self.counter += 1;
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.dev_eui);
+        nonce[8..].copy_from_slice(&counter);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[fport], payload)
+            .map_err(|_| E2eError::TooLarge)?;
+        let mut sealed = Vec::new();
+        let _ = sealed.extend_from_slice(&counter);
+        let _ = sealed.extend_from_slice(payload);
+        let _ = sealed.extend_from_slice(&tag);
+        *payload = sealed;
+        Ok(())
+    }
+}
+
+/// Checks and persists a key received by downlink, returning what to seal payloads with.
+pub fn provision(
+    store: &mut DeviceNonVolatileStore<'_>,
+    downlink: &[u8],
+    dev_eui: &[u8; 8],
+    app_key: &[u8; 16],
+) -> Result<Option<E2e>, E2eError> {
+    let mut key = [0; KEY_SIZE];
+    if !downlink.is_empty() {
+        let (wrapped, mic) = downlink.split_last_chunk::<MIC_SIZE>().ok_or(E2eError::Invalid)?;
+        let mut cmac = <Cmac<Aes128> as Mac>::new_from_slice(app_key).unwrap();
+        cmac.update(DOMAIN);
+        cmac.update(dev_eui);
+        cmac.update(wrapped);
+        if cmac.finalize().into_bytes()[..MIC_SIZE] != *mic {
+            return Err(E2eError::InvalidMic);
+        }
+        if wrapped.len() != KEY_SIZE {
+            return Err(E2eError::Invalid);
+        }
+        key.copy_from_slice(wrapped);
+        let aes = Aes128::new(GenericArray::from_slice(app_key));
+        for block in key.as_chunks_mut::<16>().0 {
+            aes.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+    }
+    let (low, high) = match downlink {
+        [] => (&[][..], &[][..]),
+        _ => key.split_at(KEY_SIZE / 2),
+    };
+    store.write_record(RecordKey::E2eKeyLow, low).map_err(E2eError::Store)?;
+    store.write_record(RecordKey::E2eKeyHigh, high).map_err(E2eError::Store)?;
+    Ok(E2e::load(store, dev_eui))
+}
@@ -0,0 +1,35 @@
+//! Echo service for operators validating the path to a device: a downlink on
+//! [`ECHO_PORT`] comes back as an uplink on the same port with `[rssi i16][snr i16]
+//! [latency u16]` appended, big endian, the latency in milliseconds from reception to the
+//! echo being sent. Data not fitting the uplink at the current data rate is cut short.
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+use crate::region::MAX_PAYLOAD_SIZE;
+use crate::schema;
+
+pub const ECHO_PORT: u8 = schema::ECHO.port;
+const TRAILER_SIZE: usize = 6;
+
+pub struct Echo {
+    data: Vec<u8, MAX_PAYLOAD_SIZE>,
+    rssi: i16,
+    snr: i16,
+    received: Instant,
+}
+impl Echo {
+    pub fn new(downlink: &[u8], rssi: i16, snr: i16) -> Self {
+        let data = Vec::from_slice(&downlink[..downlink.len().min(MAX_PAYLOAD_SIZE)]).unwrap();
+        Self { data, rssi, snr, received: Instant::now() }
+    }
+
+    pub fn encode(&self, payload: &mut Vec<u8, MAX_PAYLOAD_SIZE>, max_payload_size: usize) {
+        let len = self.data.len().min(max_payload_size.saturating_sub(TRAILER_SIZE));
+        let latency = self.received.elapsed().as_millis().min(u16::MAX as u64) as u16;
+        let _ = payload.extend_from_slice(&self.data[..len]);
+        let _ = payload.extend_from_slice(&self.rssi.to_be_bytes());
+        let _ = payload.extend_from_slice(&self.snr.to_be_bytes());
+        let _ = payload.extend_from_slice(&latency.to_be_bytes());
+    }
+}
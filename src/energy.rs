@@ -0,0 +1,136 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RadioState {
+    Idle,
+    Tx,
+    Rx,
+}
+
+/// Current draw of the board in each state, in microamps.
+pub struct EnergyCoefficients {
+    /// `(power in dBm, current)` sorted by power, the first entry at or above the
+    /// configured TX power is used
+    pub tx: &'static [(i8, u32)],
+    pub rx: u32,
+    /// MCU running the executor with the radio in standby or sleep
+    pub idle: u32,
+    pub battery_capacity_mah: u32,
+}
+
+/// Rough figures for an STM32WLE5 using the high power PA at 3.3V, measure the actual
+/// board before relying on the battery estimates.
+pub const BOARD_COEFFICIENTS: EnergyCoefficients = EnergyCoefficients {
+    tx: &[(10, 26_000), (14, 45_000), (17, 65_000), (20, 95_000), (22, 118_000)],
+    rx: 5_500,
+    idle: 1_600,
+    battery_capacity_mah: 2_600,
+};
+
+const UA_MS_PER_UAH: u64 = 3_600_000;
+
+pub struct EnergyMeter {
+    coefficients: &'static EnergyCoefficients,
+    state: RadioState,
+    tx_power: i8,
+    since: Instant,
+    /// charge used before this boot
+    restored_uah: u64,
+    /// charge used this boot per [`RadioState`], in µA·ms
+    consumed: [u64; 3],
+}
+impl EnergyMeter {
+    pub const fn new(coefficients: &'static EnergyCoefficients) -> Self {
+        Self {
+            coefficients,
+            state: RadioState::Idle,
+            tx_power: 0,
+            since: Instant::from_ticks(0),
+            restored_uah: 0,
+            consumed: [0; 3],
+        }
+    }
+
+    fn current(&self, state: RadioState) -> u32 {
+        match state {
+            RadioState::Idle => self.coefficients.idle,
+            RadioState::Rx => self.coefficients.rx,
+            RadioState::Tx => self
+                .coefficients
+                .tx
+                .iter()
+                .find(|(power, _)| *power >= self.tx_power)
+                .or(self.coefficients.tx.last())
+                .map_or(0, |(_, current)| *current),
+        }
+    }
+
+    fn integrate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.since).as_micros();
+        self.consumed[self.state as usize] += elapsed * self.current(self.state) as u64 / 1000;
+        self.since = now;
+    }
+
+    pub fn set_state(&mut self, state: RadioState) {
+        self.integrate(Instant::now());
+        self.state = state;
+    }
+
+    pub fn set_tx_power(&mut self, tx_power: i8) {
+        self.tx_power = tx_power;
+    }
+
+    /// Charge used this boot in µAh, split into idle, TX and RX.
+    pub fn session_uah(&mut self) -> [u64; 3] {
+        self.integrate(Instant::now());
+        self.consumed.map(|c| c / UA_MS_PER_UAH)
+    }
+
+    /// Charge used over the lifetime of the battery in µAh.
+    pub fn consumed_uah(&mut self) -> u64 {
+        self.restored_uah + self.session_uah().iter().sum::<u64>()
+    }
+
+    pub fn remaining_permille(&mut self) -> i32 {
+        let capacity = self.coefficients.battery_capacity_mah as u64 * 1000;
+        (capacity.saturating_sub(self.consumed_uah()) * 1000 / capacity.max(1)) as i32
+    }
+
+    /// Battery life left assuming the average current of this boot continues.
+    pub fn remaining_life(&mut self) -> Option<Duration> {
+        let session = self.session_uah().iter().sum::<u64>();
+        let elapsed = Instant::now().as_secs();
+        if session == 0 || elapsed == 0 {
+            return None;
+        }
+        let capacity = self.coefficients.battery_capacity_mah as u64 * 1000;
+        let remaining = capacity.saturating_sub(self.consumed_uah());
+        Some(Duration::from_secs(remaining * elapsed / session))
+    }
+}
+
+pub static ENERGY: Mutex<CriticalSectionRawMutex, RefCell<EnergyMeter>> =
+    Mutex::new(RefCell::new(EnergyMeter::new(&BOARD_COEFFICIENTS)));
+
+pub fn with_meter<R>(f: impl FnOnce(&mut EnergyMeter) -> R) -> R {
+    ENERGY.lock(|meter| f(&mut meter.borrow_mut()))
+}
+
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) {
+    let mut buf = [0; 8];
+    if let Ok(8) = store.read_record(RecordKey::EnergyUsed, &mut buf) {
+        with_meter(|meter| meter.restored_uah = u64::from_le_bytes(buf));
+    }
+}
+
+pub fn checkpoint(store: &mut DeviceNonVolatileStore<'_>) -> Result<(), NonVolatileStoreError> {
+    let consumed = with_meter(|meter| meter.consumed_uah());
+    store.write_record(RecordKey::EnergyUsed, &consumed.to_le_bytes())
+}
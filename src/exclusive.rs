@@ -0,0 +1,59 @@
+//! Lends the radio to the application between MAC operations, for CW tests, P2P or
+//! spectrum scans. The MAC only uses the radio from within `join` and `send`, so holding
+//! [`ExclusiveRadio`] keeps it out while the session stays untouched in the `Mac`.
+
+use embassy_time::{Duration, Timer};
+use lora_phy::mod_params::{Bandwidth, RadioError, SpreadingFactor};
+use lorawan::device::Device;
+
+use crate::coding_rate;
+use crate::device::LoraDevice;
+use crate::lora_radio::LoraType;
+
+pub struct ExclusiveRadio<'a, 'd> {
+    device: &'a mut LoraDevice<'d>,
+}
+impl<'a, 'd> ExclusiveRadio<'a, 'd> {
+    pub(crate) fn new(device: &'a mut LoraDevice<'d>) -> Self {
+        defmt::info!("MAC suspended");
+        coding_rate::set_mac_suspended(true);
+        Self { device }
+    }
+
+    pub fn radio(&mut self) -> &mut LoraType<'d> {
+        self.device.radio()
+    }
+
+    /// Puts the radio back to sleep with its configuration retained and hands it back to
+    /// the MAC, which sets up the modulation again for every operation.
+    pub async fn resume(self) -> Result<(), RadioError> {
+        let res = self.device.radio().sleep(true).await;
+        coding_rate::set_mac_suspended(false);
+        defmt::info!("MAC resumed");
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RadioJob {
+    /// Unmodulated carrier, as asked for by certification labs.
+    ContinuousWave { frequency: u32, power: i32, duration: Duration },
+}
+
+pub async fn run(radio: &mut ExclusiveRadio<'_, '_>, job: RadioJob) -> Result<(), RadioError> {
+    defmt::info!("running {:?}", job);
+    match job {
+        RadioJob::ContinuousWave { frequency, power, duration } => {
+            let radio = radio.radio();
+            let params = radio.create_modulation_params(
+                SpreadingFactor::_7,
+                Bandwidth::_125KHz,
+                coding_rate::configured(),
+                frequency,
+            )?;
+            radio.prepare_for_cw(&params, power).await?;
+            Timer::after(duration).await;
+            radio.enter_standby().await
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//! How often FCntUp is written to flash. Saving the session on every uplink wears out its
+//! page, so a save that only moves FCntUp is skipped until the counter is
+//! [`FcntPolicy::save_every`] past the one on flash. A restored session then starts that
+//! many frames further, past any frame sent since, so the network never sees a counter
+//! go back.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct FcntPolicy {
+    /// Frames between saves, and the margin added on restore. 1 saves every frame.
+    pub save_every: u32,
+}
+impl FcntPolicy {
+    pub const DEFAULT: Self = Self { save_every: 32 };
+
+    /// Whether a session at `fcnt_up` is to be written, `saved` being on flash.
+    pub fn due(&self, saved: u32, fcnt_up: u32) -> bool {
+        fcnt_up.wrapping_sub(saved) >= self.save_every
+    }
+
+    /// FCntUp to go on with after a restore of `saved`.
+    pub fn restore(&self, saved: u32) -> u32 {
+        saved.saturating_add(self.save_every)
+    }
+}
@@ -0,0 +1,45 @@
+//! Clone detection: devices flashed with the same credentials by mistake both join and
+//! uplink, and the network only notices once their frame counters collide. Each device
+//! draws a random seed the first time it boots and keeps it in the journal, and reports
+//! it with its boot count after every join. A server seeing the seed change without the
+//! device being erased, or the boot count go back, has two devices behind one DevEUI.
+
+use lorawan::device::rng::Rng;
+use lorawan::device::Device;
+
+use crate::device::LoraDevice;
+use crate::journal::RecordKey;
+
+pub const FINGERPRINT_SIZE: usize = 8;
+const SEED_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Fingerprint {
+    seed: u32,
+    boot_count: u32,
+}
+impl Fingerprint {
+    /// The seed persisted, or a new one on the first boot.
+    pub fn load(device: &mut LoraDevice<'_>, boot_count: u32) -> Self {
+        let mut buf = [0; SEED_SIZE];
+        let seed = match device.non_volatile_store().read_record(RecordKey::CloneSeed, &mut buf) {
+            Ok(SEED_SIZE) => u32::from_le_bytes(buf),
+            Ok(_) | Err(_) => {
+                let Ok(seed) = device.rng().next_u32();
+                let store = device.non_volatile_store();
+                if let Err(e) = store.write_record(RecordKey::CloneSeed, &seed.to_le_bytes()) {
+                    defmt::error!("clone seed not saved {:?}", e);
+                }
+                seed
+            }
+        };
+        Self { seed, boot_count }
+    }
+
+    pub fn encode(&self) -> [u8; FINGERPRINT_SIZE] {
+        let mut buf = [0; FINGERPRINT_SIZE];
+        buf[..4].copy_from_slice(&self.seed.to_be_bytes());
+        buf[4..].copy_from_slice(&self.boot_count.to_be_bytes());
+        buf
+    }
+}
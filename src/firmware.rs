@@ -0,0 +1,143 @@
+//! Identity of the running image and the anti-rollback counter. Every image carries an
+//! [`ImageInfo`] block. The highest security version that ever booted is persisted and
+//! an update is only applied if its version is at least that high.
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+
+const MAGIC: u32 = 0x4C50_494D;
+const BUILD_ID_SIZE: usize = 16;
+
+/// Raise whenever a release fixes a vulnerability, older images can't be installed after.
+pub const SECURITY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(C)]
+pub struct ImageInfo {
+    magic: u32,
+    pub security_version: u32,
+    /// See [`version`].
+    pub version: u32,
+    /// `BUILD_ID` from the build environment, e.g. the git commit, or the crate version
+    pub build_id: [u8; BUILD_ID_SIZE],
+}
+impl ImageInfo {
+    const SIZE: usize = 12 + BUILD_ID_SIZE;
+
+    /// Looks for the info block in an image received by FUOTA.
+    pub fn find(image: &[u8]) -> Option<Self> {
+        image.windows(Self::SIZE).step_by(4).map(Self::from_bytes).find(|info| info.magic == MAGIC)
+    }
+
+    fn from_bytes(block: &[u8]) -> Self {
+        Self {
+            magic: u32::from_le_bytes([block[0], block[1], block[2], block[3]]),
+            security_version: u32::from_le_bytes([block[4], block[5], block[6], block[7]]),
+            version: u32::from_le_bytes([block[8], block[9], block[10], block[11]]),
+            build_id: block[12..Self::SIZE].try_into().unwrap(),
+        }
+    }
+
+    pub fn build_id(&self) -> &str {
+        let len = self.build_id.iter().position(|b| *b == 0).unwrap_or(BUILD_ID_SIZE);
+        core::str::from_utf8(&self.build_id[..len]).unwrap_or("?")
+    }
+}
+
+const fn build_id() -> [u8; BUILD_ID_SIZE] {
+    let id = match option_env!("BUILD_ID") {
+        Some(id) => id.as_bytes(),
+        None => env!("CARGO_PKG_VERSION").as_bytes(),
+    };
+    let mut buf = [0; BUILD_ID_SIZE];
+    let mut i = 0;
+    while i < id.len() && i < BUILD_ID_SIZE {
+        buf[i] = id[i];
+        i += 1;
+    }
+    buf
+}
+
+const fn parse(number: &str) -> u32 {
+    let digits = number.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+/// `CARGO_PKG_VERSION` as major, minor and patch in the upper three bytes, and in the
+/// lowest a hash of the build ID telling builds of one version apart.
+const fn version(build_id: &[u8; BUILD_ID_SIZE]) -> u32 {
+    // FNV-1a
+    let mut hash: u32 = 0x811C_9DC5;
+    let mut i = 0;
+    while i < BUILD_ID_SIZE {
+        hash = (hash ^ build_id[i] as u32).wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    (parse(env!("CARGO_PKG_VERSION_MAJOR")) & 0xFF) << 24
+        | (parse(env!("CARGO_PKG_VERSION_MINOR")) & 0xFF) << 16
+        | (parse(env!("CARGO_PKG_VERSION_PATCH")) & 0xFF) << 8
+        | hash & 0xFF
+}
+
+#[used]
+#[no_mangle]
+pub static IMAGE_INFO: ImageInfo = ImageInfo {
+    magic: MAGIC,
+    security_version: SECURITY_VERSION,
+    version: version(&build_id()),
+    build_id: build_id(),
+};
+
+#[cfg_attr(not(feature = "fuota"), allow(dead_code))]
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum UpdateError {
+    NoImageInfo,
+    /// the image is older than the minimum security version
+    Rollback {
+        image: u32,
+        minimum: u32,
+    },
+    Store(NonVolatileStoreError),
+    /// larger than the staging partition
+    TooLarge,
+    /// the staged image no longer matches its CRC
+    Corrupted,
+}
+
+fn minimum_version(store: &mut DeviceNonVolatileStore<'_>) -> u32 {
+    let mut buf = [0; 4];
+    match store.read_record(RecordKey::MinSecurityVersion, &mut buf) {
+        Ok(4) => u32::from_le_bytes(buf),
+        _ => 0,
+    }
+}
+
+/// Raises the persisted minimum to the running image's version, called at every boot.
+pub fn ratchet(store: &mut DeviceNonVolatileStore<'_>) -> Result<(), NonVolatileStoreError> {
+    defmt::info!("build {} security version {}", IMAGE_INFO.build_id(), SECURITY_VERSION);
+    if minimum_version(store) < SECURITY_VERSION {
+        store.write_record(RecordKey::MinSecurityVersion, &SECURITY_VERSION.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Checks that an update image may replace the running one.
+#[cfg_attr(not(feature = "fuota"), allow(dead_code))]
+pub fn check_update(
+    store: &mut DeviceNonVolatileStore<'_>,
+    image: &[u8],
+) -> Result<ImageInfo, UpdateError> {
+    let info = ImageInfo::find(image).ok_or(UpdateError::NoImageInfo)?;
+    let minimum = minimum_version(store).max(SECURITY_VERSION);
+    if info.security_version < minimum {
+        defmt::error!("refusing update {}", info.build_id());
+        return Err(UpdateError::Rollback { image: info.security_version, minimum });
+    }
+    Ok(info)
+}
@@ -0,0 +1,196 @@
+//! Firmware management package (TS006) on [`FMP_PORT`]: the network can read the
+//! version of the running firmware, schedule a reboot at a GPS time or after a countdown,
+//! and check or drop an update staged by [`crate::update`], which a reboot activates.
+//!
+//! The firmware version is [`firmware::IMAGE_INFO`]'s, from `CARGO_PKG_VERSION` and the
+//! build ID. A reboot time can only be set once the clock of [`crate::rtc`] was set, before
+//! that DevRebootTimeAns carries 0.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::device::DeviceNonVolatileStore;
+use crate::firmware;
+use crate::rtc::NetworkTime;
+
+pub const FMP_PORT: u8 = 203;
+/// Largest uplink of answers.
+pub const MAX_ANSWER_SIZE: usize = 24;
+const PACKAGE_IDENTIFIER: u8 = 4;
+const PACKAGE_VERSION: u8 = 1;
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const DEV_VERSION_REQ: u8 = 0x01;
+const DEV_REBOOT_TIME_REQ: u8 = 0x02;
+const DEV_REBOOT_COUNTDOWN_REQ: u8 = 0x03;
+const DEV_UPGRADE_IMAGE_REQ: u8 = 0x04;
+const DEV_DELETE_IMAGE_REQ: u8 = 0x05;
+const REBOOT_NOW: u32 = 0;
+const REBOOT_TIME_CANCEL: u32 = 0xFFFF_FFFF;
+const REBOOT_COUNTDOWN_CANCEL: u32 = 0xFF_FFFF;
+/// DevUpgradeImageAns statuses.
+const NO_IMAGE: u8 = 0;
+#[cfg(feature = "fuota")]
+const IMAGE_CORRUPTED: u8 = 1;
+#[cfg(feature = "fuota")]
+const IMAGE_INCOMPATIBLE: u8 = 2;
+#[cfg(feature = "fuota")]
+const IMAGE_VALID: u8 = 3;
+/// DevDeleteImageAns error bits.
+const DELETE_NO_IMAGE: u8 = 1 << 0;
+#[cfg(feature = "fuota")]
+const DELETE_WRONG_VERSION: u8 = 1 << 1;
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum FmpError {
+    Invalid,
+}
+
+pub struct Fmp {
+    hardware_version: u32,
+    reboot_at: Option<Instant>,
+    answers: Vec<u8, MAX_ANSWER_SIZE>,
+}
+impl Fmp {
+    pub fn new(hardware_version: u32) -> Self {
+        Self { hardware_version, reboot_at: None, answers: Vec::new() }
+    }
+
+    /// When the reboot asked for is due.
+    pub fn reboot_at(&self) -> Option<Instant> {
+        self.reboot_at
+    }
+
+    /// Whether to reboot now, once the answers went out.
+    pub fn reboot_due(&self, now: Instant) -> bool {
+        self.reboot_at.is_some_and(|at| now >= at) && self.answers.is_empty()
+    }
+
+    pub fn take_answers(&mut self) -> Option<Vec<u8, MAX_ANSWER_SIZE>> {
+        (!self.answers.is_empty()).then(|| core::mem::take(&mut self.answers))
+    }
+
+    /// Handles the commands of a downlink on [`FMP_PORT`], their answers go out with the
+    /// next [`Fmp::take_answers`].
+    pub fn command(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        downlink: &[u8],
+    ) -> Result<(), FmpError> {
+        let mut rest = downlink;
+        while let Some((&cid, args)) = rest.split_first() {
+            let used = match cid {
+                PACKAGE_VERSION_REQ => {
+                    let _ =
+                        self.answers.extend_from_slice(&[cid, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                    0
+                }
+                DEV_VERSION_REQ => {
+                    let _ = self.answers.push(cid);
+                    let _ =
+                        self.answers.extend_from_slice(&firmware::IMAGE_INFO.version.to_le_bytes());
+                    let _ = self.answers.extend_from_slice(&self.hardware_version.to_le_bytes());
+                    0
+                }
+                DEV_REBOOT_TIME_REQ if args.len() >= 4 => {
+                    let time = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                    let answer = self.reboot_time(time);
+                    let _ = self.answers.push(cid);
+                    let _ = self.answers.extend_from_slice(&answer.to_le_bytes());
+                    4
+                }
+                DEV_REBOOT_COUNTDOWN_REQ if args.len() >= 3 => {
+                    let countdown = u32::from_le_bytes([args[0], args[1], args[2], 0]);
+                    self.reboot_at = match countdown {
+                        REBOOT_COUNTDOWN_CANCEL => None,
+                        countdown => Some(Instant::now() + Duration::from_secs(countdown as u64)),
+                    };
+                    defmt::info!("reboot at {:?}", self.reboot_at);
+                    let _ = self.answers.push(cid);
+                    let _ = self.answers.extend_from_slice(&countdown.to_le_bytes()[..3]);
+                    3
+                }
+                DEV_UPGRADE_IMAGE_REQ => {
+                    let (status, version) = upgrade_image(store);
+                    let _ = self.answers.extend_from_slice(&[cid, status]);
+                    if let Some(version) = version {
+                        let _ = self.answers.extend_from_slice(&version.to_le_bytes());
+                    }
+                    0
+                }
+                DEV_DELETE_IMAGE_REQ if args.len() >= 4 => {
+                    let version = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                    let _ = self.answers.extend_from_slice(&[cid, delete_image(store, version)]);
+                    4
+                }
+                _ => return Err(FmpError::Invalid),
+            };
+            rest = &args[used..];
+        }
+        Ok(())
+    }
+
+    /// Schedules a reboot at a GPS time, returning the time for the answer.
+    fn reboot_time(&mut self, time: u32) -> u32 {
+        match time {
+            REBOOT_TIME_CANCEL => self.reboot_at = None,
+            REBOOT_NOW => self.reboot_at = Some(Instant::now()),
+            time => {
+                let Some(now) = NetworkTime::now() else {
+                    defmt::warn!("reboot time not set, the clock isn't synchronized");
+                    return 0;
+                };
+                let wait = time.saturating_sub(now.gps_seconds);
+                self.reboot_at = Some(Instant::now() + Duration::from_secs(wait as u64));
+            }
+        }
+        defmt::info!("reboot at {:?}", self.reboot_at);
+        time
+    }
+}
+
+/// DevUpgradeImageAns status of the staged update, with its version when valid.
+#[cfg(feature = "fuota")]
+fn upgrade_image(store: &mut DeviceNonVolatileStore<'_>) -> (u8, Option<u32>) {
+    use crate::firmware::UpdateError;
+    match crate::update::staged(store) {
+        Ok(Some(info)) => (IMAGE_VALID, Some(info.version)),
+        Ok(None) => (NO_IMAGE, None),
+        // an image the device refuses to install is as good as one for other hardware
+        Err(UpdateError::Rollback { .. }) => (IMAGE_INCOMPATIBLE, None),
+        Err(e) => {
+            defmt::warn!("staged update unusable {:?}", e);
+            (IMAGE_CORRUPTED, None)
+        }
+    }
+}
+
+#[cfg(not(feature = "fuota"))]
+fn upgrade_image(_store: &mut DeviceNonVolatileStore<'_>) -> (u8, Option<u32>) {
+    (NO_IMAGE, None)
+}
+
+/// Drops the staged update if it has `version`, returning the DevDeleteImageAns status.
+#[cfg(feature = "fuota")]
+fn delete_image(store: &mut DeviceNonVolatileStore<'_>, version: u32) -> u8 {
+    match crate::update::staged(store) {
+        Ok(None) => return DELETE_NO_IMAGE,
+        Ok(Some(info)) if info.version != version => return DELETE_WRONG_VERSION,
+        // deleted whatever its state, unless it is another version
+        _ => {}
+    }
+    match crate::update::discard(store) {
+        Ok(()) => {
+            defmt::info!("staged update deleted");
+            0
+        }
+        Err(e) => {
+            defmt::error!("staged update not deleted {:?}", e);
+            DELETE_NO_IMAGE
+        }
+    }
+}
+
+#[cfg(not(feature = "fuota"))]
+fn delete_image(_store: &mut DeviceNonVolatileStore<'_>, _version: u32) -> u8 {
+    DELETE_NO_IMAGE
+}
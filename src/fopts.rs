@@ -0,0 +1,114 @@
+//! The MAC commands in FOpts of a data uplink, edited by [`crate::uplink_edit`] before the
+//! frame goes to the radio: answers the board has a say in and requests the firmware makes
+//! on its own, along with the ADR bits of FCtrl for the ADR control of [`crate::adr`]. Works on the frame without its MIC, which has to be computed again after.
+//! Also included by the network server emulator tests in `tools/`; keep this file free of
+//! crate dependencies.
+
+pub const MAX_FOPTS_SIZE: usize = 15;
+pub const LINK_CHECK_REQ: u8 = 0x02;
+pub const LINK_ADR_ANS: u8 = 0x03;
+pub const DEVICE_TIME_REQ: u8 = 0x0D;
+pub const ADR: u8 = 0x80;
+pub const ADR_ACK_REQ: u8 = 0x40;
+/// MHDR, DevAddr, FCtrl and FCnt.
+const FHDR_END: usize = 8;
+const FCTRL: usize = 5;
+
+/// Argument length of each MAC command a LoRaWAN 1.0.x device sends, `None` for the ones
+/// it doesn't know, after which nothing more can be parsed.
+fn uplink_args(cid: u8) -> Option<usize> {
+    match cid {
+        0x02 | 0x04 | 0x08 | 0x09 | 0x0D => Some(0),
+        0x03 | 0x05 | 0x07 | 0x0A => Some(1),
+        0x06 => Some(2),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Edit {
+    /// Status bits cleared in each LinkADRAns.
+    pub link_adr_nack: u8,
+    /// Appends a LinkCheckReq.
+    pub link_check_req: bool,
+    /// Appends a DeviceTimeReq.
+    pub device_time_req: bool,
+    /// Sets or clears the ADR bit, `None` leaves it as it is.
+    pub adr: Option<bool>,
+    /// Sets or clears ADRACKReq.
+    pub adr_ack_req: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Edited {
+    /// LinkADRAns that had status bits cleared.
+    pub link_adr_nacked: usize,
+    /// Whether the LinkCheckReq was appended, FOpts had no room for it otherwise.
+    pub link_check_req: bool,
+    /// Whether the DeviceTimeReq was appended.
+    pub device_time_req: bool,
+    /// Whether FCtrl was changed.
+    pub fctrl: bool,
+    /// Of the frame after the edit.
+    pub len: usize,
+}
+
+/// Applies `edit` to the data uplink in `frame[..len]`, without its MIC, in place. Requests
+/// are appended to FOpts as far as they and the rest of `frame` have room. `None` if it is
+/// not a data uplink or its FOpts can't be parsed.
+pub fn edit(frame: &mut [u8], len: usize, edit: &Edit) -> Option<Edited> {
+    if len < FHDR_END || len > frame.len() || !matches!(frame[0] >> 5, 2 | 4) {
+        return None;
+    }
+    let fopts_end = FHDR_END + (frame[FCTRL] & 0x0F) as usize;
+    let fopts = frame[..len].get_mut(FHDR_END..fopts_end)?;
+    let mut edited = Edited { len, ..Edited::default() };
+    let mut i = 0;
+    while i < fopts.len() {
+        let cid = fopts[i];
+        let args = uplink_args(cid)?;
+        if i + 1 + args > fopts.len() {
+            return None;
+        }
+        if cid == LINK_ADR_ANS && fopts[i + 1] & edit.link_adr_nack != 0 {
+            fopts[i + 1] &= !edit.link_adr_nack;
+            edited.link_adr_nacked += 1;
+        }
+        i += 1 + args;
+    }
+    let fctrl = [(edit.adr, ADR), (edit.adr_ack_req, ADR_ACK_REQ)].into_iter().fold(
+        frame[FCTRL],
+        |fctrl, (set, bit)| match set {
+            Some(true) => fctrl | bit,
+            Some(false) => fctrl & !bit,
+            None => fctrl,
+        },
+    );
+    edited.fctrl = fctrl != frame[FCTRL];
+    frame[FCTRL] = fctrl;
+    if edit.link_check_req {
+        edited.link_check_req = append(frame, &mut edited.len, LINK_CHECK_REQ);
+    }
+    if edit.device_time_req {
+        edited.device_time_req = append(frame, &mut edited.len, DEVICE_TIME_REQ);
+    }
+    Some(edited)
+}
+
+/// Appends the MAC command `cid`, which has no arguments, to FOpts of the frame of `len`.
+fn append(frame: &mut [u8], len: &mut usize, cid: u8) -> bool {
+    let fopts_len = (frame[FCTRL] & 0x0F) as usize;
+    let fopts_end = FHDR_END + fopts_len;
+    // FOpts have to be empty when FPort 0 carries MAC commands
+    if fopts_len == MAX_FOPTS_SIZE
+        || *len == frame.len()
+        || frame[fopts_end..*len].first() == Some(&0)
+    {
+        return false;
+    }
+    frame.copy_within(fopts_end..*len, fopts_end + 1);
+    frame[fopts_end] = cid;
+    frame[FCTRL] += 1;
+    *len += 1;
+    true
+}
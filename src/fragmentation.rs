@@ -0,0 +1,206 @@
+//! Fragmented data block transport (TS004) on [`FRAGMENTATION_PORT`], receiving a firmware
+//! update into the staging partition of [`crate::update`]. Each uncoded fragment is written
+//! to flash where it belongs as it arrives, and once all of them are in the image is staged
+//! and activated by the next reboot, which the network asks for over [`crate::fmp`].
+//!
+//! Only session 0 without forward error correction is supported: the fragments after the
+//! first NbFrag are ignored, a lost fragment has to be sent again. Fragments are written
+//! a double word at a time, so their size must be a multiple of 8 bytes. Without the
+//! `fuota` feature every session is refused for the lack of memory.
+
+use heapless::Vec;
+
+use crate::device::DeviceNonVolatileStore;
+
+pub const FRAGMENTATION_PORT: u8 = 201;
+/// Largest uplink of answers.
+pub const MAX_ANSWER_SIZE: usize = 16;
+/// Fragments of a session, one bit each.
+const MAX_FRAGMENTS: usize = 2048;
+const PACKAGE_IDENTIFIER: u8 = 3;
+const PACKAGE_VERSION: u8 = 1;
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const FRAG_SESSION_STATUS_REQ: u8 = 0x01;
+const FRAG_SESSION_SETUP_REQ: u8 = 0x02;
+const FRAG_SESSION_DELETE_REQ: u8 = 0x03;
+const DATA_FRAGMENT: u8 = 0x08;
+/// FragSessionSetupAns status bits.
+#[cfg(feature = "fuota")]
+const ENCODING_UNSUPPORTED: u8 = 1 << 0;
+const NOT_ENOUGH_MEMORY: u8 = 1 << 1;
+const INDEX_UNSUPPORTED: u8 = 1 << 2;
+/// FragSessionDeleteAns status bit.
+const SESSION_DOES_NOT_EXIST: u8 = 1 << 2;
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum FragmentationError {
+    Invalid,
+}
+
+#[cfg_attr(not(feature = "fuota"), allow(dead_code))]
+struct Session {
+    nb_frag: u16,
+    frag_size: u8,
+    padding: u8,
+    received: u16,
+    fragments: [u8; MAX_FRAGMENTS / 8],
+}
+impl Session {
+    fn complete(&self) -> bool {
+        self.received == self.nb_frag
+    }
+}
+
+#[derive(Default)]
+pub struct Fragmentation {
+    session: Option<Session>,
+    answers: Vec<u8, MAX_ANSWER_SIZE>,
+}
+impl Fragmentation {
+    pub fn take_answers(&mut self) -> Option<Vec<u8, MAX_ANSWER_SIZE>> {
+        (!self.answers.is_empty()).then(|| core::mem::take(&mut self.answers))
+    }
+
+    /// Handles the commands of a downlink on [`FRAGMENTATION_PORT`], unicast or multicast,
+    /// their answers go out with the next [`Fragmentation::take_answers`].
+    pub fn command(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        downlink: &[u8],
+    ) -> Result<(), FragmentationError> {
+        let mut rest = downlink;
+        while let Some((&cid, args)) = rest.split_first() {
+            let used = match cid {
+                PACKAGE_VERSION_REQ => {
+                    let _ =
+                        self.answers.extend_from_slice(&[cid, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                    0
+                }
+                FRAG_SESSION_STATUS_REQ if !args.is_empty() => {
+                    let all_participants = args[0] & 1 != 0;
+                    let index = args[0] >> 1 & 0b11;
+                    match &self.session {
+                        Some(session)
+                            if index == 0 && (all_participants || !session.complete()) =>
+                        {
+                            let missing = session.nb_frag - session.received;
+                            let _ = self.answers.push(cid);
+                            let _ = self.answers.extend_from_slice(&session.received.to_le_bytes());
+                            let _ = self.answers.extend_from_slice(&[missing.min(255) as u8, 0]);
+                        }
+                        _ => {}
+                    }
+                    1
+                }
+                FRAG_SESSION_SETUP_REQ if args.len() >= 10 => {
+                    let index = args[0] >> 4 & 0b11;
+                    let status = if index != 0 {
+                        INDEX_UNSUPPORTED
+                    } else {
+                        self.setup(store, &args[..10])
+                    };
+                    let _ = self.answers.extend_from_slice(&[cid, index << 6 | status]);
+                    10
+                }
+                FRAG_SESSION_DELETE_REQ if !args.is_empty() => {
+                    let index = args[0] & 0b11;
+                    let status = match self.session.take() {
+                        Some(_) if index == 0 => {
+                            defmt::info!("fragmentation session deleted");
+                            0
+                        }
+                        session => {
+                            self.session = session;
+                            SESSION_DOES_NOT_EXIST
+                        }
+                    };
+                    let _ = self.answers.extend_from_slice(&[cid, index | status]);
+                    1
+                }
+                // the rest of the downlink is the fragment
+                DATA_FRAGMENT if args.len() >= 2 => {
+                    let index_and_n = u16::from_le_bytes([args[0], args[1]]);
+                    if index_and_n >> 14 == 0 {
+                        self.fragment(store, index_and_n & 0x3FFF, &args[2..]);
+                    }
+                    args.len()
+                }
+                _ => return Err(FragmentationError::Invalid),
+            };
+            rest = &args[used..];
+        }
+        Ok(())
+    }
+
+    /// Starts session 0 from the arguments of a FragSessionSetupReq, returning the status
+    /// for the answer.
+    #[cfg(feature = "fuota")]
+    fn setup(&mut self, store: &mut DeviceNonVolatileStore<'_>, args: &[u8]) -> u8 {
+        let nb_frag = u16::from_le_bytes([args[1], args[2]]);
+        let frag_size = args[3];
+        let frag_algo = args[4] >> 3 & 0b111;
+        let padding = args[5];
+        let (_, capacity) = DeviceNonVolatileStore::<'_>::staging();
+        let mut status = 0;
+        if frag_algo != 0 || frag_size == 0 || frag_size % 8 != 0 {
+            status |= ENCODING_UNSUPPORTED;
+        }
+        if nb_frag as usize > MAX_FRAGMENTS || nb_frag as u32 * frag_size as u32 > capacity {
+            status |= NOT_ENOUGH_MEMORY;
+        }
+        if status != 0 {
+            defmt::warn!("fragmentation session of {} x {} B refused", nb_frag, frag_size);
+            return status;
+        }
+        if let Err(e) = crate::update::discard(store) {
+            defmt::error!("staging not erased {:?}", e);
+            return NOT_ENOUGH_MEMORY;
+        }
+        defmt::info!("fragmentation session of {} x {} B", nb_frag, frag_size);
+        self.session = Some(Session {
+            nb_frag,
+            frag_size,
+            padding,
+            received: 0,
+            fragments: [0; MAX_FRAGMENTS / 8],
+        });
+        0
+    }
+
+    #[cfg(not(feature = "fuota"))]
+    fn setup(&mut self, _store: &mut DeviceNonVolatileStore<'_>, _args: &[u8]) -> u8 {
+        NOT_ENOUGH_MEMORY
+    }
+
+    /// Writes fragment `n` of the session to the staging partition, staging the image once
+    /// it was the last one missing.
+    #[cfg(feature = "fuota")]
+    fn fragment(&mut self, store: &mut DeviceNonVolatileStore<'_>, n: u16, data: &[u8]) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        if n == 0 || n > session.nb_frag || data.len() != session.frag_size as usize {
+            return;
+        }
+        let (byte, bit) = ((n - 1) as usize / 8, 1 << ((n - 1) % 8));
+        if session.fragments[byte] & bit != 0 {
+            return;
+        }
+        let offset = (n - 1) as u32 * session.frag_size as u32;
+        if let Err(e) = store.write_staging(offset, data) {
+            defmt::error!("fragment {} not written {:?}", n, e);
+            return;
+        }
+        session.fragments[byte] |= bit;
+        session.received += 1;
+        if session.complete() {
+            let size = session.nb_frag as u32 * session.frag_size as u32 - session.padding as u32;
+            if let Err(e) = crate::update::stage(store, size) {
+                defmt::error!("received update not staged {:?}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "fuota"))]
+    fn fragment(&mut self, _store: &mut DeviceNonVolatileStore<'_>, _n: u16, _data: &[u8]) {}
+}
@@ -0,0 +1,187 @@
+//! Frame identity of the PHYPayloads passing through the radio buffer, logged so that
+//! device logs can be matched against network server records.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::channels::JOIN_ACCEPT_SIZE;
+use crate::fopts::MAX_FOPTS_SIZE;
+use crate::log_filter::Module;
+use crate::multicast;
+
+const MIC_SIZE: usize = 4;
+pub const MAX_FRAME_SIZE: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameId {
+    JoinRequest { dev_nonce: u16 },
+    JoinAccept,
+    Data { confirmed: bool, dev_addr: u32, fctrl: u8, fcnt: u16, fport: Option<u8> },
+}
+impl FrameId {
+    /// Downlink FCtrl bit telling that the network has more data queued.
+    const FPENDING: u8 = 1 << 4;
+    /// FCtrl bit acknowledging the last confirmed frame from the other side.
+    const ACK: u8 = 1 << 5;
+    /// Uplink FCtrl bit asking the network to answer within ADR_ACK_DELAY uplinks.
+    const ADR_ACK_REQ: u8 = 1 << 6;
+
+    pub fn parse(phy: &[u8]) -> Option<Self> {
+        let mtype = phy.first()? >> 5;
+        match mtype {
+            0 if phy.len() >= 19 => {
+                Some(FrameId::JoinRequest { dev_nonce: u16::from_le_bytes([phy[17], phy[18]]) })
+            }
+            1 => Some(FrameId::JoinAccept),
+            2..=5 if phy.len() >= 8 + MIC_SIZE => {
+                let fopts_len = (phy[5] & 0x0F) as usize;
+                let fport_index = 8 + fopts_len;
+                Some(FrameId::Data {
+                    confirmed: mtype >= 4,
+                    dev_addr: u32::from_le_bytes([phy[1], phy[2], phy[3], phy[4]]),
+                    fctrl: phy[5],
+                    fcnt: u16::from_le_bytes([phy[6], phy[7]]),
+                    fport: (phy.len() > fport_index + MIC_SIZE).then(|| phy[fport_index]),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn dev_addr(&self) -> Option<u32> {
+        match self {
+            FrameId::Data { dev_addr, .. } => Some(*dev_addr),
+            _ => None,
+        }
+    }
+
+    /// Whether this uplink has ADRACKReq set.
+    pub fn adr_ack_req(&self) -> bool {
+        matches!(self, FrameId::Data { fctrl, .. } if fctrl & Self::ADR_ACK_REQ != 0)
+    }
+
+    /// Whether this frame carries MAC commands, in FOpts or on port 0.
+    pub fn has_mac_commands(&self) -> bool {
+        matches!(self, FrameId::Data { fctrl, fport, .. } if fctrl & 0x0F != 0 || *fport == Some(0))
+    }
+
+    /// Whether this downlink acknowledges a confirmed uplink.
+    pub fn ack(&self) -> bool {
+        matches!(self, FrameId::Data { fctrl, .. } if fctrl & Self::ACK != 0)
+    }
+
+    /// Whether this downlink has FPending set.
+    pub fn pending(&self) -> bool {
+        matches!(self, FrameId::Data { fctrl, .. } if fctrl & Self::FPENDING != 0)
+    }
+}
+impl defmt::Format for FrameId {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        match *self {
+            FrameId::JoinRequest { dev_nonce } => {
+                defmt::write!(fmt, "JoinRequest DevNonce {}", dev_nonce)
+            }
+            FrameId::JoinAccept => defmt::write!(fmt, "JoinAccept"),
+            FrameId::Data { confirmed, dev_addr, fctrl, fcnt, fport } => defmt::write!(
+                fmt,
+                "{} DevAddr {=u32:08X} FCtrl {=u8:02X} FCnt {} FPort {}",
+                if confirmed {
+                    "confirmed"
+                } else {
+                    "unconfirmed"
+                },
+                dev_addr,
+                fctrl,
+                fcnt,
+                fport
+            ),
+        }
+    }
+}
+
+static BENCH_MODE: AtomicBool = AtomicBool::new(false);
+/// Frames are read in a window the MAC didn't open and never sees.
+static OUTSIDE_MAC: AtomicBool = AtomicBool::new(false);
+/// A downlink carried MAC commands, the answers go out in FOpts of the next uplink.
+static MAC_ANSWERS_DUE: AtomicBool = AtomicBool::new(false);
+static LAST_UPLINK: Mutex<CriticalSectionRawMutex, Cell<Option<FrameId>>> =
+    Mutex::new(Cell::new(None));
+static LAST_DOWNLINK: Mutex<CriticalSectionRawMutex, Cell<Option<FrameId>>> =
+    Mutex::new(Cell::new(None));
+static LAST_JOIN_ACCEPT: Mutex<CriticalSectionRawMutex, Cell<Option<JoinAccept>>> =
+    Mutex::new(Cell::new(None));
+
+/// A JoinAccept as received, still encrypted, up to its length.
+pub type JoinAccept = ([u8; JOIN_ACCEPT_SIZE], usize);
+
+pub fn uplink(phy: &[u8]) {
+    let id = FrameId::parse(phy);
+    crate::log!(info, Module::Frames, "uplink {:?}", id);
+    LAST_UPLINK.lock(|last| last.set(id));
+    MAC_ANSWERS_DUE.store(false, Ordering::Relaxed);
+}
+
+/// Only log frames addressed to this device, for benches with several boards in range
+/// of each other.
+pub fn set_bench_mode(enabled: bool) {
+    BENCH_MODE.store(enabled, Ordering::Relaxed);
+}
+
+/// Set around a window opened with the MAC suspended, so that its frames don't count as
+/// the MAC's last downlink.
+pub fn set_outside_mac(outside: bool) {
+    OUTSIDE_MAC.store(outside, Ordering::Relaxed);
+}
+
+/// Returns whether the frame can be for this device, join accepts can't be told apart.
+pub fn downlink(phy: &[u8]) -> bool {
+    let id = FrameId::parse(phy);
+    let own = last_uplink().and_then(|id| id.dev_addr());
+    let dev_addr = id.and_then(|id| id.dev_addr());
+    let ours = match dev_addr {
+        Some(dev_addr) => own == Some(dev_addr),
+        None => id.is_some(),
+    };
+    let multicast = !ours && dev_addr.is_some_and(|dev_addr| multicast::received(dev_addr, phy));
+    if ours || multicast || !BENCH_MODE.load(Ordering::Relaxed) {
+        crate::log!(info, Module::Frames, "downlink {:?}", id);
+    }
+    if ours && !OUTSIDE_MAC.load(Ordering::Relaxed) {
+        LAST_DOWNLINK.lock(|last| last.set(id));
+        if id.is_some_and(|id| id.has_mac_commands()) {
+            MAC_ANSWERS_DUE.store(true, Ordering::Relaxed);
+        }
+    }
+    if id == Some(FrameId::JoinAccept) && phy.len() <= JOIN_ACCEPT_SIZE {
+        let mut join_accept = [0; JOIN_ACCEPT_SIZE];
+        join_accept[..phy.len()].copy_from_slice(phy);
+        LAST_JOIN_ACCEPT.lock(|last| last.set(Some((join_accept, phy.len()))));
+    }
+    ours
+}
+
+pub fn last_uplink() -> Option<FrameId> {
+    LAST_UPLINK.lock(Cell::get)
+}
+
+pub fn last_downlink() -> Option<FrameId> {
+    LAST_DOWNLINK.lock(Cell::get)
+}
+
+/// Bytes of the next uplink's payload taken by FOpts, the MAC piggybacks its answers to
+/// the last downlink's commands there. Their length isn't known up front, so the most
+/// FOpts can hold is kept free.
+pub fn fopts_reserved() -> usize {
+    if MAC_ANSWERS_DUE.load(Ordering::Relaxed) {
+        MAX_FOPTS_SIZE
+    } else {
+        0
+    }
+}
+
+pub fn last_join_accept() -> Option<JoinAccept> {
+    LAST_JOIN_ACCEPT.lock(Cell::get)
+}
@@ -0,0 +1,135 @@
+//! GFSK for the FSK data rate of EU868, AS923 and IN865, which the radio driver can't
+//! configure as it only knows LoRa. The MAC still sets the radio up as if for a LoRa
+//! data rate and [`crate::iv::SubghzSpiDevice`] passes each command through
+//! [`translate`], which turns the modulation and packet parameters into their GFSK
+//! equivalents: 50 kbps, 25 kHz deviation, Gaussian BT 1, a 5 byte preamble, the
+//! `C194C1` sync word and a CCITT CRC over whitened data.
+//!
+//! The uplink data rate is all that is known, so the modulation the MAC asks for first
+//! after [`set_uplink_data_rate`] is taken as the FSK one and every later use of it, the
+//! RX1 window without an offset, is translated as well. RX2 stays on LoRa.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+
+use crate::region;
+
+const SET_PACKET_TYPE: u8 = 0x8A;
+const SET_MODULATION_PARAMS: u8 = 0x8B;
+const SET_PACKET_PARAMS: u8 = 0x8C;
+const WRITE_REGISTER: u8 = 0x0D;
+const PACKET_TYPE_GFSK: u8 = 0x00;
+const PACKET_TYPE_LORA: u8 = 0x01;
+
+/// 32 * 32 MHz / 50 kbps
+const BIT_RATE: [u8; 3] = [0x00, 0x50, 0x00];
+const PULSE_SHAPE_BT_1: u8 = 0x0B;
+const RX_BANDWIDTH_117_KHZ: u8 = 0x0B;
+/// 25 kHz in 32 MHz / 2^25 steps
+const DEVIATION: [u8; 3] = [0x00, 0x66, 0x66];
+const PREAMBLE_BITS: u16 = 5 * 8;
+const PREAMBLE_DETECTOR_8_BITS: u8 = 0x04;
+const SYNC_WORD_BITS: u8 = 24;
+const VARIABLE_LENGTH: u8 = 0x01;
+const CRC_2_BYTES_INVERTED: u8 = 0x06;
+const WHITENING_ON: u8 = 0x01;
+/// Registers 0x06C0 sync word, 0x06BC CRC seed and polynomial, 0x06B8 whitening seed.
+const SYNC_WORD: [u8; 6] = [WRITE_REGISTER, 0x06, 0xC0, 0xC1, 0x94, 0xC1];
+const CRC: [u8; 7] = [WRITE_REGISTER, 0x06, 0xBC, 0x1D, 0x0F, 0x10, 0x21];
+const WHITENING: [u8; 5] = [WRITE_REGISTER, 0x06, 0xB8, 0x01, 0xFF];
+
+pub type Command = Vec<u8, 10>;
+
+struct Fsk {
+    /// The uplink goes out on the FSK data rate.
+    active: bool,
+    /// LoRa modulation the MAC uses for the FSK data rate.
+    modulation: Option<Command>,
+    /// The radio is in GFSK mode.
+    gfsk: bool,
+}
+
+static FSK: Mutex<CriticalSectionRawMutex, RefCell<Fsk>> =
+    Mutex::new(RefCell::new(Fsk { active: false, modulation: None, gfsk: false }));
+
+fn with_fsk<R>(f: impl FnOnce(&mut Fsk) -> R) -> R {
+    FSK.lock(|fsk| f(&mut fsk.borrow_mut()))
+}
+
+/// Before every join and uplink.
+pub fn set_uplink_data_rate(data_rate: u8) {
+    with_fsk(|fsk| {
+        fsk.active = Some(data_rate) == region::current().fsk_data_rate();
+        fsk.modulation = None;
+    })
+}
+
+/// The commands to send instead of `command`, `None` to send it as is.
+pub fn translate(command: &[u8]) -> Option<Vec<Command, 5>> {
+    with_fsk(|fsk| {
+        let mut commands = Vec::new();
+        match command {
+            [SET_PACKET_TYPE, packet_type] => {
+                fsk.gfsk = *packet_type == PACKET_TYPE_GFSK;
+                return None;
+            }
+            [SET_MODULATION_PARAMS, ..] => {
+                if fsk.active && fsk.modulation.is_none() {
+                    fsk.modulation = Vec::from_slice(command).ok();
+                }
+                let gfsk = fsk.active && fsk.modulation.as_deref() == Some(command);
+                match (fsk.gfsk, gfsk) {
+                    (false, false) => return None,
+                    (true, true) => push(&mut commands, &modulation()),
+                    (false, true) => {
+                        push(&mut commands, &[SET_PACKET_TYPE, PACKET_TYPE_GFSK]);
+                        push(&mut commands, &modulation());
+                        for register in [&SYNC_WORD[..], &CRC, &WHITENING] {
+                            push(&mut commands, register);
+                        }
+                    }
+                    (true, false) => {
+                        push(&mut commands, &[SET_PACKET_TYPE, PACKET_TYPE_LORA]);
+                        push(&mut commands, command);
+                    }
+                }
+                fsk.gfsk = gfsk;
+            }
+            // preamble, header type, payload length, CRC and IQ for LoRa
+            [SET_PACKET_PARAMS, _, _, _, payload_length, ..] if fsk.gfsk => {
+                let [hi, lo] = PREAMBLE_BITS.to_be_bytes();
+                push(
+                    &mut commands,
+                    &[
+                        SET_PACKET_PARAMS,
+                        hi,
+                        lo,
+                        PREAMBLE_DETECTOR_8_BITS,
+                        SYNC_WORD_BITS,
+                        0x00,
+                        VARIABLE_LENGTH,
+                        *payload_length,
+                        CRC_2_BYTES_INVERTED,
+                        WHITENING_ON,
+                    ],
+                );
+            }
+            _ => return None,
+        }
+        Some(commands)
+    })
+}
+
+fn push(commands: &mut Vec<Command, 5>, command: &[u8]) {
+    // every command fits, at most five are pushed
+    let _ = commands.push(Vec::from_slice(command).unwrap_or_default());
+}
+
+fn modulation() -> [u8; 9] {
+    let [b0, b1, b2] = BIT_RATE;
+    let [d0, d1, d2] = DEVIATION;
+    [SET_MODULATION_PARAMS, b0, b1, b2, PULSE_SHAPE_BT_1, RX_BANDWIDTH_117_KHZ, d0, d1, d2]
+}
@@ -0,0 +1,196 @@
+//! Geofences evaluated against every GNSS fix, so that crossing one is reported at once
+//! instead of waiting for the application server to notice it in the position reports.
+//!
+//! Fences are set by downlink on [`GEOFENCE_PORT`] as `[id][shape]`, big endian, where
+//! the shape is one of
+//! - empty, removing the fence
+//! - `[1][latitude i32][longitude i32][radius u32]`, a circle with the radius in meters
+//! - `[2][latitude i32][longitude i32]([latitude i16][longitude i16])*`, a polygon of up
+//!   to [`MAX_VERTICES`] with every other vertex given relative to the first in 10^-5
+//!   degrees
+//!
+//! Each shape is persisted as received in a journal record of its own.
+
+use heapless::Vec;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::gnss::Position;
+use crate::journal::{RecordKey, MAX_VALUE_SIZE};
+use crate::payload;
+use crate::schema;
+
+pub const GEOFENCE_PORT: u8 = schema::GEOFENCE.port;
+pub const GEOFENCE_PAYLOAD_SIZE: usize = schema::GEOFENCE.header_size();
+pub const MAX_FENCES: usize = 4;
+pub const MAX_VERTICES: usize = 5;
+const RECORD_KEYS: [RecordKey; MAX_FENCES] =
+    [RecordKey::Geofence0, RecordKey::Geofence1, RecordKey::Geofence2, RecordKey::Geofence3];
+/// Consecutive fixes on the other side of a fence before it counts as crossed, so that
+/// position jitter along the boundary doesn't cause a burst of alerts.
+const CONFIRM_FIXES: u8 = 3;
+const CIRCLE: u8 = 1;
+const POLYGON: u8 = 2;
+/// 10^-5 degree vertex offsets in 10^-7 degree units
+const OFFSET_SCALE: i32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Shape {
+    Circle { center: Position, radius: u32 },
+    Polygon(Vec<Position, MAX_VERTICES>),
+}
+impl Shape {
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (&kind, data) = data.split_first()?;
+        let i32_at = |i: usize| Some(i32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?));
+        let origin = Position { latitude: i32_at(0)?, longitude: i32_at(4)? };
+        match kind {
+            CIRCLE if data.len() == 12 => {
+                let radius = u32::from_be_bytes(data[8..12].try_into().unwrap());
+                Some(Shape::Circle { center: origin, radius })
+            }
+            POLYGON if data.len() >= 8 + 2 * 4 && (data.len() - 8) % 4 == 0 => {
+                let mut vertices = Vec::new();
+                vertices.push(origin).ok()?;
+                for offset in data[8..].chunks_exact(4) {
+                    let latitude = i16::from_be_bytes([offset[0], offset[1]]) as i32;
+                    let longitude = i16::from_be_bytes([offset[2], offset[3]]) as i32;
+                    vertices
+                        .push(Position {
+                            latitude: origin.latitude + latitude * OFFSET_SCALE,
+                            longitude: origin.longitude + longitude * OFFSET_SCALE,
+                        })
+                        .ok()?;
+                }
+                Some(Shape::Polygon(vertices))
+            }
+            _ => None,
+        }
+    }
+
+    fn contains(&self, position: Position) -> bool {
+        match self {
+            Shape::Circle { center, radius } => {
+                let latitude = center.latitude as f32 * 1e-7;
+                let dy = (position.latitude - center.latitude) as f32 * 1e-7 * 110_540.0;
+                let dx = (position.longitude - center.longitude) as f32
+                    * 1e-7
+                    * 111_320.0
+                    * cos(latitude.to_radians());
+                let radius = *radius as f32;
+                dx * dx + dy * dy <= radius * radius
+            }
+            Shape::Polygon(vertices) => {
+                // ray casting, planar over the few kilometers a fence spans
+                let (x, y) = (position.longitude as i64, position.latitude as i64);
+                let mut inside = false;
+                for (i, a) in vertices.iter().enumerate() {
+                    let b = vertices[(i + 1) % vertices.len()];
+                    let (xa, ya) = (a.longitude as i64, a.latitude as i64);
+                    let (xb, yb) = (b.longitude as i64, b.latitude as i64);
+                    let lhs = (x - xa) * (yb - ya);
+                    let rhs = (xb - xa) * (y - ya);
+                    if (yb > ya && y >= ya && y < yb && lhs < rhs)
+                        || (yb < ya && y >= yb && y < ya && lhs > rhs)
+                    {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Taylor series, accurate to 10^-5 over the ±90° a latitude can take.
+fn cos(x: f32) -> f32 {
+    let x2 = x * x;
+    1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)))
+}
+
+struct Fence {
+    shape: Shape,
+    /// `None` until the first fixes have settled
+    inside: Option<bool>,
+    /// Consecutive fixes disagreeing with `inside`
+    pending: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct GeofenceEvent {
+    pub id: u8,
+    pub entered: bool,
+    pub position: Position,
+}
+impl GeofenceEvent {
+    pub fn encode(&self) -> [u8; GEOFENCE_PAYLOAD_SIZE] {
+        payload::geofence(self.id, self.entered, self.position.latitude, self.position.longitude)
+    }
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum GeofenceError {
+    InvalidId,
+    InvalidShape,
+    Store(NonVolatileStoreError),
+}
+
+pub struct Geofences {
+    fences: [Option<Fence>; MAX_FENCES],
+}
+impl Geofences {
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>) -> Self {
+        let mut fences = [const { None }; MAX_FENCES];
+        for (fence, key) in fences.iter_mut().zip(RECORD_KEYS) {
+            let mut buf = [0; MAX_VALUE_SIZE];
+            if let Ok(len) = store.read_record(key, &mut buf) {
+                *fence = Shape::decode(&buf[..len]).map(|shape| Fence {
+                    shape,
+                    inside: None,
+                    pending: 0,
+                });
+            }
+        }
+        Self { fences }
+    }
+
+    /// Applies a downlink, the fence starts over as if the device had just booted.
+    pub fn configure(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        downlink: &[u8],
+    ) -> Result<(), GeofenceError> {
+        let (&id, data) = downlink.split_first().ok_or(GeofenceError::InvalidId)?;
+        let fence = self.fences.get_mut(id as usize).ok_or(GeofenceError::InvalidId)?;
+        let shape = match data {
+            [] => None,
+            _ => Some(Shape::decode(data).ok_or(GeofenceError::InvalidShape)?),
+        };
+        store.write_record(RECORD_KEYS[id as usize], data).map_err(GeofenceError::Store)?;
+        defmt::info!("geofence {} set to {:?}", id, shape);
+        *fence = shape.map(|shape| Fence { shape, inside: None, pending: 0 });
+        Ok(())
+    }
+
+    pub fn update(&mut self, position: Position) -> Vec<GeofenceEvent, MAX_FENCES> {
+        let mut events = Vec::new();
+        for (id, fence) in self.fences.iter_mut().enumerate() {
+            let Some(fence) = fence else {
+                continue;
+            };
+            let inside = fence.shape.contains(position);
+            if fence.inside == Some(inside) {
+                fence.pending = 0;
+                continue;
+            }
+            fence.pending += 1;
+            if fence.pending < CONFIRM_FIXES {
+                continue;
+            }
+            fence.pending = 0;
+            if fence.inside.replace(inside).is_some() {
+                let _ = events.push(GeofenceEvent { id: id as u8, entered: inside, position });
+            }
+        }
+        events
+    }
+}
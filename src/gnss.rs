@@ -0,0 +1,90 @@
+//! Position fixes from a GNSS receiver streaming NMEA on USART1 at 9600 baud. Only GGA
+//! sentences are used, which every receiver sends by default.
+
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::UartRx;
+use heapless::Vec;
+
+const MAX_SENTENCE: usize = 82;
+
+/// In units of 10^-7 degrees, positive north and east.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Position {
+    pub latitude: i32,
+    pub longitude: i32,
+}
+
+pub struct Gnss<'d> {
+    uart: UartRx<'d, Async>,
+    /// Kept between calls, waiting for a fix is cancelled whenever something else is due.
+    sentence: Vec<u8, MAX_SENTENCE>,
+}
+impl<'d> Gnss<'d> {
+    pub fn new(uart: UartRx<'d, Async>) -> Self {
+        Self { uart, sentence: Vec::new() }
+    }
+
+    pub async fn next_fix(&mut self) -> Position {
+        let mut buf = [0; 64];
+        loop {
+            let len = match self.uart.read_until_idle(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    defmt::warn!("GNSS {:?}", e);
+                    self.sentence.clear();
+                    continue;
+                }
+            };
+            for byte in &buf[..len] {
+                if *byte == b'\n' {
+                    let fix = parse_gga(&self.sentence);
+                    self.sentence.clear();
+                    if let Some(fix) = fix {
+                        return fix;
+                    }
+                } else if self.sentence.push(*byte).is_err() {
+                    self.sentence.clear();
+                }
+            }
+        }
+    }
+}
+
+/// `$xxGGA,time,lat,N,lon,E,quality,...`, `None` without a fix.
+fn parse_gga(sentence: &[u8]) -> Option<Position> {
+    let sentence = core::str::from_utf8(sentence).ok()?.trim_end();
+    let mut fields = sentence.split(',');
+    if !fields.next()?.ends_with("GGA") {
+        return None;
+    }
+    let _time = fields.next()?;
+    let latitude = coordinate(fields.next()?, 2)?;
+    let latitude = match fields.next()? {
+        "N" => latitude,
+        "S" => -latitude,
+        _ => return None,
+    };
+    let longitude = coordinate(fields.next()?, 3)?;
+    let longitude = match fields.next()? {
+        "E" => longitude,
+        "W" => -longitude,
+        _ => return None,
+    };
+    let quality: u8 = fields.next()?.parse().ok()?;
+    (quality > 0).then_some(Position { latitude, longitude })
+}
+
+/// `dddmm.mmmm` to 10^-7 degrees.
+fn coordinate(field: &str, degree_digits: usize) -> Option<i32> {
+    let (whole, fraction) = field.split_once('.').unwrap_or((field, ""));
+    let degrees: i64 = whole.get(..degree_digits)?.parse().ok()?;
+    let minutes: i64 = whole.get(degree_digits..)?.parse().ok()?;
+    // minutes in 10^-5
+    let mut minutes = minutes * 100_000;
+    let mut scale = 10_000;
+    for digit in fraction.bytes().take(5) {
+        minutes += (digit.checked_sub(b'0').filter(|d| *d <= 9)? as i64) * scale;
+        scale /= 10;
+    }
+    Some((degrees * 10_000_000 + minutes * 100 / 60) as i32)
+}
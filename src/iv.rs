@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use embassy_stm32::interrupt;
 
 use embassy_stm32::interrupt::InterruptExt;
@@ -6,14 +8,65 @@ use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
 use embassy_stm32::pac;
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use embassy_time::{with_deadline, Duration, Instant, Timer};
 use embedded_hal::digital::OutputPin;
 use embedded_hal::spi::ErrorType;
 use embedded_hal::spi::Operation;
 use embedded_hal_async::spi::SpiBus;
 use embedded_hal_async::spi::SpiDevice;
+use heapless::Vec;
 use lora_phy::mod_params::RadioError;
 use lora_phy::mod_traits::InterfaceVariant;
+
+use crate::airtime;
+use crate::coding_rate;
+use crate::derating;
+use crate::dev_nonce;
+use crate::duty_cycle;
+use crate::energy::{self, RadioState};
+use crate::frames::{self, MAX_FRAME_SIZE};
+use crate::fsk;
+use crate::latency;
+use crate::lbt::{self, LbtConfig};
+use crate::pa_limits;
+use crate::radio_irq;
+use crate::radio_telemetry;
+use crate::readback;
+use crate::redundant;
+use crate::region;
+use crate::regulatory;
+use crate::rx_abort;
+use crate::rx_schedule;
+use crate::rx_stats;
+use crate::trace;
+use crate::uplink_edit;
+
+const CLEAR_IRQ_STATUS: u8 = 0x02;
+const SET_DIO_IRQ_PARAMS: u8 = 0x08;
+const WRITE_BUFFER: u8 = 0x0E;
+const GET_IRQ_STATUS: u8 = 0x12;
+const GET_PACKET_STATUS: u8 = 0x14;
+const GET_RSSI_INST: u8 = 0x15;
+const GET_DEVICE_ERRORS: u8 = 0x17;
+const CLEAR_DEVICE_ERRORS: u8 = 0x07;
+const READ_BUFFER: u8 = 0x1E;
+const SET_STANDBY: u8 = 0x80;
+const SET_RX: u8 = 0x82;
+const SET_TX: u8 = 0x83;
+const SET_RF_FREQUENCY: u8 = 0x86;
+const SET_PACKET_TYPE: u8 = 0x8A;
+const SET_MODULATION_PARAMS: u8 = 0x8B;
+const SET_STOP_RX_TIMER_ON_PREAMBLE: u8 = 0x9F;
+const SET_TX_PARAMS: u8 = 0x8E;
+const SET_PACKET_PARAMS: u8 = 0x8C;
+const SET_PA_CONFIG: u8 = 0x95;
+const CALIBRATE_IMAGE: u8 = 0x98;
+const GET_PACKET_TYPE: u8 = 0x11;
+const WRITE_REGISTER: u8 = 0x0D;
+const READ_REGISTER: u8 = 0x1D;
+const LORA_SYNC_WORD: [u8; 2] = [0x07, 0x40];
+const TX_DONE: u16 = 1 << 0;
+const RX_DONE: u16 = 1 << 1;
 pub struct InterruptHandler {}
 
 impl interrupt::typelevel::Handler<interrupt::typelevel::SUBGHZ_RADIO> for InterruptHandler {
@@ -24,6 +77,31 @@ impl interrupt::typelevel::Handler<interrupt::typelevel::SUBGHZ_RADIO> for Inter
 }
 
 static IRQ_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Longer than any single TX or RX window, continuous RX needs to wait in a loop.
+const IRQ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Set when a future waiting on the radio was dropped, the radio may still be in RX or TX.
+static ABANDONED: AtomicBool = AtomicBool::new(false);
+/// Cleared by a reset, which leaves the image calibrated for 902 to 928 MHz.
+static IMAGE_CALIBRATED: AtomicBool = AtomicBool::new(false);
+
+/// Has the image calibrated again before the next frequency is set, for another region.
+pub fn recalibrate_image() {
+    IMAGE_CALIBRATED.store(false, Ordering::Relaxed);
+}
+
+/// Makes the next radio command start by putting the radio in standby with its IRQs cleared.
+pub fn abandon() {
+    ABANDONED.store(true, Ordering::Relaxed);
+}
+
+/// Marks the wait as abandoned unless it completes.
+struct IrqWaitGuard;
+impl Drop for IrqWaitGuard {
+    fn drop(&mut self) {
+        interrupt::SUBGHZ_RADIO.disable();
+        abandon();
+    }
+}
 
 pub struct SubghzSpiDevice<T>(pub T);
 
@@ -36,13 +114,126 @@ impl<T: SpiBus> SpiDevice for SubghzSpiDevice<T> {
         &mut self,
         operations: &mut [Operation<'_, u8>],
     ) -> Result<(), Self::Error> {
+        if ABANDONED.swap(false, Ordering::Relaxed) {
+            defmt::warn!("radio operation abandoned, returning to standby");
+            self.command(&[SET_STANDBY, 0x00]).await?;
+            self.command(&[CLEAR_IRQ_STATUS, 0xFF, 0xFF]).await?;
+            IRQ_SIGNAL.reset();
+            energy::with_meter(|meter| meter.set_state(RadioState::Idle));
+            trace::radio(RadioState::Idle);
+            readback::request();
+        }
+        if radio_telemetry::errors_due() {
+            let mut errors = [0; 3];
+            self.query(&[GET_DEVICE_ERRORS], &mut errors).await?;
+            self.command(&[CLEAR_DEVICE_ERRORS, 0x00, 0x00]).await?;
+            let end_of_life = pac::PWR.sr2().read().rfeolf();
+            let errors = u16::from_be_bytes([errors[1], errors[2]]);
+            radio_telemetry::device_errors(errors, end_of_life);
+            pa_limits::device_errors(errors);
+        }
+        if let [Operation::Write([WRITE_BUFFER, ..]), Operation::Write(_)] = operations {
+            if let Some(config) = lbt::config() {
+                self.listen_before_talk(&config).await?;
+            }
+        }
+        if let [Operation::Write([WRITE_BUFFER, offset]), Operation::Write(frame)] = operations {
+            let len = frame.len();
+            if let Some(frame) = uplink_edit::edit(frame) {
+                let mut command: Vec<u8, { MAX_FRAME_SIZE + 2 }> = Vec::new();
+                let _ = command.extend_from_slice(&[WRITE_BUFFER, *offset]);
+                let _ = command.extend_from_slice(&frame);
+                self.command(&command).await?;
+                if frame.len() != len {
+                    if let Some(command) = uplink_edit::packet_params(frame.len()) {
+                        observe(&command);
+                        self.command(&command).await?;
+                    }
+                }
+                frames::uplink(&frame);
+                redundant::uplink(&frame);
+                return Ok(());
+            }
+        }
+        if let [Operation::Write([SET_RF_FREQUENCY, ..])] = operations {
+            if !IMAGE_CALIBRATED.swap(true, Ordering::Relaxed) {
+                let [f1, f2] = region::current().image_calibration();
+                self.command(&[CALIBRATE_IMAGE, f1, f2]).await?;
+            }
+        }
+        if let [Operation::Write([SET_RX, ..])] = operations {
+            if readback::due() {
+                self.verify_config().await?;
+            }
+            if rx_abort::enabled() {
+                self.command(&[SET_STOP_RX_TIMER_ON_PREAMBLE, 0x01]).await?;
+            }
+        }
+        if let [Operation::Write(command)] = operations {
+            if let Some(commands) = fsk::translate(command) {
+                for command in &commands {
+                    observe(command);
+                    self.command(command).await?;
+                }
+                return Ok(());
+            }
+        }
         pac::PWR.subghzspicr().modify(|w| w.set_nss(false));
 
         let op_res = 'ops: {
-            for op in operations {
+            for op in operations.iter_mut() {
                 let res = match op {
                     Operation::Read(buf) => self.0.read(buf).await,
-                    Operation::Write(buf) => self.0.write(buf).await,
+                    Operation::Write([SET_TX_PARAMS, power, ramp]) => {
+                        let power = derating::limit(*power as i8);
+                        let power = regulatory::limit_tx_power(pa_limits::limit(power));
+                        energy::with_meter(|meter| meter.set_tx_power(power));
+                        airtime::set_tx_power(power);
+                        self.0.write(&[SET_TX_PARAMS, power as u8, *ramp]).await
+                    }
+                    Operation::Write([CALIBRATE_IMAGE, ..]) => {
+                        let [f1, f2] = region::current().image_calibration();
+                        self.0.write(&[CALIBRATE_IMAGE, f1, f2]).await
+                    }
+                    Operation::Write([SET_PA_CONFIG, duty_cycle, hp_max, device_sel, lut]) => {
+                        // deviceSel 0 is the high power PA
+                        pa_limits::set_high_power_pa(*device_sel == 0);
+                        let [duty_cycle, hp_max] = region::current()
+                            .pa_config(*device_sel == 0)
+                            .unwrap_or([*duty_cycle, *hp_max]);
+                        self.0.write(&[SET_PA_CONFIG, duty_cycle, hp_max, *device_sel, *lut]).await
+                    }
+                    Operation::Write([SET_MODULATION_PARAMS, params @ ..])
+                        if (3..=8).contains(&params.len())
+                            && coding_rate::held(params[2]) != params[2] =>
+                    {
+                        defmt::warn!("coding rate {=u8} of the MAC held to 4/5", params[2]);
+                        let mut buf = [0; 9];
+                        let command = &mut buf[..params.len() + 1];
+                        command[0] = SET_MODULATION_PARAMS;
+                        command[1..].copy_from_slice(params);
+                        command[3] = coding_rate::held(params[2]);
+                        observe(command);
+                        self.0.write(command).await
+                    }
+                    Operation::Write([SET_RX, a, b, c]) => {
+                        let requested = u32::from_be_bytes([0, *a, *b, *c]);
+                        // the more reliable window keeps the full window the MAC asked for
+                        rx_schedule::window_opened();
+                        let window = rx_stats::window_opened();
+                        let timeout = if window.is_some() && window == rx_stats::preferred_window()
+                        {
+                            requested
+                        } else {
+                            rx_abort::rx_timeout(requested)
+                        };
+                        let [_, a, b, c] = timeout.to_be_bytes();
+                        self.0.write(&[SET_RX, a, b, c]).await
+                    }
+                    Operation::Write(buf) => {
+                        observe(buf);
+                        self.0.write(buf).await
+                    }
                     Operation::Transfer(read, write) => self.0.transfer(read, write).await,
                     Operation::TransferInPlace(buf) => self.0.transfer_in_place(buf).await,
                     Operation::DelayNs(ns) => match self.0.flush().await {
@@ -68,6 +259,153 @@ impl<T: SpiBus> SpiDevice for SubghzSpiDevice<T> {
         op_res?;
         flush_res?;
 
+        match operations {
+            [Operation::Write([WRITE_BUFFER, ..]), Operation::Write(frame)] => {
+                frames::uplink(frame);
+                redundant::uplink(frame);
+                dev_nonce::uplink(frame);
+            }
+            [Operation::Write([READ_BUFFER, ..]), Operation::Read(frame)] => {
+                if frames::downlink(frame) {
+                    rx_stats::received();
+                }
+            }
+            [Operation::Write([WRITE_REGISTER, 0x07, 0x40]), Operation::Write([a, b])] => {
+                readback::sync_word(&[WRITE_REGISTER, 0x07, 0x40, *a, *b])
+            }
+            [Operation::Write([GET_IRQ_STATUS, ..]), .., Operation::Read([hi, lo])] => {
+                let status = u16::from_be_bytes([*hi, *lo]);
+                radio_irq::status(status);
+                airtime::irq_status(status);
+                if status & RX_DONE != 0 {
+                    latency::rx_done();
+                }
+                if status & TX_DONE != 0 {
+                    radio_telemetry::tx_done();
+                }
+            }
+            [Operation::Write([GET_PACKET_STATUS, ..]), Operation::Read([_, snr, ..])] => {
+                rx_stats::packet_status(*snr as i8)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the radio configuration from a command on its way to the radio.
+fn observe(command: &[u8]) {
+    match *command {
+        [SET_RF_FREQUENCY, a, b, c, d] => {
+            regulatory::set_frequency([a, b, c, d]);
+            readback::frequency(command);
+        }
+        [SET_TX, ..] => {
+            airtime::tx_started(regulatory::frequency());
+            rx_stats::transmitting();
+            rx_schedule::transmitting();
+            redundant::transmitting();
+        }
+        [SET_DIO_IRQ_PARAMS, hi, lo, ..] => radio_irq::set_enabled(u16::from_be_bytes([hi, lo])),
+        [SET_PACKET_TYPE, packet_type] => {
+            rx_abort::set_packet_type(packet_type);
+            duty_cycle::set_packet_type(packet_type);
+            readback::packet_type(command);
+        }
+        // the bit rate for GFSK, which rx_abort ignores outside of LoRa
+        [SET_MODULATION_PARAMS, spreading_factor, bandwidth, coding_rate, ..] => {
+            rx_abort::set_modulation(spreading_factor, bandwidth);
+            pa_limits::set_spreading_factor(spreading_factor);
+            redundant::set_modulation(spreading_factor, bandwidth, coding_rate);
+            duty_cycle::set_modulation(spreading_factor, bandwidth);
+            readback::modulation(command);
+        }
+        [SET_PACKET_PARAMS, ..] => {
+            duty_cycle::set_packet(command);
+            uplink_edit::set_packet(command);
+            readback::packet(command);
+        }
+        [WRITE_REGISTER, 0x07, 0x40, _, _] => readback::sync_word(command),
+        _ => {}
+    }
+}
+
+impl<T: SpiBus> SubghzSpiDevice<T> {
+    /// Sends a command of our own in between the ones issued by the driver.
+    async fn command(&mut self, command: &[u8]) -> Result<(), T::Error> {
+        while pac::PWR.sr2().read().rfbusys() {}
+        pac::PWR.subghzspicr().modify(|w| w.set_nss(false));
+        let res = self.0.write(command).await;
+        let flush_res = self.0.flush().await;
+        pac::PWR.subghzspicr().modify(|w| w.set_nss(true));
+        while pac::PWR.sr2().read().rfbusys() {}
+        res?;
+        flush_res
+    }
+
+    /// Sends a command of our own and reads the status followed by the response.
+    async fn query(&mut self, command: &[u8], response: &mut [u8]) -> Result<(), T::Error> {
+        while pac::PWR.sr2().read().rfbusys() {}
+        pac::PWR.subghzspicr().modify(|w| w.set_nss(false));
+        let res = match self.0.write(command).await {
+            Ok(()) => self.0.read(response).await,
+            Err(e) => Err(e),
+        };
+        let flush_res = self.0.flush().await;
+        pac::PWR.subghzspicr().modify(|w| w.set_nss(true));
+        while pac::PWR.sr2().read().rfbusys() {}
+        res?;
+        flush_res
+    }
+
+    /// Senses the channel the radio is tuned to until it is clear or the attempts run out,
+    /// before the uplink is written and the RF switch goes to TX.
+    async fn listen_before_talk(&mut self, config: &LbtConfig) -> Result<(), T::Error> {
+        let mut rssi = i16::MIN;
+        for attempt in 0..config.max_attempts.max(1) {
+            if attempt > 0 {
+                Timer::after(config.backoff * attempt as u32).await;
+            }
+            self.command(&[SET_RX, 0xFF, 0xFF, 0xFF]).await?;
+            let deadline = Instant::now() + config.sense_time;
+            rssi = i16::MIN;
+            while Instant::now() < deadline && rssi < config.threshold {
+                let mut response = [0; 2];
+                self.query(&[GET_RSSI_INST], &mut response).await?;
+                rssi = rssi.max(-(response[1] as i16) / 2);
+                Timer::after_micros(500).await;
+            }
+            self.command(&[SET_STANDBY, 0x00]).await?;
+            self.command(&[CLEAR_IRQ_STATUS, 0xFF, 0xFF]).await?;
+            if rssi < config.threshold {
+                lbt::sensed(false, rssi);
+                return Ok(());
+            }
+        }
+        lbt::sensed(true, rssi);
+        Ok(())
+    }
+
+    /// Reads back what [`readback`] can check and sends the configuration again if off.
+    async fn verify_config(&mut self) -> Result<(), T::Error> {
+        let (packet_type, sync_word) = readback::expected();
+        let mut actual_packet_type = [0; 2];
+        self.query(&[GET_PACKET_TYPE], &mut actual_packet_type).await?;
+        let mut actual_sync_word = [0; 3];
+        let [hi, lo] = LORA_SYNC_WORD;
+        self.query(&[READ_REGISTER, hi, lo], &mut actual_sync_word).await?;
+        let matches = packet_type.is_none_or(|expected| expected == actual_packet_type[1])
+            && sync_word.is_none_or(|expected| expected == actual_sync_word[1..]);
+        if let Some(commands) = readback::verified(matches) {
+            defmt::error!(
+                "radio configuration lost, packet type {:#x} sync word {:#x}",
+                actual_packet_type[1],
+                &actual_sync_word[1..]
+            );
+            for command in commands.iter().flatten() {
+                self.command(command).await?;
+            }
+        }
         Ok(())
     }
 }
@@ -103,12 +441,34 @@ where
     }
 
     async fn await_irq(&mut self) -> Result<(), RadioError> {
-        unsafe { interrupt::SUBGHZ_RADIO.enable() };
-        IRQ_SIGNAL.wait().await;
+        let guard = IrqWaitGuard;
+        let deadline = Instant::now() + IRQ_TIMEOUT;
+        loop {
+            unsafe { interrupt::SUBGHZ_RADIO.enable() };
+            if with_deadline(deadline, IRQ_SIGNAL.wait()).await.is_err() {
+                defmt::error!("no radio IRQ within {}ms", IRQ_TIMEOUT.as_millis());
+                if radio_irq::timeout() {
+                    defmt::error!("radio wedged, resetting");
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                // the guard puts the radio back in standby
+                return Err(RadioError::Irq);
+            }
+            // the IRQ line is level triggered, with the interrupt disabled by the handler
+            // it is pending again only if the radio still asserts it
+            if interrupt::SUBGHZ_RADIO.is_pending() {
+                break;
+            }
+            radio_irq::spurious();
+        }
+        core::mem::forget(guard);
+        radio_irq::irq();
         Ok(())
     }
 
     async fn enable_rf_switch_rx(&mut self) -> Result<(), RadioError> {
+        energy::with_meter(|meter| meter.set_state(RadioState::Rx));
+        trace::radio(RadioState::Rx);
         if let Some(pin) = &mut self.rf_switch_tx {
             pin.set_low().map_err(|_| RadioError::RfSwitchRx)?
         }
@@ -118,6 +478,15 @@ where
         Ok(())
     }
     async fn enable_rf_switch_tx(&mut self) -> Result<(), RadioError> {
+        if !regulatory::permit_tx()
+            || !duty_cycle::permit_tx()
+            || !lbt::permit_tx()
+            || !dev_nonce::permit_tx()
+        {
+            return Err(RadioError::RfSwitchTx);
+        }
+        energy::with_meter(|meter| meter.set_state(RadioState::Tx));
+        trace::radio(RadioState::Tx);
         if let Some(pin) = &mut self.rf_switch_rx {
             pin.set_low().map_err(|_| RadioError::RfSwitchRx)?
         }
@@ -127,6 +496,8 @@ where
         Ok(())
     }
     async fn disable_rf_switch(&mut self) -> Result<(), RadioError> {
+        energy::with_meter(|meter| meter.set_state(RadioState::Idle));
+        trace::radio(RadioState::Idle);
         if let Some(pin) = &mut self.rf_switch_rx {
             pin.set_low().map_err(|_| RadioError::RfSwitchRx)?
         }
@@ -139,6 +510,7 @@ where
     async fn reset(&mut self, _delay: &mut impl lora_phy::DelayNs) -> Result<(), RadioError> {
         pac::RCC.csr().modify(|w| w.set_rfrst(true));
         pac::RCC.csr().modify(|w| w.set_rfrst(false));
+        IMAGE_CALIBRATED.store(false, Ordering::Relaxed);
         Ok(())
     }
 }
@@ -0,0 +1,27 @@
+//! How a device without a session looks for the network. Without a clock or anything
+//! persisted there is no telling how far the gateway is, so the first attempts go out at
+//! a mid data rate that is cheap in airtime and the following ones step down towards the
+//! slowest, longest range data rate, where the device stays until it joins.
+//!
+//! Channels are not rotated here: in EU868 the MAC draws every join request from the
+//! three mandatory channels at random, which spreads attempts over all of them.
+
+use embassy_time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct JoinStrategy {
+    pub first_data_rate: u8,
+    /// The slowest data rate tried, lower is slower.
+    pub last_data_rate: u8,
+    pub attempts_per_data_rate: u32,
+    /// Pause after a failed attempt.
+    pub retry_after: Duration,
+}
+impl JoinStrategy {
+    /// Data rate for the join after `failures` failed ones.
+    pub fn data_rate(&self, failures: u32) -> u8 {
+        let steps = failures / self.attempts_per_data_rate.max(1);
+        let steps = steps.min(self.first_data_rate.saturating_sub(self.last_data_rate) as u32);
+        self.first_data_rate - steps as u8
+    }
+}
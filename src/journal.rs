@@ -0,0 +1,267 @@
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+
+pub const RECORD_SIZE: usize = 32;
+pub const MAX_VALUE_SIZE: usize = RECORD_SIZE - 4;
+const ERASED: u8 = 0xFF;
+const HEADER_KEY: u8 = 0xFE;
+const MAX_KEYS: usize = 48;
+
+/// Identifies a value in the journal, values are replaced by writing the same key again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum RecordKey {
+    BootStats = 0x01,
+    EnergyUsed = 0x02,
+    RadioLastGood = 0x03,
+    RadioCandidate = 0x04,
+    Settings = 0x05,
+    MinSecurityVersion = 0x06,
+    Geofence0 = 0x07,
+    Geofence1 = 0x08,
+    Geofence2 = 0x09,
+    Geofence3 = 0x0A,
+    PinMap = 0x0B,
+    E2eKeyLow = 0x0C,
+    E2eKeyHigh = 0x0D,
+    E2eCounter = 0x0E,
+    CfList = 0x0F,
+    // 0x10 held the AU915 sub-band, now kept with the session
+    LogFilter = 0x11,
+    Region = 0x12,
+    RegionScan = 0x13,
+    PendingUplink = 0x14,
+    PaBlacklist = 0x15,
+    Multicast0 = 0x16,
+    Multicast1 = 0x17,
+    Multicast2 = 0x18,
+    Multicast3 = 0x19,
+    Commissioned = 0x1A,
+    StagedImage = 0x1B,
+    CloneSeed = 0x1C,
+    LinkTimes = 0x1D,
+    CrashLoop = 0x1E,
+    // 0x1F held the frame counters of an ABP session
+    Onboarded = 0x20,
+    Adr = 0x21,
+    NwkSKey = 0x22,
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum JournalError<E> {
+    Flash(E),
+    NotFound,
+    TooLarge,
+}
+
+/// Append only key/value log spread over two flash pages, which need not be adjacent.
+///
+/// Records are appended to the active page until it is full, at which point the latest
+/// record of every key is copied to the other page. Only once everything is copied the
+/// new page gets a header with a higher sequence number and the old page is erased, so
+/// an interrupted compaction leaves the old page in use.
+pub struct Journal {
+    pages: [u32; 2],
+    page_size: u32,
+    mounted: bool,
+    active: u32,
+    seq: u32,
+    next: u32,
+}
+impl Journal {
+    pub const fn new(pages: [u32; 2], page_size: u32) -> Self {
+        Self { pages, page_size, mounted: false, active: 0, seq: 0, next: 0 }
+    }
+
+    pub fn read<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: RecordKey,
+        buf: &mut [u8],
+    ) -> Result<usize, JournalError<F::Error>> {
+        self.mount(flash)?;
+        let record = self.find(flash, self.active, key as u8)?.ok_or(JournalError::NotFound)?;
+        let len = (record[1] as usize).min(buf.len());
+        buf[..len].copy_from_slice(&record[2..2 + len]);
+        Ok(len)
+    }
+
+    pub fn write<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: RecordKey,
+        data: &[u8],
+    ) -> Result<(), JournalError<F::Error>> {
+        if data.len() > MAX_VALUE_SIZE {
+            return Err(JournalError::TooLarge);
+        }
+        self.mount(flash)?;
+        if self.next + RECORD_SIZE as u32 > self.page_size {
+            self.compact(flash)?;
+        }
+        self.append(flash, &encode(key as u8, data))
+    }
+
+    fn page_offset(&self, page: u32) -> u32 {
+        self.pages[page as usize]
+    }
+
+    fn read_slot<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+        slot: u32,
+    ) -> Result<[u8; RECORD_SIZE], JournalError<F::Error>> {
+        let mut record = [0; RECORD_SIZE];
+        flash
+            .read(self.page_offset(page) + slot * RECORD_SIZE as u32, &mut record)
+            .map_err(JournalError::Flash)?;
+        Ok(record)
+    }
+
+    fn header_seq<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+    ) -> Result<Option<u32>, JournalError<F::Error>> {
+        let header = self.read_slot(flash, page, 0)?;
+        Ok((header[0] == HEADER_KEY && is_valid(&header))
+            .then(|| u32::from_le_bytes([header[2], header[3], header[4], header[5]])))
+    }
+
+    fn mount<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), JournalError<F::Error>> {
+        if self.mounted {
+            return Ok(());
+        }
+        let (active, seq) = match (self.header_seq(flash, 0)?, self.header_seq(flash, 1)?) {
+            (Some(a), Some(b)) if b > a => (1, b),
+            (Some(a), _) => (0, a),
+            (None, Some(b)) => (1, b),
+            (None, None) => {
+                self.erase_page(flash, 0)?;
+                self.write_header(flash, 0, 0)?;
+                (0, 0)
+            }
+        };
+        self.active = active;
+        self.seq = seq;
+        self.next = RECORD_SIZE as u32;
+        while self.next + RECORD_SIZE as u32 <= self.page_size
+            && self.read_slot(flash, active, self.next / RECORD_SIZE as u32)?[0] != ERASED
+        {
+            self.next += RECORD_SIZE as u32;
+        }
+        self.mounted = true;
+        Ok(())
+    }
+
+    fn erase_page<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+    ) -> Result<(), JournalError<F::Error>> {
+        let offset = self.page_offset(page);
+        flash.erase(offset, offset + self.page_size).map_err(JournalError::Flash)
+    }
+
+    fn write_header<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+        seq: u32,
+    ) -> Result<(), JournalError<F::Error>> {
+        let header = encode(HEADER_KEY, &seq.to_le_bytes());
+        flash.write(self.page_offset(page), &header).map_err(JournalError::Flash)
+    }
+
+    fn find<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+        key: u8,
+    ) -> Result<Option<[u8; RECORD_SIZE]>, JournalError<F::Error>> {
+        let mut found = None;
+        for slot in 1..self.page_size / RECORD_SIZE as u32 {
+            let record = self.read_slot(flash, page, slot)?;
+            if record[0] == ERASED {
+                break;
+            }
+            if record[0] == key && is_valid(&record) {
+                found = Some(record);
+            }
+        }
+        Ok(found)
+    }
+
+    fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        record: &[u8; RECORD_SIZE],
+    ) -> Result<(), JournalError<F::Error>> {
+        flash
+            .write(self.page_offset(self.active) + self.next, record)
+            .map_err(JournalError::Flash)?;
+        self.next += RECORD_SIZE as u32;
+        Ok(())
+    }
+
+    fn compact<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), JournalError<F::Error>> {
+        let old = self.active;
+        let new = 1 - old;
+        self.erase_page(flash, new)?;
+        let mut copied: Vec<u8, MAX_KEYS> = Vec::new();
+        let mut next = RECORD_SIZE as u32;
+        for slot in 1..self.page_size / RECORD_SIZE as u32 {
+            let key = self.read_slot(flash, old, slot)?[0];
+            if key == ERASED {
+                break;
+            }
+            if copied.contains(&key) {
+                continue;
+            }
+            if let Some(record) = self.find(flash, old, key)? {
+                flash.write(self.page_offset(new) + next, &record).map_err(JournalError::Flash)?;
+                next += RECORD_SIZE as u32;
+            }
+            let _ = copied.push(key);
+        }
+        let seq = self.seq.wrapping_add(1);
+        self.write_header(flash, new, seq)?;
+        // appends go to the old page until the new one is complete
+        self.active = new;
+        self.seq = seq;
+        self.next = next;
+        self.erase_page(flash, old)
+    }
+}
+
+fn encode(key: u8, data: &[u8]) -> [u8; RECORD_SIZE] {
+    let mut record = [ERASED; RECORD_SIZE];
+    record[0] = key;
+    record[1] = data.len() as u8;
+    record[2..2 + data.len()].copy_from_slice(data);
+    let crc = crc16(&record[..RECORD_SIZE - 2]);
+    record[RECORD_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+fn is_valid(record: &[u8; RECORD_SIZE]) -> bool {
+    record[1] as usize <= MAX_VALUE_SIZE
+        && crc16(&record[..RECORD_SIZE - 2]).to_le_bytes() == record[RECORD_SIZE - 2..]
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
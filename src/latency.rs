@@ -0,0 +1,89 @@
+//! Time from a downlink leaving the radio to the application acting on it, for actuators
+//! with a latency budget. The radio's RxDone is taken as the start and the handler in
+//! `main` marks the end; latencies are kept in a histogram to give percentiles, and fed
+//! to the alarms as [`Measurement::DownlinkLatency`](crate::sensor::Measurement) so that
+//! exceeding the budget raises an alarm like any other threshold.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets in milliseconds, the last one catches the rest.
+const BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct LatencyStats {
+    pub count: u32,
+    /// bucket bounds in milliseconds, `u32::MAX` past the last one
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    /// milliseconds
+    pub max: u32,
+}
+
+struct Latency {
+    rx_done: Option<Instant>,
+    counts: [u32; BUCKETS_MS.len() + 1],
+    max: Duration,
+}
+impl Latency {
+    fn percentile(&self, percent: u32) -> u32 {
+        let total: u32 = self.counts.iter().sum();
+        let wanted = (total as u64 * percent as u64).div_ceil(100);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= wanted {
+                return BUCKETS_MS.get(i).map_or(u32::MAX, |bound| *bound as u32);
+            }
+        }
+        u32::MAX
+    }
+}
+
+static LATENCY: Mutex<CriticalSectionRawMutex, RefCell<Latency>> =
+    Mutex::new(RefCell::new(Latency {
+        rx_done: None,
+        counts: [0; BUCKETS_MS.len() + 1],
+        max: Duration::from_ticks(0),
+    }));
+
+fn with_latency<R>(f: impl FnOnce(&mut Latency) -> R) -> R {
+    LATENCY.lock(|latency| f(&mut latency.borrow_mut()))
+}
+
+/// From the IRQ status, a downlink was received.
+pub fn rx_done() {
+    with_latency(|latency| latency.rx_done = Some(Instant::now()));
+}
+
+/// The application is acting on the last downlink received, returning how long that
+/// took. `None` if it was already handled.
+pub fn handled() -> Option<Duration> {
+    with_latency(|latency| {
+        let elapsed = latency.rx_done.take()?.elapsed();
+        let ms = elapsed.as_millis();
+        let bucket = BUCKETS_MS.iter().position(|bound| ms <= *bound).unwrap_or(BUCKETS_MS.len());
+        latency.counts[bucket] += 1;
+        latency.max = latency.max.max(elapsed);
+        Some(elapsed)
+    })
+}
+
+pub fn stats() -> LatencyStats {
+    with_latency(|latency| {
+        if latency.counts.iter().all(|count| *count == 0) {
+            return LatencyStats::default();
+        }
+        LatencyStats {
+            count: latency.counts.iter().sum(),
+            p50: latency.percentile(50),
+            p90: latency.percentile(90),
+            p99: latency.percentile(99),
+            max: latency.max.as_millis() as u32,
+        }
+    })
+}
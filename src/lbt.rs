@@ -0,0 +1,55 @@
+//! Listen before talk, as KR920 requires: before an uplink is written to the radio the
+//! channel it is tuned to is sensed in RX, and the transmission only goes ahead once the
+//! signal stays below the threshold for the whole sensing time. A channel still busy
+//! after the last attempt fails the transmission like any other refused TX.
+//!
+//! Sensing is done in [`crate::iv::SubghzSpiDevice`] with the RF switch still on RX.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LbtConfig {
+    /// dBm, the channel is busy at or above it.
+    pub threshold: i16,
+    pub sense_time: Duration,
+    pub max_attempts: u8,
+    /// Wait before sensing again, multiplied by the attempt.
+    pub backoff: Duration,
+}
+
+static CONFIG: Mutex<CriticalSectionRawMutex, Cell<Option<LbtConfig>>> =
+    Mutex::new(Cell::new(None));
+static BUSY: AtomicBool = AtomicBool::new(false);
+static BLOCKED: AtomicU32 = AtomicU32::new(0);
+
+pub fn configure(config: Option<LbtConfig>) {
+    CONFIG.lock(|cell| cell.set(config));
+}
+
+pub fn config() -> Option<LbtConfig> {
+    CONFIG.lock(|cell| cell.get())
+}
+
+/// Result of sensing for the uplink about to be sent.
+pub fn sensed(busy: bool, rssi: i16) {
+    if busy {
+        defmt::warn!("channel busy at {} dBm, uplink dropped", rssi);
+        BLOCKED.fetch_add(1, Ordering::Relaxed);
+    }
+    BUSY.store(busy, Ordering::Relaxed);
+}
+
+/// Whether the transmission sensed for may go ahead.
+pub fn permit_tx() -> bool {
+    !BUSY.swap(false, Ordering::Relaxed)
+}
+
+/// Uplinks not sent for a busy channel since boot.
+pub fn blocked() -> u32 {
+    BLOCKED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,99 @@
+//! Watches the uplinks for signs of the ADR backoff: ADRACKReq set by [`crate::adr`] after
+//! too many uplinks without a downlink, followed by the data rate being lowered step by
+//! step.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::frames::FrameId;
+use crate::schema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LinkEvent {
+    AdrAckReqSet,
+    AdrAckReqCleared,
+    /// The backoff lowered the data rate, coverage got worse.
+    DataRateLowered {
+        from: u8,
+        to: u8,
+    },
+}
+
+struct LinkMonitor {
+    adr_ack_req: bool,
+    data_rate: Option<u8>,
+    backoff: bool,
+    probe_requested: bool,
+    flush_requested: bool,
+}
+
+static LINK: Mutex<CriticalSectionRawMutex, RefCell<LinkMonitor>> =
+    Mutex::new(RefCell::new(LinkMonitor {
+        adr_ack_req: false,
+        data_rate: None,
+        backoff: false,
+        probe_requested: false,
+        flush_requested: false,
+    }));
+
+fn with_link<R>(f: impl FnOnce(&mut LinkMonitor) -> R) -> R {
+    LINK.lock(|link| f(&mut link.borrow_mut()))
+}
+
+/// Feeds the last uplink and the data rate for the next one.
+pub fn update(uplink: Option<FrameId>, data_rate: u8) -> Option<LinkEvent> {
+    with_link(|link| {
+        let previous = link.data_rate.replace(data_rate);
+        let adr_ack_req = uplink.is_some_and(|id| id.adr_ack_req());
+        if adr_ack_req != link.adr_ack_req {
+            link.adr_ack_req = adr_ack_req;
+            link.backoff &= adr_ack_req;
+            return Some(if adr_ack_req {
+                LinkEvent::AdrAckReqSet
+            } else {
+                LinkEvent::AdrAckReqCleared
+            });
+        }
+        match previous {
+            Some(from) if link.adr_ack_req && data_rate < from => {
+                link.backoff = true;
+                Some(LinkEvent::DataRateLowered { from, to: data_rate })
+            }
+            _ => None,
+        }
+    })
+}
+
+pub fn adr_ack_req() -> bool {
+    with_link(|link| link.adr_ack_req)
+}
+
+/// Whether the ADR backoff has lowered the data rate and no downlink confirmed the link since.
+pub fn backoff_active() -> bool {
+    with_link(|link| link.backoff)
+}
+
+/// Asks for a confirmed uplink to check the link without waiting for the backoff.
+pub fn request_probe() {
+    with_link(|link| link.probe_requested = true);
+}
+
+pub fn take_probe() -> bool {
+    with_link(|link| core::mem::take(&mut link.probe_requested))
+}
+
+/// Port of the empty uplinks sent in place of those without FPort or payload, which the
+/// lorawan crate can't send. Reserved, applications can't use it.
+pub const POLL_PORT: u8 = schema::POLL.port;
+
+/// Asks for an empty uplink on [`POLL_PORT`], carrying only the MAC command answers and
+/// giving the network a receive window.
+pub fn request_flush() {
+    with_link(|link| link.flush_requested = true);
+}
+
+pub fn take_flush() -> bool {
+    with_link(|link| core::mem::take(&mut link.flush_requested))
+}
@@ -0,0 +1,16 @@
+//! The board's say in LinkADRAns: a LinkADRReq asking for more TX power than the PA does
+//! gets the power bit NACKed, which per LoRaWAN rejects the whole command, so the network
+//! can't push the device into a PA configuration it doesn't support. Also included by the
+//! network server emulator tests in `tools/`; keep this file free of crate dependencies.
+
+pub const POWER_ACK: u8 = 0b100;
+
+/// The status bits to clear in the LinkADRAns to a LinkADRReq for `tx_power`, in dBm or
+/// `None` to keep the current one, the power bit if it is above `supported`.
+pub fn nack(tx_power: Option<i8>, supported: i8) -> u8 {
+    if tx_power.is_some_and(|tx_power| tx_power > supported) {
+        POWER_ACK
+    } else {
+        0
+    }
+}
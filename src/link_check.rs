@@ -0,0 +1,56 @@
+//! LinkCheckReq on behalf of application tasks: [`check`] has the main loop send the MAC
+//! command right away on an uplink of its own and returns the answer, how many gateways
+//! received the uplink and with how much margin. The lorawan MAC has no request for it,
+//! [`crate::uplink_edit`] appends it to FOpts. The MAC hands the answer to
+//! [`Device::handle_link_check`](lorawan::device::Device::handle_link_check), which
+//! passes it on here.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+
+use crate::packet_queue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkCheckAns {
+    /// dB above the demodulation floor of the best gateway.
+    pub margin: u8,
+    pub gateway_count: u8,
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static ANSWER: Mutex<CriticalSectionRawMutex, Cell<Option<LinkCheckAns>>> =
+    Mutex::new(Cell::new(None));
+static RESOLVED: Signal<CriticalSectionRawMutex, Option<LinkCheckAns>> = Signal::new();
+/// One check at a time, later callers wait for their own.
+static CHECK: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+/// Checks the link, `None` if the uplink went unanswered.
+pub async fn check() -> Option<LinkCheckAns> {
+    let _check = CHECK.lock().await;
+    RESOLVED.reset();
+    REQUESTED.store(true, Ordering::Relaxed);
+    packet_queue::wake();
+    RESOLVED.wait().await
+}
+
+/// Whether a check was asked for, the LinkCheckReq goes out with the next uplink.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Called by the MAC with the LinkCheckAns of the last uplink.
+pub fn answered(answer: LinkCheckAns) {
+    ANSWER.lock(|last| last.set(Some(answer)));
+}
+
+/// After the uplink carrying the LinkCheckReq, answered or not.
+pub fn sent() {
+    let answer = ANSWER.lock(|last| last.take());
+    defmt::info!("link check {:?}", answer);
+    RESOLVED.signal(answer);
+}
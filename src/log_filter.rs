@@ -0,0 +1,148 @@
+//! Runtime filter for the chattier logs, to debug a single unit in the field without
+//! every device flooding RTT. A downlink on [`LOG_FILTER_PORT`] as `[module mask]
+//! [level][minutes u16]`, big endian, lets the modules in the mask log down to the level
+//! for that long, persisted over resets. An empty downlink ends it early.
+//!
+//! Only logs going through [`log!`](crate::log) are filtered, warnings and errors are
+//! always logged.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+use crate::schema;
+
+pub const LOG_FILTER_PORT: u8 = schema::LOG_FILTER.port;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+#[repr(u8)]
+pub enum Level {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Module {
+    App = 1 << 0,
+    Frames = 1 << 1,
+    Radio = 1 << 2,
+    Sensors = 1 << 3,
+}
+
+/// Everything at info, as built.
+const DEFAULT: (u8, Level) = (0xFF, Level::Info);
+
+static MASK: AtomicU8 = AtomicU8::new(DEFAULT.0);
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT.1 as u8);
+static EXPIRES: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+
+/// Logs at `trace`, `debug` or `info` if the filter lets `$module` through.
+#[macro_export]
+macro_rules! log {
+    (trace, $module:expr, $($arg:tt)+) => {
+        if $crate::log_filter::enabled($module, $crate::log_filter::Level::Trace) {
+            defmt::trace!($($arg)+)
+        }
+    };
+    (debug, $module:expr, $($arg:tt)+) => {
+        if $crate::log_filter::enabled($module, $crate::log_filter::Level::Debug) {
+            defmt::debug!($($arg)+)
+        }
+    };
+    (info, $module:expr, $($arg:tt)+) => {
+        if $crate::log_filter::enabled($module, $crate::log_filter::Level::Info) {
+            defmt::info!($($arg)+)
+        }
+    };
+}
+
+pub fn enabled(module: Module, level: Level) -> bool {
+    let (mask, min) = (MASK.load(Ordering::Relaxed), LEVEL.load(Ordering::Relaxed));
+    let min = if mask & module as u8 != 0 {
+        min
+    } else {
+        DEFAULT.1 as u8
+    };
+    level as u8 >= min
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum LogFilterError {
+    Invalid,
+    Store(NonVolatileStoreError),
+}
+
+fn apply(mask: u8, level: Level, duration: Option<Duration>) {
+    MASK.store(mask, Ordering::Relaxed);
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    EXPIRES.lock(|expires| expires.set(duration.map(|duration| Instant::now() + duration)));
+}
+
+fn decode(data: &[u8]) -> Option<(u8, Level, Duration)> {
+    let [mask, level, hi, lo] = *data else {
+        return None;
+    };
+    let level = match level {
+        0 => Level::Trace,
+        1 => Level::Debug,
+        2 => Level::Info,
+        _ => return None,
+    };
+    let minutes = u16::from_be_bytes([hi, lo]);
+    Some((mask, level, Duration::from_secs(minutes as u64 * 60)))
+}
+
+/// Picks up a filter set before the reset, its full time starts over.
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) {
+    let mut buf = [0; 4];
+    if let Ok(4) = store.read_record(RecordKey::LogFilter, &mut buf) {
+        if let Some((mask, level, duration)) = decode(&buf) {
+            defmt::warn!("log filter {=u8:02X} at {:?} for {}s", mask, level, duration.as_secs());
+            apply(mask, level, Some(duration));
+        }
+    }
+}
+
+pub fn configure(
+    store: &mut DeviceNonVolatileStore<'_>,
+    downlink: &[u8],
+) -> Result<(), LogFilterError> {
+    let filter = match downlink {
+        [] => None,
+        _ => Some(decode(downlink).ok_or(LogFilterError::Invalid)?),
+    };
+    store.write_record(RecordKey::LogFilter, downlink).map_err(LogFilterError::Store)?;
+    match filter {
+        Some((mask, level, duration)) => {
+            defmt::warn!("log filter {=u8:02X} at {:?} for {}s", mask, level, duration.as_secs());
+            apply(mask, level, Some(duration));
+        }
+        None => apply(DEFAULT.0, DEFAULT.1, None),
+    }
+    Ok(())
+}
+
+/// Goes back to the default filter once the time is up.
+pub fn update(store: &mut DeviceNonVolatileStore<'_>) {
+    let expired = EXPIRES.lock(|expires| {
+        let expired = expires.get().is_some_and(|at| Instant::now() >= at);
+        if expired {
+            expires.set(None);
+        }
+        expired
+    });
+    if expired {
+        defmt::warn!("log filter expired");
+        apply(DEFAULT.0, DEFAULT.1, None);
+        if let Err(e) = store.write_record(RecordKey::LogFilter, &[]) {
+            defmt::error!("log filter not cleared {:?}", e);
+        }
+    }
+}
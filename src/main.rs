@@ -6,31 +6,190 @@
 #![feature(impl_trait_in_assoc_type)]
 #![feature(try_blocks)]
 
+use accelerometer::{MotionEvent, MOTION_PORT};
+use alarm::{AlarmConfig, AlarmEngine, Direction, ALARM_PORT};
+use antenna::{AntennaEvent, AntennaMonitor, ANTENNA_PORT};
+use backup::BACKUP_PORT;
+use batch::{Batch, Sample, BATCH_PORT};
+use beacon::BeaconTracker;
+use cayenne::Cayenne;
+use clock_sync::{ClockSync, CLOCK_SYNC_PORT};
+use commissioning::Commissioning;
+use delivery::Deliveries;
+use diagnostics::{Diagnostics, STATUS_PORT};
+use echo::{Echo, ECHO_PORT};
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, select4, Either3, Either4};
 use embassy_stm32::pac;
 use embassy_stm32::time::Hertz;
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use fingerprint::Fingerprint;
+use fmp::{Fmp, FMP_PORT};
+use fragmentation::{Fragmentation, FRAGMENTATION_PORT};
+use frames::FrameId;
+use geofence::{GeofenceEvent, Geofences, GEOFENCE_PORT};
+use heapless::{Deque, Vec};
+use link::LinkEvent;
+use log_filter::{Module, LOG_FILTER_PORT};
+use mtu::{Mtu, MTU_PORT};
+use multicast::{MULTICAST_PORT, PACKET_BUS_MULTICAST};
+use onboarding::{Onboarding, Report, ONBOARDING_PORT};
+use packet_queue::{DataRateOverride, DownlinkMessage, Outcome};
+use pin_map::PIN_MAP_PORT;
+use ping_slot::PingSlots;
+use preset::{Preset, Profile};
+use provisioning::Provisioning;
 
+mod accelerometer;
+mod adr;
+mod airtime;
+mod alarm;
+mod antenna;
+mod app;
+mod backup;
+mod batch;
+mod beacon;
+mod cayenne;
+mod channels;
+mod clock_sync;
+mod codec;
+mod coding_rate;
+mod commissioning;
+mod compat;
+mod dedup;
+mod delivery;
+mod derating;
+mod dev_nonce;
 mod device;
+mod diagnostics;
+mod duty_cycle;
+#[cfg(feature = "as923")]
+mod dwell;
+#[cfg(feature = "e2e")]
+mod e2e;
+mod echo;
+mod energy;
+mod exclusive;
+mod fcnt;
+mod fingerprint;
+mod firmware;
+mod fmp;
+mod fopts;
+mod fragmentation;
+mod frames;
+mod fsk;
+mod geofence;
+mod gnss;
 mod iv;
+mod join;
+mod journal;
+mod latency;
+mod lbt;
+mod link;
+mod link_adr;
+mod link_check;
+mod log_filter;
 mod lora_radio;
+#[cfg(feature = "markers")]
+mod markers;
+mod metrics;
+mod migration;
+mod mobility;
+mod mtu;
+mod multicast;
+mod onboarding;
+mod pa_limits;
+mod packet_queue;
+// also included by the host tools
+#[allow(dead_code)]
+mod payload;
+mod pin_map;
+mod ping_slot;
+mod pre_uplink;
+mod preset;
+mod provisioning;
+mod radio_config;
+mod radio_irq;
+mod radio_telemetry;
+mod readback;
+mod redundant;
+mod region;
+mod region_scan;
+mod regulatory;
+mod request;
+mod rtc;
+mod rx_abort;
+mod rx_schedule;
+mod rx_stats;
+mod safe_mode;
+// also included by the host tools, which use the parts the firmware does not
+#[allow(dead_code)]
+mod schema;
+mod sensor;
+mod settings;
+mod soak;
+mod spool;
+mod storm;
+mod supervisor;
+mod tdma;
 mod timer;
+mod trace;
+#[cfg(feature = "fuota")]
+mod update;
+mod uplink_edit;
+mod wake;
 
 use defmt_rtt as _;
 use device::*;
+use lorawan::device::radio::types::RadioBuffer;
 use lorawan::device::Device;
-use lorawan::mac::region::channel_plan::dynamic::DynamicChannelPlan;
-use lorawan::mac::region::eu868::EU868;
-use lorawan::mac::types::Credentials;
-use lorawan::mac::Mac;
+use lorawan::mac::types::{Configuration, Credentials};
 #[cfg(debug_assertions)]
 use panic_probe as _;
 // release profile: minimize the binary size of the application
 #[cfg(not(debug_assertions))]
 use panic_reset as _;
+use region::{with_mac, RegionChoice, RegionMac, MAX_PAYLOAD_SIZE, RADIO_BUFFER_SIZE};
+use region_scan::{RegionScanner, ScanCandidate};
+use safe_mode::CrashLoop;
+use sensor::Measurement;
+use settings::Settings;
+use spool::Replay;
+use storm::Admission;
+use supervisor::Task;
+use tdma::TDMA_PORT;
+use trace::TraceEvent;
+use wake::{WakeEvent, WAKE_PORT};
+
+/// Deployment archetype the configuration is taken from, `None` for [`CUSTOM_PROFILE`].
+const PRESET: Option<Preset> = None;
+/// The settings this deployment differs in from the defaults, e.g.
+/// `Profile { report_interval: Duration::from_secs(600), class_b: true, ..Profile::DEFAULT }`.
+const CUSTOM_PROFILE: Profile = Profile::DEFAULT;
+const PROFILE: Profile = match PRESET {
+    Some(preset) => preset.profile(),
+    None => CUSTOM_PROFILE,
+};
+const _: () = assert!(region_scan::built(PROFILE.region_scan), "region scan outside the build");
+/// Longest the application loop may go between heartbeats outside of its sleeps, before
+/// it is taken as stalled and restarted.
+const STEP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest a downlink may take from the radio to its handler before an alarm is raised.
+const DOWNLINK_LATENCY_BUDGET: Duration = Duration::from_millis(100);
+/// Hardware version answered to the firmware management package.
+const HARDWARE_VERSION: u32 = 1;
+const DEFAULT_SETTINGS: Settings = Settings {
+    report_interval: PROFILE.report_interval,
+    sample_interval: PROFILE.sample_interval,
+    compat_profile: PROFILE.compat_profile,
+    rx_preamble_symbols: PROFILE.rx_preamble_symbols,
+    bench_mode: PROFILE.bench_mode,
+};
+
+type SampleBatch = Batch<32>;
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let mut config = embassy_stm32::Config::default();
     {
         use embassy_stm32::rcc::*;
@@ -52,49 +211,1114 @@ async fn main(_spawner: Spawner) {
     let peripherals = embassy_stm32::init(config);
 
     pac::RCC.ccipr().modify(|w| w.set_rngsel(pac::rcc::vals::Rngsel::MSI));
-    let mut device = LoraDevice::new(peripherals).await;
-    let mut radio_buffer = Default::default();
-    let mut mac = get_mac(&mut device);
+    let mut device = LoraDevice::new(peripherals, PROFILE.fcnt_policy).await;
+    let mut crash_loop =
+        PROFILE.crash_loop.map(|config| CrashLoop::load(device.non_volatile_store(), config));
+    #[cfg(feature = "fuota")]
+    update::install(device.non_volatile_store());
+    let settings = Settings::load(device.non_volatile_store(), DEFAULT_SETTINGS);
+    settings.apply(&mut device);
+    lbt::configure(PROFILE.lbt);
+    coding_rate::configure(PROFILE.coding_rate);
+    if let Err(e) = firmware::ratchet(device.non_volatile_store()) {
+        defmt::error!("security version not saved {:?}", e);
+    }
+    let diagnostics = Diagnostics::load(device.non_volatile_store());
+    energy::load(device.non_volatile_store());
+    pa_limits::load(device.non_volatile_store());
+    multicast::load(device.non_volatile_store());
+    uplink_edit::load(device.non_volatile_store());
+    if let Err(e) = device.non_volatile_store().load_dev_nonces() {
+        defmt::error!("DevNonces not loaded {:?}", e);
+    }
+    let provisioning = Provisioning::read();
+    let mut mac = get_mac(&mut device, provisioning);
+    regulatory::check_region(device.non_volatile_store());
+    let region_scan = RegionScanner::load(device.non_volatile_store(), PROFILE.region_scan);
+    if let Some(candidate) = region_scan.current().filter(|_| !mac.is_joined()) {
+        try_candidate(&mut device, &mut mac, provisioning, candidate);
+    }
+    if let Some(crash_loop) = crash_loop.take_if(|crash_loop| crash_loop.is_looping()) {
+        let fmp = Fmp::new(HARDWARE_VERSION);
+        let intervals = (PROFILE.join_strategy.retry_after, PROFILE.status_interval);
+        safe_mode::run(&mut device, &mut mac, &diagnostics, crash_loop, fmp, intervals).await;
+    }
+    for (slot, input) in device.take_pulse_inputs() {
+        spawner.spawn(pin_map::count_pulses(slot, input)).unwrap();
+    }
+    for (slot, input) in device.take_wake_inputs() {
+        spawner.spawn(wake::watch(slot, input, PROFILE.wake)).unwrap();
+    }
+    if let Some(config) = PROFILE.app {
+        spawner.spawn(app::run(config)).unwrap();
+    }
+    if PROFILE.mobility.is_some() {
+        mobility::start();
+    }
+    if let Some(job) = PROFILE.startup_radio_job.or(provisioning.and_then(|p| p.test_mode)) {
+        let mut radio = device.suspend_mac();
+        if let Err(e) = exclusive::run(&mut radio, job).await {
+            defmt::error!("{:?} failed {:?}", job, e);
+        }
+        if let Err(e) = radio.resume().await {
+            defmt::error!("radio not handed back {:?}", e);
+        }
+    }
+    if let Some(config) = PROFILE.soak {
+        soak::run(&mut device, credentials(provisioning), config).await;
+    }
+    let mut alarms: AlarmEngine<4> = AlarmEngine::new();
+    alarms
+        .add(AlarmConfig {
+            id: 0,
+            measurement: Measurement::Temperature,
+            direction: Direction::Above,
+            threshold: 6000,
+            hysteresis: 200,
+            min_interval: Duration::from_secs(900),
+        })
+        .unwrap();
+    alarms
+        .add(AlarmConfig {
+            id: 1,
+            measurement: Measurement::Battery,
+            direction: Direction::Below,
+            threshold: 100,
+            hysteresis: 20,
+            min_interval: Duration::from_secs(24 * 3600),
+        })
+        .unwrap();
+    alarms
+        .add(AlarmConfig {
+            id: 2,
+            measurement: Measurement::DownlinkLatency,
+            direction: Direction::Above,
+            threshold: DOWNLINK_LATENCY_BUDGET.as_millis() as i32,
+            hysteresis: 0,
+            min_interval: Duration::from_secs(3600),
+        })
+        .unwrap();
+    let geofences = Geofences::load(device.non_volatile_store());
+    log_filter::load(device.non_volatile_store());
+    adr::load(device.non_volatile_store(), PROFILE.adr);
+    let deliveries = Deliveries::load(device.non_volatile_store(), PROFILE.delivery);
+    let commissioning = Commissioning::load(device.non_volatile_store(), PROFILE.commissioning);
+    #[cfg(feature = "e2e")]
+    let e2e = e2e::E2e::load(device.non_volatile_store(), &credentials(provisioning).1);
+    let fingerprint =
+        PROFILE.fingerprint_port.map(|_| Fingerprint::load(&mut device, diagnostics.boot_count()));
+    let capabilities =
+        [(PROFILE.class_b, onboarding::CLASS_B), (PROFILE.spool.is_some(), onboarding::SPOOL)]
+            .into_iter()
+            .filter(|(on, _)| *on)
+            .fold(0, |capabilities, (_, flag)| capabilities | flag);
+    let report = Report { hardware_revision: device.hardware_revision(), capabilities };
+    let onboarding =
+        PROFILE.onboarding.then(|| Onboarding::load(device.non_volatile_store(), report));
+    let mut application = Application {
+        device,
+        mac,
+        radio_buffer: Default::default(),
+        region_scan,
+        crash_loop,
+        alarms,
+        sample_ticker: Ticker::every(settings.sample_interval),
+        batch: SampleBatch::new(),
+        next_status: Instant::now(),
+        next_backup: Instant::now(),
+        next_checkpoint: Instant::now() + PROFILE.checkpoint_interval,
+        pending_polls: 0,
+        join_failures: 0,
+        storage_alerted: false,
+        batch_uplinks: 0,
+        silent_uplinks: 0,
+        replay: PROFILE.spool.map(Replay::new),
+        motion_event: None,
+        wake_events: Deque::new(),
+        geofences,
+        schedule: tdma::Schedule::new(PROFILE.uplink_spacing),
+        echo: None,
+        multicast_answer: None,
+        clock_sync: ClockSync::new(PROFILE.clock_sync_period),
+        fmp: Fmp::new(HARDWARE_VERSION),
+        fragmentation: Fragmentation::default(),
+        dedup: dedup::Dedup::default(),
+        antenna: AntennaMonitor::default(),
+        beacons: BeaconTracker::new(),
+        ping_slots: PingSlots::new(PROFILE.ping_slot_periodicity),
+        deliveries,
+        antenna_event: None,
+        commissioning,
+        #[cfg(feature = "e2e")]
+        e2e,
+        geofence_events: Vec::new(),
+        last_position: None,
+        cayenne_due: false,
+        fingerprint,
+        fingerprint_due: false,
+        onboarding,
+        mtu: PROFILE.mtu.map(Mtu::new),
+        awaiting: None,
+        link_check_sent: false,
+        device_time_sent: false,
+    };
+    loop {
+        select(
+            run_application(&mut application, provisioning, &settings, &diagnostics),
+            supervisor::stalled(Task::Application),
+        )
+        .await;
+        // dropped maybe half way through an uplink, the MAC may have sent a frame its
+        // FCntUp doesn't count yet: the session is restored from flash as at boot, where
+        // FCntUp goes past any frame sent since it was saved
+        let Application { device, mac, region_scan, .. } = &mut application;
+        *mac = get_mac(device, provisioning);
+        if let Some(candidate) = region_scan.current().filter(|_| !mac.is_joined()) {
+            try_candidate(device, mac, provisioning, candidate);
+        }
+    }
+}
+
+/// What the application keeps across restarts by [`run_application`].
+struct Application {
+    device: LoraDevice<'static>,
+    mac: RegionMac,
+    radio_buffer: RadioBuffer<RADIO_BUFFER_SIZE>,
+    region_scan: RegionScanner,
+    crash_loop: Option<CrashLoop>,
+    alarms: AlarmEngine<4>,
+    sample_ticker: Ticker,
+    batch: SampleBatch,
+    next_status: Instant,
+    next_backup: Instant,
+    next_checkpoint: Instant,
+    pending_polls: u8,
+    join_failures: u32,
+    storage_alerted: bool,
+    batch_uplinks: u32,
+    silent_uplinks: u32,
+    replay: Option<Replay>,
+    motion_event: Option<(MotionEvent, u32)>,
+    wake_events: Deque<WakeEvent, { pin_map::SLOTS }>,
+    geofences: Geofences,
+    schedule: tdma::Schedule,
+    echo: Option<Echo>,
+    multicast_answer: Option<Vec<u8, { multicast::MAX_ANSWER_SIZE }>>,
+    clock_sync: ClockSync,
+    fmp: Fmp,
+    fragmentation: Fragmentation,
+    dedup: dedup::Dedup,
+    antenna: AntennaMonitor,
+    beacons: BeaconTracker,
+    ping_slots: PingSlots,
+    deliveries: Deliveries,
+    antenna_event: Option<AntennaEvent>,
+    commissioning: Commissioning,
+    #[cfg(feature = "e2e")]
+    e2e: Option<e2e::E2e>,
+    geofence_events: Vec<GeofenceEvent, { geofence::MAX_FENCES }>,
+    last_position: Option<gnss::Position>,
+    cayenne_due: bool,
+    fingerprint: Option<Fingerprint>,
+    fingerprint_due: bool,
+    onboarding: Option<Onboarding>,
+    mtu: Option<Mtu>,
+    awaiting: Option<packet_queue::Ticket>,
+    link_check_sent: bool,
+    device_time_sent: bool,
+}
+
+/// Joins and sends for good. Run against [`supervisor::stalled`], which drops it part way
+/// through when it stops sending heartbeats, to be run again with what `application`
+/// keeps. Only a stall is covered: a panic here ends in the panic handler as anywhere else.
+async fn run_application(
+    application: &mut Application,
+    provisioning: Option<Provisioning>,
+    settings: &Settings,
+    diagnostics: &Diagnostics,
+) -> ! {
+    let Application {
+        device,
+        mac,
+        radio_buffer,
+        region_scan,
+        crash_loop,
+        alarms,
+        sample_ticker,
+        batch,
+        next_status,
+        next_backup,
+        next_checkpoint,
+        pending_polls,
+        join_failures,
+        storage_alerted,
+        batch_uplinks,
+        silent_uplinks,
+        replay,
+        motion_event,
+        wake_events,
+        geofences,
+        schedule,
+        echo,
+        multicast_answer,
+        clock_sync,
+        fmp,
+        fragmentation,
+        dedup,
+        antenna,
+        beacons,
+        ping_slots,
+        deliveries,
+        antenna_event,
+        commissioning,
+        #[cfg(feature = "e2e")]
+        e2e,
+        geofence_events,
+        last_position,
+        cayenne_due,
+        fingerprint,
+        fingerprint_due,
+        onboarding,
+        mtu,
+        awaiting,
+        link_check_sent,
+        device_time_sent,
+    } = application;
     loop {
         while !mac.is_joined() {
-            defmt::info!("JOINING");
-            match mac.join(&mut device, &mut radio_buffer).await {
-                Ok(res) => defmt::info!("Network joined! {:?}", res),
+            supervisor::heartbeat(Task::Application, STEP_TIMEOUT);
+            let data_rate =
+                PROFILE.join_strategy.data_rate(*join_failures).max(region::min_data_rate());
+            defmt::info!("JOINING at DR{}", data_rate);
+            mac.configuration_mut().tx_data_rate = region::data_rate(data_rate);
+            fsk::set_uplink_data_rate(data_rate);
+            let res = with_mac!(&mut *mac, mac => mac.join(device, radio_buffer).await);
+            if let Some(dev_nonce) = dev_nonce::take_sent() {
+                if let Err(e) = device.non_volatile_store().record_dev_nonce(dev_nonce) {
+                    defmt::error!("DevNonce not persisted {:?}", e);
+                }
+            }
+            match res {
+                Ok(res) => {
+                    defmt::info!("Network joined! {:?}", res);
+                    *join_failures = 0;
+                    region_scan.joined(device.non_volatile_store());
+                    if let Some((join_accept, len)) = frames::last_join_accept() {
+                        let (_, _, app_key) = credentials(provisioning);
+                        let store = device.non_volatile_store();
+                        match channels::save(store, &join_accept[..len], &app_key) {
+                            Ok(channels) => defmt::info!("CFList {:?}", channels),
+                            Err(e) => defmt::error!("CFList not saved {:?}", e),
+                        }
+                        if let Some(FrameId::JoinRequest { dev_nonce }) = frames::last_uplink() {
+                            let join_accept = &join_accept[..len];
+                            if let Err(e) =
+                                uplink_edit::joined(store, join_accept, dev_nonce, &app_key)
+                            {
+                                defmt::error!("NwkSKey not saved {:?}", e);
+                            }
+                        }
+                    }
+                    let selection = device.radio_selection();
+                    if let Err(e) = radio_config::confirm(device.non_volatile_store(), selection) {
+                        defmt::error!("radio profile not saved {:?}", e);
+                    }
+                }
+                Err(_) if dev_nonce::take_refused() => {
+                    // the MAC draws another DevNonce for the next one
+                    continue;
+                }
                 Err(e) => {
                     defmt::error!("Join failed {:?}", e);
-                    embassy_time::Timer::after(Duration::from_secs(600)).await;
+                    *join_failures += 1;
+                    if let Some(candidate) = region_scan.join_failed(device.non_volatile_store()) {
+                        try_candidate(device, mac, provisioning, candidate);
+                    }
+                    if *join_failures >= radio_config::JOIN_ATTEMPTS {
+                        let selection = device.radio_selection();
+                        match radio_config::reject(device.non_volatile_store(), selection) {
+                            Ok(()) => cortex_m::peripheral::SCB::sys_reset(),
+                            Err(e) => defmt::error!("radio profile not rejected {:?}", e),
+                        }
+                    }
+                    trace::record(TraceEvent::SleepEnter);
+                    supervisor::heartbeat(Task::Application, PROFILE.join_strategy.retry_after);
+                    Timer::after(PROFILE.join_strategy.retry_after).await;
+                    trace::record(TraceEvent::SleepExit);
                 }
             };
         }
+        let mut next_report = Instant::now();
+        // data rate to go back to after an uplink on one of its own, and that one
+        let mut overridden = None;
+        commissioning.joined();
+        adr::joined(mac);
+        *fingerprint_due = fingerprint.is_some();
+        if let Some(onboarding) = onboarding.as_mut() {
+            onboarding.joined();
+        }
+        if let Some(mtu) = mtu.as_mut() {
+            mtu.joined();
+        }
         'sending: while mac.is_joined() {
+            supervisor::heartbeat(Task::Application, STEP_TIMEOUT);
+            if PROFILE.class_b {
+                beacons.start();
+            }
+            log_filter::update(device.non_volatile_store());
+            adr::update(device.non_volatile_store(), settings.compat_profile);
+            if metrics::take_request() {
+                metrics::export(&mut metrics::RttSink, diagnostics);
+            }
+            let commissioning_active = commissioning.active(device.non_volatile_store());
+            if let Some(crash_loop) = crash_loop.as_mut() {
+                crash_loop.update(device.non_volatile_store(), Instant::now());
+            }
+            if fmp.reboot_due(Instant::now()) {
+                defmt::info!("rebooting as asked by the network");
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+            if device::storage_degraded() && !*storage_alerted {
+                // checkpoints stop and the status reports the flag right away
+                *storage_alerted = true;
+                *next_status = Instant::now();
+            }
+            if Instant::now() >= *next_checkpoint && !device::storage_degraded() {
+                *next_checkpoint += PROFILE.checkpoint_interval;
+                if let Err(e) = diagnostics.checkpoint(device.non_volatile_store()) {
+                    defmt::error!("boot stats not saved {:?}", e);
+                }
+                if let Err(e) = energy::checkpoint(device.non_volatile_store()) {
+                    defmt::error!("energy usage not saved {:?}", e);
+                }
+            }
+            if let Some((previous, data_rate)) = overridden.take() {
+                // unless the network moved it since
+                if mac.configuration().tx_data_rate == region::data_rate(data_rate) {
+                    mac.configuration_mut().tx_data_rate = previous;
+                }
+            }
+            let mut data_rate_policy = PROFILE.data_rate;
+            if let Some(adr) = adr::control() {
+                data_rate_policy = adr.data_rate_policy(data_rate_policy);
+            }
+            if let Some(mobility) = PROFILE.mobility {
+                if mobility::update(&mobility) {
+                    defmt::info!("stationary, back to ADR");
+                    mac.configuration_mut().number_of_transmissions = 1;
+                }
+                if mobility::is_mobile() {
+                    mac.configuration_mut().number_of_transmissions = mobility.nb_trans;
+                }
+                data_rate_policy = mobility.data_rate_policy(data_rate_policy);
+            }
+            let current = mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8);
+            if let Some(data_rate) = data_rate_policy.enforce(current) {
+                defmt::info!("data rate {} not allowed, using {}", current, data_rate);
+                mac.configuration_mut().tx_data_rate = region::data_rate(data_rate);
+            }
+            if mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8) < region::min_data_rate() {
+                mac.configuration_mut().tx_data_rate = region::data_rate(region::min_data_rate());
+            }
+            let mut payload: Vec<u8, MAX_PAYLOAD_SIZE> = Vec::new();
+            let mut max_payload_size =
+                region::max_payload_size(mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8))
+                    .saturating_sub(frames::fopts_reserved());
+            if let Some(mtu) = mtu.as_ref() {
+                max_payload_size = mtu.max_payload_size(max_payload_size);
+            }
+            #[cfg(feature = "e2e")]
+            if e2e.is_some() {
+                max_payload_size = max_payload_size.saturating_sub(e2e::OVERHEAD);
+            }
+            let mut queued = None;
+            let mut spooled = false;
+            let (fport, confirmed) = if let Some(port) = deliveries.retry(&mut payload) {
+                defmt::info!("port {} sent again", port);
+                (Some(port), true)
+            } else if let Some(event) = alarms.next_event(Instant::now()) {
+                defmt::info!("ALARM {:?}", event);
+                payload.extend_from_slice(&event.encode()).unwrap();
+                (Some(ALARM_PORT), true)
+            } else if let Some(event) = antenna_event.take() {
+                defmt::warn!("antenna {:?}", event);
+                payload.extend_from_slice(&event.encode()).unwrap();
+                (Some(ANTENNA_PORT), true)
+            } else if let Some(event) = wake_events.pop_front() {
+                defmt::info!("{:?}", event);
+                payload.extend_from_slice(&event.encode()).unwrap();
+                (Some(WAKE_PORT), true)
+            } else if let Some((event, timestamp)) = motion_event.take() {
+                payload.extend_from_slice(&event.encode(timestamp)).unwrap();
+                (Some(MOTION_PORT), event == MotionEvent::Shock)
+            } else if let Some(event) = geofence_events.pop() {
+                defmt::info!("{:?}", event);
+                payload.extend_from_slice(&event.encode()).unwrap();
+                (Some(GEOFENCE_PORT), true)
+            } else if let Some(echo) = echo.take() {
+                echo.encode(&mut payload, max_payload_size);
+                (Some(ECHO_PORT), false)
+            } else if let Some(answer) = multicast_answer.take() {
+                payload.extend_from_slice(&answer).unwrap();
+                (Some(MULTICAST_PORT), false)
+            } else if let Some(uplink) = clock_sync.take_uplink(Instant::now()) {
+                payload.extend_from_slice(&uplink).unwrap();
+                (Some(CLOCK_SYNC_PORT), false)
+            } else if let Some(answers) = fmp.take_answers() {
+                payload.extend_from_slice(&answers).unwrap();
+                (Some(FMP_PORT), false)
+            } else if let Some(answers) = fragmentation.take_answers() {
+                payload.extend_from_slice(&answers).unwrap();
+                (Some(FRAGMENTATION_PORT), false)
+            } else if let Some(fingerprint) = fingerprint.filter(|_| *fingerprint_due) {
+                *fingerprint_due = false;
+                defmt::info!("{:?}", fingerprint);
+                payload.extend_from_slice(&fingerprint.encode()).unwrap();
+                (PROFILE.fingerprint_port, false)
+            } else if let Some(report) = onboarding.as_mut().and_then(Onboarding::take_report) {
+                payload.extend_from_slice(&report).unwrap();
+                (Some(ONBOARDING_PORT), true)
+            } else if let Some(report) = mtu.as_mut().and_then(Mtu::take_report) {
+                payload.extend_from_slice(&report).unwrap();
+                (Some(MTU_PORT), false)
+            } else if link::take_probe() {
+                defmt::info!("probing link");
+                (None, true)
+            } else if *pending_polls > 0 {
+                *pending_polls -= 1;
+                defmt::info!("fetching pending downlink, {} polls left", *pending_polls);
+                (None, false)
+            } else if link::take_flush() {
+                defmt::info!("flushing MAC answers");
+                (None, false)
+            } else if link_check::take_request() {
+                uplink_edit::request_link_check();
+                *link_check_sent = true;
+                (None, false)
+            } else if rtc::take_request() {
+                uplink_edit::request_device_time();
+                *device_time_sent = true;
+                (None, false)
+            } else if let Some(uplink) = packet_queue::next() {
+                if let Some(data_rate) = uplink.data_rate {
+                    let data_rate = match data_rate {
+                        DataRateOverride::Fixed(data_rate) => data_rate,
+                        DataRateOverride::Fastest => data_rate_policy.fastest(),
+                    };
+                    defmt::info!("port {} sent on DR{} as asked", uplink.fport, data_rate);
+                    overridden = Some((mac.configuration().tx_data_rate, data_rate));
+                    mac.configuration_mut().tx_data_rate = region::data_rate(data_rate);
+                    max_payload_size = region::max_payload_size(data_rate)
+                        .saturating_sub(frames::fopts_reserved());
+                    if let Some(mtu) = mtu.as_ref() {
+                        max_payload_size = mtu.max_payload_size(max_payload_size);
+                    }
+                    #[cfg(feature = "e2e")]
+                    if e2e.is_some() {
+                        max_payload_size = max_payload_size.saturating_sub(e2e::OVERHEAD);
+                    }
+                }
+                if uplink.payload.len() > max_payload_size {
+                    defmt::warn!(
+                        "port {} packet of {} bytes too large",
+                        uplink.fport,
+                        uplink.payload.len()
+                    );
+                    uplink.ticket.resolve(Outcome::TooLarge);
+                    continue 'sending;
+                }
+                if airtime::budget_left() == Duration::from_ticks(0) {
+                    defmt::warn!("port {} packet dropped, no airtime left", uplink.fport);
+                    uplink.ticket.resolve(Outcome::NoAirtime);
+                    continue 'sending;
+                }
+                let time_on_air = airtime::time_on_air(
+                    mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8),
+                    airtime::FRAME_OVERHEAD + uplink.payload.len(),
+                );
+                match duty_cycle::available_at(time_on_air) {
+                    Some(at) if at <= Instant::now() + PROFILE.duty_cycle_max_delay => {
+                        if at > Instant::now() {
+                            defmt::info!("port {} packet held for the duty cycle", uplink.fport);
+                            supervisor::heartbeat(Task::Application, PROFILE.duty_cycle_max_delay);
+                            Timer::at(at).await;
+                        }
+                    }
+                    _ => {
+                        defmt::warn!("port {} packet dropped, no duty cycle left", uplink.fport);
+                        uplink.ticket.resolve(Outcome::NoAirtime);
+                        continue 'sending;
+                    }
+                }
+                payload.extend_from_slice(&uplink.payload).unwrap();
+                queued = Some(uplink.ticket);
+                (Some(uplink.fport), uplink.confirmed)
+            } else if request::take_poll() {
+                defmt::info!("polling for the answer to a request");
+                (None, false)
+            } else if let Some(port) = PROFILE.cayenne_port.filter(|_| *cayenne_due) {
+                *cayenne_due = false;
+                let battery = energy::with_meter(|meter| meter.remaining_permille());
+                let sensors = device.sensors();
+                let mut lpp = Cayenne::new(&mut payload, max_payload_size);
+                // channels: temperature, battery %, supply V and the last fix
+                let res: Result<(), cayenne::PayloadFull> = try {
+                    lpp.temperature(1, sensors.temperature())?;
+                    lpp.analog_input(2, battery * 10)?;
+                    lpp.analog_input(3, sensors.supply_voltage() as i32 / 10)?;
+                    if let Some(position) = *last_position {
+                        // GGA altitudes aren't parsed
+                        lpp.gps(4, position, 0)?;
+                    }
+                };
+                if let Err(e) = res {
+                    defmt::warn!("readings left out {:?}", e);
+                }
+                (Some(port), false)
+            } else if Instant::now() >= *next_status {
+                *next_status += PROFILE.status_interval;
+                payload.extend_from_slice(&diagnostics.encode_status()).unwrap();
+                (Some(STATUS_PORT), false)
+            } else if let Some(interval) =
+                PROFILE.backup_interval.filter(|_| Instant::now() >= *next_backup)
+            {
+                *next_backup += interval;
+                payload.extend_from_slice(&backup::encode(settings)).unwrap();
+                (Some(BACKUP_PORT), false)
+            } else if let Some(port) = replay.as_mut().and_then(|replay| {
+                replay.next(device.non_volatile_store(), &mut payload, max_payload_size)
+            }) {
+                defmt::info!("spooled port {} uplink sent", port);
+                spooled = true;
+                (Some(port), true)
+            } else {
+                let batch_size =
+                    mtu.as_ref().map_or(max_payload_size, |mtu| mtu.batch_size(max_payload_size));
+                if batch.len() < SampleBatch::capacity(batch_size) {
+                    trace::record(TraceEvent::SleepEnter);
+                    let (accelerometer, gnss) = device.event_sources();
+                    let asleep = next_report.saturating_duration_since(Instant::now());
+                    supervisor::heartbeat(Task::Application, asleep);
+                    let dev_addr = frames::last_uplink().and_then(|id| id.dev_addr());
+                    let ping_slot =
+                        beacons.last_beacon().zip(dev_addr).map(|(beacon, dev_addr)| {
+                            ping_slots.next_slot(beacon, dev_addr, Instant::now())
+                        });
+                    let wake = select4(
+                        select3(
+                            Timer::at(
+                                fmp.reboot_at().map_or(next_report, |at| at.min(next_report)),
+                            ),
+                            beacons.window_due(),
+                            ping_slot::due(ping_slot),
+                        ),
+                        sample_ticker.next(),
+                        select3(
+                            accelerometer::next_event(accelerometer),
+                            wake::next_event(),
+                            packet_queue::queued(),
+                        ),
+                        gnss.next_fix(),
+                    )
+                    .await;
+                    trace::record(TraceEvent::SleepExit);
+                    match wake {
+                        Either4::First(Either3::Third(())) => {
+                            let slot = ping_slot.unwrap();
+                            let mut radio = device.suspend_mac();
+                            if let Err(e) = ping_slot::receive(&mut radio, slot).await {
+                                defmt::error!("ping slot failed {:?}", e);
+                            }
+                            if let Err(e) = radio.resume().await {
+                                defmt::error!("radio not handed back {:?}", e);
+                            }
+                            while let Ok(message) = PACKET_BUS_MULTICAST.try_receive() {
+                                defmt::info!(
+                                    "multicast group {} FCnt {} FPort {} {=[u8]:02X}",
+                                    message.group,
+                                    message.fcnt,
+                                    message.fport,
+                                    message.payload
+                                );
+                                if message.fport == FRAGMENTATION_PORT {
+                                    let store = device.non_volatile_store();
+                                    if let Err(e) = fragmentation.command(store, &message.payload) {
+                                        defmt::warn!("fragment rejected {:?}", e);
+                                    }
+                                }
+                            }
+                            multicast::save(device.non_volatile_store());
+                            continue 'sending;
+                        }
+                        Either4::First(Either3::Second(())) => {
+                            let window = beacons.window();
+                            supervisor::heartbeat(Task::Application, window);
+                            let mut radio = device.suspend_mac();
+                            if let Err(e) = beacons.receive(&mut radio).await {
+                                defmt::error!("beacon window failed {:?}", e);
+                            }
+                            if let Err(e) = radio.resume().await {
+                                defmt::error!("radio not handed back {:?}", e);
+                            }
+                            while let Ok(status) = beacon::STATUS.try_receive() {
+                                defmt::info!("beacon {:?}", status);
+                            }
+                            continue 'sending;
+                        }
+                        Either4::First(Either3::First(())) => {
+                            if Instant::now() < next_report {
+                                // woken up for the reboot
+                                continue 'sending;
+                            }
+                            next_report += commissioning.report_interval(settings.report_interval);
+                            *cayenne_due = PROFILE.cayenne_port.is_some();
+                            log!(debug, Module::Sensors, "pulses {:?}", pin_map::pulse_counts());
+                        }
+                        Either4::Second(_) => {
+                            let value = device.sensors().temperature();
+                            alarms.update(Measurement::Temperature, value);
+                            match derating::update(value) {
+                                Some(true) => {
+                                    defmt::warn!("TX power derated at {} cC", value)
+                                }
+                                Some(false) => {
+                                    defmt::info!("TX power derating lifted at {} cC", value)
+                                }
+                                None => {}
+                            }
+                            let battery = energy::with_meter(|meter| meter.remaining_permille());
+                            alarms.update(Measurement::Battery, battery);
+                            let sample = Sample {
+                                measurement: Measurement::Temperature,
+                                timestamp: clock_sync::timestamp(Instant::now()),
+                                value,
+                            };
+                            if let Some(dropped) = batch.push(sample) {
+                                defmt::warn!("batch full, dropped {:?}", dropped);
+                            }
+                            continue 'sending;
+                        }
+                        Either4::Third(Either3::Third(())) => continue 'sending,
+                        Either4::Third(Either3::Second(event)) => {
+                            if wake_events.push_back(event).is_err() {
+                                defmt::warn!("dropped {:?}", event);
+                            }
+                            continue 'sending;
+                        }
+                        Either4::Third(Either3::First(event)) => {
+                            defmt::info!("{:?}", event);
+                            // movement is only reported when it starts mobility mode
+                            let started_moving = PROFILE.mobility.is_some() && mobility::motion();
+                            if event == MotionEvent::Shock || started_moving {
+                                *motion_event = Some((event, Instant::now().as_secs() as u32));
+                            }
+                            continue 'sending;
+                        }
+                        Either4::Fourth(position) => {
+                            *last_position = Some(position);
+                            for event in geofences.update(position) {
+                                if geofence_events.push(event).is_err() {
+                                    defmt::warn!("dropped {:?}", event);
+                                }
+                            }
+                            continue 'sending;
+                        }
+                    }
+                }
+                if batch.is_empty() {
+                    continue 'sending;
+                }
+                payload.resize_default(batch_size).unwrap();
+                let len = batch.encode(&mut payload);
+                payload.truncate(len);
+                *batch_uplinks = *batch_uplinks.wrapping_add(1);
+                let confirmed = PROFILE.confirm(*batch_uplinks) || commissioning_active;
+                (Some(BATCH_PORT), confirmed)
+            };
+            if matches!(fport, Some(BATCH_PORT | STATUS_PORT | BACKUP_PORT)) {
+                if let Some(start) = schedule.next_uplink(Instant::now()) {
+                    trace::record(TraceEvent::SleepEnter);
+                    let asleep = start.saturating_duration_since(Instant::now());
+                    supervisor::heartbeat(Task::Application, asleep);
+                    Timer::at(start).await;
+                    trace::record(TraceEvent::SleepExit);
+                }
+            }
+            if let Some(hook) = PROFILE.pre_uplink_hook {
+                let mut uplink = pre_uplink::Uplink {
+                    fport,
+                    data_rate: mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8),
+                    max_payload_size,
+                    payload: &mut payload,
+                };
+                if !pre_uplink::run(hook, device.sensors(), &mut uplink) {
+                    continue 'sending;
+                }
+            }
+            if let (Some(replay), Some(port)) = (replay.as_mut(), fport) {
+                // retries and replays still go out, they tell when the network is back
+                if replay.spools(port) && !spooled && !deliveries.is_outstanding() {
+                    match device.non_volatile_store().spool_push(port, &payload) {
+                        Ok(()) => {
+                            defmt::info!("network unreachable, port {} uplink spooled", port);
+                            replay.spooled();
+                            continue 'sending;
+                        }
+                        Err(e) => defmt::error!("uplink not spooled {:?}", e),
+                    }
+                }
+            }
+            let confirmed = spooled
+                || deliveries.sending(device.non_volatile_store(), fport, &payload, confirmed);
+            #[cfg(feature = "e2e")]
+            if let (Some(e2e), Some(port)) = (e2e.as_mut(), fport.filter(|port| *port != ECHO_PORT))
+            {
+                // never fall back to plaintext
+                if let Err(e) = e2e.seal(device.non_volatile_store(), port, &mut payload) {
+                    defmt::error!("port {} payload dropped {:?}", port, e);
+                    continue 'sending;
+                }
+            }
+            #[cfg(feature = "as923")]
+            {
+                let current = mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8);
+                match dwell::data_rate_for(current, payload.len()) {
+                    Ok(data_rate) if data_rate != current => {
+                        defmt::info!("port {:?} sent on DR{} for the dwell time", fport, data_rate);
+                        mac.configuration_mut().tx_data_rate = region::data_rate(data_rate);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        defmt::error!("port {:?} payload dropped {:?}", fport, e);
+                        continue 'sending;
+                    }
+                }
+            }
+            fsk::set_uplink_data_rate(mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8));
+            // what a LinkADRReq in the downlink would change
+            let link_adr_before = (
+                mac.configuration().tx_data_rate,
+                mac.configuration().tx_power,
+                mac.configuration().number_of_transmissions,
+            );
             defmt::info!("SENDING");
-            let send_res = mac.send(&mut device, &mut radio_buffer, b"PING", 1, false, None).await;
+            airtime::uplink_started();
+            schedule.uplink_started(Instant::now());
+            let (payload, port) = match fport {
+                Some(fport) => (&payload[..], fport),
+                None => (&[][..], link::POLL_PORT),
+            };
+            let send_res = with_mac!(&mut *mac, mac => {
+                mac.send(device, radio_buffer, payload, port, confirmed, None)
+                    .await
+            });
+            let answered = matches!(send_res, Ok(Some(_)));
+            let acked = answered && frames::last_downlink().is_some_and(|id| id.ack());
+            if acked {
+                diagnostics.uplink_acked();
+            }
+            deliveries.sent(device.non_volatile_store(), acked);
+            if core::mem::take(link_check_sent) {
+                link_check::sent();
+            }
+            if core::mem::take(device_time_sent) {
+                rtc::sent();
+            }
+            if let Some(onboarding) = onboarding.as_mut().filter(|_| fport == Some(ONBOARDING_PORT))
+            {
+                onboarding.sent(device.non_volatile_store(), acked);
+            }
+            if let Some(replay) = replay.as_mut() {
+                replay.sent(answered, confirmed);
+                if spooled {
+                    let retry_at = Instant::now() + settings.report_interval;
+                    replay.replayed(device.non_volatile_store(), acked, retry_at);
+                }
+            }
+            // a queued packet learns its outcome after its last retransmission
+            if let Some(ticket) = queued.or_else(|| awaiting.take()) {
+                if deliveries.is_outstanding() {
+                    *awaiting = Some(ticket);
+                } else {
+                    ticket.resolve(Outcome::of(send_res.is_ok(), confirmed, acked));
+                }
+            }
+            if send_res.is_ok()
+                && !acked
+                && fport.is_some_and(|port| PROFILE.redundant_ports.contains(&port))
+            {
+                let used = airtime::report().frequency;
+                if let Some(frequency) = redundant::channel(device.non_volatile_store(), used) {
+                    let mut radio = device.suspend_mac();
+                    if let Err(e) = redundant::repeat(&mut radio, frequency).await {
+                        defmt::error!("uplink not repeated {:?}", e);
+                    }
+                    if let Err(e) = radio.resume().await {
+                        defmt::error!("radio not handed back {:?}", e);
+                    }
+                }
+            }
+            if commissioning_active && confirmed {
+                commissioning::signal(device.led(), acked).await;
+            }
             match send_res {
                 Ok(Some((len, status))) => {
-                    defmt::info!("Sent: Rx len: {} RSSI: {} SNR:{}", len, status.rssi, status.snr)
+                    *silent_uplinks = 0;
+                    diagnostics.downlink_received();
+                    *antenna_event = antenna.downlink(status.rssi).or(*antenna_event);
+                    let mut downlink = frames::last_downlink();
+                    match storm::admit(Instant::now()) {
+                        Admission::Process => {}
+                        Admission::StormStarted => {
+                            defmt::warn!("downlink storm, dropping downlinks");
+                            *next_status = Instant::now();
+                            downlink = None;
+                        }
+                        Admission::Drop => downlink = None,
+                    }
+                    let data = &radio_buffer.as_ref()[..len];
+                    if downlink.is_some_and(|id| dedup.is_duplicate(id, data)) {
+                        defmt::warn!("duplicate downlink {:?} ignored", downlink);
+                        downlink = None;
+                    }
+                    if let Some(latency) = downlink.and_then(|_| latency::handled()) {
+                        let latency = latency.as_millis() as i32;
+                        alarms.update(Measurement::DownlinkLatency, latency);
+                    }
+                    match downlink {
+                        Some(FrameId::Data { fport: Some(BACKUP_PORT), .. }) => {
+                            match backup::decode(&radio_buffer.as_ref()[..len]) {
+                                Some(restored) => {
+                                    defmt::info!("restoring {:?}", restored);
+                                    match restored.save(device.non_volatile_store()) {
+                                        Ok(()) => cortex_m::peripheral::SCB::sys_reset(),
+                                        Err(e) => {
+                                            defmt::error!("settings not restored {:?}", e)
+                                        }
+                                    }
+                                }
+                                None => defmt::warn!("invalid settings backup"),
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(GEOFENCE_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            if let Err(e) = geofences.configure(device.non_volatile_store(), data) {
+                                defmt::warn!("geofence not set {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(ECHO_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            *echo = Some(Echo::new(data, status.rssi, status.snr));
+                        }
+                        #[cfg(feature = "e2e")]
+                        Some(FrameId::Data { fport: Some(e2e::E2E_KEY_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            let (_, dev_eui, app_key) = credentials(provisioning);
+                            let store = device.non_volatile_store();
+                            match e2e::provision(store, data, &dev_eui, &app_key) {
+                                Ok(key) => *e2e = key,
+                                Err(e) => defmt::warn!("payload key rejected {:?}", e),
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(MULTICAST_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            let (_, _, app_key) = credentials(provisioning);
+                            let store = device.non_volatile_store();
+                            match multicast::command(store, data, &app_key) {
+                                Ok(answer) => *multicast_answer = Some(answer),
+                                Err(e) => defmt::warn!("multicast setup rejected {:?}", e),
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(CLOCK_SYNC_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            if let Err(e) = clock_sync.command(data) {
+                                defmt::warn!("clock sync rejected {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(FMP_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            if let Err(e) = fmp.command(device.non_volatile_store(), data) {
+                                defmt::warn!("firmware management rejected {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(FRAGMENTATION_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            let store = device.non_volatile_store();
+                            if let Err(e) = fragmentation.command(store, data) {
+                                defmt::warn!("fragmentation rejected {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(LOG_FILTER_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            let store = device.non_volatile_store();
+                            if let Err(e) = log_filter::configure(store, data) {
+                                defmt::warn!("log filter not set {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(MTU_PORT), .. }) if mtu.is_some() => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            if let Err(e) = mtu.as_mut().unwrap().configure(data) {
+                                defmt::warn!("payload caps not set {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(TDMA_PORT), .. }) => {
+                            if let Err(e) = schedule.configure(&radio_buffer.as_ref()[..len]) {
+                                defmt::warn!("TDMA slot not set {:?}", e);
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(PIN_MAP_PORT), .. }) => {
+                            let data = &radio_buffer.as_ref()[..len];
+                            let (_, dev_eui, app_key) = credentials(provisioning);
+                            let store = device.non_volatile_store();
+                            match pin_map::update(store, data, &dev_eui, &app_key) {
+                                Ok(pin_map) => {
+                                    defmt::info!("{:?} applied at reset", pin_map);
+                                    cortex_m::peripheral::SCB::sys_reset();
+                                }
+                                Err(e) => defmt::warn!("pin map rejected {:?}", e),
+                            }
+                        }
+                        Some(FrameId::Data { fport: Some(fport @ 1..=223), fcnt, .. })
+                            if fport != link::POLL_PORT =>
+                        {
+                            let data = &radio_buffer.as_ref()[..len.min(MAX_PAYLOAD_SIZE)];
+                            let message = DownlinkMessage {
+                                fport,
+                                payload: Vec::from_slice(data).unwrap(),
+                                rssi: status.rssi,
+                                snr: status.snr,
+                                fcnt,
+                                window: rx_stats::last_received(),
+                            };
+                            if let Some(message) = request::answer(message) {
+                                packet_queue::deliver(message);
+                            }
+                        }
+                        _ => {}
+                    }
+                    if downlink.is_some_and(|id| id.pending()) {
+                        // a poll answered with FPending keeps counting down the burst
+                        if fport.is_some() {
+                            *pending_polls = PROFILE.max_pending_polls;
+                        }
+                    } else {
+                        *pending_polls = 0;
+                    }
+                    if PROFILE.flush_mac_answers && downlink.is_some_and(|id| id.has_mac_commands())
+                    {
+                        link::request_flush();
+                    }
+                    defmt::info!(
+                        "Sent {:?}: Rx {:?} len: {} RSSI: {} SNR:{}",
+                        frames::last_uplink(),
+                        downlink,
+                        len,
+                        status.rssi,
+                        status.snr
+                    )
+                }
+                Ok(None) => {
+                    *pending_polls = 0;
+                    *silent_uplinks += 1;
+                    if confirmed {
+                        *antenna_event = antenna.unanswered().or(*antenna_event);
+                    }
+                    defmt::info!("Sent {:?}: no downlink", frames::last_uplink())
                 }
-                Ok(None) => defmt::info!("Sent: no downlink"),
                 Err(e) => {
-                    defmt::error!("{:?}", e);
+                    *silent_uplinks += 1;
+                    if confirmed {
+                        *antenna_event = antenna.unanswered().or(*antenna_event);
+                    }
+                    defmt::error!("{:?} sending {:?}", e, frames::last_uplink());
+                    if let Err(e) = device.abort_rx().await {
+                        defmt::error!("radio not returned to standby {:?}", e);
+                    }
                     if let lorawan::Error::Mac(lorawan::mac::Error::SessionExpired) = e {
                         defmt::info!("Session expired");
                         break 'sending;
                     };
                 }
             }
-
-            embassy_time::Timer::after(Duration::from_secs(300)).await;
+            log!(info, Module::Radio, "{:?}", airtime::report());
+            let sensors = device.sensors();
+            radio_telemetry::uplink_sent(sensors.temperature(), sensors.supply_voltage());
+            *antenna_event =
+                antenna.radio_errors(radio_telemetry::telemetry().errors).or(*antenna_event);
+            pa_limits::save(device.non_volatile_store());
+            let pa_limit = pa_limits::band_limit();
+            let nack = link_adr::nack(mac.configuration().tx_power, pa_limit);
+            if nack != 0 && mac.configuration().tx_power != link_adr_before.1 {
+                // taken whole by the MAC, the NACK in its answer rejects all of it
+                defmt::warn!(
+                    "LinkADRReq for {:?} dBm NACKed, the PA does {}",
+                    mac.configuration().tx_power,
+                    pa_limit
+                );
+                let configuration = mac.configuration_mut();
+                (
+                    configuration.tx_data_rate,
+                    configuration.tx_power,
+                    configuration.number_of_transmissions,
+                ) = link_adr_before;
+                uplink_edit::nack_link_adr(nack);
+            }
+            adr::sent(mac, settings.compat_profile, link_adr_before.0, answered);
+            let data_rate = mac.configuration().tx_data_rate.map_or(0, |dr| dr as u8);
+            if let Some(event) = link::update(frames::last_uplink(), data_rate) {
+                defmt::warn!("link {:?}", event);
+                if event == LinkEvent::AdrAckReqSet && PROFILE.probe_on_adr_ack_req {
+                    link::request_probe();
+                }
+            }
+            let [rx1, rx2] = rx_stats::stats();
+            log!(
+                debug,
+                Module::Radio,
+                "RX1 {:?} RX2 {:?} schedule {:?} IRQ {:?} config repairs {} LBT blocked {} downlink latency {:?}",
+                rx1,
+                rx2,
+                rx_schedule::stats(),
+                radio_irq::stats(),
+                readback::repairs(),
+                lbt::blocked(),
+                latency::stats()
+            );
+            if PROFILE.rejoin_after.is_some_and(|limit| *silent_uplinks >= limit) {
+                defmt::warn!("no downlink in {} uplinks, joining again", *silent_uplinks);
+                *silent_uplinks = 0;
+                let region = region::current();
+                let (configuration, credentials) = fresh_session(provisioning, region);
+                // the region the MAC already runs in
+                *mac = device::new_mac(region, configuration, credentials).unwrap();
+                set_up_region(device, mac, false);
+            }
         }
     }
 }
-pub fn get_mac(device: &mut LoraDevice<'static>) -> Mac<EU868, DynamicChannelPlan<EU868>> {
+
+/// Credentials used on boards that were never provisioned.
+const DEFAULT_APP_EUI: [u8; 8] = [0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01];
+const DEFAULT_APP_KEY: [u8; 16] = [
+    0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F, 0x3C,
+];
+
+/// AppEUI, DevEUI and AppKey.
+fn credentials(provisioning: Option<Provisioning>) -> ([u8; 8], [u8; 8], [u8; 16]) {
     pub const DEVICE_ID_PTR: *const u8 = 0x1FFF_7580 as _;
-    let dev_eui: [u8; 8] = unsafe { *DEVICE_ID_PTR.cast::<[u8; 8]>() };
-    let app_eui: [u8; 8] = [0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01];
-    let app_key: [u8; 16] = [
-        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F,
-        0x3C,
-    ];
+    let dev_eui: [u8; 8] = provisioning
+        .and_then(|p| p.dev_eui)
+        .unwrap_or_else(|| unsafe { *DEVICE_ID_PTR.cast::<[u8; 8]>() });
+    let app_eui = provisioning.map_or(DEFAULT_APP_EUI, |p| p.app_eui);
+    let app_key = provisioning.map_or(DEFAULT_APP_KEY, |p| p.app_key);
+    (app_eui, dev_eui, app_key)
+}
+
+pub fn get_mac(device: &mut LoraDevice<'static>, provisioning: Option<Provisioning>) -> RegionMac {
+    let (app_eui, dev_eui, app_key) = credentials(provisioning);
+    if provisioning.is_none() {
+        defmt::warn!("not provisioned, using default credentials");
+    }
     defmt::info!(
         "deveui:\t{:X}-{:X}-{:X}-{:X}-{:X}-{:X}-{:X}-{:X}",
         dev_eui[7],
@@ -112,7 +1336,77 @@ pub fn get_mac(device: &mut LoraDevice<'static>) -> Mac<EU868, DynamicChannelPla
         Ok(_) => defmt::info!("credentials and configuration loaded from non volatile"),
         Err(_) => defmt::info!("credentials and configuration not found in non volatile"),
     };
+    let restored = hydrate_res.is_ok();
+    let store = device.non_volatile_store();
+    // a session from before the region was persisted with it is in the one recorded
+    let choice = match store.region_choice() {
+        Some(choice) => choice,
+        None if restored => regulatory::recorded_region(store).unwrap_or(region::DEFAULT),
+        None => region::DEFAULT,
+    };
     let (configuration, credentials) =
-        hydrate_res.unwrap_or((Default::default(), Credentials::new(app_eui, dev_eui, app_key)));
-    Mac::new(configuration, credentials)
+        hydrate_res.unwrap_or_else(|_| fresh_session(provisioning, region::DEFAULT));
+    let (mut mac, restored) = match device::new_mac(choice, configuration, credentials) {
+        Ok(mac) => (mac, restored),
+        Err(e) => {
+            defmt::error!("session not restored {:?}", e);
+            let (configuration, credentials) = fresh_session(provisioning, region::DEFAULT);
+            // DEFAULT is always built
+            (device::new_mac(region::DEFAULT, configuration, credentials).unwrap(), false)
+        }
+    };
+    device.non_volatile_store().set_region_choice(region::current());
+    set_up_region(device, &mut mac, restored);
+    mac
+}
+
+/// Configuration and credentials of a MAC that has yet to join in `region`.
+fn fresh_session(
+    provisioning: Option<Provisioning>,
+    region: RegionChoice,
+) -> (Configuration, Credentials) {
+    let (app_eui, dev_eui, app_key) = credentials(provisioning);
+    let mut configuration = Default::default();
+    region::configure(&mut configuration, region);
+    (configuration, Credentials::new(app_eui, dev_eui, app_key))
+}
+
+/// Moves a MAC that has yet to join to the region of a region scan candidate, with a fresh
+/// session, and onto its sub-band.
+fn try_candidate(
+    device: &mut LoraDevice<'static>,
+    mac: &mut RegionMac,
+    provisioning: Option<Provisioning>,
+    candidate: ScanCandidate,
+) {
+    if candidate.region != region::current() {
+        let (configuration, credentials) = fresh_session(provisioning, candidate.region);
+        match device::new_mac(candidate.region, configuration, credentials) {
+            Ok(new_mac) => *mac = new_mac,
+            Err(e) => {
+                defmt::error!("region scan candidate skipped {:?}", e);
+                return;
+            }
+        }
+        device.non_volatile_store().set_region_choice(candidate.region);
+        set_up_region(device, mac, false);
+    }
+    region_scan::apply(mac, device.non_volatile_store(), candidate);
+}
+
+/// Channels, sub-band and dwell time of the region, on a MAC just created with a
+/// `restored` session or a fresh one.
+fn set_up_region(device: &mut LoraDevice<'static>, mac: &mut RegionMac, restored: bool) {
+    let region = region::current();
+    if let Some(saved_channels) = channels::load(device.non_volatile_store())
+        .filter(|_| restored && region != RegionChoice::Au915)
+    {
+        channels::restore(mac, &saved_channels);
+    }
+    #[cfg(feature = "au915")]
+    if region == RegionChoice::Au915 {
+        region::select_sub_band(mac, device.non_volatile_store(), PROFILE.sub_band);
+    }
+    #[cfg(feature = "as923")]
+    dwell::set_enabled(PROFILE.uplink_dwell_time && region.is_as923());
 }
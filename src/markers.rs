@@ -0,0 +1,43 @@
+//! GPIO markers for a power analyzer rig, so that a current capture can be split into
+//! firmware states and sleep current compared between releases. With the `markers`
+//! feature every [`TraceEvent`] also drives a pin: the sleep marker is high while the
+//! application sleeps, the TX marker while the radio transmits and the RX marker while
+//! an RX window is open. The pins are picked in `LoraDevice::new`.
+
+use core::cell::RefCell;
+
+use embassy_stm32::gpio::Output;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::trace::TraceEvent;
+
+pub struct Markers {
+    pub sleep: Output<'static>,
+    pub tx: Output<'static>,
+    pub rx: Output<'static>,
+}
+
+static MARKERS: Mutex<CriticalSectionRawMutex, RefCell<Option<Markers>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn init(markers: Markers) {
+    MARKERS.lock(|cell| *cell.borrow_mut() = Some(markers));
+}
+
+pub fn record(event: TraceEvent) {
+    MARKERS.lock(|cell| {
+        let mut markers = cell.borrow_mut();
+        let Some(markers) = markers.as_mut() else {
+            return;
+        };
+        match event {
+            TraceEvent::SleepEnter => markers.sleep.set_high(),
+            TraceEvent::SleepExit => markers.sleep.set_low(),
+            TraceEvent::TxStart => markers.tx.set_high(),
+            TraceEvent::TxEnd => markers.tx.set_low(),
+            TraceEvent::RxOpen => markers.rx.set_high(),
+            TraceEvent::RxClose => markers.rx.set_low(),
+        }
+    });
+}
@@ -0,0 +1,147 @@
+//! The internal counters in one place, for bench rigs that scrape devices into their
+//! existing monitoring. [`export`] walks them into a [`MetricsSink`]; [`RttSink`] prints
+//! them over RTT in the Prometheus text exposition format as `metrics <line>`, ended by
+//! `metrics # EOF`, which `tools/src/bin/metrics_export.rs` turns into a file for a
+//! textfile collector.
+//!
+//! A dump is asked for by setting [`METRICS_REQUEST`] through the debug probe and is
+//! printed at the next wake-up.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::Instant;
+
+use crate::airtime;
+use crate::diagnostics::Diagnostics;
+use crate::energy;
+use crate::latency;
+use crate::lbt;
+use crate::pin_map::{self, SLOTS};
+use crate::radio_irq;
+use crate::radio_telemetry;
+use crate::readback;
+use crate::regulatory;
+use crate::rx_schedule;
+use crate::rx_stats;
+
+/// Set to 1 by the rig, cleared once the dump is printed.
+#[no_mangle]
+pub static METRICS_REQUEST: AtomicBool = AtomicBool::new(false);
+
+const WINDOWS: [&str; 2] = ["rx1", "rx2"];
+const SLOT_LABELS: [&str; SLOTS] = ["0", "1", "2", "3"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+pub trait MetricsSink {
+    /// Comes before the samples of a metric.
+    fn describe(&mut self, name: &'static str, kind: Kind);
+    fn sample(
+        &mut self,
+        name: &'static str,
+        label: Option<(&'static str, &'static str)>,
+        value: i64,
+    );
+    fn finish(&mut self) {}
+}
+
+pub struct RttSink;
+impl MetricsSink for RttSink {
+    fn describe(&mut self, name: &'static str, kind: Kind) {
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        defmt::println!("metrics # TYPE {=str} {=str}", name, kind);
+    }
+
+    fn sample(
+        &mut self,
+        name: &'static str,
+        label: Option<(&'static str, &'static str)>,
+        value: i64,
+    ) {
+        match label {
+            Some((key, label)) => {
+                defmt::println!(
+                    "metrics {=str}{{{=str}=\"{=str}\"}} {=i64}",
+                    name,
+                    key,
+                    label,
+                    value
+                )
+            }
+            None => defmt::println!("metrics {=str} {=i64}", name, value),
+        }
+    }
+
+    fn finish(&mut self) {
+        defmt::println!("metrics # EOF");
+    }
+}
+
+/// Whether a dump was asked for since the last call.
+pub fn take_request() -> bool {
+    METRICS_REQUEST.swap(false, Ordering::Relaxed)
+}
+
+pub fn export(sink: &mut impl MetricsSink, diagnostics: &Diagnostics) {
+    let mut single = |name, kind, value: i64| {
+        sink.describe(name, kind);
+        sink.sample(name, None, value);
+    };
+    single("lorawan_boot_count", Kind::Counter, diagnostics.boot_count() as i64);
+    single("lorawan_session_seconds", Kind::Gauge, Instant::now().as_secs() as i64);
+    single("lorawan_uptime_seconds", Kind::Counter, diagnostics.uptime() as i64);
+    if let Some(since) = diagnostics.since_last_ack() {
+        single("lorawan_seconds_since_ack", Kind::Gauge, since as i64);
+    }
+    if let Some(since) = diagnostics.since_last_downlink() {
+        single("lorawan_seconds_since_downlink", Kind::Gauge, since as i64);
+    }
+    let irq = radio_irq::stats();
+    single("lorawan_radio_irqs_total", Kind::Counter, irq.irqs as i64);
+    single("lorawan_radio_irqs_spurious_total", Kind::Counter, irq.spurious as i64);
+    single("lorawan_radio_irq_timeouts_total", Kind::Counter, irq.timeouts as i64);
+    let schedule = rx_schedule::stats();
+    single("lorawan_rx_windows_late_total", Kind::Counter, schedule.late as i64);
+    single("lorawan_rx_windows_missed_total", Kind::Counter, schedule.missed as i64);
+    single("lorawan_rx_max_lateness_us", Kind::Gauge, schedule.max_lateness as i64);
+    single("lorawan_config_repairs_total", Kind::Counter, readback::repairs() as i64);
+    single("lorawan_lbt_blocked_total", Kind::Counter, lbt::blocked() as i64);
+    single("lorawan_regulatory_violations_total", Kind::Counter, regulatory::violations() as i64);
+    let airtime = airtime::report();
+    single("lorawan_airtime_budget_left_ms", Kind::Gauge, airtime.budget_left.as_millis() as i64);
+    single("lorawan_tx_power_dbm", Kind::Gauge, airtime.tx_power as i64);
+    let (consumed, battery) =
+        energy::with_meter(|meter| (meter.consumed_uah(), meter.remaining_permille()));
+    single("lorawan_energy_consumed_uah", Kind::Counter, consumed as i64);
+    single("lorawan_battery_permille", Kind::Gauge, battery as i64);
+    let telemetry = radio_telemetry::telemetry();
+    single("lorawan_radio_errors", Kind::Gauge, telemetry.errors as i64);
+    single("lorawan_die_temperature_centidegrees", Kind::Gauge, telemetry.temperature as i64);
+    single("lorawan_supply_mv", Kind::Gauge, telemetry.supply_mv as i64);
+    let latency = latency::stats();
+    single("lorawan_downlink_latency_count", Kind::Counter, latency.count as i64);
+    single("lorawan_downlink_latency_p99_ms", Kind::Gauge, latency.p99 as i64);
+    single("lorawan_downlink_latency_max_ms", Kind::Gauge, latency.max as i64);
+
+    let windows = rx_stats::stats();
+    sink.describe("lorawan_rx_opened_total", Kind::Counter);
+    for (window, stats) in WINDOWS.iter().zip(&windows) {
+        sink.sample("lorawan_rx_opened_total", Some(("window", window)), stats.opened as i64);
+    }
+    sink.describe("lorawan_rx_received_total", Kind::Counter);
+    for (window, stats) in WINDOWS.iter().zip(&windows) {
+        sink.sample("lorawan_rx_received_total", Some(("window", window)), stats.received as i64);
+    }
+    sink.describe("lorawan_pulses_total", Kind::Counter);
+    for (slot, count) in SLOT_LABELS.iter().zip(pin_map::pulse_counts()) {
+        sink.sample("lorawan_pulses_total", Some(("slot", slot)), count as i64);
+    }
+    sink.finish();
+}
@@ -0,0 +1,102 @@
+//! Schema versions of the session page, so that a firmware update changing what the MAC
+//! persists converts the stored session instead of failing to decode it and forcing a
+//! rejoin. The page starts with [`MAGIC`], the schema version it was written with and the
+//! [`Header`] kept along with the session, followed by the
+//! [`Storable`](lorawan::mac::types::Storable) as encoded by the codec.
+//!
+//! Bumping [`SCHEMA_VERSION`] takes a migration from the previous version appended to
+//! [`MIGRATIONS`]. Each one is handed the whole page and leaves it in its target version
+//! with the header updated. Sessions from newer firmware are not decoded.
+
+use crate::codec::CodecError;
+
+pub const SCHEMA_VERSION: u8 = 3;
+const MAGIC: [u8; 2] = *b"SV";
+const VERSION_HEADER_SIZE: usize = MAGIC.len() + 1;
+pub const HEADER_SIZE: usize = VERSION_HEADER_SIZE + 2;
+
+type Migration = fn(&mut [u8]) -> Result<(), CodecError>;
+
+/// `MIGRATIONS[n]` converts a page from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] =
+    [from_unversioned, with_sub_band, with_region];
+
+/// Device state the MAC doesn't persist itself but that belongs with the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Header {
+    /// AU915 sub-band the session was joined on, 1 to 8, 0 before one was selected.
+    pub sub_band: u8,
+    /// [`RegionChoice`](crate::region::RegionChoice) ID of the region the session runs in,
+    /// [`NO_REGION`] before one was set.
+    pub region: u8,
+}
+impl Header {
+    pub const EMPTY: Header = Header { sub_band: 0, region: NO_REGION };
+}
+const NO_REGION: u8 = 0xFF;
+
+pub fn write_header(page: &mut [u8], header: &Header) {
+    page[..MAGIC.len()].copy_from_slice(&MAGIC);
+    page[MAGIC.len()] = SCHEMA_VERSION;
+    page[VERSION_HEADER_SIZE] = header.sub_band;
+    page[VERSION_HEADER_SIZE + 1] = header.region;
+}
+
+/// The header of a page in the current schema.
+pub fn header(page: &[u8]) -> Header {
+    Header { sub_band: page[VERSION_HEADER_SIZE], region: page[VERSION_HEADER_SIZE + 1] }
+}
+
+/// Version 0 is the page before it had a header.
+fn version(page: &[u8]) -> u8 {
+    match page {
+        [m0, m1, version, ..] if [*m0, *m1] == MAGIC => *version,
+        _ => 0,
+    }
+}
+
+/// Brings a page read from flash up to [`SCHEMA_VERSION`], returning whether it changed.
+pub fn migrate(page: &mut [u8]) -> Result<bool, CodecError> {
+    if page.iter().all(|b| *b == 0xFF) {
+        // erased, nothing was ever saved
+        return Ok(false);
+    }
+    let from = version(page);
+    if from > SCHEMA_VERSION {
+        defmt::error!("session schema {} is newer than {}", from, SCHEMA_VERSION);
+        return Err(CodecError);
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        defmt::info!("migrating session from schema {}", version);
+        migration(page)?;
+    }
+    Ok(from < SCHEMA_VERSION)
+}
+
+/// Makes room for the header, the encoding itself is unchanged.
+fn from_unversioned(page: &mut [u8]) -> Result<(), CodecError> {
+    let len = page.len();
+    page.copy_within(..len - VERSION_HEADER_SIZE, VERSION_HEADER_SIZE);
+    page[..MAGIC.len()].copy_from_slice(&MAGIC);
+    page[MAGIC.len()] = 1;
+    Ok(())
+}
+
+/// Adds the sub-band, none was selected with the session yet.
+fn with_sub_band(page: &mut [u8]) -> Result<(), CodecError> {
+    let len = page.len();
+    page.copy_within(VERSION_HEADER_SIZE..len - 1, VERSION_HEADER_SIZE + 1);
+    page[MAGIC.len()] = 2;
+    page[VERSION_HEADER_SIZE] = Header::EMPTY.sub_band;
+    Ok(())
+}
+
+/// Adds the region, left unset: older sessions were written by a build for a single region,
+/// which [`crate::regulatory`] has recorded.
+fn with_region(page: &mut [u8]) -> Result<(), CodecError> {
+    let len = page.len();
+    page.copy_within(VERSION_HEADER_SIZE + 1..len - 1, VERSION_HEADER_SIZE + 2);
+    page[MAGIC.len()] = 3;
+    page[VERSION_HEADER_SIZE + 1] = Header::EMPTY.region;
+    Ok(())
+}
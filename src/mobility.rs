@@ -0,0 +1,60 @@
+//! Mobility mode for devices on the move, following the LoRaWAN recommendation to turn
+//! ADR off: the network can't track a link that changes between uplinks, so a mid data
+//! rate is fixed and every uplink is repeated. Once no motion has been reported for a
+//! while the device is considered stationary and ADR takes over again.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::preset::DataRatePolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MobilityConfig {
+    pub data_rate: u8,
+    /// NbTrans, how often each uplink is sent
+    pub nb_trans: u8,
+    /// Time without motion before ADR is used again, `None` without a motion sensor.
+    pub stationary_after: Option<Duration>,
+}
+
+static MOBILE: AtomicBool = AtomicBool::new(false);
+static LAST_MOTION: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Starts out mobile, as trackers are usually moving when switched on.
+pub fn start() {
+    motion();
+}
+
+/// Reported by the motion sensor, returns whether the device was stationary.
+pub fn motion() -> bool {
+    LAST_MOTION.lock(|last| last.set(Some(Instant::now())));
+    !MOBILE.swap(true, Ordering::Relaxed)
+}
+
+pub fn is_mobile() -> bool {
+    MOBILE.load(Ordering::Relaxed)
+}
+
+/// Returns `true` when the device just became stationary.
+pub fn update(config: &MobilityConfig) -> bool {
+    let Some(stationary_after) = config.stationary_after else {
+        return false;
+    };
+    let still = LAST_MOTION.lock(Cell::get).is_some_and(|last| last.elapsed() >= stationary_after);
+    still && MOBILE.swap(false, Ordering::Relaxed)
+}
+
+impl MobilityConfig {
+    pub fn data_rate_policy(&self, stationary: DataRatePolicy) -> DataRatePolicy {
+        if is_mobile() {
+            DataRatePolicy::Fixed(self.data_rate)
+        } else {
+            stationary
+        }
+    }
+}
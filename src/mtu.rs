@@ -0,0 +1,101 @@
+//! Payload size negotiation with backends that can't take the largest frames, e.g. when
+//! forwarding over a constrained link. After every join the device reports on
+//! [`MTU_PORT`] what it can send as `[version][preferred batch samples][max payload DR0]
+//! [max payload DR1]...`, one byte each, and the server may answer there with caps as
+//! `[max payload][max batch samples]`, 0 leaving either uncapped. An empty downlink lifts
+//! both.
+//!
+//! The caps apply to every uplink through [`Mtu::max_payload_size`], batches also stop
+//! at [`Mtu::batch_size`].
+
+use heapless::Vec;
+
+use crate::payload::{BATCH_HEADER_SIZE, BATCH_ITEM_SIZE, STATUS_SIZE};
+use crate::region;
+use crate::schema;
+
+pub const MTU_PORT: u8 = schema::MTU.port;
+const VERSION: u8 = 1;
+/// Data rates reported, the first 16 cover every region.
+const MAX_DATA_RATES: u8 = 16;
+pub const MAX_REPORT_SIZE: usize = 2 + MAX_DATA_RATES as usize;
+const CAPS_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MtuConfig {
+    /// Samples the device would rather put in a batch, fewer get through to the
+    /// application sooner.
+    pub preferred_batch: u8,
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub struct InvalidCaps;
+
+pub struct Mtu {
+    config: MtuConfig,
+    max_payload: Option<u8>,
+    max_batch: Option<u8>,
+    report_due: bool,
+}
+impl Mtu {
+    pub fn new(config: MtuConfig) -> Self {
+        Self { config, max_payload: None, max_batch: None, report_due: false }
+    }
+
+    pub fn joined(&mut self) {
+        self.report_due = true;
+    }
+
+    /// The report for [`MTU_PORT`] once after every join.
+    pub fn take_report(&mut self) -> Option<Vec<u8, MAX_REPORT_SIZE>> {
+        if !core::mem::take(&mut self.report_due) {
+            return None;
+        }
+        let mut report = Vec::new();
+        let _ = report.extend_from_slice(&[VERSION, self.config.preferred_batch]);
+        for data_rate in 0..MAX_DATA_RATES {
+            // rates the region doesn't define report 0
+            let size =
+                region::data_rate(data_rate).map_or(0, |_| region::max_payload_size(data_rate));
+            let _ = report.push(size.min(u8::MAX as usize) as u8);
+        }
+        Some(report)
+    }
+
+    pub fn configure(&mut self, downlink: &[u8]) -> Result<(), InvalidCaps> {
+        if downlink.is_empty() {
+            defmt::info!("payload caps lifted");
+            self.max_payload = None;
+            self.max_batch = None;
+            return Ok(());
+        }
+        let &[max_payload, max_batch] =
+            <&[u8; CAPS_SIZE]>::try_from(downlink).map_err(|_| InvalidCaps)?;
+        // uplinks of a fixed size can't be split, the largest has to fit
+        if max_payload != 0 && (max_payload as usize) < STATUS_SIZE {
+            return Err(InvalidCaps);
+        }
+        self.max_payload = (max_payload != 0).then_some(max_payload);
+        self.max_batch = (max_batch != 0).then_some(max_batch);
+        defmt::info!(
+            "payload capped at {:?} bytes, batches at {:?} samples",
+            self.max_payload,
+            self.max_batch
+        );
+        Ok(())
+    }
+
+    /// `max_payload_size` of the data rate, capped by the server.
+    pub fn max_payload_size(&self, max_payload_size: usize) -> usize {
+        self.max_payload.map_or(max_payload_size, |cap| max_payload_size.min(cap as usize))
+    }
+
+    /// Size of a batch uplink within `max_payload_size`, at most the preferred or capped
+    /// number of samples.
+    pub fn batch_size(&self, max_payload_size: usize) -> usize {
+        let samples = self
+            .max_batch
+            .map_or(self.config.preferred_batch, |cap| cap.min(self.config.preferred_batch));
+        max_payload_size.min(BATCH_HEADER_SIZE + samples as usize * BATCH_ITEM_SIZE)
+    }
+}
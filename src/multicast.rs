@@ -0,0 +1,307 @@
+//! Multicast groups as set up by the network with the remote multicast setup package
+//! (TS005) on [`MULTICAST_PORT`]. A group is kept in the journal as its McAddr, McKey and
+//! frame counter range, and its session keys are derived again at boot.
+//!
+//! Downlinks from the radio buffer with the McAddr of a group are checked and decrypted
+//! here rather than by the MAC, which only knows the unicast session, and published on
+//! [`PACKET_BUS_MULTICAST`] apart from unicast traffic. The stack has no Class C, so
+//! multicast is limited to the Class A windows the device opens anyway, and the Class B
+//! ping slots it opens with its own parameters. McClassCSessionReq and McClassBSessionReq
+//! are answered with the frequency and data rate errors, the server has to send to the
+//! groups in those windows itself.
+//!
+//! The McKey is unwrapped with a McKEKey derived from the AppKey as GenAppKey, as for
+//! LoRaWAN 1.0.x devices.
+
+use core::cell::RefCell;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use heapless::Vec;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::frames::MAX_FRAME_SIZE;
+use crate::journal::RecordKey;
+
+pub const MULTICAST_PORT: u8 = 200;
+pub const MAX_GROUPS: usize = 4;
+/// Largest answer to a downlink of commands.
+pub const MAX_ANSWER_SIZE: usize = 16;
+const RECORDS: [RecordKey; MAX_GROUPS] =
+    [RecordKey::Multicast0, RecordKey::Multicast1, RecordKey::Multicast2, RecordKey::Multicast3];
+const RECORD_SIZE: usize = 28;
+const PACKAGE_IDENTIFIER: u8 = 2;
+const PACKAGE_VERSION: u8 = 1;
+const PACKAGE_VERSION_REQ: u8 = 0x00;
+const GROUP_SETUP_REQ: u8 = 0x02;
+const GROUP_DELETE_REQ: u8 = 0x03;
+const MC_CLASS_C_SESSION_REQ: u8 = 0x04;
+const MC_CLASS_B_SESSION_REQ: u8 = 0x05;
+const GROUP_SETUP_SIZE: usize = 29;
+const SESSION_SIZE: usize = 10;
+/// Answer bit for a group ID that can't be set up or isn't defined.
+const ID_ERROR: u8 = 1 << 2;
+/// Session answer bits.
+const DATA_RATE_ERROR: u8 = 1 << 2;
+const FREQUENCY_ERROR: u8 = 1 << 3;
+const GROUP_UNDEFINED: u8 = 1 << 4;
+const UNCONFIRMED_DATA_DOWN: u8 = 3;
+const MIC_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum MulticastError {
+    Invalid,
+    Store(NonVolatileStoreError),
+}
+
+/// A multicast downlink, decrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticastMessage {
+    pub group: u8,
+    pub fcnt: u32,
+    pub fport: u8,
+    pub payload: Vec<u8, MAX_FRAME_SIZE>,
+}
+
+/// Downlinks to a multicast group of this device, for the application to take.
+pub static PACKET_BUS_MULTICAST: Channel<CriticalSectionRawMutex, MulticastMessage, 2> =
+    Channel::new();
+
+#[derive(Clone, Copy)]
+struct Group {
+    mc_addr: u32,
+    mc_key: [u8; 16],
+    app_s_key: [u8; 16],
+    nwk_s_key: [u8; 16],
+    /// Lowest frame counter still accepted.
+    fcnt: u32,
+    max_fcnt: u32,
+    dirty: bool,
+}
+impl Group {
+    fn new(mc_addr: u32, mc_key: [u8; 16], fcnt: u32, max_fcnt: u32) -> Self {
+        let aes = Aes128::new(GenericArray::from_slice(&mc_key));
+        let derive = |prefix: u8| {
+            let mut block = [0; 16];
+            block[0] = prefix;
+            block[1..5].copy_from_slice(&mc_addr.to_le_bytes());
+            aes.encrypt_block(GenericArray::from_mut_slice(&mut block));
+            block
+        };
+        Self {
+            mc_addr,
+            mc_key,
+            app_s_key: derive(0x01),
+            nwk_s_key: derive(0x02),
+            fcnt,
+            max_fcnt,
+            dirty: false,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[..4].copy_from_slice(&self.mc_addr.to_le_bytes());
+        buf[4..20].copy_from_slice(&self.mc_key);
+        buf[20..24].copy_from_slice(&self.fcnt.to_le_bytes());
+        buf[24..].copy_from_slice(&self.max_fcnt.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; RECORD_SIZE]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Self::new(word(0), buf[4..20].try_into().unwrap(), word(20), word(24))
+    }
+
+    /// Checks a frame to this group, returning its frame counter and decrypted FRMPayload.
+    fn open(&mut self, phy: &[u8]) -> Option<(u32, u8, Vec<u8, MAX_FRAME_SIZE>)> {
+        let (message, mic) = phy.split_last_chunk::<MIC_SIZE>()?;
+        // no FOpts, ACK or FPending in multicast frames, and an FPort for the application
+        if message.len() < 9 || phy[0] >> 5 != UNCONFIRMED_DATA_DOWN || phy[5] & 0x3F != 0 {
+            return None;
+        }
+        let fcnt16 = u16::from_le_bytes([phy[6], phy[7]]) as u32;
+        let mut fcnt = (self.fcnt & !0xFFFF) | fcnt16;
+        if fcnt < self.fcnt {
+            fcnt = fcnt.checked_add(0x1_0000)?;
+        }
+        if fcnt > self.max_fcnt {
+            return None;
+        }
+        let block = |first: u8, last: u8| {
+            let mut block = [0; 16];
+            block[0] = first;
+            block[5] = 1; // downlink
+            block[6..10].copy_from_slice(&self.mc_addr.to_le_bytes());
+            block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+            block[15] = last;
+            block
+        };
+        let mut cmac = <Cmac<Aes128> as Mac>::new_from_slice(&self.nwk_s_key).unwrap();
+        cmac.update(&block(0x49, message.len() as u8));
+        cmac.update(message);
+        if cmac.finalize().into_bytes()[..MIC_SIZE] != *mic {
+            return None;
+        }
+        let fport = message[8];
+        if fport == 0 {
+            return None;
+        }
+        let mut payload = Vec::from_slice(&message[9..]).ok()?;
+        let aes = Aes128::new(GenericArray::from_slice(&self.app_s_key));
+        for (i, chunk) in payload.chunks_mut(16).enumerate() {
+            let mut keystream = block(0x01, i as u8 + 1);
+            aes.encrypt_block(GenericArray::from_mut_slice(&mut keystream));
+            chunk.iter_mut().zip(keystream).for_each(|(byte, key)| *byte ^= key);
+        }
+        self.fcnt = fcnt + 1;
+        self.dirty = true;
+        Some((fcnt, fport, payload))
+    }
+}
+
+static GROUPS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Group>; MAX_GROUPS]>> =
+    Mutex::new(RefCell::new([None; MAX_GROUPS]));
+
+fn with_groups<R>(f: impl FnOnce(&mut [Option<Group>; MAX_GROUPS]) -> R) -> R {
+    GROUPS.lock(|groups| f(&mut groups.borrow_mut()))
+}
+
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) {
+    for (id, key) in RECORDS.iter().enumerate() {
+        let mut buf = [0; RECORD_SIZE];
+        if let Ok(RECORD_SIZE) = store.read_record(*key, &mut buf) {
+            let group = Group::from_bytes(&buf);
+            defmt::info!("multicast group {} McAddr {=u32:08X}", id, group.mc_addr);
+            with_groups(|groups| groups[id] = Some(group));
+        }
+    }
+}
+
+/// Persists the frame counters of groups that received since the last call.
+pub fn save(store: &mut DeviceNonVolatileStore<'_>) {
+    for (id, key) in RECORDS.iter().enumerate() {
+        let Some(group) = with_groups(|groups| {
+            let group = groups[id].as_mut()?;
+            core::mem::take(&mut group.dirty).then_some(*group)
+        }) else {
+            continue;
+        };
+        if let Err(e) = store.write_record(*key, &group.to_bytes()) {
+            defmt::error!("multicast group {} not saved {:?}", id, e);
+        }
+    }
+}
+
+/// From a downlink read from the radio, returns whether it was addressed to a group.
+pub fn received(dev_addr: u32, phy: &[u8]) -> bool {
+    let Some(message) = with_groups(|groups| {
+        let (id, group) = groups.iter_mut().enumerate().find_map(|(id, group)| {
+            group.as_mut().filter(|group| group.mc_addr == dev_addr).map(|group| (id, group))
+        })?;
+        let opened = group.open(phy);
+        Some(opened.map(|(fcnt, fport, payload)| MulticastMessage {
+            group: id as u8,
+            fcnt,
+            fport,
+            payload,
+        }))
+    }) else {
+        return false;
+    };
+    match message {
+        Some(message) => {
+            if PACKET_BUS_MULTICAST.try_send(message).is_err() {
+                defmt::warn!("multicast downlink dropped");
+            }
+        }
+        None => defmt::warn!("multicast downlink to {=u32:08X} rejected", dev_addr),
+    }
+    true
+}
+
+/// Handles the commands of a downlink on [`MULTICAST_PORT`], returning their answers.
+pub fn command(
+    store: &mut DeviceNonVolatileStore<'_>,
+    downlink: &[u8],
+    app_key: &[u8; 16],
+) -> Result<Vec<u8, MAX_ANSWER_SIZE>, MulticastError> {
+    let mut answer = Vec::new();
+    let mut rest = downlink;
+    while let Some((&cid, args)) = rest.split_first() {
+        let used = match cid {
+            PACKAGE_VERSION_REQ => {
+                let _ = answer.extend_from_slice(&[cid, PACKAGE_IDENTIFIER, PACKAGE_VERSION]);
+                0
+            }
+            GROUP_SETUP_REQ if args.len() >= GROUP_SETUP_SIZE => {
+                let id = args[0] & 0x03;
+                let status = setup(store, id, &args[..GROUP_SETUP_SIZE], app_key)?;
+                let _ = answer.extend_from_slice(&[cid, id | status]);
+                GROUP_SETUP_SIZE
+            }
+            GROUP_DELETE_REQ if !args.is_empty() => {
+                let id = args[0] & 0x03;
+                let status = delete(store, id)?;
+                let _ = answer.extend_from_slice(&[cid, id | status]);
+                1
+            }
+            MC_CLASS_C_SESSION_REQ | MC_CLASS_B_SESSION_REQ if args.len() >= SESSION_SIZE => {
+                let id = args[0] & 0x03;
+                let status = if with_groups(|groups| groups[id as usize].is_none()) {
+                    GROUP_UNDEFINED
+                } else {
+                    defmt::warn!("multicast group {} session refused, Class A only", id);
+                    FREQUENCY_ERROR | DATA_RATE_ERROR
+                };
+                let _ = answer.extend_from_slice(&[cid, id | status]);
+                SESSION_SIZE
+            }
+            _ => return Err(MulticastError::Invalid),
+        };
+        rest = &args[used..];
+    }
+    Ok(answer)
+}
+
+fn setup(
+    store: &mut DeviceNonVolatileStore<'_>,
+    id: u8,
+    args: &[u8],
+    app_key: &[u8; 16],
+) -> Result<u8, MulticastError> {
+    let word = |i: usize| u32::from_le_bytes([args[i], args[i + 1], args[i + 2], args[i + 3]]);
+    let (mc_addr, min_fcnt, max_fcnt) = (word(1), word(21), word(25));
+    if min_fcnt > max_fcnt {
+        return Ok(ID_ERROR);
+    }
+    let encrypt = |key: &[u8; 16]| {
+        let mut block = [0; 16];
+        Aes128::new(GenericArray::from_slice(key))
+            .encrypt_block(GenericArray::from_mut_slice(&mut block));
+        block
+    };
+    let mc_ke_key = encrypt(&encrypt(app_key));
+    let mut mc_key: [u8; 16] = args[5..21].try_into().unwrap();
+    Aes128::new(GenericArray::from_slice(&mc_ke_key))
+        .decrypt_block(GenericArray::from_mut_slice(&mut mc_key));
+    let group = Group::new(mc_addr, mc_key, min_fcnt, max_fcnt);
+    store.write_record(RECORDS[id as usize], &group.to_bytes()).map_err(MulticastError::Store)?;
+    defmt::info!("multicast group {} set up, McAddr {=u32:08X}", id, mc_addr);
+    with_groups(|groups| groups[id as usize] = Some(group));
+    Ok(0)
+}
+
+fn delete(store: &mut DeviceNonVolatileStore<'_>, id: u8) -> Result<u8, MulticastError> {
+    if with_groups(|groups| groups[id as usize].take()).is_none() {
+        return Ok(ID_ERROR);
+    }
+    store.write_record(RECORDS[id as usize], &[]).map_err(MulticastError::Store)?;
+    defmt::info!("multicast group {} deleted", id);
+    Ok(0)
+}
@@ -0,0 +1,87 @@
+//! Self-provisioning for backend onboarding automation: after its first join the device
+//! reports what it is, so that its device profile can be set up without anyone typing
+//! it in. The report is `[version][firmware version u32][region][hardware revision]
+//! [capabilities u16]` as in [`schema::ONBOARDING`], sent confirmed after every join until
+//! it is acknowledged, which is recorded in the journal.
+
+use crate::device::DeviceNonVolatileStore;
+use crate::firmware::IMAGE_INFO;
+use crate::journal::RecordKey;
+use crate::region;
+use crate::schema;
+
+pub const ONBOARDING_PORT: u8 = schema::ONBOARDING.port;
+pub const REPORT_SIZE: usize = schema::ONBOARDING.header_size();
+const VERSION: u8 = 1;
+
+/// Capability flags.
+pub const CLASS_B: u16 = 1 << 0;
+pub const FUOTA: u16 = 1 << 1;
+pub const E2E: u16 = 1 << 2;
+// 1 << 3 was ABP, which the lorawan crate can't be activated with
+pub const SPOOL: u16 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Report {
+    /// Read from the strap pins, see [`LoraDevice::hardware_revision`].
+    ///
+    /// [`LoraDevice::hardware_revision`]: crate::device::LoraDevice::hardware_revision
+    pub hardware_revision: u8,
+    /// Those the build doesn't decide on by its features.
+    pub capabilities: u16,
+}
+impl Report {
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut capabilities = self.capabilities;
+        if cfg!(feature = "fuota") {
+            capabilities |= FUOTA;
+        }
+        if cfg!(feature = "e2e") {
+            capabilities |= E2E;
+        }
+        let mut buf = [0; REPORT_SIZE];
+        buf[0] = VERSION;
+        buf[1..5].copy_from_slice(&IMAGE_INFO.version.to_be_bytes());
+        buf[5] = region::current() as u8;
+        buf[6] = self.hardware_revision;
+        buf[7..].copy_from_slice(&capabilities.to_be_bytes());
+        buf
+    }
+}
+
+pub struct Onboarding {
+    /// `None` once acknowledged.
+    report: Option<Report>,
+    due: bool,
+}
+impl Onboarding {
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>, report: Report) -> Self {
+        let mut buf = [0];
+        let reported = matches!(store.read_record(RecordKey::Onboarded, &mut buf), Ok(1));
+        Self { report: (!reported).then_some(report), due: false }
+    }
+
+    pub fn joined(&mut self) {
+        self.due = self.report.is_some();
+    }
+
+    /// The report once after every join until it is acknowledged.
+    pub fn take_report(&mut self) -> Option<[u8; REPORT_SIZE]> {
+        let report = self.report.filter(|_| self.due)?;
+        self.due = false;
+        defmt::info!("onboarding {:?}", report);
+        Some(report.encode())
+    }
+
+    /// After the report was sent, it's not sent again once acknowledged.
+    pub fn sent(&mut self, store: &mut DeviceNonVolatileStore<'_>, acked: bool) {
+        if !acked {
+            defmt::warn!("onboarding report not acknowledged, sent again after the next join");
+            return;
+        }
+        self.report = None;
+        if let Err(e) = store.write_record(RecordKey::Onboarded, &[1]) {
+            defmt::error!("onboarding not saved {:?}", e);
+        }
+    }
+}
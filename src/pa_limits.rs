@@ -0,0 +1,206 @@
+//! What the PA of this board can actually do, which on some modules is well short of what
+//! the region allows: the matching network of the high power PA is tuned for one band and
+//! long transmissions at full power overheat it. [`BOARD_PA_LIMITS`] caps the power by PA,
+//! frequency and spreading factor, and a spreading factor and power that made the PA fail
+//! to ramp up is blacklisted in the journal, so that neither a LinkADRReq nor a test
+//! command gets the radio back into it.
+//!
+//! The caps are applied to SetTxParams in [`crate::iv::SubghzSpiDevice`] like the legal
+//! limit. A LinkADRReq for more than [`band_limit`] is NACKed through
+//! [`crate::link_adr`], in the answer rewritten by [`crate::uplink_edit`].
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::RecordKey;
+use crate::radio_telemetry::PA_RAMP_ERROR;
+use crate::region;
+use crate::regulatory;
+
+/// Blacklisted combinations kept, one per spreading factor.
+const MAX_BLACKLISTED: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PaLimit {
+    pub high_power_pa: bool,
+    /// Hz, inclusive
+    pub band: (u32, u32),
+    /// inclusive
+    pub spreading_factors: (u8, u8),
+    /// dBm
+    pub max_tx_power: i8,
+}
+impl PaLimit {
+    const fn applies(&self, high_power_pa: bool, frequency: u32, spreading_factor: u8) -> bool {
+        self.high_power_pa == high_power_pa
+            && frequency >= self.band.0
+            && frequency <= self.band.1
+            && spreading_factor >= self.spreading_factors.0
+            && spreading_factor <= self.spreading_factors.1
+    }
+}
+
+/// Capability matrix of the board, combinations not listed are limited by the region only.
+pub const BOARD_PA_LIMITS: &[PaLimit] = &[
+    PaLimit {
+        high_power_pa: false,
+        band: (150_000_000, 960_000_000),
+        spreading_factors: (5, 12),
+        max_tx_power: 15,
+    },
+    // the high power matching network is tuned for 868/915 MHz
+    PaLimit {
+        high_power_pa: true,
+        band: (150_000_000, 700_000_000),
+        spreading_factors: (5, 12),
+        max_tx_power: 17,
+    },
+    // seconds long transmissions at +22 dBm overheat the PA
+    PaLimit {
+        high_power_pa: true,
+        band: (700_000_000, 960_000_000),
+        spreading_factors: (11, 12),
+        max_tx_power: 20,
+    },
+];
+
+struct State {
+    high_power_pa: bool,
+    spreading_factor: u8,
+    /// Spreading factor and power of the last SetTxParams.
+    last_tx: Option<(u8, i8)>,
+    /// Spreading factors with the lowest power the PA failed at.
+    blacklist: Vec<(u8, i8), MAX_BLACKLISTED>,
+    dirty: bool,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    high_power_pa: true,
+    spreading_factor: 0,
+    last_tx: None,
+    blacklist: Vec::new(),
+    dirty: false,
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.lock(|state| f(&mut state.borrow_mut()))
+}
+
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) {
+    let mut buf = [0; 2 * MAX_BLACKLISTED];
+    let Ok(len) = store.read_record(RecordKey::PaBlacklist, &mut buf) else {
+        return;
+    };
+    with_state(|state| {
+        for entry in buf[..len].chunks_exact(2) {
+            let _ = state.blacklist.push((entry[0], entry[1] as i8));
+        }
+        if !state.blacklist.is_empty() {
+            defmt::warn!("PA blacklist {:?}", state.blacklist.as_slice());
+        }
+    })
+}
+
+/// Persists combinations blacklisted since the last call.
+pub fn save(store: &mut DeviceNonVolatileStore<'_>) {
+    let Some(buf) = with_state(|state| {
+        core::mem::take(&mut state.dirty).then(|| {
+            let mut buf: Vec<u8, { 2 * MAX_BLACKLISTED }> = Vec::new();
+            for (spreading_factor, tx_power) in &state.blacklist {
+                let _ = buf.extend_from_slice(&[*spreading_factor, *tx_power as u8]);
+            }
+            buf
+        })
+    }) else {
+        return;
+    };
+    if let Err(e) = store.write_record(RecordKey::PaBlacklist, &buf) {
+        defmt::error!("PA blacklist not saved {:?}", e);
+    }
+}
+
+/// From a SetPaConfig command.
+pub fn set_high_power_pa(high_power_pa: bool) {
+    with_state(|state| state.high_power_pa = high_power_pa)
+}
+
+/// From a SetModulationParams command, the bit rate for GFSK which no limit matches.
+pub fn set_spreading_factor(spreading_factor: u8) {
+    with_state(|state| state.spreading_factor = spreading_factor)
+}
+
+fn max_tx_power(state: &State, frequency: u32, spreading_factor: u8) -> i8 {
+    let board = BOARD_PA_LIMITS
+        .iter()
+        .filter(|limit| limit.applies(state.high_power_pa, frequency, spreading_factor))
+        .map(|limit| limit.max_tx_power);
+    let blacklisted = state
+        .blacklist
+        .iter()
+        .filter(|(sf, _)| *sf == spreading_factor)
+        .map(|(_, tx_power)| tx_power - 1);
+    board.chain(blacklisted).fold(region::current().max_tx_power(), i8::min)
+}
+
+/// Caps the power of a SetTxParams command at what the PA does on the current channel.
+pub fn limit(tx_power: i8) -> i8 {
+    let frequency = regulatory::frequency();
+    with_state(|state| {
+        let spreading_factor = state.spreading_factor;
+        let max = max_tx_power(state, frequency, spreading_factor);
+        if tx_power > max {
+            defmt::warn!(
+                "TX power {} dBm above the PA's {} dBm at SF{}",
+                tx_power,
+                max,
+                spreading_factor
+            );
+        }
+        let tx_power = tx_power.min(max);
+        state.last_tx = Some((spreading_factor, tx_power));
+        tx_power
+    })
+}
+
+/// From GetDeviceErrors after a TX, blacklists what the PA just failed at.
+pub fn device_errors(errors: u16) {
+    if errors & PA_RAMP_ERROR == 0 {
+        return;
+    }
+    with_state(|state| {
+        let Some((spreading_factor, tx_power)) = state.last_tx else {
+            return;
+        };
+        defmt::warn!("blacklisting {} dBm at SF{}", tx_power, spreading_factor);
+        match state.blacklist.iter_mut().find(|(sf, _)| *sf == spreading_factor) {
+            Some(entry) => entry.1 = entry.1.min(tx_power),
+            None => {
+                if state.blacklist.push((spreading_factor, tx_power)).is_err() {
+                    return;
+                }
+            }
+        }
+        state.dirty = true;
+    })
+}
+
+/// Power the board does at any spreading factor in the region's band, for the MAC's TX power.
+pub fn band_limit() -> i8 {
+    let tx_band = region::current().tx_band();
+    with_state(|state| {
+        let board = BOARD_PA_LIMITS
+            .iter()
+            .filter(|limit| {
+                limit.high_power_pa == state.high_power_pa
+                    && limit.band.0 <= tx_band.1
+                    && limit.band.1 >= tx_band.0
+            })
+            .map(|limit| limit.max_tx_power);
+        let blacklisted = state.blacklist.iter().map(|(_, tx_power)| tx_power - 1);
+        board.chain(blacklisted).fold(region::current().max_tx_power(), i8::min)
+    })
+}
@@ -0,0 +1,303 @@
+//! Uplinks queued by application tasks on [`PACKET_BUS_UPLINK`], sent by the main loop
+//! between its own traffic. Each packet asks for a confirmed uplink or not, and
+//! [`Uplinks::send`] hands back a [`Token`] for its [`Outcome`]: whether the network
+//! acknowledged it, counting the retransmissions its port's delivery policy asks for, or
+//! why it never went out.
+//!
+//! A packet may ask for a data rate of its own, e.g. for range tests or a payload too
+//! large for the data rate ADR picked, which is checked against the region as it is
+//! queued and only applies to that uplink.
+//!
+//! Every packet holds one of [`SLOTS`] report slots until its outcome was taken or its
+//! token dropped, and [`Uplinks::send`] waits for a free one, so an application sending
+//! faster than the network allows is slowed down instead of losing packets.
+//!
+//! Downlinks on ports the firmware doesn't handle itself go the other way on
+//! [`PACKET_BUS_APPLICATION`], with the metadata of their reception.
+//!
+//! Neither direction loses a packet without it showing in [`stats`].
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::link;
+use crate::region::{self, MAX_PAYLOAD_SIZE};
+use crate::rx_stats::RxWindow;
+
+pub const SLOTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Outcome {
+    /// Sent unconfirmed.
+    Sent,
+    Acked,
+    /// Confirmed, but no acknowledgement after the last retransmission.
+    NotAcked,
+    /// The MAC or the radio failed to send it.
+    Failed,
+    /// Dropped before it went out, e.g. by the pre-uplink hook or the dwell time.
+    Dropped,
+    /// Larger than the data rate allows.
+    TooLarge,
+    /// No airtime left in the duty cycle window.
+    NoAirtime,
+}
+impl Outcome {
+    pub fn of(sent: bool, confirmed: bool, acked: bool) -> Self {
+        match (sent, confirmed, acked) {
+            (false, _, _) => Outcome::Failed,
+            (true, false, _) => Outcome::Sent,
+            (true, true, true) => Outcome::Acked,
+            (true, true, false) => Outcome::NotAcked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum QueueError {
+    /// FPort 0 and 224 and up are the MAC's and the test protocol's, [`link::POLL_PORT`]
+    /// is the firmware's.
+    InvalidPort,
+    TooLarge,
+    /// Not an uplink data rate of the region.
+    InvalidDataRate,
+}
+
+/// Data rate of a single uplink in place of the one ADR or the profile picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DataRateOverride {
+    Fixed(u8),
+    /// The fastest the data rate policy allows.
+    Fastest,
+}
+
+/// States of a report slot.
+const FREE: u8 = 0;
+const QUEUED: u8 = 1;
+const RESOLVED: u8 = 2;
+/// The token was dropped before the outcome was known.
+const ABANDONED: u8 = 3;
+
+/// Reports the outcome of a packet when dropped, [`Outcome::Dropped`] unless resolved.
+pub struct Ticket {
+    slot: usize,
+    outcome: Outcome,
+}
+impl Ticket {
+    pub fn resolve(mut self, outcome: Outcome) {
+        self.outcome = outcome;
+    }
+}
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let counter = match self.outcome {
+            Outcome::Sent | Outcome::Acked | Outcome::NotAcked => &DELIVERED,
+            Outcome::Failed | Outcome::Dropped | Outcome::TooLarge | Outcome::NoAirtime => &DROPPED,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        QUEUED_AT.lock(|queued_at| {
+            let mut at = queued_at.get();
+            at[self.slot] = None;
+            queued_at.set(at);
+        });
+        REPORTS[self.slot].signal(self.outcome);
+        let state = &STATES[self.slot];
+        if state.compare_exchange(QUEUED, RESOLVED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            release(self.slot);
+        }
+    }
+}
+
+/// The outcome of a queued packet, [`Token::outcome`] waits for it.
+pub struct Token {
+    slot: usize,
+}
+impl Token {
+    /// Waits for the outcome, the slot is released as the token is dropped.
+    pub async fn outcome(self) -> Outcome {
+        REPORTS[self.slot].wait().await
+    }
+}
+impl Drop for Token {
+    fn drop(&mut self) {
+        let state = &STATES[self.slot];
+        // not resolved yet, the ticket releases the slot instead
+        if state.compare_exchange(QUEUED, ABANDONED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            release(self.slot);
+        }
+    }
+}
+
+/// Queues uplinks for application tasks.
+#[derive(Debug, Clone, Copy)]
+pub struct Uplinks(());
+impl Uplinks {
+    /// Queues an uplink as soon as a slot is free, the token tells when it went out.
+    pub async fn send(
+        &self,
+        fport: u8,
+        payload: &[u8],
+        confirmed: bool,
+    ) -> Result<Token, QueueError> {
+        self.send_at(fport, payload, confirmed, None).await
+    }
+
+    /// Like [`Uplinks::send`], on the data rate asked for rather than the current one.
+    pub async fn send_at(
+        &self,
+        fport: u8,
+        payload: &[u8],
+        confirmed: bool,
+        data_rate: Option<DataRateOverride>,
+    ) -> Result<Token, QueueError> {
+        if fport == 0 || fport == link::POLL_PORT || fport >= 224 {
+            return Err(QueueError::InvalidPort);
+        }
+        if let Some(DataRateOverride::Fixed(data_rate)) = data_rate {
+            if !region::uplink_data_rate(data_rate) {
+                return Err(QueueError::InvalidDataRate);
+            }
+        }
+        let payload = Vec::from_slice(payload).map_err(|_| QueueError::TooLarge)?;
+        let slot = loop {
+            if let Some(slot) = claim() {
+                break slot;
+            }
+            RELEASED.receive().await;
+        };
+        REPORTS[slot].reset();
+        ENQUEUED.fetch_add(1, Ordering::Relaxed);
+        QUEUED_AT.lock(|queued_at| {
+            let mut at = queued_at.get();
+            at[slot] = Some(Instant::now());
+            queued_at.set(at);
+        });
+        let ticket = Ticket { slot, outcome: Outcome::Dropped };
+        // a slot per place in the queue, there is always room
+        let _ = PACKET_BUS_UPLINK.try_send(QueuedUplink {
+            fport,
+            payload,
+            confirmed,
+            data_rate,
+            ticket,
+        });
+        WAKE.signal(());
+        Ok(Token { slot })
+    }
+}
+
+pub fn uplinks() -> Uplinks {
+    Uplinks(())
+}
+
+pub struct QueuedUplink {
+    pub fport: u8,
+    pub payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+    pub confirmed: bool,
+    pub data_rate: Option<DataRateOverride>,
+    pub ticket: Ticket,
+}
+
+/// A downlink for the application, decrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownlinkMessage {
+    pub fport: u8,
+    pub payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+    pub rssi: i16,
+    pub snr: i16,
+    /// The 16 bits of FCntDown in the frame.
+    pub fcnt: u16,
+    /// `None` when the RX window it came in isn't known.
+    pub window: Option<RxWindow>,
+}
+
+/// Counted since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct QueueStats {
+    /// Uplinks queued by application tasks.
+    pub enqueued: u32,
+    /// Uplinks that went out, acknowledged or not.
+    pub delivered: u32,
+    /// Uplinks rejected, failed, or dropped before they went out.
+    pub dropped: u32,
+    /// Downlinks dropped with the application not keeping up.
+    pub downlinks_dropped: u32,
+    /// How long the oldest uplink still waiting for its outcome has been queued.
+    pub oldest_age: Option<Duration>,
+}
+
+pub static PACKET_BUS_UPLINK: Channel<CriticalSectionRawMutex, QueuedUplink, SLOTS> =
+    Channel::new();
+pub static PACKET_BUS_APPLICATION: Channel<CriticalSectionRawMutex, DownlinkMessage, 2> =
+    Channel::new();
+static REPORTS: [Signal<CriticalSectionRawMutex, Outcome>; SLOTS] =
+    [const { Signal::new() }; SLOTS];
+static STATES: [AtomicU8; SLOTS] = [const { AtomicU8::new(FREE) }; SLOTS];
+/// A slot was released, possibly stale, senders waiting for one try again.
+static RELEASED: Channel<CriticalSectionRawMutex, (), SLOTS> = Channel::new();
+static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static ENQUEUED: AtomicU32 = AtomicU32::new(0);
+static DELIVERED: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static DOWNLINKS_DROPPED: AtomicU32 = AtomicU32::new(0);
+/// When the packet of each slot in use was queued.
+static QUEUED_AT: Mutex<CriticalSectionRawMutex, Cell<[Option<Instant>; SLOTS]>> =
+    Mutex::new(Cell::new([None; SLOTS]));
+
+/// The next packet to send.
+pub fn next() -> Option<QueuedUplink> {
+    PACKET_BUS_UPLINK.try_receive().ok()
+}
+
+/// The next downlink for the application.
+pub async fn receive() -> DownlinkMessage {
+    PACKET_BUS_APPLICATION.receive().await
+}
+
+/// Hands a downlink to the application, dropped if it doesn't keep up.
+pub fn deliver(message: DownlinkMessage) {
+    let fport = message.fport;
+    if PACKET_BUS_APPLICATION.try_send(message).is_err() {
+        DOWNLINKS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        defmt::warn!("port {} downlink dropped, application queue full", fport);
+    }
+}
+
+pub fn stats() -> QueueStats {
+    let oldest = QUEUED_AT.lock(|queued_at| queued_at.get().into_iter().flatten().min());
+    QueueStats {
+        enqueued: ENQUEUED.load(Ordering::Relaxed),
+        delivered: DELIVERED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        downlinks_dropped: DOWNLINKS_DROPPED.load(Ordering::Relaxed),
+        oldest_age: oldest.map(|at| Instant::now().saturating_duration_since(at)),
+    }
+}
+
+/// Wakes the main loop for other requests of application tasks.
+pub fn wake() {
+    WAKE.signal(());
+}
+
+/// Wakes the main loop for a packet queued while it sleeps.
+pub async fn queued() {
+    WAKE.wait().await
+}
+
+fn claim() -> Option<usize> {
+    STATES.iter().position(|state| {
+        state.compare_exchange(FREE, QUEUED, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    })
+}
+
+fn release(slot: usize) {
+    STATES[slot].store(FREE, Ordering::Release);
+    let _ = RELEASED.try_send(());
+}
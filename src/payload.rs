@@ -0,0 +1,100 @@
+//! Byte layouts of the uplinks described in `schema.rs`, the firmware's types encode
+//! through these. Also included by the host side test vector generator in `tools/`, so
+//! that its fixtures are exactly what the firmware sends; keep this file free of crate
+//! dependencies other than `schema`.
+
+use crate::schema;
+
+pub const ALARM_SIZE: usize = schema::ALARM.header_size();
+pub const MOTION_SIZE: usize = schema::MOTION.header_size();
+pub const GEOFENCE_SIZE: usize = schema::GEOFENCE.header_size();
+pub const BATCH_HEADER_SIZE: usize = schema::BATCH.header_size();
+pub const BATCH_ITEM_SIZE: usize = schema::BATCH.item_size();
+pub const STATUS_SIZE: usize = schema::STATUS.header_size();
+pub const ANTENNA_SIZE: usize = schema::ANTENNA.header_size();
+pub const WAKE_SIZE: usize = schema::WAKE.header_size();
+
+pub fn alarm(id: u8, raised: bool, measurement: u8, value: i32) -> [u8; ALARM_SIZE] {
+    let value = value.to_be_bytes();
+    [id, raised as u8, measurement, value[0], value[1], value[2], value[3]]
+}
+
+pub fn motion(event: u8, timestamp: u32) -> [u8; MOTION_SIZE] {
+    let mut buf = [0; MOTION_SIZE];
+    buf[0] = event;
+    buf[1..5].copy_from_slice(&timestamp.to_be_bytes());
+    buf
+}
+
+/// Coordinates in 10^-7 degrees.
+pub fn geofence(id: u8, entered: bool, latitude: i32, longitude: i32) -> [u8; GEOFENCE_SIZE] {
+    let mut buf = [0; GEOFENCE_SIZE];
+    buf[0] = id;
+    buf[1] = entered as u8;
+    buf[2..6].copy_from_slice(&latitude.to_be_bytes());
+    buf[6..10].copy_from_slice(&longitude.to_be_bytes());
+    buf
+}
+
+pub fn batch_header(buf: &mut [u8], base_timestamp: u32, count: u8) {
+    buf[..4].copy_from_slice(&base_timestamp.to_be_bytes());
+    buf[4] = count;
+}
+
+/// `offset` seconds after the batch's base timestamp.
+pub fn batch_item(buf: &mut [u8], measurement: u8, offset: u16, value: i32) {
+    buf[0] = measurement;
+    buf[1..3].copy_from_slice(&offset.to_be_bytes());
+    buf[3..BATCH_ITEM_SIZE].copy_from_slice(&value.to_be_bytes());
+}
+
+/// The fields of [`schema::STATUS`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub boot_count: u32,
+    pub uptime: u32,
+    pub last_session: u32,
+    pub battery_permille: u16,
+    pub battery_days_left: u16,
+    pub flags: u8,
+    pub radio_temperature: i32,
+    pub radio_supply_mv: u16,
+    pub radio_errors: u16,
+    pub since_last_ack: u32,
+    pub since_last_downlink: u32,
+}
+impl Status {
+    pub fn encode(&self) -> [u8; STATUS_SIZE] {
+        let mut buf = [0; STATUS_SIZE];
+        buf[..4].copy_from_slice(&self.boot_count.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.uptime.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.last_session.to_be_bytes());
+        buf[12..14].copy_from_slice(&self.battery_permille.to_be_bytes());
+        buf[14..16].copy_from_slice(&self.battery_days_left.to_be_bytes());
+        buf[16] = self.flags;
+        buf[17..21].copy_from_slice(&self.radio_temperature.to_be_bytes());
+        buf[21..23].copy_from_slice(&self.radio_supply_mv.to_be_bytes());
+        buf[23..25].copy_from_slice(&self.radio_errors.to_be_bytes());
+        buf[25..29].copy_from_slice(&self.since_last_ack.to_be_bytes());
+        buf[29..33].copy_from_slice(&self.since_last_downlink.to_be_bytes());
+        buf
+    }
+}
+
+/// RSSIs in dBm.
+pub fn antenna(suspected: bool, reason: u8, baseline_rssi: i16, rssi: i16) -> [u8; ANTENNA_SIZE] {
+    let mut buf = [0; ANTENNA_SIZE];
+    buf[0] = suspected as u8;
+    buf[1] = reason;
+    buf[2..6].copy_from_slice(&(baseline_rssi as i32).to_be_bytes());
+    buf[6..10].copy_from_slice(&(rssi as i32).to_be_bytes());
+    buf
+}
+
+pub fn wake(slot: u8, high: bool, timestamp: u32) -> [u8; WAKE_SIZE] {
+    let mut buf = [0; WAKE_SIZE];
+    buf[0] = slot;
+    buf[1] = high as u8;
+    buf[2..6].copy_from_slice(&timestamp.to_be_bytes());
+    buf
+}
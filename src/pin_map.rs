@@ -0,0 +1,110 @@
+//! What the spare GPIOs are wired to on each hardware SKU, so that one binary serves all
+//! of them. The map is sent by downlink on [`PIN_MAP_PORT`] as `[version][function]*`
+//! followed by the first 4 bytes of an AES-CMAC over `"PINMAP" | DevEUI | map` keyed
+//! with the AppKey, persisted and applied after the device resets.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use embassy_stm32::exti::ExtiInput;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+use crate::schema;
+
+pub const PIN_MAP_PORT: u8 = schema::PIN_MAP.port;
+/// Assignable pins: PA0, PA1, PB4 and PB5.
+pub const SLOTS: usize = 4;
+const VERSION: u8 = 1;
+const DOMAIN: &[u8] = b"PINMAP";
+const MIC_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PinFunction {
+    Unused,
+    /// Counts falling edges, for meters with a dry contact output.
+    PulseInput,
+    /// Output driving a relay coil, off at boot.
+    Relay,
+    /// Edges send an uplink right away, see [`crate::wake`].
+    WakeInput,
+}
+impl PinFunction {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PinFunction::Unused),
+            1 => Some(PinFunction::PulseInput),
+            2 => Some(PinFunction::Relay),
+            3 => Some(PinFunction::WakeInput),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PinMap(pub [PinFunction; SLOTS]);
+impl PinMap {
+    pub const UNUSED: PinMap = PinMap([PinFunction::Unused; SLOTS]);
+
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>) -> Self {
+        let mut buf = [0; 1 + SLOTS];
+        match store.read_record(RecordKey::PinMap, &mut buf) {
+            Ok(len) if len == buf.len() => Self::from_bytes(&buf).unwrap_or(Self::UNUSED),
+            _ => Self::UNUSED,
+        }
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let (&version, functions) = data.split_first()?;
+        if version != VERSION || functions.len() != SLOTS {
+            return None;
+        }
+        let mut map = Self::UNUSED;
+        for (function, value) in map.0.iter_mut().zip(functions) {
+            *function = PinFunction::from_u8(*value)?;
+        }
+        Some(map)
+    }
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum PinMapError {
+    InvalidMic,
+    Invalid,
+    Store(NonVolatileStoreError),
+}
+
+/// Checks and persists a map received by downlink, it is used from the next boot.
+pub fn update(
+    store: &mut DeviceNonVolatileStore<'_>,
+    downlink: &[u8],
+    dev_eui: &[u8; 8],
+    app_key: &[u8; 16],
+) -> Result<PinMap, PinMapError> {
+    let (map, mic) = downlink.split_last_chunk::<MIC_SIZE>().ok_or(PinMapError::Invalid)?;
+    let mut cmac = <Cmac<Aes128> as Mac>::new_from_slice(app_key).unwrap();
+    cmac.update(DOMAIN);
+    cmac.update(dev_eui);
+    cmac.update(map);
+    if cmac.finalize().into_bytes()[..MIC_SIZE] != *mic {
+        return Err(PinMapError::InvalidMic);
+    }
+    let pin_map = PinMap::from_bytes(map).ok_or(PinMapError::Invalid)?;
+    store.write_record(RecordKey::PinMap, map).map_err(PinMapError::Store)?;
+    Ok(pin_map)
+}
+
+static PULSES: [AtomicU32; SLOTS] = [const { AtomicU32::new(0) }; SLOTS];
+
+#[embassy_executor::task(pool_size = SLOTS)]
+pub async fn count_pulses(slot: usize, mut input: ExtiInput<'static>) {
+    loop {
+        input.wait_for_falling_edge().await;
+        PULSES[slot].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn pulse_counts() -> [u32; SLOTS] {
+    core::array::from_fn(|slot| PULSES[slot].load(Ordering::Relaxed))
+}
@@ -0,0 +1,140 @@
+//! Class B ping slots. With the beacon locked, the device opens a short RX window in each
+//! of its `2^(7 - periodicity)` ping slots per beacon period, at an offset the network
+//! derives the same way from the beacon time and DevAddr. The network has to be given the
+//! periodicity with the device profile, it isn't announced with PingSlotInfoReq.
+//!
+//! Frames for a multicast group are opened by [`crate::multicast`] as they are read from
+//! the radio. The MAC has no entry point for frames it didn't receive in its own RX
+//! windows, so unicast frames can't be checked or decrypted and are dropped: Class B is
+//! for multicast only.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use embassy_time::{Duration, Instant, Timer};
+use lora_phy::mod_params::{Bandwidth, RadioError, RxMode, SpreadingFactor};
+
+use crate::beacon::BEACON_PERIOD;
+use crate::coding_rate;
+use crate::exclusive::ExclusiveRadio;
+use crate::frames::{self, FrameId, MAX_FRAME_SIZE};
+use crate::region;
+
+/// Start of the beacon period taken up by the beacon itself.
+const BEACON_RESERVED: Duration = Duration::from_millis(2_120);
+const SLOT_LENGTH: Duration = Duration::from_millis(30);
+/// Time to set the radio up ahead of a slot.
+const SETUP_TIME: Duration = Duration::from_millis(15);
+const PREAMBLE_SYMBOLS: u16 = 8;
+/// Symbols waited for a preamble, about 33 ms at SF9.
+const RX_SYMBOLS: u16 = 8;
+
+pub struct PingSlots {
+    periodicity: u8,
+    /// Slot offset for the beacon time it was computed for
+    offset: Option<(u32, u32)>,
+}
+impl PingSlots {
+    /// `periodicity` from 0 for 128 slots per beacon period to 7 for a single one.
+    pub const fn new(periodicity: u8) -> Self {
+        assert!(periodicity <= 7);
+        Self { periodicity, offset: None }
+    }
+
+    /// Slots of 30 ms between two ping slots.
+    fn period(&self) -> u32 {
+        1 << (5 + self.periodicity)
+    }
+
+    fn offset(&mut self, beacon_time: u32, dev_addr: u32) -> u32 {
+        if let Some((time, offset)) = self.offset {
+            if time == beacon_time {
+                return offset;
+            }
+        }
+        let mut block = [0; 16];
+        block[..4].copy_from_slice(&beacon_time.to_le_bytes());
+        block[4..8].copy_from_slice(&dev_addr.to_le_bytes());
+        let block = GenericArray::from_mut_slice(&mut block);
+        Aes128::new(&GenericArray::from([0; 16])).encrypt_block(block);
+        let offset = (block[0] as u32 + block[1] as u32 * 256) % self.period();
+        self.offset = Some((beacon_time, offset));
+        offset
+    }
+
+    /// Start of the first ping slot at or after `now`, from the last beacon's GPS time
+    /// and start.
+    pub fn next_slot(&mut self, beacon: (u32, Instant), dev_addr: u32, now: Instant) -> Instant {
+        let (mut beacon_time, mut beacon_start) = beacon;
+        if now > beacon_start {
+            let periods = ((now - beacon_start).as_ticks() / BEACON_PERIOD.as_ticks()) as u32;
+            beacon_time += periods * BEACON_PERIOD.as_secs() as u32;
+            beacon_start += BEACON_PERIOD * periods;
+        }
+        let slots = 128 >> self.periodicity;
+        loop {
+            let first =
+                beacon_start + BEACON_RESERVED + SLOT_LENGTH * self.offset(beacon_time, dev_addr);
+            for n in 0..slots {
+                let slot = first + SLOT_LENGTH * (n * self.period());
+                if slot >= now {
+                    return slot;
+                }
+            }
+            beacon_time += BEACON_PERIOD.as_secs() as u32;
+            beacon_start += BEACON_PERIOD;
+        }
+    }
+}
+
+/// Completes when it's time to set up for `slot`, never without one.
+pub async fn due(slot: Option<Instant>) {
+    match slot {
+        Some(slot) => Timer::at(slot - SETUP_TIME).await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Listens in the ping slot starting at `slot`, on the beacon's channel and SF9.
+pub async fn receive(radio: &mut ExclusiveRadio<'_, '_>, slot: Instant) -> Result<(), RadioError> {
+    let Some(beacon) = region::current().beacon() else {
+        return Ok(());
+    };
+    let radio = radio.radio();
+    let params = radio.create_modulation_params(
+        SpreadingFactor::_9,
+        Bandwidth::_125KHz,
+        coding_rate::LORAWAN,
+        beacon.frequency,
+    )?;
+    let packet = radio.create_rx_packet_params(
+        PREAMBLE_SYMBOLS,
+        false,
+        MAX_FRAME_SIZE as u8,
+        false,
+        true,
+        &params,
+    )?;
+    Timer::at(slot).await;
+    radio.prepare_for_rx(RxMode::Single(RX_SYMBOLS), &params, &packet).await?;
+    let mut buf = [0; MAX_FRAME_SIZE];
+    frames::set_outside_mac(true);
+    let res = radio.rx(&packet, &mut buf).await;
+    frames::set_outside_mac(false);
+    match res {
+        Ok((len, status)) => {
+            let dev_addr = FrameId::parse(&buf[..len as usize]).and_then(|id| id.dev_addr());
+            let own = frames::last_uplink().and_then(|id| id.dev_addr());
+            if dev_addr.is_some() && dev_addr == own {
+                defmt::warn!(
+                    "unicast ping slot downlink dropped, RSSI {} SNR {}",
+                    status.rssi,
+                    status.snr
+                );
+            }
+            Ok(())
+        }
+        Err(RadioError::ReceiveTimeout) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
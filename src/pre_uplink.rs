@@ -0,0 +1,38 @@
+//! A hook for the application, run with each uplink right before it goes to the MAC:
+//! after the data rate was settled and the uplink waited for its TDMA slot, and before
+//! it is kept for retries or sealed. It may refresh or replace the payload, so that an
+//! uplink held back by the schedule goes out with the latest values rather than those
+//! at the time it was built.
+//!
+//! The payload must stay within `max_payload_size`, an uplink the hook made larger is
+//! dropped. The FPort can't be changed.
+
+use heapless::Vec;
+
+use crate::region::MAX_PAYLOAD_SIZE;
+use crate::sensor::Sensors;
+
+pub struct Uplink<'p> {
+    /// `None` for an uplink of MAC commands only.
+    pub fport: Option<u8>,
+    pub data_rate: u8,
+    pub max_payload_size: usize,
+    pub payload: &'p mut Vec<u8, MAX_PAYLOAD_SIZE>,
+}
+
+pub type Hook = fn(&mut Sensors<'_>, &mut Uplink<'_>);
+
+/// Runs `hook` on the uplink, returning whether it is still to be sent.
+pub fn run(hook: Hook, sensors: &mut Sensors<'_>, uplink: &mut Uplink<'_>) -> bool {
+    hook(sensors, uplink);
+    if uplink.payload.len() > uplink.max_payload_size {
+        defmt::error!(
+            "port {:?} payload of {} bytes from the hook dropped, {} fit",
+            uplink.fport,
+            uplink.payload.len(),
+            uplink.max_payload_size
+        );
+        return false;
+    }
+    true
+}
@@ -0,0 +1,286 @@
+//! Configuration bundles for common deployments, so that a new deployment only has to
+//! pick the one closest to it instead of getting every setting in [`Profile`] right.
+
+use embassy_time::Duration;
+use lora_phy::mod_params::CodingRate;
+
+use crate::adr::AdrControl;
+use crate::alarm::ALARM_PORT;
+use crate::antenna::ANTENNA_PORT;
+use crate::app::AppConfig;
+use crate::batch::BATCH_PORT;
+use crate::commissioning::CommissioningConfig;
+use crate::compat::CompatProfile;
+use crate::delivery::Delivery;
+use crate::diagnostics::STATUS_PORT;
+use crate::exclusive::RadioJob;
+use crate::fcnt::FcntPolicy;
+use crate::join::JoinStrategy;
+use crate::lbt::LbtConfig;
+use crate::mobility::MobilityConfig;
+use crate::mtu::MtuConfig;
+use crate::pre_uplink;
+use crate::region_scan::ScanCandidate;
+use crate::safe_mode::CrashLoopConfig;
+use crate::soak::SoakConfig;
+use crate::spool::SpoolConfig;
+use crate::wake::{Edge, WakeConfig, WAKE_PORT};
+
+#[allow(dead_code)] // only the one in `PRESET` is constructed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Preset {
+    /// Meters in basements and cabinets: rare reports, deep coverage needed.
+    IndoorMetering,
+    /// Trackers on the move, where ADR can't keep up with the changing link.
+    MobileTracker,
+    /// Fixed outdoor sensors with a stable link.
+    StaticOutdoor,
+}
+impl Preset {
+    pub const fn profile(self) -> Profile {
+        match self {
+            Preset::IndoorMetering => Profile {
+                report_interval: Duration::from_secs(3600),
+                sample_interval: Duration::from_secs(300),
+                data_rate: DataRatePolicy::Adaptive { min: 0, max: 5 },
+                confirmed_every: 24,
+                rejoin_after: Some(96),
+                ..Profile::DEFAULT
+            },
+            Preset::MobileTracker => Profile {
+                report_interval: Duration::from_secs(120),
+                sample_interval: Duration::from_secs(30),
+                data_rate: DataRatePolicy::Fixed(3),
+                confirmed_every: 0,
+                rejoin_after: None,
+                ..Profile::DEFAULT
+            },
+            Preset::StaticOutdoor => Profile {
+                report_interval: Duration::from_secs(900),
+                sample_interval: Duration::from_secs(60),
+                data_rate: DataRatePolicy::Adaptive { min: 0, max: 5 },
+                confirmed_every: 12,
+                rejoin_after: Some(48),
+                ..Profile::DEFAULT
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DataRatePolicy {
+    /// Data rates ADR may choose from.
+    Adaptive { min: u8, max: u8 },
+    /// Pinned, whatever the network asks for.
+    Fixed(u8),
+}
+impl DataRatePolicy {
+    /// The data rate to switch to, if `current` is not allowed.
+    pub fn enforce(&self, current: u8) -> Option<u8> {
+        let allowed = match *self {
+            DataRatePolicy::Adaptive { min, max } => current.clamp(min, max),
+            DataRatePolicy::Fixed(data_rate) => data_rate,
+        };
+        (allowed != current).then_some(allowed)
+    }
+
+    pub fn fastest(&self) -> u8 {
+        match *self {
+            DataRatePolicy::Adaptive { max, .. } => max,
+            DataRatePolicy::Fixed(data_rate) => data_rate,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Profile {
+    pub report_interval: Duration,
+    pub sample_interval: Duration,
+    pub data_rate: DataRatePolicy,
+    /// Every nth batch uplink is confirmed, 0 for never.
+    pub confirmed_every: u32,
+    /// Uplinks without any downlink before the session is dropped and the device joins
+    /// again, in case the network lost it.
+    pub rejoin_after: Option<u32>,
+    /// How often FCntUp is written to flash, handed to the store when the device is set
+    /// up so that it holds for the first session restored.
+    pub fcnt_policy: FcntPolicy,
+    pub status_interval: Duration,
+    pub checkpoint_interval: Duration,
+    pub compat_profile: CompatProfile,
+    /// Symbols an RX window waits for a preamble, wide enough to cover the window margin
+    /// at SF7 and still detect the 8 symbol preamble.
+    pub rx_preamble_symbols: Option<u16>,
+    /// Empty uplinks sent back to back to fetch downlinks queued by the network (FPending).
+    pub max_pending_polls: u8,
+    /// Delivery semantics by FPort, other ports are confirmed or not as they are built.
+    pub delivery: &'static [(u8, Delivery)],
+    /// How the pins mapped as wake inputs are reported.
+    pub wake: WakeConfig,
+    /// Run on every uplink right before it is sent, to refresh its payload, `None` to send
+    /// uplinks as they were built.
+    pub pre_uplink_hook: Option<pre_uplink::Hook>,
+    /// Also send the latest readings in CayenneLPP on this port with every report, for
+    /// servers that decode it without a payload formatter. `None` to only send batches.
+    pub cayenne_port: Option<u8>,
+    /// Report the clone detection fingerprint on this port after every join, `None` to leave
+    /// clone detection to the network's frame counter checks.
+    pub fingerprint_port: Option<u8>,
+    /// Keep uplinks on these ports in flash while the network doesn't answer, to be sent
+    /// oldest first once it does. `None` to send them regardless.
+    pub spool: Option<SpoolConfig>,
+    /// Report the payload sizes after every join and take the caps the server answers, see
+    /// [`crate::mtu`]. `None` to fill uplinks as far as the data rate allows.
+    pub mtu: Option<MtuConfig>,
+    /// Report firmware, region, hardware revision and capabilities after the first join,
+    /// see [`crate::onboarding`]. `false` for devices set up by hand.
+    pub onboarding: bool,
+    /// Ports whose uplinks are sent a second time on another channel unless acknowledged.
+    pub redundant_ports: &'static [u8],
+    /// Boot into safe mode after more resets in a row than this, each within the window of
+    /// the boot before. `None` to always boot normally.
+    pub crash_loop: Option<CrashLoopConfig>,
+    /// How often the clock is synchronized with the network until it sets a period itself.
+    pub clock_sync_period: Duration,
+    /// Acquire and track Class B beacons once joined.
+    pub class_b: bool,
+    /// Fast confirmed reports for a while after the first join, for installers to check the
+    /// link on the LED. `None` to start on the normal schedule.
+    pub commissioning: Option<CommissioningConfig>,
+    /// Class B ping slots every `2^periodicity` seconds, as set in the network's device
+    /// profile.
+    pub ping_slot_periodicity: u8,
+    /// Answer MAC commands with an uplink of their own at once instead of with the next
+    /// application uplink, for networks that wait on the answers.
+    pub flush_mac_answers: bool,
+    /// Send a confirmed uplink as soon as ADRACKReq is set rather than waiting for the
+    /// ADR backoff to start lowering the data rate.
+    pub probe_on_adr_ack_req: bool,
+    /// ADR until an application task sets its own with [`crate::adr::set`], with ADR off the
+    /// device stays at `data_rate`.
+    pub adr: AdrControl,
+    /// Fixed data rate and uplink repetitions while moving, `None` for devices that never
+    /// move.
+    pub mobility: Option<MobilityConfig>,
+    /// Hide frames for other devices from the logs when several boards share a bench.
+    pub bench_mode: bool,
+    /// Coding rate of radio jobs outside of LoRaWAN, whose frames are always sent at 4/5.
+    pub coding_rate: CodingRate,
+    /// Radio job run with the MAC suspended before joining, e.g. a CW test for a lab.
+    pub startup_radio_job: Option<RadioJob>,
+    /// Data rates tried while joining.
+    pub join_strategy: JoinStrategy,
+    /// AU915 sub-band joined on, 1 to 8, e.g. 2 for TTN. Only used until one is persisted.
+    #[cfg(feature = "au915")]
+    pub sub_band: u8,
+    /// Regions, and AU915 sub-bands, tried in turn until a join succeeds, empty to join in
+    /// the region of the session or the default one, on `sub_band`. E.g. EU868, then AU915
+    /// on sub-band 2 for TTN.
+    pub region_scan: &'static [ScanCandidate],
+    /// Listen before talk, required in KR920.
+    pub lbt: Option<LbtConfig>,
+    /// Least time between the start of one uplink and the start of a routine one after it,
+    /// beyond what the duty cycle requires.
+    pub uplink_spacing: Duration,
+    /// Longest a queued uplink is held back for the duty cycle of its sub-band before it is
+    /// dropped instead.
+    pub duty_cycle_max_delay: Duration,
+    /// Whether the 400 ms AS923 uplink dwell time applies, as it does in Japan.
+    #[cfg(feature = "as923")]
+    pub uplink_dwell_time: bool,
+    /// Endless joins with random timing for hunting rare hangs on the bench, `None` in the
+    /// field.
+    pub soak: Option<SoakConfig>,
+    /// How often the settings are backed up to the application server, `None` to never.
+    pub backup_interval: Option<Duration>,
+    /// Spawn the example application of [`crate::app`], `None` to leave the device to the
+    /// firmware's own reports.
+    pub app: Option<AppConfig>,
+}
+impl Profile {
+    /// Starting point of the presets and of a custom profile.
+    pub const DEFAULT: Profile = Profile {
+        report_interval: Duration::from_secs(300),
+        sample_interval: Duration::from_secs(10),
+        data_rate: DataRatePolicy::Adaptive { min: 0, max: 5 },
+        confirmed_every: 0,
+        rejoin_after: None,
+        fcnt_policy: FcntPolicy::DEFAULT,
+        status_interval: Duration::from_secs(24 * 3600),
+        checkpoint_interval: Duration::from_secs(3600),
+        compat_profile: CompatProfile::Standard,
+        rx_preamble_symbols: Some(24),
+        max_pending_polls: 4,
+        delivery: &[
+            (ALARM_PORT, Delivery::Persistent { retries: 3 }),
+            (ANTENNA_PORT, Delivery::AtLeastOnce { retries: 2 }),
+            (STATUS_PORT, Delivery::AtMostOnce),
+            (WAKE_PORT, Delivery::AtLeastOnce { retries: 2 }),
+        ],
+        wake: WakeConfig {
+            edge: Edge::Both,
+            debounce: Duration::from_millis(50),
+            min_interval: Duration::from_secs(10),
+        },
+        pre_uplink_hook: None,
+        cayenne_port: None,
+        fingerprint_port: None,
+        spool: Some(SpoolConfig { ports: &[ALARM_PORT, BATCH_PORT], offline_after: 3 }),
+        mtu: None,
+        onboarding: true,
+        redundant_ports: &[],
+        crash_loop: Some(CrashLoopConfig { max_resets: 5, window: Duration::from_secs(10 * 60) }),
+        clock_sync_period: Duration::from_secs(24 * 3600),
+        class_b: false,
+        commissioning: Some(CommissioningConfig {
+            duration: Duration::from_secs(30 * 60),
+            report_interval: Duration::from_secs(60),
+        }),
+        ping_slot_periodicity: 7,
+        flush_mac_answers: false,
+        probe_on_adr_ack_req: true,
+        adr: AdrControl {
+            enabled: true,
+            ack_limit: 64,
+            ack_delay: 32,
+            data_rate: 0,
+            max_data_rate: 5,
+        },
+        mobility: None,
+        bench_mode: false,
+        coding_rate: CodingRate::_4_5,
+        startup_radio_job: None,
+        // DR3 down to DR0, two attempts each
+        join_strategy: JoinStrategy {
+            first_data_rate: 3,
+            last_data_rate: 0,
+            attempts_per_data_rate: 2,
+            retry_after: Duration::from_secs(600),
+        },
+        #[cfg(feature = "au915")]
+        sub_band: 2,
+        region_scan: &[],
+        // -65 dBm sensed over 5 ms
+        lbt: if cfg!(feature = "kr920") {
+            Some(LbtConfig {
+                threshold: -65,
+                sense_time: Duration::from_millis(5),
+                max_attempts: 5,
+                backoff: Duration::from_millis(50),
+            })
+        } else {
+            None
+        },
+        uplink_spacing: Duration::from_secs(5),
+        duty_cycle_max_delay: Duration::from_secs(60),
+        #[cfg(feature = "as923")]
+        uplink_dwell_time: true,
+        soak: None,
+        backup_interval: Some(Duration::from_secs(7 * 24 * 3600)),
+        app: None,
+    };
+
+    pub fn confirm(&self, batch_uplinks: u32) -> bool {
+        self.confirmed_every != 0 && batch_uplinks % self.confirmed_every == 0
+    }
+}
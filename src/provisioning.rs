@@ -0,0 +1,76 @@
+//! Per-device data written on the production line into its own flash page, kept apart
+//! from STORAGE so that reflashing the firmware or wiping the session leaves it in place.
+//! The `provision` host tool writes it through the debug probe.
+
+use embassy_time::Duration;
+
+use crate::exclusive::RadioJob;
+use crate::journal::crc16;
+
+const MAGIC: u32 = 0x564F_5250;
+const VERSION: u8 = 1;
+const PAGE_SIZE: usize = 50;
+/// Pages of version 2 append an ABP session, which the lorawan crate can't be activated
+/// with. The rest of them is read as a page of version 1.
+const V2_SIZE: usize = 86;
+const TEST_MODE_CW: u8 = 1;
+const ACTIVATION_ABP: u8 = 1;
+const UNSET_EUI: [u8; 8] = [0xFF; 8];
+
+extern "C" {
+    static __provisioning: u8;
+}
+
+/// EUIs are least significant byte first, as they are sent over the air.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Provisioning {
+    /// `None` to use the EUI derived from the chip's unique ID
+    pub dev_eui: Option<[u8; 8]>,
+    pub app_eui: [u8; 8],
+    pub app_key: [u8; 16],
+    /// Run with the MAC suspended at every boot until the page is rewritten without it.
+    pub test_mode: Option<RadioJob>,
+}
+impl Provisioning {
+    /// `None` if the page was never written or is corrupt.
+    pub fn read() -> Option<Self> {
+        let page: &[u8; V2_SIZE] =
+            unsafe { &*(&__provisioning as *const u8).cast::<[u8; V2_SIZE]>() };
+        let provisioning = Self::from_bytes(page);
+        if provisioning.is_none() && page.iter().any(|b| *b != 0xFF) {
+            defmt::error!("invalid provisioning page");
+        }
+        provisioning
+    }
+
+    fn from_bytes(page: &[u8; V2_SIZE]) -> Option<Self> {
+        let magic = u32::from_le_bytes([page[0], page[1], page[2], page[3]]);
+        let size = match page[4] {
+            VERSION => PAGE_SIZE,
+            2 => V2_SIZE,
+            _ => return None,
+        };
+        let crc = u16::from_le_bytes([page[size - 2], page[size - 1]]);
+        if magic != MAGIC || crc != crc16(&page[..size - 2]) {
+            return None;
+        }
+        let test_mode = match page[5] {
+            TEST_MODE_CW => Some(RadioJob::ContinuousWave {
+                frequency: u32::from_le_bytes([page[8], page[9], page[10], page[11]]),
+                power: page[12] as i8 as i32,
+                duration: Duration::from_secs(u16::from_le_bytes([page[6], page[7]]) as u64),
+            }),
+            _ => None,
+        };
+        if size == V2_SIZE && page[13] == ACTIVATION_ABP {
+            defmt::error!("ABP session provisioned, joining with the OTAA credentials instead");
+        }
+        let dev_eui: [u8; 8] = page[16..24].try_into().unwrap();
+        Some(Self {
+            dev_eui: (dev_eui != UNSET_EUI).then_some(dev_eui),
+            app_eui: page[24..32].try_into().unwrap(),
+            app_key: page[32..48].try_into().unwrap(),
+            test_mode,
+        })
+    }
+}
@@ -0,0 +1,121 @@
+//! Radio settings that differ between board revisions. The profile that last completed a
+//! join is kept in the journal and used at boot, without one the candidates are tried in
+//! order, moving on to the next after repeated join failures.
+
+use lora_phy::sx126x::{self, Stm32wl, TcxoCtrlVoltage};
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+
+const PROFILE_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RadioProfile {
+    pub high_power_pa: bool,
+    /// TCXO supply in millivolts, `None` for a crystal
+    pub tcxo_mv: Option<u16>,
+    pub dcdc: bool,
+    pub rx_boost: bool,
+}
+impl RadioProfile {
+    pub fn config(&self) -> sx126x::Config<Stm32wl> {
+        sx126x::Config {
+            chip: Stm32wl { use_high_power_pa: self.high_power_pa },
+            tcxo_ctrl: self.tcxo_mv.map(tcxo_voltage),
+            use_dcdc: self.dcdc,
+            rx_boost: self.rx_boost,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; PROFILE_SIZE] {
+        let tcxo = self.tcxo_mv.map_or(0, |mv| (mv / 100) as u8);
+        [self.high_power_pa as u8, tcxo, self.dcdc as u8, self.rx_boost as u8]
+    }
+
+    fn from_bytes(bytes: [u8; PROFILE_SIZE]) -> Self {
+        Self {
+            high_power_pa: bytes[0] != 0,
+            tcxo_mv: (bytes[1] != 0).then(|| bytes[1] as u16 * 100),
+            dcdc: bytes[2] != 0,
+            rx_boost: bytes[3] != 0,
+        }
+    }
+}
+
+/// Rounds up to the nearest supply the radio can provide.
+fn tcxo_voltage(mv: u16) -> TcxoCtrlVoltage {
+    match mv {
+        0..=1600 => TcxoCtrlVoltage::Ctrl1V6,
+        1601..=1700 => TcxoCtrlVoltage::Ctrl1V7,
+        1701..=1800 => TcxoCtrlVoltage::Ctrl1V8,
+        1801..=2200 => TcxoCtrlVoltage::Ctrl2V2,
+        2201..=2400 => TcxoCtrlVoltage::Ctrl2V4,
+        2401..=2700 => TcxoCtrlVoltage::Ctrl2V7,
+        2701..=3000 => TcxoCtrlVoltage::Ctrl3V0,
+        _ => TcxoCtrlVoltage::Ctrl3V3,
+    }
+}
+
+/// Tried in order on boards without a known good profile, the first is the reference design.
+pub const CANDIDATES: &[RadioProfile] = &[
+    RadioProfile { high_power_pa: true, tcxo_mv: Some(1700), dcdc: true, rx_boost: false },
+    RadioProfile { high_power_pa: true, tcxo_mv: Some(3300), dcdc: true, rx_boost: false },
+    RadioProfile { high_power_pa: false, tcxo_mv: Some(1700), dcdc: true, rx_boost: false },
+    RadioProfile { high_power_pa: true, tcxo_mv: None, dcdc: false, rx_boost: false },
+];
+
+/// Join failures before a profile that never joined is given up on.
+pub const JOIN_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Selection {
+    LastGood(RadioProfile),
+    Candidate(u8),
+}
+impl Selection {
+    pub fn profile(&self) -> RadioProfile {
+        match *self {
+            Selection::LastGood(profile) => profile,
+            Selection::Candidate(index) => CANDIDATES[index as usize % CANDIDATES.len()],
+        }
+    }
+}
+
+pub fn select(store: &mut DeviceNonVolatileStore<'_>) -> Selection {
+    let mut buf = [0; PROFILE_SIZE];
+    if let Ok(PROFILE_SIZE) = store.read_record(RecordKey::RadioLastGood, &mut buf) {
+        return Selection::LastGood(RadioProfile::from_bytes(buf));
+    }
+    let mut index = [0];
+    match store.read_record(RecordKey::RadioCandidate, &mut index) {
+        Ok(1) => Selection::Candidate(index[0] % CANDIDATES.len() as u8),
+        _ => Selection::Candidate(0),
+    }
+}
+
+/// Remembers the profile after a successful join.
+pub fn confirm(
+    store: &mut DeviceNonVolatileStore<'_>,
+    selection: Selection,
+) -> Result<(), NonVolatileStoreError> {
+    if let Selection::Candidate(_) = selection {
+        defmt::info!("radio profile {:?} joined, keeping it", selection.profile());
+        store.write_record(RecordKey::RadioLastGood, &selection.profile().to_bytes())?;
+    }
+    Ok(())
+}
+
+/// Gives up on the profile in use, the next boot uses the next candidate.
+pub fn reject(
+    store: &mut DeviceNonVolatileStore<'_>,
+    selection: Selection,
+) -> Result<(), NonVolatileStoreError> {
+    defmt::warn!("radio profile {:?} failed to join", selection.profile());
+    match selection {
+        // an empty record hides the last good profile
+        Selection::LastGood(_) => store.write_record(RecordKey::RadioLastGood, &[]),
+        Selection::Candidate(index) => {
+            store.write_record(RecordKey::RadioCandidate, &[(index + 1) % CANDIDATES.len() as u8])
+        }
+    }
+}
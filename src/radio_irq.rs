@@ -0,0 +1,84 @@
+//! Bookkeeping of the radio IRQ line: wakeups without a pending IRQ, waits that never
+//! ended and IRQ bits raised by the radio without being enabled.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Consecutive IRQ timeouts after which the radio is considered wedged and the MCU reset.
+pub const MAX_TIMEOUTS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct IrqStats {
+    pub irqs: u32,
+    pub spurious: u32,
+    pub timeouts: u32,
+    /// times each IRQ status bit was set while not enabled by SetDioIrqParams
+    pub unexpected: [u16; 16],
+}
+
+struct IrqState {
+    stats: IrqStats,
+    enabled: u16,
+    consecutive_timeouts: u8,
+}
+
+static IRQ: Mutex<CriticalSectionRawMutex, RefCell<IrqState>> =
+    Mutex::new(RefCell::new(IrqState {
+        stats: IrqStats { irqs: 0, spurious: 0, timeouts: 0, unexpected: [0; 16] },
+        enabled: 0,
+        consecutive_timeouts: 0,
+    }));
+
+fn with_irq<R>(f: impl FnOnce(&mut IrqState) -> R) -> R {
+    IRQ.lock(|irq| f(&mut irq.borrow_mut()))
+}
+
+/// From a SetDioIrqParams command.
+pub fn set_enabled(mask: u16) {
+    with_irq(|irq| irq.enabled = mask);
+}
+
+/// From a GetIrqStatus response.
+pub fn status(status: u16) {
+    with_irq(|irq| {
+        let unexpected = status & !irq.enabled;
+        if unexpected != 0 {
+            defmt::warn!(
+                "unexpected radio IRQ {=u16:04X}, enabled {=u16:04X}",
+                status,
+                irq.enabled
+            );
+            for (bit, count) in irq.stats.unexpected.iter_mut().enumerate() {
+                if unexpected & (1 << bit) != 0 {
+                    *count = count.saturating_add(1);
+                }
+            }
+        }
+    })
+}
+
+pub fn irq() {
+    with_irq(|irq| {
+        irq.stats.irqs += 1;
+        irq.consecutive_timeouts = 0;
+    });
+}
+
+pub fn spurious() {
+    with_irq(|irq| irq.stats.spurious += 1);
+}
+
+/// Returns whether the radio has timed out too often in a row to be trusted.
+pub fn timeout() -> bool {
+    with_irq(|irq| {
+        irq.stats.timeouts += 1;
+        irq.consecutive_timeouts += 1;
+        irq.consecutive_timeouts >= MAX_TIMEOUTS
+    })
+}
+
+pub fn stats() -> IrqStats {
+    with_irq(|irq| irq.stats)
+}
@@ -0,0 +1,72 @@
+//! What the transceiver can tell about itself, for telling a PA running hot or starved of
+//! supply from a detuned antenna when a device's uplinks come in weak. The radio shares
+//! the die with the MCU, so the die temperature and VDDA sampled after each uplink stand
+//! in for it, while the errors come from the radio's GetDeviceErrors after each TX along
+//! with the RF end of life flag the PWR block raises when its supply drops too low.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// GetDeviceErrors bit set when the PA failed to ramp up.
+pub const PA_RAMP_ERROR: u16 = 1 << 8;
+/// Not a radio error, the PWR RFEOLF flag.
+pub const SUPPLY_END_OF_LIFE: u16 = 1 << 15;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct RadioTelemetry {
+    /// Die temperature after the last uplink in centidegrees Celsius.
+    pub temperature: i32,
+    /// VDDA after the last uplink.
+    pub supply_mv: u16,
+    /// GetDeviceErrors bits seen since boot and [`SUPPLY_END_OF_LIFE`].
+    pub errors: u16,
+}
+
+struct State {
+    telemetry: RadioTelemetry,
+    errors_due: bool,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    telemetry: RadioTelemetry { temperature: 0, supply_mv: 0, errors: 0 },
+    errors_due: false,
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.lock(|state| f(&mut state.borrow_mut()))
+}
+
+pub fn uplink_sent(temperature: i32, supply_mv: u16) {
+    with_state(|state| {
+        state.telemetry.temperature = temperature;
+        state.telemetry.supply_mv = supply_mv;
+    })
+}
+
+/// From a GetIrqStatus response with TxDone, the errors are read before the next command.
+pub fn tx_done() {
+    with_state(|state| state.errors_due = true)
+}
+
+pub fn errors_due() -> bool {
+    with_state(|state| core::mem::take(&mut state.errors_due))
+}
+
+pub fn device_errors(errors: u16, supply_end_of_life: bool) {
+    let errors = errors
+        | if supply_end_of_life {
+            SUPPLY_END_OF_LIFE
+        } else {
+            0
+        };
+    if errors & (PA_RAMP_ERROR | SUPPLY_END_OF_LIFE) != 0 {
+        defmt::warn!("radio errors {=u16:#06X}", errors);
+    }
+    with_state(|state| state.telemetry.errors |= errors)
+}
+
+pub fn telemetry() -> RadioTelemetry {
+    with_state(|state| state.telemetry)
+}
@@ -0,0 +1,107 @@
+//! Catches radio configuration corrupted by a glitch on the SPI bus, which would leave
+//! the radio deaf until the next reboot since the sync word is only written once at init.
+//!
+//! The last configuration commands are remembered as sent. Before an RX at most every
+//! [`VERIFY_INTERVAL`], and after an abandoned operation, the packet type and LoRa sync
+//! word are read back and on a mismatch the whole configuration is sent again. The RF
+//! frequency can't be read back and is simply part of what is sent again.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+const PACKET_TYPE_LORA: u8 = 0x01;
+const VERIFY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest configuration command, SetPacketParams for FSK.
+type Command = Vec<u8, 10>;
+
+/// Index into [`Readback::commands`], in the order they are sent again.
+const PACKET_TYPE: usize = 0;
+const MODULATION: usize = 1;
+const PACKET: usize = 2;
+const FREQUENCY: usize = 3;
+const SYNC_WORD: usize = 4;
+
+struct Readback {
+    commands: [Option<Command>; 5],
+    next_verify: Option<Instant>,
+    repairs: u32,
+}
+
+static READBACK: Mutex<CriticalSectionRawMutex, RefCell<Readback>> =
+    Mutex::new(RefCell::new(Readback {
+        commands: [const { None }; 5],
+        next_verify: None,
+        repairs: 0,
+    }));
+
+fn with_readback<R>(f: impl FnOnce(&mut Readback) -> R) -> R {
+    READBACK.lock(|readback| f(&mut readback.borrow_mut()))
+}
+
+fn remember(command: &[u8], index: usize) {
+    with_readback(|readback| readback.commands[index] = Vec::from_slice(command).ok());
+}
+
+pub fn packet_type(command: &[u8]) {
+    remember(command, PACKET_TYPE);
+}
+
+pub fn modulation(command: &[u8]) {
+    remember(command, MODULATION);
+}
+
+pub fn packet(command: &[u8]) {
+    remember(command, PACKET);
+}
+
+pub fn frequency(command: &[u8]) {
+    remember(command, FREQUENCY);
+}
+
+/// From a WriteRegister command setting the LoRa sync word.
+pub fn sync_word(command: &[u8]) {
+    remember(command, SYNC_WORD);
+}
+
+/// Has the configuration checked before the next RX.
+pub fn request() {
+    with_readback(|readback| readback.next_verify = None);
+}
+
+pub fn due() -> bool {
+    with_readback(|readback| readback.next_verify.is_none_or(|next| Instant::now() >= next))
+}
+
+/// What the radio should report, the packet type and the LoRa sync word if in LoRa mode.
+pub fn expected() -> (Option<u8>, Option<[u8; 2]>) {
+    with_readback(|readback| {
+        let packet_type = readback.commands[PACKET_TYPE].as_ref().map(|command| command[1]);
+        let sync_word = match (packet_type, &readback.commands[SYNC_WORD]) {
+            (Some(PACKET_TYPE_LORA), Some(command)) => Some([command[3], command[4]]),
+            _ => None,
+        };
+        (packet_type, sync_word)
+    })
+}
+
+/// Records the outcome of a check, returning the commands to send again on a mismatch.
+pub fn verified(matches: bool) -> Option<[Option<Command>; 5]> {
+    with_readback(|readback| {
+        readback.next_verify = Some(Instant::now() + VERIFY_INTERVAL);
+        if matches {
+            return None;
+        }
+        readback.repairs += 1;
+        Some(readback.commands.clone())
+    })
+}
+
+/// Times the configuration had to be sent again.
+pub fn repairs() -> u32 {
+    with_readback(|readback| readback.repairs)
+}
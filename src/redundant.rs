@@ -0,0 +1,136 @@
+//! Sends critical uplinks a second time on another channel, for safety related events
+//! where a single channel being jammed or busy at the gateways must not lose the alarm.
+//! Like an NbTrans repetition the frame is sent again unchanged, FCnt and MIC included,
+//! so the network server takes the two as one. The copy goes out right after the MAC's
+//! receive windows, on the same data rate and power, on the known channel furthest from
+//! the one the MAC picked.
+//!
+//! The copy passes through [`crate::iv::SubghzSpiDevice`] like any other transmission, so
+//! it is held to the band and power limits and counted against the duty cycle budget,
+//! and it is skipped when that budget couldn't take it.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+use lora_phy::mod_params::{Bandwidth, CodingRate, RadioError, SpreadingFactor};
+
+use crate::airtime;
+use crate::device::DeviceNonVolatileStore;
+use crate::exclusive::ExclusiveRadio;
+use crate::frames::MAX_FRAME_SIZE;
+use crate::region::{self, RegionChoice};
+
+const PREAMBLE_SYMBOLS: u16 = 8;
+
+struct Uplink {
+    phy: Vec<u8, MAX_FRAME_SIZE>,
+    /// SetModulationParams spreading factor, bandwidth and coding rate, until a TX.
+    modulation: [u8; 3],
+    /// Of the last transmission.
+    sent_with: Option<[u8; 3]>,
+}
+
+static UPLINK: Mutex<CriticalSectionRawMutex, RefCell<Uplink>> =
+    Mutex::new(RefCell::new(Uplink { phy: Vec::new(), modulation: [0; 3], sent_with: None }));
+
+fn with_uplink<R>(f: impl FnOnce(&mut Uplink) -> R) -> R {
+    UPLINK.lock(|uplink| f(&mut uplink.borrow_mut()))
+}
+
+/// From the radio buffer written for an uplink.
+pub fn uplink(phy: &[u8]) {
+    with_uplink(|uplink| {
+        uplink.phy.clear();
+        let _ = uplink.phy.extend_from_slice(&phy[..phy.len().min(MAX_FRAME_SIZE)]);
+    })
+}
+
+/// From a SetModulationParams command, the bit rate for GFSK which isn't repeated.
+pub fn set_modulation(spreading_factor: u8, bandwidth: u8, coding_rate: u8) {
+    with_uplink(|uplink| uplink.modulation = [spreading_factor, bandwidth, coding_rate])
+}
+
+/// From a SetTx command.
+pub fn transmitting() {
+    with_uplink(|uplink| uplink.sent_with = Some(uplink.modulation))
+}
+
+fn lora_params(modulation: [u8; 3]) -> Option<(SpreadingFactor, Bandwidth, CodingRate)> {
+    let [spreading_factor, bandwidth, coding_rate] = modulation;
+    let spreading_factor = match spreading_factor {
+        5 => SpreadingFactor::_5,
+        6 => SpreadingFactor::_6,
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        12 => SpreadingFactor::_12,
+        _ => return None,
+    };
+    let bandwidth = match bandwidth {
+        0x04 => Bandwidth::_125KHz,
+        0x05 => Bandwidth::_250KHz,
+        0x06 => Bandwidth::_500KHz,
+        _ => return None,
+    };
+    let coding_rate = match coding_rate {
+        0x01 => CodingRate::_4_5,
+        0x02 => CodingRate::_4_6,
+        0x03 => CodingRate::_4_7,
+        0x04 => CodingRate::_4_8,
+        _ => return None,
+    };
+    Some((spreading_factor, bandwidth, coding_rate))
+}
+
+/// The known channel furthest from `used`, `None` with only the one. On AU915 the channel
+/// of the sub-band in use, gateways are only expected to listen to the one sub-band.
+pub fn channel(store: &mut DeviceNonVolatileStore<'_>, used: u32) -> Option<u32> {
+    const STEP: u32 = 200_000;
+    const SUB_BAND: u32 = 8 * STEP;
+    let region = region::current();
+    let default_channels = region.default_channels();
+    if region == RegionChoice::Au915 {
+        let first = default_channels[0];
+        let sub_band_start = first + (used.checked_sub(first)? / SUB_BAND) * SUB_BAND;
+        return (0..8)
+            .map(|i| sub_band_start + i * STEP)
+            .filter(|frequency| *frequency != used)
+            .max_by_key(|frequency| frequency.abs_diff(used));
+    }
+    let cf_list = crate::channels::load(store).unwrap_or_default();
+    default_channels
+        .iter()
+        .chain(cf_list.iter())
+        .copied()
+        .filter(|frequency| *frequency != 0 && *frequency != used)
+        .max_by_key(|frequency| frequency.abs_diff(used))
+}
+
+/// Sends the last uplink again on `frequency`.
+pub async fn repeat(radio: &mut ExclusiveRadio<'_, '_>, frequency: u32) -> Result<(), RadioError> {
+    let (phy, sent_with) = with_uplink(|uplink| (uplink.phy.clone(), uplink.sent_with));
+    let report = airtime::report();
+    let Some((spreading_factor, bandwidth, coding_rate)) = sent_with.and_then(lora_params) else {
+        defmt::warn!("uplink not on LoRa, not repeated");
+        return Ok(());
+    };
+    // every transmission of the uplink took about as long
+    let time_on_air = report.time_on_air / report.transmissions.max(1) as u32;
+    if report.budget_left < time_on_air {
+        defmt::warn!("no duty cycle left to repeat the uplink");
+        return Ok(());
+    }
+    let radio = radio.radio();
+    let params =
+        radio.create_modulation_params(spreading_factor, bandwidth, coding_rate, frequency)?;
+    let mut packet =
+        radio.create_tx_packet_params(PREAMBLE_SYMBOLS, false, true, false, &params)?;
+    radio.prepare_for_tx(&params, &mut packet, report.tx_power as i32, &phy).await?;
+    radio.tx().await?;
+    defmt::info!("uplink repeated on {} Hz", frequency);
+    Ok(())
+}
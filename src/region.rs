@@ -0,0 +1,535 @@
+//! Regional parameters of the regions built in: EU868 unless the `au915`, `eu433`,
+//! `in865`, `kr920`, `ru864` or one of the `as923-N` features is set, any of which can be
+//! combined, with `eu868` as well. The MAC runs in the region its session was persisted
+//! for, see [`crate::device::new_mac`], and the tables here are those of [`current`].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_time::Duration;
+#[cfg(feature = "as923-1")]
+use lorawan::mac::region::as923::AS923_1;
+#[cfg(feature = "as923-2")]
+use lorawan::mac::region::as923::AS923_2;
+#[cfg(feature = "as923-3")]
+use lorawan::mac::region::as923::AS923_3;
+#[cfg(feature = "as923-4")]
+use lorawan::mac::region::as923::AS923_4;
+#[cfg(feature = "au915")]
+use lorawan::mac::region::au915::AU915;
+#[cfg(any(
+    eu868,
+    feature = "as923",
+    feature = "in865",
+    feature = "kr920",
+    feature = "ru864",
+    feature = "eu433"
+))]
+use lorawan::mac::region::channel_plan::dynamic::DynamicChannelPlan;
+#[cfg(feature = "au915")]
+use lorawan::mac::region::channel_plan::fixed::FixedChannelPlan;
+#[cfg(feature = "eu433")]
+use lorawan::mac::region::eu433::EU433;
+#[cfg(eu868)]
+use lorawan::mac::region::eu868::EU868;
+#[cfg(feature = "in865")]
+use lorawan::mac::region::in865::IN865;
+#[cfg(feature = "kr920")]
+use lorawan::mac::region::kr920::KR920;
+#[cfg(feature = "ru864")]
+use lorawan::mac::region::ru864::RU864;
+use lorawan::mac::types::{Configuration, DR};
+use lorawan::mac::Mac;
+
+#[cfg(feature = "au915")]
+use crate::device::DeviceNonVolatileStore;
+
+/// Regions a session can be persisted for, with the ID it is stored as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum RegionChoice {
+    Eu868 = 0,
+    Au915 = 1,
+    As923_1 = 2,
+    As923_2 = 3,
+    As923_3 = 4,
+    As923_4 = 5,
+    In865 = 6,
+    Kr920 = 7,
+    Ru864 = 8,
+    Eu433 = 9,
+}
+impl RegionChoice {
+    pub const fn from_id(id: u8) -> Option<Self> {
+        Some(match id {
+            0 => RegionChoice::Eu868,
+            1 => RegionChoice::Au915,
+            2 => RegionChoice::As923_1,
+            3 => RegionChoice::As923_2,
+            4 => RegionChoice::As923_3,
+            5 => RegionChoice::As923_4,
+            6 => RegionChoice::In865,
+            7 => RegionChoice::Kr920,
+            8 => RegionChoice::Ru864,
+            9 => RegionChoice::Eu433,
+            _ => return None,
+        })
+    }
+
+    /// Whether this binary has the MAC for it.
+    pub const fn built(self) -> bool {
+        let mut i = 0;
+        while i < BUILT.len() {
+            if BUILT[i] as u8 == self as u8 {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    pub const fn is_as923(self) -> bool {
+        matches!(
+            self,
+            RegionChoice::As923_1
+                | RegionChoice::As923_2
+                | RegionChoice::As923_3
+                | RegionChoice::As923_4
+        )
+    }
+
+    /// Frequencies the device may transmit on, in Hz.
+    pub const fn tx_band(self) -> (u32, u32) {
+        match self {
+            RegionChoice::Eu868 => (863_000_000, 870_000_000),
+            RegionChoice::Au915 | RegionChoice::As923_1 => (915_000_000, 928_000_000),
+            RegionChoice::As923_2 => (920_000_000, 923_000_000),
+            RegionChoice::As923_3 => (915_000_000, 921_000_000),
+            RegionChoice::As923_4 => (917_000_000, 920_000_000),
+            RegionChoice::In865 => (865_000_000, 867_000_000),
+            RegionChoice::Kr920 => (920_900_000, 923_300_000),
+            RegionChoice::Ru864 => (864_000_000, 870_000_000),
+            RegionChoice::Eu433 => (433_175_000, 434_665_000),
+        }
+    }
+
+    pub const fn in_tx_band(self, frequency: u32) -> bool {
+        let (low, high) = self.tx_band();
+        frequency >= low && frequency <= high
+    }
+
+    /// Highest conducted TX power in dBm.
+    pub const fn max_tx_power(self) -> i8 {
+        match self {
+            // 16 dBm EIRP
+            RegionChoice::Eu868
+            | RegionChoice::As923_1
+            | RegionChoice::As923_2
+            | RegionChoice::As923_3
+            | RegionChoice::As923_4
+            | RegionChoice::Ru864 => 16,
+            // 30 dBm EIRP, more than the radio's 22 dBm
+            RegionChoice::Au915 | RegionChoice::In865 => 22,
+            RegionChoice::Kr920 => 14,
+            // 12.15 dBm EIRP
+            RegionChoice::Eu433 => 12,
+        }
+    }
+
+    /// Mandatory join channels, every plan must contain them.
+    pub const fn default_channels(self) -> &'static [u32] {
+        match self {
+            RegionChoice::Eu868 => &[868_100_000, 868_300_000, 868_500_000],
+            // the lowest and highest 125 kHz uplink channels
+            RegionChoice::Au915 => &[915_200_000, 927_800_000],
+            // 923.2 and 923.4 MHz moved down by the group's offset
+            RegionChoice::As923_1 => &[923_200_000, 923_400_000],
+            RegionChoice::As923_2 => &[921_400_000, 921_600_000],
+            RegionChoice::As923_3 => &[916_600_000, 916_800_000],
+            RegionChoice::As923_4 => &[917_300_000, 917_500_000],
+            RegionChoice::In865 => &[865_062_500, 865_402_500, 865_985_000],
+            RegionChoice::Kr920 => &[922_100_000, 922_300_000, 922_500_000],
+            RegionChoice::Ru864 => &[868_900_000, 869_100_000],
+            RegionChoice::Eu433 => &[433_175_000, 433_375_000, 433_575_000],
+        }
+    }
+
+    pub const fn beacon(self) -> Option<BeaconParams> {
+        let frequency = match self {
+            RegionChoice::Eu868 => 869_525_000,
+            // beacons hop over eight channels at SF12 500 kHz, not supported
+            RegionChoice::Au915 => return None,
+            RegionChoice::As923_1 => 923_400_000,
+            RegionChoice::As923_2 => 921_600_000,
+            RegionChoice::As923_3 => 916_800_000,
+            RegionChoice::As923_4 => 917_500_000,
+            RegionChoice::In865 => {
+                return Some(BeaconParams {
+                    frequency: 866_550_000,
+                    rfu: 1,
+                    size: 19,
+                    airtime: Duration::from_micros(173_056),
+                })
+            }
+            RegionChoice::Kr920 => 923_100_000,
+            RegionChoice::Ru864 => 869_100_000,
+            RegionChoice::Eu433 => 434_665_000,
+        };
+        Some(BeaconParams { frequency, rfu: 2, size: 17, airtime: Duration::from_micros(152_576) })
+    }
+
+    /// CalibrateImage frequencies, in 4 MHz steps, around the band transmitted and received
+    /// in. The radio comes out of reset calibrated for 902 to 928 MHz.
+    pub const fn image_calibration(self) -> [u8; 2] {
+        match self {
+            RegionChoice::Eu868 | RegionChoice::In865 | RegionChoice::Ru864 => [0xD7, 0xDB],
+            RegionChoice::Eu433 => [0x6B, 0x6F],
+            _ => [0xE1, 0xE9],
+        }
+    }
+
+    /// SetPaConfig paDutyCycle and hpMax in place of the driver's, which are tuned for the
+    /// 868 and 915 MHz matching networks, by whether the high power PA is used.
+    pub const fn pa_config(self, high_power_pa: bool) -> Option<[u8; 2]> {
+        match self {
+            // the +14 dBm rows of the reference tables, the closest to the 12 dBm limit
+            RegionChoice::Eu433 => Some(if high_power_pa {
+                [0x02, 0x02]
+            } else {
+                [0x04, 0x00]
+            }),
+            _ => None,
+        }
+    }
+
+    /// Data rate using GFSK rather than LoRa, see [`crate::fsk`].
+    pub const fn fsk_data_rate(self) -> Option<u8> {
+        match self {
+            RegionChoice::Au915 | RegionChoice::Kr920 => None,
+            _ => Some(7),
+        }
+    }
+
+    /// Largest application payload (N) allowed for each data rate, assuming no FOpts.
+    /// Downlink only data rates belong here as well, the buffers are sized from it.
+    const fn max_payload_sizes(self) -> &'static [usize] {
+        match self {
+            RegionChoice::Eu868 => &[51, 51, 51, 115, 222, 222, 222, 222],
+            // with uplink dwell time off, DR8 to DR13 are downlink only
+            RegionChoice::Au915 => {
+                &[51, 51, 51, 115, 242, 242, 242, 50, 53, 129, 242, 242, 242, 242]
+            }
+            // with uplink dwell time off
+            RegionChoice::As923_1
+            | RegionChoice::As923_2
+            | RegionChoice::As923_3
+            | RegionChoice::As923_4 => &[51, 51, 115, 242, 242, 242, 242, 242],
+            // DR6 is not defined
+            RegionChoice::In865 => &[51, 51, 51, 115, 242, 242, 0, 242],
+            RegionChoice::Kr920 => &[51, 51, 51, 115, 242, 242],
+            RegionChoice::Ru864 | RegionChoice::Eu433 => &[51, 51, 51, 115, 242, 242, 242, 242],
+        }
+    }
+
+    /// Uplinks may use the data rates below it, the others are downlink only.
+    const fn uplink_data_rates(self) -> u8 {
+        match self {
+            RegionChoice::Au915 => 7,
+            _ => self.max_payload_sizes().len() as u8,
+        }
+    }
+}
+
+/// Regions this binary has the MAC for, the one a device starts in first.
+pub const BUILT: &[RegionChoice] = &[
+    #[cfg(eu868)]
+    RegionChoice::Eu868,
+    #[cfg(feature = "au915")]
+    RegionChoice::Au915,
+    #[cfg(feature = "as923-1")]
+    RegionChoice::As923_1,
+    #[cfg(feature = "as923-2")]
+    RegionChoice::As923_2,
+    #[cfg(feature = "as923-3")]
+    RegionChoice::As923_3,
+    #[cfg(feature = "as923-4")]
+    RegionChoice::As923_4,
+    #[cfg(feature = "in865")]
+    RegionChoice::In865,
+    #[cfg(feature = "kr920")]
+    RegionChoice::Kr920,
+    #[cfg(feature = "ru864")]
+    RegionChoice::Ru864,
+    #[cfg(feature = "eu433")]
+    RegionChoice::Eu433,
+];
+/// Region of a device without a session persisted for another.
+pub const DEFAULT: RegionChoice = BUILT[0];
+
+static CURRENT: AtomicU8 = AtomicU8::new(DEFAULT as u8);
+
+/// The region the MAC runs in.
+pub fn current() -> RegionChoice {
+    RegionChoice::from_id(CURRENT.load(Ordering::Relaxed)).unwrap_or(DEFAULT)
+}
+
+/// From [`crate::device::new_mac`].
+pub fn set_current(region: RegionChoice) {
+    defmt::info!("region {:?}", region);
+    CURRENT.store(region as u8, Ordering::Relaxed);
+}
+
+/// The MAC of one of the regions built in, its generic calls go through [`with_mac`].
+pub enum RegionMac {
+    #[cfg(eu868)]
+    Eu868(Mac<EU868, DynamicChannelPlan<EU868>>),
+    #[cfg(feature = "au915")]
+    Au915(Mac<AU915, FixedChannelPlan<AU915>>),
+    #[cfg(feature = "as923-1")]
+    As923_1(Mac<AS923_1, DynamicChannelPlan<AS923_1>>),
+    #[cfg(feature = "as923-2")]
+    As923_2(Mac<AS923_2, DynamicChannelPlan<AS923_2>>),
+    #[cfg(feature = "as923-3")]
+    As923_3(Mac<AS923_3, DynamicChannelPlan<AS923_3>>),
+    #[cfg(feature = "as923-4")]
+    As923_4(Mac<AS923_4, DynamicChannelPlan<AS923_4>>),
+    #[cfg(feature = "in865")]
+    In865(Mac<IN865, DynamicChannelPlan<IN865>>),
+    #[cfg(feature = "kr920")]
+    Kr920(Mac<KR920, DynamicChannelPlan<KR920>>),
+    #[cfg(feature = "ru864")]
+    Ru864(Mac<RU864, DynamicChannelPlan<RU864>>),
+    #[cfg(feature = "eu433")]
+    Eu433(Mac<EU433, DynamicChannelPlan<EU433>>),
+}
+
+/// Evaluates `$body` with `$mac` bound to the MAC in a [`RegionMac`], whichever region it
+/// is for, e.g. `with_mac!(&mut mac, mac => mac.join(device, buf).await)`.
+macro_rules! with_mac {
+    ($region_mac:expr, $mac:ident => $body:expr) => {
+        match $region_mac {
+            #[cfg(eu868)]
+            $crate::region::RegionMac::Eu868($mac) => $body,
+            #[cfg(feature = "au915")]
+            $crate::region::RegionMac::Au915($mac) => $body,
+            #[cfg(feature = "as923-1")]
+            $crate::region::RegionMac::As923_1($mac) => $body,
+            #[cfg(feature = "as923-2")]
+            $crate::region::RegionMac::As923_2($mac) => $body,
+            #[cfg(feature = "as923-3")]
+            $crate::region::RegionMac::As923_3($mac) => $body,
+            #[cfg(feature = "as923-4")]
+            $crate::region::RegionMac::As923_4($mac) => $body,
+            #[cfg(feature = "in865")]
+            $crate::region::RegionMac::In865($mac) => $body,
+            #[cfg(feature = "kr920")]
+            $crate::region::RegionMac::Kr920($mac) => $body,
+            #[cfg(feature = "ru864")]
+            $crate::region::RegionMac::Ru864($mac) => $body,
+            #[cfg(feature = "eu433")]
+            $crate::region::RegionMac::Eu433($mac) => $body,
+        }
+    };
+}
+pub(crate) use with_mac;
+
+impl RegionMac {
+    pub fn configuration(&self) -> &Configuration {
+        with_mac!(self, mac => &mac.configuration)
+    }
+
+    pub fn configuration_mut(&mut self) -> &mut Configuration {
+        with_mac!(self, mac => &mut mac.configuration)
+    }
+
+    pub fn is_joined(&self) -> bool {
+        with_mac!(self, mac => mac.is_joined())
+    }
+
+    /// Adds a channel to a dynamic channel plan, AU915 has a fixed one.
+    pub fn add_channel(&mut self, index: usize, frequency: u32) {
+        match self {
+            #[cfg(eu868)]
+            RegionMac::Eu868(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "au915")]
+            RegionMac::Au915(_) => {}
+            #[cfg(feature = "as923-1")]
+            RegionMac::As923_1(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "as923-2")]
+            RegionMac::As923_2(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "as923-3")]
+            RegionMac::As923_3(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "as923-4")]
+            RegionMac::As923_4(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "in865")]
+            RegionMac::In865(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "kr920")]
+            RegionMac::Kr920(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "ru864")]
+            RegionMac::Ru864(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+            #[cfg(feature = "eu433")]
+            RegionMac::Eu433(mac) => {
+                mac.channel_plan.add_channel(index, frequency);
+            }
+        }
+    }
+
+    /// Restricts an AU915 MAC to `sub_band`, the other regions have no sub-bands.
+    #[cfg(feature = "au915")]
+    pub fn set_sub_band(&mut self, sub_band: u8) {
+        if let RegionMac::Au915(mac) = self {
+            mac.channel_plan.set_sub_band(sub_band);
+        }
+    }
+}
+
+pub const MAX_PAYLOAD_SIZE: usize = {
+    let mut max = 0;
+    let mut region = 0;
+    while region < BUILT.len() {
+        let sizes = BUILT[region].max_payload_sizes();
+        let mut i = 0;
+        while i < sizes.len() {
+            if sizes[i] > max {
+                max = sizes[i];
+            }
+            i += 1;
+        }
+        region += 1;
+    }
+    max
+};
+/// MHDR, FHDR without FOpts, FPort and MIC around the largest payload, FOpts take away
+/// from the payload instead.
+pub const RADIO_BUFFER_SIZE: usize = 1 + 7 + 1 + MAX_PAYLOAD_SIZE + 4;
+/// AS923 with the 400 ms uplink dwell time, DR0 and DR1 can't be used at all.
+#[cfg(feature = "as923")]
+const DWELL_PAYLOAD_SIZES: [usize; 8] = [0, 0, 11, 53, 125, 242, 242, 242];
+
+#[cfg(feature = "as923")]
+fn dwell_limited() -> bool {
+    current().is_as923() && crate::dwell::enabled()
+}
+
+pub fn max_payload_size(data_rate: u8) -> usize {
+    #[cfg(feature = "as923")]
+    if dwell_limited() {
+        return DWELL_PAYLOAD_SIZES.get(data_rate as usize).copied().unwrap_or(0);
+    }
+    let sizes = current().max_payload_sizes();
+    sizes.get(data_rate as usize).copied().unwrap_or(sizes[0])
+}
+
+/// Slowest data rate uplinks may use.
+pub fn min_data_rate() -> u8 {
+    #[cfg(feature = "as923")]
+    if dwell_limited() {
+        return 2;
+    }
+    0
+}
+
+/// Whether uplinks can be sent on the data rate now.
+pub fn uplink_data_rate(index: u8) -> bool {
+    index >= min_data_rate() && index < current().uplink_data_rates() && max_payload_size(index) > 0
+}
+
+pub fn data_rate(index: u8) -> Option<DR> {
+    Some(match index {
+        0 => DR::_0,
+        1 => DR::_1,
+        2 => DR::_2,
+        3 => DR::_3,
+        4 => DR::_4,
+        5 => DR::_5,
+        6 => DR::_6,
+        7 => DR::_7,
+        _ => return None,
+    })
+}
+
+/// The lowest [`RegionChoice::max_tx_power`] of the regions built in.
+pub const MAX_TX_POWER_ANYWHERE: i8 = {
+    let mut min = i8::MAX;
+    let mut i = 0;
+    while i < BUILT.len() {
+        if BUILT[i].max_tx_power() < min {
+            min = BUILT[i].max_tx_power();
+        }
+        i += 1;
+    }
+    min
+};
+
+/// Class B beacons: sent every 128 s at SF9 125 kHz in implicit header mode, with the GPS
+/// time after `rfu` reserved bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BeaconParams {
+    pub frequency: u32,
+    pub rfu: usize,
+    pub size: usize,
+    pub airtime: Duration,
+}
+
+/// IN865 TXPower indexes step down 2 dB from 30 dBm EIRP.
+const IN865_MAX_EIRP: i8 = 30;
+/// IN865 RX2 frequency and data rate, until the network moves it.
+const IN865_RX2: (u32, u8) = (866_550_000, 2);
+
+/// Region defaults for a session in `region` that wasn't restored.
+pub fn configure(configuration: &mut Configuration, region: RegionChoice) {
+    if region == RegionChoice::In865 {
+        configuration.rx2_frequency = IN865_RX2.0;
+        configuration.rx2_data_rate = data_rate(IN865_RX2.1);
+        // conducted power for TXPower 0, capped at what the radio does
+        configuration.tx_power = Some(IN865_MAX_EIRP.min(region.max_tx_power()));
+    }
+}
+
+/// Restricts the MAC to a sub-band of eight 125 kHz channels and the 500 kHz channel
+/// above them. The first sub-band selected on a device is persisted with the session and
+/// kept over any later `default`, so that a firmware update can't move a deployed device
+/// off its gateways.
+#[cfg(feature = "au915")]
+pub fn select_sub_band(mac: &mut RegionMac, store: &mut DeviceNonVolatileStore<'_>, default: u8) {
+    let sub_band = store.sub_band().unwrap_or(default);
+    if let Err(e) = store.set_sub_band(sub_band) {
+        defmt::error!("sub-band not saved {:?}", e);
+    }
+    defmt::info!("AU915 sub-band {}", sub_band);
+    mac.set_sub_band(sub_band);
+}
+
+pub fn in_tx_band(frequency: u32) -> bool {
+    current().in_tx_band(frequency)
+}
+
+const _: () = {
+    let mut region = 0;
+    while region < BUILT.len() {
+        let choice = BUILT[region];
+        let channels = choice.default_channels();
+        let mut i = 0;
+        while i < channels.len() {
+            assert!(choice.in_tx_band(channels[i]), "default channel outside of the TX band");
+            i += 1;
+        }
+        region += 1;
+    }
+    assert!(crate::derating::DERATING_POLICY.max_tx_power <= MAX_TX_POWER_ANYWHERE);
+};
@@ -0,0 +1,120 @@
+//! Region discovery for devices shipped with one firmware image without knowing where
+//! they will be deployed or the network they will join, e.g. TTN on AU915 sub-band 2 or
+//! Helium on others: joins cycle through a list of candidate regions and sub-bands until
+//! one succeeds, whose region is then kept with the session for good and recorded as the
+//! device's for [`crate::regulatory`].
+//!
+//! The position in the list is persisted, the radio profile check resets the device after
+//! a few failed joins and the scan carries on from where it was.
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::journal::RecordKey;
+use crate::region::{RegionChoice, RegionMac};
+use crate::regulatory;
+
+/// Failed joins before moving on to the next candidate.
+const ATTEMPTS_PER_CANDIDATE: u8 = 2;
+const STATE_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ScanCandidate {
+    /// One of the regions built in.
+    pub region: RegionChoice,
+    /// AU915 sub-band, 1 to 8, ignored in other regions.
+    pub sub_band: u8,
+}
+
+pub struct RegionScanner {
+    candidates: &'static [ScanCandidate],
+    index: u8,
+    failures: u8,
+    found: bool,
+}
+impl RegionScanner {
+    pub fn load(
+        store: &mut DeviceNonVolatileStore<'_>,
+        candidates: &'static [ScanCandidate],
+    ) -> Self {
+        let mut buf = [0; STATE_SIZE];
+        let mut scanner = match store.read_record(RecordKey::RegionScan, &mut buf) {
+            Ok(STATE_SIZE) => {
+                Self { candidates, index: buf[0], failures: buf[1], found: buf[2] != 0 }
+            }
+            _ => Self { candidates, index: 0, failures: 0, found: false },
+        };
+        if scanner.index as usize >= candidates.len() {
+            scanner.index = 0;
+        }
+        scanner
+    }
+
+    /// The candidate to join with, `None` once one joined or without candidates.
+    pub fn current(&self) -> Option<ScanCandidate> {
+        if self.found {
+            return None;
+        }
+        self.candidates.get(self.index as usize).copied()
+    }
+
+    fn save(&self, store: &mut DeviceNonVolatileStore<'_>) -> Result<(), NonVolatileStoreError> {
+        store.write_record(RecordKey::RegionScan, &[self.index, self.failures, self.found as u8])
+    }
+
+    /// After a failed join, the next candidate if it is time to switch.
+    pub fn join_failed(&mut self, store: &mut DeviceNonVolatileStore<'_>) -> Option<ScanCandidate> {
+        self.current()?;
+        self.failures += 1;
+        let switched = self.failures >= ATTEMPTS_PER_CANDIDATE;
+        if switched {
+            self.failures = 0;
+            self.index = ((self.index as usize + 1) % self.candidates.len()) as u8;
+        }
+        if let Err(e) = self.save(store) {
+            defmt::error!("region scan not saved {:?}", e);
+        }
+        switched.then(|| self.current()).flatten()
+    }
+
+    /// Ends the scan, the region and sub-band that joined are saved with the session.
+    pub fn joined(&mut self, store: &mut DeviceNonVolatileStore<'_>) {
+        let Some(candidate) = self.current() else {
+            return;
+        };
+        defmt::info!("region scan joined with {:?}", candidate);
+        self.found = true;
+        if let Err(e) = self.save(store) {
+            defmt::error!("region scan not saved {:?}", e);
+        }
+        regulatory::record_region(store, candidate.region);
+    }
+}
+
+/// Sets a MAC already in the candidate's region up for joining on its sub-band, which the
+/// session is saved with.
+#[cfg_attr(not(feature = "au915"), allow(unused_variables))]
+pub fn apply(
+    mac: &mut RegionMac,
+    store: &mut DeviceNonVolatileStore<'_>,
+    candidate: ScanCandidate,
+) {
+    defmt::info!("region scan trying {:?}", candidate);
+    #[cfg(feature = "au915")]
+    if candidate.region == RegionChoice::Au915 {
+        if let Err(e) = store.set_sub_band(candidate.sub_band) {
+            defmt::error!("sub-band not saved {:?}", e);
+        }
+        mac.set_sub_band(candidate.sub_band);
+    }
+}
+
+/// Whether every candidate is in a region built in.
+pub const fn built(candidates: &[ScanCandidate]) -> bool {
+    let mut i = 0;
+    while i < candidates.len() {
+        if !candidates[i].region.built() {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
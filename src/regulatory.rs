@@ -0,0 +1,105 @@
+//! Last line of defence against transmitting outside of the region's legal band,
+//! whatever a channel plan or test command asks for. Checks are done on the radio
+//! commands themselves in [`crate::iv::SubghzSpiDevice`].
+//!
+//! With the `region-lock` feature a device stays with the region it was first booted
+//! with, or the one a [`crate::region_scan`] joined in: if the persisted state was written
+//! for another region than the MAC runs in, nothing is transmitted until the device is
+//! erased.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::RecordKey;
+use crate::region::{self, RegionChoice};
+
+/// SX126x PLL step is 32 MHz / 2^25.
+const XTAL_FREQ: u64 = 32_000_000;
+
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static VIOLATIONS: AtomicU32 = AtomicU32::new(0);
+static TX_ALLOWED: AtomicBool = AtomicBool::new(false);
+static REGION_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// The region the persisted state was first written for, whatever the session says.
+pub fn recorded_region(store: &mut DeviceNonVolatileStore<'_>) -> Option<RegionChoice> {
+    let mut buf = [0];
+    match store.read_record(RecordKey::Region, &mut buf) {
+        Ok(1) => RegionChoice::from_id(buf[0]),
+        _ => None,
+    }
+}
+
+/// Records `region` as the device's, once a region scan joined in it.
+pub fn record_region(store: &mut DeviceNonVolatileStore<'_>, region: RegionChoice) {
+    if let Err(e) = store.write_record(RecordKey::Region, &[region as u8]) {
+        defmt::error!("region not saved {:?}", e);
+    }
+}
+
+/// Compares the region the persisted state was written for with the one the MAC runs in,
+/// recording it on first boot.
+pub fn check_region(store: &mut DeviceNonVolatileStore<'_>) {
+    let current = region::current();
+    match recorded_region(store) {
+        Some(recorded) if recorded == current => {}
+        Some(recorded) if cfg!(feature = "region-lock") => {
+            defmt::error!(
+                "state from region {:?}, running in {:?}, TX disabled",
+                recorded,
+                current
+            );
+            REGION_MISMATCH.store(true, Ordering::Relaxed);
+        }
+        _ => record_region(store, current),
+    }
+}
+
+pub fn region_mismatch() -> bool {
+    REGION_MISMATCH.load(Ordering::Relaxed)
+}
+
+/// Records the frequency from a SetRfFrequency command, TX is refused until the
+/// radio is tuned back into the band.
+pub fn set_frequency(rf_freq: [u8; 4]) {
+    let frequency = ((u32::from_be_bytes(rf_freq) as u64 * XTAL_FREQ) >> 25) as u32;
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    TX_ALLOWED.store(region::in_tx_band(frequency), Ordering::Relaxed);
+}
+
+/// Whether a SetTx command may be passed on to the radio.
+pub fn permit_tx() -> bool {
+    if region_mismatch() {
+        defmt::error!("refusing TX, persisted state is from another region");
+        return false;
+    }
+    let allowed = TX_ALLOWED.load(Ordering::Relaxed);
+    if !allowed {
+        VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        defmt::error!(
+            "refusing TX on {} Hz, outside of the band",
+            FREQUENCY.load(Ordering::Relaxed)
+        );
+    }
+    allowed
+}
+
+pub fn limit_tx_power(tx_power: i8) -> i8 {
+    let max_tx_power = region::current().max_tx_power();
+    if tx_power > max_tx_power {
+        VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        defmt::error!("TX power {} dBm above the {} dBm limit", tx_power, max_tx_power);
+        max_tx_power
+    } else {
+        tx_power
+    }
+}
+
+/// Frequency the radio was last tuned to in Hz.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn violations() -> u32 {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
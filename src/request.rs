@@ -0,0 +1,102 @@
+//! Request and response with an application server: an uplink asks, and the answer comes
+//! back as a downlink on a port of its own. As a Class A device only hears downlinks
+//! right after its uplinks, [`request`] has the main loop poll with empty uplinks until the
+//! answer arrives or the time is up, and sends the request again up to the retries given.
+//!
+//! The request goes out through [`crate::packet_queue`] like any other uplink of an
+//! application task. The main loop hands every downlink for the application to [`answer`]
+//! first, those that don't answer the request go on to the application as usual.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::packet_queue::{self, DownlinkMessage, Outcome, QueueError};
+use crate::region::MAX_PAYLOAD_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Request<'p> {
+    pub port: u8,
+    pub payload: &'p [u8],
+    pub response_port: u8,
+    /// How long to wait for the answer to each attempt.
+    pub timeout: Duration,
+    /// Attempts after the first.
+    pub retries: u8,
+    /// Between the polls for the answer.
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RequestError {
+    /// No answer after the last attempt.
+    Timeout,
+    Queue(QueueError),
+}
+
+/// Port the request in progress waits for an answer on, 0 for none.
+static AWAITED: AtomicU8 = AtomicU8::new(0);
+static ANSWER: Signal<CriticalSectionRawMutex, DownlinkMessage> = Signal::new();
+static POLL: AtomicBool = AtomicBool::new(false);
+/// One request at a time, later callers wait for their own.
+static REQUEST: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+/// Sends `request` and returns the payload of the answer.
+pub async fn request(request: &Request<'_>) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, RequestError> {
+    let _request = REQUEST.lock().await;
+    ANSWER.reset();
+    AWAITED.store(request.response_port, Ordering::Relaxed);
+    let res = attempts(request).await;
+    AWAITED.store(0, Ordering::Relaxed);
+    POLL.store(false, Ordering::Relaxed);
+    res
+}
+
+async fn attempts(request: &Request<'_>) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, RequestError> {
+    let uplinks = packet_queue::uplinks();
+    for attempt in 0..=request.retries {
+        defmt::info!("request on port {}, attempt {}", request.port, attempt + 1);
+        let deadline = Instant::now() + request.timeout;
+        let token = uplinks
+            .send(request.port, request.payload, false)
+            .await
+            .map_err(RequestError::Queue)?;
+        let outcome = token.outcome().await;
+        if !matches!(outcome, Outcome::Sent | Outcome::Acked | Outcome::NotAcked) {
+            defmt::warn!("request on port {} not sent {:?}", request.port, outcome);
+        }
+        loop {
+            let poll_at = deadline.min(Instant::now() + request.poll_interval);
+            if let Either::First(answer) = select(ANSWER.wait(), Timer::at(poll_at)).await {
+                return Ok(answer.payload);
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            POLL.store(true, Ordering::Relaxed);
+            packet_queue::wake();
+        }
+    }
+    defmt::warn!("no answer on port {}", request.response_port);
+    Err(RequestError::Timeout)
+}
+
+/// Takes a downlink answering the request in progress, returning any other.
+pub fn answer(message: DownlinkMessage) -> Option<DownlinkMessage> {
+    let awaited = AWAITED.load(Ordering::Relaxed);
+    if awaited == 0 || message.fport != awaited {
+        return Some(message);
+    }
+    ANSWER.signal(message);
+    None
+}
+
+/// Whether a request waits for its answer, the main loop polls with an empty uplink.
+pub fn take_poll() -> bool {
+    POLL.swap(false, Ordering::Relaxed)
+}
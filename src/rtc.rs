@@ -0,0 +1,98 @@
+//! The wall clock: GPS time kept against [`Instant`], set by the DeviceTimeAns of the
+//! network or corrected by the application layer clock synchronization of
+//! [`crate::clock_sync`]. Everything wanting the time of the network goes through
+//! [`NetworkTime`], which stays `None` until either.
+//!
+//! Application tasks ask for the time with [`sync`], which has the main loop send a
+//! DeviceTimeReq right away on an uplink of its own. The lorawan MAC has no request for it,
+//! [`crate::uplink_edit`] appends it to FOpts. The MAC hands the answer to
+//! [`Device::handle_device_time`](lorawan::device::Device::handle_device_time), which
+//! sets the clock as of the end of that uplink.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+
+use crate::packet_queue;
+
+/// GPS time in milliseconds at [`Instant`] zero, `None` until set by the network.
+static EPOCH: Mutex<CriticalSectionRawMutex, Cell<Option<i64>>> = Mutex::new(Cell::new(None));
+
+/// Time of the network, seconds since the GPS epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub struct NetworkTime {
+    pub gps_seconds: u32,
+    pub millis: u16,
+}
+impl NetworkTime {
+    /// The network time at `instant`, `None` until the clock was set.
+    pub fn at(instant: Instant) -> Option<Self> {
+        let epoch = EPOCH.lock(|epoch| epoch.get())?;
+        let millis = epoch + instant.as_millis() as i64;
+        Some(Self { gps_seconds: (millis / 1000) as u32, millis: (millis % 1000) as u16 })
+    }
+
+    pub fn now() -> Option<Self> {
+        Self::at(Instant::now())
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.gps_seconds as i64 * 1000 + self.millis as i64
+    }
+}
+
+pub fn synchronized() -> bool {
+    EPOCH.lock(|epoch| epoch.get()).is_some()
+}
+
+/// GPS milliseconds at `instant`, counted from boot until the clock was set.
+pub fn millis_at(instant: Instant) -> i64 {
+    EPOCH.lock(|epoch| epoch.get()).unwrap_or(0) + instant.as_millis() as i64
+}
+
+/// Moves the clock by `seconds`, starting from boot time if it was never set.
+pub fn correct(seconds: i32) {
+    EPOCH.lock(|epoch| {
+        epoch.set(Some(epoch.get().unwrap_or(0) + seconds as i64 * 1000));
+    });
+}
+
+/// Sets the clock to `time` as of `at`.
+pub fn set(time: NetworkTime, at: Instant) {
+    EPOCH.lock(|epoch| epoch.set(Some(time.as_millis() - at.as_millis() as i64)));
+    ANSWERED.store(true, Ordering::Relaxed);
+    defmt::info!("clock set to {:?}", NetworkTime::now());
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static ANSWERED: AtomicBool = AtomicBool::new(false);
+static RESOLVED: Signal<CriticalSectionRawMutex, Option<NetworkTime>> = Signal::new();
+/// One request at a time, later callers wait for their own.
+static SYNC: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+/// Sets the clock from the network, returning the time or `None` if the uplink went
+/// unanswered.
+pub async fn sync() -> Option<NetworkTime> {
+    let _sync = SYNC.lock().await;
+    RESOLVED.reset();
+    ANSWERED.store(false, Ordering::Relaxed);
+    REQUESTED.store(true, Ordering::Relaxed);
+    packet_queue::wake();
+    RESOLVED.wait().await
+}
+
+/// Whether the time was asked for, the DeviceTimeReq goes out with the next uplink.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// After the uplink carrying the DeviceTimeReq, answered or not.
+pub fn sent() {
+    let answered = ANSWERED.swap(false, Ordering::Relaxed);
+    RESOLVED.signal(answered.then(NetworkTime::now).flatten());
+}
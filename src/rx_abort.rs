@@ -0,0 +1,52 @@
+//! Shortens RX windows to a few symbols and lets the radio stop its RX timer once a
+//! preamble is detected. Windows without a downlink end as soon as no preamble can
+//! have started, while a preamble found near the end of the window keeps the receiver
+//! open until the frame is done.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+/// SetRx timeouts that disable the RX timer, single without timeout and continuous.
+const NO_TIMEOUT: [u32; 2] = [0, 0xFF_FFFF];
+
+static SYMBOLS: AtomicU16 = AtomicU16::new(0);
+static LORA: AtomicBool = AtomicBool::new(false);
+static SYMBOL_TIME_US: AtomicU32 = AtomicU32::new(0);
+
+/// Number of symbols to wait for a preamble, `None` keeps the windows requested by the MAC.
+pub fn configure(symbols: Option<u16>) {
+    SYMBOLS.store(symbols.unwrap_or(0), Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    SYMBOLS.load(Ordering::Relaxed) > 0 && LORA.load(Ordering::Relaxed)
+}
+
+/// From a SetPacketType command.
+pub fn set_packet_type(packet_type: u8) {
+    LORA.store(packet_type == 0x01, Ordering::Relaxed);
+}
+
+/// From a SetModulationParams command while in LoRa mode.
+pub fn set_modulation(spreading_factor: u8, bandwidth: u8) {
+    let bandwidth_hz: u32 = match bandwidth {
+        0x04 => 125_000,
+        0x05 => 250_000,
+        0x06 => 500_000,
+        _ => 0,
+    };
+    let symbol_time = match bandwidth_hz {
+        0 => 0,
+        hz => (1_000_000u32 << spreading_factor.min(12)) / hz,
+    };
+    SYMBOL_TIME_US.store(symbol_time, Ordering::Relaxed);
+}
+
+/// RX timeout to use instead of `timeout`, both in the radio's 15.625 µs steps.
+pub fn rx_timeout(timeout: u32) -> u32 {
+    let symbol_time = SYMBOL_TIME_US.load(Ordering::Relaxed);
+    if !enabled() || symbol_time == 0 || NO_TIMEOUT.contains(&timeout) {
+        return timeout;
+    }
+    let window_us = SYMBOLS.load(Ordering::Relaxed) as u32 * symbol_time;
+    timeout.min(window_us * 64 / 1000).max(1)
+}
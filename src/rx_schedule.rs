@@ -0,0 +1,85 @@
+//! Watches the RX windows against the schedule the MAC set them up for. Each window is
+//! due when the timer the MAC waits on fires; the SetRx that opens it should follow
+//! within a few milliseconds. Anything blocking the executor for longer, like a flash
+//! erase, shows up here as late or missed windows well before downlinks go missing.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// Later than this and the window starts after a short preamble could have been sent.
+const LATE_AFTER: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct ScheduleStats {
+    pub opened: u32,
+    pub late: u32,
+    /// windows that were due and never opened
+    pub missed: u32,
+    /// worst lateness in microseconds
+    pub max_lateness: u32,
+}
+
+struct RxSchedule {
+    stats: ScheduleStats,
+    due: Option<Instant>,
+    /// whether the last window was on time
+    on_time: bool,
+}
+
+static SCHEDULE: Mutex<CriticalSectionRawMutex, RefCell<RxSchedule>> =
+    Mutex::new(RefCell::new(RxSchedule {
+        stats: ScheduleStats { opened: 0, late: 0, missed: 0, max_lateness: 0 },
+        due: None,
+        on_time: true,
+    }));
+
+fn with_schedule<R>(f: impl FnOnce(&mut RxSchedule) -> R) -> R {
+    SCHEDULE.lock(|schedule| f(&mut schedule.borrow_mut()))
+}
+
+/// From the timer the MAC waits on before each window, once it fired.
+pub fn window_due(due: Instant) {
+    with_schedule(|schedule| {
+        if schedule.due.replace(due).is_some() {
+            schedule.stats.missed += 1;
+            schedule.on_time = false;
+            defmt::warn!("RX window missed");
+        }
+    })
+}
+
+/// From a SetTx command, the MAC only waits for RX windows after a transmission.
+pub fn transmitting() {
+    with_schedule(|schedule| schedule.due = None);
+}
+
+/// From a SetRx command.
+pub fn window_opened() {
+    let now = Instant::now();
+    with_schedule(|schedule| {
+        let Some(due) = schedule.due.take() else {
+            return;
+        };
+        schedule.stats.opened += 1;
+        let lateness = now.saturating_duration_since(due);
+        let micros = lateness.as_micros().min(u32::MAX as u64) as u32;
+        schedule.stats.max_lateness = schedule.stats.max_lateness.max(micros);
+        schedule.on_time = lateness <= LATE_AFTER;
+        if !schedule.on_time {
+            schedule.stats.late += 1;
+            defmt::warn!("RX window opened {} us late", micros);
+        }
+    })
+}
+
+pub fn stats() -> ScheduleStats {
+    with_schedule(|schedule| schedule.stats)
+}
+
+/// Whether the last window was late or missed.
+pub fn is_drifting() -> bool {
+    with_schedule(|schedule| !schedule.on_time)
+}
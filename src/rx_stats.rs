@@ -0,0 +1,138 @@
+//! Downlink statistics per RX window, tracked from the commands sent to the radio: the
+//! first SetRx after a transmission opens RX1 and the second RX2.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Openings of each window before the statistics are trusted.
+const MIN_SAMPLES: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RxWindow {
+    Rx1,
+    Rx2,
+}
+
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct WindowStats {
+    pub opened: u32,
+    pub received: u32,
+    /// moving average of the downlink SNR in quarter dB
+    pub snr: i16,
+}
+impl WindowStats {
+    /// Downlinks per thousand openings.
+    pub fn success_permille(&self) -> u32 {
+        self.received * 1000 / self.opened.max(1)
+    }
+}
+
+struct RxStats {
+    windows: [WindowStats; 2],
+    /// SetRx commands since the last transmission
+    opened_since_tx: u8,
+    last_snr: i8,
+    last_received: Option<RxWindow>,
+    preferred: Option<RxWindow>,
+}
+impl RxStats {
+    const fn new() -> Self {
+        Self {
+            windows: [WindowStats { opened: 0, received: 0, snr: 0 }; 2],
+            opened_since_tx: 0,
+            last_snr: 0,
+            last_received: None,
+            preferred: None,
+        }
+    }
+
+    fn current(&self) -> Option<RxWindow> {
+        match self.opened_since_tx {
+            1 => Some(RxWindow::Rx1),
+            2 => Some(RxWindow::Rx2),
+            _ => None,
+        }
+    }
+
+    fn update_preferred(&mut self) {
+        let [rx1, rx2] = self.windows;
+        if rx1.opened < MIN_SAMPLES || rx2.opened < MIN_SAMPLES {
+            return;
+        }
+        let preferred = match rx1.success_permille().cmp(&rx2.success_permille()) {
+            core::cmp::Ordering::Greater => RxWindow::Rx1,
+            core::cmp::Ordering::Less => RxWindow::Rx2,
+            core::cmp::Ordering::Equal if rx2.snr > rx1.snr => RxWindow::Rx2,
+            core::cmp::Ordering::Equal => RxWindow::Rx1,
+        };
+        if self.preferred != Some(preferred) {
+            defmt::info!("preferring {:?}, RX1 {:?} RX2 {:?}", preferred, rx1, rx2);
+            self.preferred = Some(preferred);
+        }
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<RxStats>> =
+    Mutex::new(RefCell::new(RxStats::new()));
+
+fn with_stats<R>(f: impl FnOnce(&mut RxStats) -> R) -> R {
+    STATS.lock(|stats| f(&mut stats.borrow_mut()))
+}
+
+/// From a SetTx command.
+pub fn transmitting() {
+    with_stats(|stats| stats.opened_since_tx = 0);
+}
+
+/// From a SetRx command, returns the window being opened.
+pub fn window_opened() -> Option<RxWindow> {
+    with_stats(|stats| {
+        stats.opened_since_tx = stats.opened_since_tx.saturating_add(1);
+        let window = stats.current();
+        if let Some(window) = window {
+            stats.windows[window as usize].opened += 1;
+            stats.update_preferred();
+        }
+        window
+    })
+}
+
+/// From a GetPacketStatus response.
+pub fn packet_status(snr: i8) {
+    with_stats(|stats| stats.last_snr = snr);
+}
+
+/// A frame was read out of the radio.
+pub fn received() {
+    with_stats(|stats| {
+        stats.last_received = stats.current();
+        let Some(window) = stats.current() else {
+            return;
+        };
+        let snr = stats.last_snr as i16;
+        let window = &mut stats.windows[window as usize];
+        window.snr = if window.received == 0 {
+            snr
+        } else {
+            (window.snr * 7 + snr) / 8
+        };
+        window.received += 1;
+        stats.update_preferred();
+    })
+}
+
+/// The window the last frame came in, `None` if it wasn't in one of the MAC's.
+pub fn last_received() -> Option<RxWindow> {
+    with_stats(|stats| stats.last_received)
+}
+
+pub fn stats() -> [WindowStats; 2] {
+    with_stats(|stats| stats.windows)
+}
+
+/// The window with the better downlink rate once both have been opened often enough.
+pub fn preferred_window() -> Option<RxWindow> {
+    with_stats(|stats| stats.preferred)
+}
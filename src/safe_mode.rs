@@ -0,0 +1,125 @@
+//! Safe mode after a crash loop, so that a driver resetting the device at every boot
+//! doesn't cut a field unit off for good. Boots that end within
+//! [`CrashLoopConfig::window`] of starting are counted in the journal, and once there
+//! were more than [`CrashLoopConfig::max_resets`] in a row the device boots into
+//! [`run`]: it only joins, sends its status and answers the firmware management package,
+//! with no sensors, inputs or radio jobs. It stays there until it is rebooted, e.g. with
+//! DevRebootCountdownReq after an update was staged.
+
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use lorawan::device::radio::types::RadioBuffer;
+use lorawan::device::Device;
+
+use crate::device::{DeviceNonVolatileStore, LoraDevice};
+use crate::diagnostics::{Diagnostics, STATUS_PORT};
+use crate::fmp::{Fmp, FMP_PORT};
+use crate::frames::{self, FrameId};
+use crate::journal::RecordKey;
+use crate::region::{with_mac, RegionMac, MAX_PAYLOAD_SIZE, RADIO_BUFFER_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CrashLoopConfig {
+    pub max_resets: u8,
+    /// A boot lasting longer ends the streak.
+    pub window: Duration,
+}
+
+pub struct CrashLoop {
+    config: CrashLoopConfig,
+    resets: u8,
+    cleared: bool,
+}
+impl CrashLoop {
+    /// Counts this boot as a reset until [`CrashLoop::update`] sees it last.
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>, config: CrashLoopConfig) -> Self {
+        let mut buf = [0; 1];
+        let resets = match store.read_record(RecordKey::CrashLoop, &mut buf) {
+            Ok(1) => buf[0].saturating_add(1),
+            Ok(_) | Err(_) => 1,
+        };
+        if let Err(e) = store.write_record(RecordKey::CrashLoop, &[resets]) {
+            defmt::error!("reset streak not saved {:?}", e);
+        }
+        Self { config, resets, cleared: false }
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.resets > self.config.max_resets
+    }
+
+    /// Ends the streak once this boot lasted the window.
+    pub fn update(&mut self, store: &mut DeviceNonVolatileStore<'_>, now: Instant) {
+        if self.cleared || Duration::from_ticks(now.as_ticks()) < self.config.window {
+            return;
+        }
+        self.cleared = true;
+        // an empty record removes it
+        if let Err(e) = store.write_record(RecordKey::CrashLoop, &[]) {
+            defmt::error!("reset streak not cleared {:?}", e);
+        }
+    }
+}
+
+/// Joins and sends the status every `status_interval` until the network reboots the
+/// device.
+pub async fn run(
+    device: &mut LoraDevice<'static>,
+    mac: &mut RegionMac,
+    diagnostics: &Diagnostics,
+    mut crash_loop: CrashLoop,
+    mut fmp: Fmp,
+    (join_retry, status_interval): (Duration, Duration),
+) -> ! {
+    defmt::warn!("safe mode after {} resets", crash_loop.resets);
+    let mut radio_buffer: RadioBuffer<RADIO_BUFFER_SIZE> = Default::default();
+    let mut next_status = Instant::now();
+    loop {
+        crash_loop.update(device.non_volatile_store(), Instant::now());
+        if fmp.reboot_due(Instant::now()) {
+            defmt::info!("rebooting as asked by the network");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        if !mac.is_joined() {
+            if let Err(e) = with_mac!(&mut *mac, mac => mac.join(device, &mut radio_buffer).await) {
+                defmt::error!("Join failed {:?}", e);
+                Timer::after(join_retry).await;
+            }
+            continue;
+        }
+        let mut payload: Vec<u8, MAX_PAYLOAD_SIZE> = Vec::new();
+        let fport = if let Some(answers) = fmp.take_answers() {
+            payload.extend_from_slice(&answers).unwrap();
+            FMP_PORT
+        } else {
+            let wake = fmp.reboot_at().map_or(next_status, |at| at.min(next_status));
+            Timer::at(wake).await;
+            if Instant::now() < next_status {
+                continue;
+            }
+            next_status += status_interval;
+            payload.extend_from_slice(&diagnostics.encode_status()).unwrap();
+            STATUS_PORT
+        };
+        let sent = with_mac!(&mut *mac, mac => {
+            mac.send(device, &mut radio_buffer, &payload, fport, false, None).await
+        });
+        match sent {
+            Ok(Some((len, _))) => {
+                if let Some(FrameId::Data { fport: Some(FMP_PORT), .. }) = frames::last_downlink() {
+                    let data = &radio_buffer.as_ref()[..len];
+                    if let Err(e) = fmp.command(device.non_volatile_store(), data) {
+                        defmt::warn!("firmware management rejected {:?}", e);
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                defmt::error!("{:?} sending {:?}", e, frames::last_uplink());
+                if let Err(e) = device.abort_rx().await {
+                    defmt::error!("radio not returned to standby {:?}", e);
+                }
+            }
+        }
+    }
+}
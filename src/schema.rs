@@ -0,0 +1,302 @@
+//! Uplink payload layouts shared between the firmware encoders and the host side
+//! codec generator in `tools/`. Keep this file free of other crate dependencies so
+//! that it can be included from std builds.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    U8,
+    U16,
+    U32,
+    I32,
+    /// a `u8` indexing into the given names
+    Enum(&'static [&'static str]),
+}
+impl FieldKind {
+    pub const fn size(&self) -> usize {
+        match self {
+            FieldKind::U8 | FieldKind::Enum(_) => 1,
+            FieldKind::U16 => 2,
+            FieldKind::U32 | FieldKind::I32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: FieldKind,
+}
+
+/// An uplink payload made of `header` followed by zero or more `item`s, all big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadSchema {
+    pub name: &'static str,
+    pub port: u8,
+    pub header: &'static [Field],
+    pub item: &'static [Field],
+}
+impl PayloadSchema {
+    pub const fn header_size(&self) -> usize {
+        fields_size(self.header)
+    }
+
+    pub const fn item_size(&self) -> usize {
+        fields_size(self.item)
+    }
+}
+
+const fn fields_size(fields: &[Field]) -> usize {
+    let mut size = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        size += fields[i].kind.size();
+        i += 1;
+    }
+    size
+}
+
+pub const MEASUREMENTS: &[&str] = &["temperature", "battery", "downlink_latency"];
+
+/// Empty uplink carrying only MAC command answers, sent in place of one without FPort.
+/// Reserved for the firmware, applications can't send or receive on it.
+pub const POLL: PayloadSchema = PayloadSchema { name: "poll", port: 1, header: &[], item: &[] };
+
+pub const ALARM: PayloadSchema = PayloadSchema {
+    name: "alarm",
+    port: 10,
+    header: &[
+        Field { name: "id", kind: FieldKind::U8 },
+        Field { name: "state", kind: FieldKind::Enum(&["cleared", "raised"]) },
+        Field { name: "measurement", kind: FieldKind::Enum(MEASUREMENTS) },
+        Field { name: "value", kind: FieldKind::I32 },
+    ],
+    item: &[],
+};
+
+pub const MOTION: PayloadSchema = PayloadSchema {
+    name: "motion",
+    port: 11,
+    header: &[
+        Field { name: "event", kind: FieldKind::Enum(&["motion", "shock"]) },
+        Field { name: "timestamp", kind: FieldKind::U32 },
+    ],
+    item: &[],
+};
+
+pub const GEOFENCE: PayloadSchema = PayloadSchema {
+    name: "geofence",
+    port: 12,
+    header: &[
+        Field { name: "id", kind: FieldKind::U8 },
+        Field { name: "state", kind: FieldKind::Enum(&["exited", "entered"]) },
+        // 10^-7 degrees
+        Field { name: "latitude", kind: FieldKind::I32 },
+        Field { name: "longitude", kind: FieldKind::I32 },
+    ],
+    item: &[],
+};
+
+pub const BATCH: PayloadSchema = PayloadSchema {
+    name: "batch",
+    port: 2,
+    header: &[
+        Field { name: "base_timestamp", kind: FieldKind::U32 },
+        Field { name: "count", kind: FieldKind::U8 },
+    ],
+    item: &[
+        Field { name: "measurement", kind: FieldKind::Enum(MEASUREMENTS) },
+        Field { name: "offset", kind: FieldKind::U16 },
+        Field { name: "value", kind: FieldKind::I32 },
+    ],
+};
+
+pub const STATUS: PayloadSchema = PayloadSchema {
+    name: "status",
+    port: 3,
+    header: &[
+        Field { name: "boot_count", kind: FieldKind::U32 },
+        Field { name: "uptime", kind: FieldKind::U32 },
+        Field { name: "last_session", kind: FieldKind::U32 },
+        Field { name: "battery_permille", kind: FieldKind::U16 },
+        Field { name: "battery_days_left", kind: FieldKind::U16 },
+        // bit 0: TX power derated for temperature, bit 1: TX refused by the regulatory guard,
+        // bit 2: ADRACKReq set, bit 3: ADR backoff lowered the data rate, bit 4: mobility mode,
+        // bit 5: downlinks dropped in a downlink storm, bit 6: flash worn out, no longer written,
+        // bit 7: the last RX window opened late or not at all
+        Field { name: "flags", kind: FieldKind::U8 },
+        // die temperature in centidegrees Celsius and VDDA in mV after the last uplink
+        Field { name: "radio_temperature", kind: FieldKind::I32 },
+        Field { name: "radio_supply_mv", kind: FieldKind::U16 },
+        // SX126x GetDeviceErrors bits since boot, bit 8: PA ramp failed,
+        // bit 15: radio supply below its end of life threshold
+        Field { name: "radio_errors", kind: FieldKind::U16 },
+        // seconds since the last acknowledged uplink and the last downlink, across reboots,
+        // 0xFFFFFFFF if there was none
+        Field { name: "since_last_ack", kind: FieldKind::U32 },
+        Field { name: "since_last_downlink", kind: FieldKind::U32 },
+    ],
+    item: &[],
+};
+
+/// Sent back as a downlink on the same port to restore the settings.
+pub const BACKUP: PayloadSchema = PayloadSchema {
+    name: "backup",
+    port: 4,
+    header: &[
+        Field { name: "version", kind: FieldKind::U8 },
+        Field { name: "report_interval", kind: FieldKind::U32 },
+        Field { name: "sample_interval", kind: FieldKind::U16 },
+        Field { name: "compat_profile", kind: FieldKind::Enum(&["standard", "loramac-node"]) },
+        Field { name: "rx_preamble_symbols", kind: FieldKind::U8 },
+        Field { name: "bench_mode", kind: FieldKind::U8 },
+        Field { name: "crc", kind: FieldKind::U16 },
+    ],
+    item: &[],
+};
+
+pub const ANTENNA: PayloadSchema = PayloadSchema {
+    name: "antenna",
+    port: 18,
+    header: &[
+        Field { name: "state", kind: FieldKind::Enum(&["cleared", "suspected"]) },
+        Field { name: "reason", kind: FieldKind::Enum(&["pa_ramp", "rssi_drop", "no_downlink"]) },
+        // dBm, the baseline is 0 until enough downlinks were heard
+        Field { name: "baseline_rssi", kind: FieldKind::I32 },
+        Field { name: "rssi", kind: FieldKind::I32 },
+    ],
+    item: &[],
+};
+
+pub const WAKE: PayloadSchema = PayloadSchema {
+    name: "wake",
+    port: 19,
+    header: &[
+        Field { name: "slot", kind: FieldKind::U8 },
+        Field { name: "state", kind: FieldKind::Enum(&["low", "high"]) },
+        Field { name: "timestamp", kind: FieldKind::U32 },
+    ],
+    item: &[],
+};
+
+/// Sent after every join, the server may answer on the same port with payload caps.
+pub const MTU: PayloadSchema = PayloadSchema {
+    name: "mtu",
+    port: 20,
+    header: &[
+        Field { name: "version", kind: FieldKind::U8 },
+        Field { name: "preferred_batch", kind: FieldKind::U8 },
+    ],
+    // one per data rate from DR0, 0 for those the region doesn't define
+    item: &[Field { name: "max_payload", kind: FieldKind::U8 }],
+};
+
+/// Sent confirmed after joining until it is acknowledged.
+pub const ONBOARDING: PayloadSchema = PayloadSchema {
+    name: "onboarding",
+    port: 21,
+    header: &[
+        Field { name: "version", kind: FieldKind::U8 },
+        Field { name: "firmware_version", kind: FieldKind::U32 },
+        Field {
+            name: "region",
+            kind: FieldKind::Enum(&[
+                "EU868", "AU915", "AS923-1", "AS923-2", "AS923-3", "AS923-4", "IN865", "KR920",
+                "RU864", "EU433",
+            ]),
+        },
+        Field { name: "hardware_revision", kind: FieldKind::U8 },
+        // bit 0: Class B, bit 1: FUOTA, bit 2: end-to-end encryption, bit 3: unused,
+        // bit 4: uplinks spooled in flash
+        Field { name: "capabilities", kind: FieldKind::U16 },
+    ],
+    item: &[],
+};
+
+/// Request of the example application, the server answers on the same port with the
+/// interval between its rounds in minutes, a big endian u16.
+pub const APP_SETTINGS: PayloadSchema = PayloadSchema {
+    name: "app_settings",
+    port: 22,
+    header: &[Field { name: "version", kind: FieldKind::U8 }],
+    item: &[],
+};
+
+/// Sent by the example application every round.
+pub const APP_REPORT: PayloadSchema = PayloadSchema {
+    name: "app_report",
+    port: 23,
+    header: &[
+        Field { name: "round", kind: FieldKind::U16 },
+        Field { name: "dropped", kind: FieldKind::U16 },
+        Field { name: "downlinks_dropped", kind: FieldKind::U16 },
+        Field { name: "link_margin", kind: FieldKind::U8 },
+        Field { name: "gateway_count", kind: FieldKind::U8 },
+        Field { name: "gps_seconds", kind: FieldKind::U32 },
+        Field { name: "duty_cycle_refused", kind: FieldKind::U16 },
+    ],
+    item: &[],
+};
+
+/// Downlink, followed by the first 4 bytes of an AES-CMAC, see `pin_map`. Not in
+/// [`SCHEMAS`], the device doesn't send it.
+pub const PIN_MAP: PayloadSchema = PayloadSchema {
+    name: "pin_map",
+    port: 13,
+    header: &[Field { name: "version", kind: FieldKind::U8 }],
+    // one per assignable pin
+    item: &[Field {
+        name: "function",
+        kind: FieldKind::Enum(&["unused", "pulse_input", "relay", "wake_input"]),
+    }],
+};
+
+/// Downlink assigning the uplink slot, see `tdma`. Empty to turn slots off.
+pub const TDMA: PayloadSchema = PayloadSchema {
+    name: "tdma",
+    port: 14,
+    header: &[
+        // seconds
+        Field { name: "period", kind: FieldKind::U16 },
+        Field { name: "offset", kind: FieldKind::U16 },
+    ],
+    item: &[],
+};
+
+/// Downlink of any data, echoed back on the same port with the RSSI and SNR of the
+/// downlink and the latency until the echo was sent appended, see `echo`. Not in
+/// [`SCHEMAS`] for the lack of a fixed layout.
+pub const ECHO: PayloadSchema = PayloadSchema { name: "echo", port: 15, header: &[], item: &[] };
+
+/// Downlink of the encrypted application key followed by the first 4 bytes of an AES-CMAC,
+/// see `e2e`. Empty to remove the key.
+pub const E2E_KEY: PayloadSchema =
+    PayloadSchema { name: "e2e_key", port: 16, header: &[], item: &[] };
+
+/// Downlink filtering the logs, see `log_filter`. Out of the way of the certification
+/// port 224, empty to end the filter.
+pub const LOG_FILTER: PayloadSchema = PayloadSchema {
+    name: "log_filter",
+    port: 17,
+    header: &[
+        Field { name: "module_mask", kind: FieldKind::U8 },
+        Field { name: "level", kind: FieldKind::Enum(&["trace", "debug", "info"]) },
+        Field { name: "minutes", kind: FieldKind::U16 },
+    ],
+    item: &[],
+};
+
+pub const SCHEMAS: &[PayloadSchema] = &[
+    POLL,
+    ALARM,
+    MOTION,
+    GEOFENCE,
+    BATCH,
+    STATUS,
+    BACKUP,
+    ANTENNA,
+    WAKE,
+    MTU,
+    ONBOARDING,
+    APP_SETTINGS,
+    APP_REPORT,
+];
@@ -0,0 +1,58 @@
+use embassy_stm32::adc::{Adc, SampleTime, Temperature, VrefInt};
+use embassy_stm32::peripherals::ADC;
+
+const VREFINT_CAL_PTR: *const u16 = 0x1FFF_75AA as _;
+const TS_CAL1_PTR: *const u16 = 0x1FFF_75A8 as _;
+const TS_CAL2_PTR: *const u16 = 0x1FFF_75C8 as _;
+const TS_CAL1_TEMP: i32 = 30;
+const TS_CAL2_TEMP: i32 = 130;
+
+/// Measurements that can be sampled on the device and referenced by id in uplinks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Measurement {
+    /// MCU die temperature in centidegrees Celsius.
+    Temperature = 0,
+    /// Estimated battery charge left in per mille, see [`crate::energy`].
+    Battery = 1,
+    /// Milliseconds from a downlink's RxDone to its handler, see [`crate::latency`].
+    DownlinkLatency = 2,
+}
+
+pub struct Sensors<'d> {
+    adc: Adc<'d, ADC>,
+    vrefint: VrefInt,
+    temperature: Temperature,
+}
+impl<'d> Sensors<'d> {
+    pub fn new(adc: ADC) -> Self {
+        let mut adc = Adc::new(adc);
+        adc.set_sample_time(SampleTime::CYCLES160_5);
+        let vrefint = adc.enable_vrefint();
+        let temperature = adc.enable_temperature();
+        Self { adc, vrefint, temperature }
+    }
+
+    /// VDDA in mV, from the internal reference calibrated at 3.3V.
+    pub fn supply_voltage(&mut self) -> u16 {
+        let vrefint_cal = unsafe { VREFINT_CAL_PTR.read_volatile() } as u32;
+        let vrefint = self.adc.blocking_read(&mut self.vrefint) as u32;
+        (3300 * vrefint_cal / vrefint.max(1)) as u16
+    }
+
+    pub fn temperature(&mut self) -> i32 {
+        let (vrefint_cal, ts_cal1, ts_cal2) = unsafe {
+            (
+                VREFINT_CAL_PTR.read_volatile() as i32,
+                TS_CAL1_PTR.read_volatile() as i32,
+                TS_CAL2_PTR.read_volatile() as i32,
+            )
+        };
+        let vrefint = self.adc.blocking_read(&mut self.vrefint) as i32;
+        let raw = self.adc.blocking_read(&mut self.temperature) as i32;
+        // calibration values are taken at VDDA = 3.3V, so scale the reading to that reference
+        let raw = raw * vrefint_cal / vrefint.max(1);
+        (raw - ts_cal1) * (TS_CAL2_TEMP - TS_CAL1_TEMP) * 100 / (ts_cal2 - ts_cal1)
+            + TS_CAL1_TEMP * 100
+    }
+}
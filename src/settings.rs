@@ -0,0 +1,79 @@
+//! Settings that can be changed after deployment, persisted in the journal. Those of the
+//! profile in `main.rs` are used until a record has been written.
+
+use embassy_time::Duration;
+
+use crate::compat::CompatProfile;
+use crate::device::{DeviceNonVolatileStore, LoraDevice, NonVolatileStoreError};
+use crate::frames;
+use crate::journal::RecordKey;
+use crate::rx_abort;
+
+pub const SETTINGS_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    pub report_interval: Duration,
+    pub sample_interval: Duration,
+    pub compat_profile: CompatProfile,
+    pub rx_preamble_symbols: Option<u16>,
+    pub bench_mode: bool,
+}
+impl Settings {
+    /// Big endian, in the order of the fields, as in the backup uplink.
+    pub fn to_bytes(&self) -> [u8; SETTINGS_SIZE] {
+        let mut buf = [0; SETTINGS_SIZE];
+        buf[..4].copy_from_slice(&(self.report_interval.as_secs() as u32).to_be_bytes());
+        buf[4..6].copy_from_slice(&(self.sample_interval.as_secs() as u16).to_be_bytes());
+        buf[6] = match self.compat_profile {
+            CompatProfile::Standard => 0,
+            CompatProfile::LoRaMacNode => 1,
+        };
+        buf[7] = self.rx_preamble_symbols.map_or(0, |symbols| symbols.min(u8::MAX as u16) as u8);
+        buf[8] = self.bench_mode as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let buf: &[u8; SETTINGS_SIZE] = buf.try_into().ok()?;
+        let report_interval = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let sample_interval = u16::from_be_bytes([buf[4], buf[5]]);
+        if report_interval == 0 || sample_interval == 0 {
+            return None;
+        }
+        Some(Self {
+            report_interval: Duration::from_secs(report_interval as u64),
+            sample_interval: Duration::from_secs(sample_interval as u64),
+            compat_profile: match buf[6] {
+                0 => CompatProfile::Standard,
+                1 => CompatProfile::LoRaMacNode,
+                _ => return None,
+            },
+            rx_preamble_symbols: (buf[7] != 0).then_some(buf[7] as u16),
+            bench_mode: buf[8] != 0,
+        })
+    }
+
+    pub fn load(store: &mut DeviceNonVolatileStore<'_>, default: Settings) -> Self {
+        let mut buf = [0; SETTINGS_SIZE];
+        match store.read_record(RecordKey::Settings, &mut buf) {
+            Ok(SETTINGS_SIZE) => Self::from_bytes(&buf).unwrap_or(default),
+            _ => default,
+        }
+    }
+
+    pub fn save(
+        &self,
+        store: &mut DeviceNonVolatileStore<'_>,
+    ) -> Result<(), NonVolatileStoreError> {
+        store.write_record(RecordKey::Settings, &self.to_bytes())
+    }
+
+    /// Applies everything but the intervals, which the main loop reads directly.
+    pub fn apply(&self, device: &mut LoraDevice<'_>) {
+        defmt::info!("{:?}", self);
+        device.set_compat_profile(self.compat_profile);
+        rx_abort::configure(self.rx_preamble_symbols);
+        frames::set_bench_mode(self.bench_mode);
+    }
+}
@@ -0,0 +1,75 @@
+//! Soak test for the bench, joining a test network server over and over for hours with
+//! a random pause between attempts. Each join starts from a fresh MAC, so every attempt
+//! draws a new DevNonce and join channel, and the jitter shakes out races between the
+//! radio IRQ and the executor that regular traffic never hits. A join that doesn't end
+//! within [`SoakConfig::join_timeout`] is counted as hung and the radio reset.
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use lorawan::device::radio::types::RadioBuffer;
+use lorawan::device::rng::Rng;
+use lorawan::device::Device;
+use lorawan::mac::types::Credentials;
+
+use crate::device::{self, LoraDevice};
+use crate::log_filter::Module;
+use crate::region::{self, with_mac, RADIO_BUFFER_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SoakConfig {
+    /// Pause between joins, drawn uniformly from this range.
+    pub min_pause: Duration,
+    pub max_pause: Duration,
+    /// Longer than the join with both RX windows and all its retries could take.
+    pub join_timeout: Duration,
+    /// How often the counters are logged.
+    pub report_every: u32,
+}
+
+#[derive(Debug, Default, defmt::Format)]
+struct SoakStats {
+    attempts: u32,
+    accepted: u32,
+    failed: u32,
+    hung: u32,
+}
+
+pub async fn run(
+    device: &mut LoraDevice<'static>,
+    (app_eui, dev_eui, app_key): ([u8; 8], [u8; 8], [u8; 16]),
+    config: SoakConfig,
+) -> ! {
+    defmt::warn!("soak test {:?}", config);
+    let mut radio_buffer: RadioBuffer<RADIO_BUFFER_SIZE> = Default::default();
+    let mut stats = SoakStats::default();
+    let started = Instant::now();
+    loop {
+        let spread = (config.max_pause - config.min_pause).as_ticks().max(1);
+        let Ok(random) = device.rng().next_u32();
+        Timer::after(config.min_pause + Duration::from_ticks(random as u64 % spread)).await;
+
+        let credentials = Credentials::new(app_eui, dev_eui, app_key);
+        // the region the MAC already runs in
+        let mut mac = device::new_mac(region::current(), Default::default(), credentials).unwrap();
+        stats.attempts += 1;
+        let join = with_mac!(&mut mac, mac => {
+            with_timeout(config.join_timeout, mac.join(device, &mut radio_buffer)).await
+        });
+        match join {
+            Ok(Ok(_)) => stats.accepted += 1,
+            Ok(Err(e)) => {
+                crate::log!(debug, Module::App, "join failed {:?}", e);
+                stats.failed += 1;
+            }
+            Err(_) => {
+                stats.hung += 1;
+                defmt::error!("join hung, attempt {}", stats.attempts);
+                if let Err(e) = device.abort_rx().await {
+                    defmt::error!("radio not returned to standby {:?}", e);
+                }
+            }
+        }
+        if stats.attempts % config.report_every == 0 {
+            defmt::info!("soak after {}s {:?}", started.elapsed().as_secs(), stats);
+        }
+    }
+}
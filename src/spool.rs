@@ -0,0 +1,368 @@
+use embassy_time::Instant;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::device::DeviceNonVolatileStore;
+use crate::journal::crc16;
+use crate::region::MAX_PAYLOAD_SIZE;
+
+/// Flash is programmed a double word at a time.
+const WORD: u32 = 8;
+const ERASED: u8 = 0xFF;
+const PAGE_MARK: u8 = 0x5B;
+const ENTRY_MARK: u8 = 0xE5;
+/// The header word, then the word cleared once the entry was delivered.
+const ENTRY_HEADER_SIZE: u32 = 2 * WORD;
+pub const MAX_ENTRY_SIZE: usize = u8::MAX as usize;
+
+/// Which uplinks are spooled, and when.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SpoolConfig {
+    pub ports: &'static [u8],
+    /// Confirmed uplinks in a row left unanswered before the network is taken as gone.
+    pub offline_after: u8,
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum SpoolError<E> {
+    Flash(E),
+    TooLarge,
+}
+
+/// Decides which uplinks the application loop spools, and when they are sent again.
+pub struct Replay {
+    config: SpoolConfig,
+    unanswered: u8,
+    /// Whether the spool may hold uplinks, they may have been kept before the reset.
+    pending: bool,
+    next: Instant,
+}
+impl Replay {
+    pub fn new(config: SpoolConfig) -> Self {
+        Self { config, unanswered: 0, pending: true, next: Instant::now() }
+    }
+
+    /// Whether an uplink on `fport` is to be spooled rather than sent.
+    pub fn spools(&self, fport: u8) -> bool {
+        self.unanswered >= self.config.offline_after && self.config.ports.contains(&fport)
+    }
+
+    pub fn spooled(&mut self) {
+        self.pending = true;
+    }
+
+    /// After an uplink went out, answered by a downlink or not.
+    pub fn sent(&mut self, answered: bool, confirmed: bool) {
+        if answered {
+            self.unanswered = 0;
+        } else if confirmed {
+            self.unanswered = self.unanswered.saturating_add(1);
+        }
+    }
+
+    /// Copies the oldest spooled uplink into `payload` once it is due and fits, returning
+    /// its port. It is sent confirmed and stays spooled until [`Replay::replayed`].
+    pub fn next(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        payload: &mut Vec<u8, MAX_PAYLOAD_SIZE>,
+        max_payload_size: usize,
+    ) -> Option<u8> {
+        if !self.pending || Instant::now() < self.next {
+            return None;
+        }
+        payload.resize_default(MAX_PAYLOAD_SIZE).unwrap();
+        let res = match store.spool_peek(payload) {
+            Ok(Some((fport, len))) if len <= max_payload_size => {
+                payload.truncate(len);
+                return Some(fport);
+            }
+            Ok(Some((fport, len))) => {
+                defmt::debug!(
+                    "spooled port {} uplink of {} bytes waits for a faster DR",
+                    fport,
+                    len
+                );
+                None
+            }
+            Ok(None) => {
+                self.pending = false;
+                None
+            }
+            Err(e) => {
+                defmt::error!("spool not read {:?}", e);
+                self.pending = false;
+                None
+            }
+        };
+        payload.clear();
+        res
+    }
+
+    /// Removes the uplink [`Replay::next`] returned once acknowledged, or tries again at
+    /// `retry_at`.
+    pub fn replayed(
+        &mut self,
+        store: &mut DeviceNonVolatileStore<'_>,
+        acked: bool,
+        retry_at: Instant,
+    ) {
+        if !acked {
+            self.next = retry_at;
+            return;
+        }
+        if let Err(e) = store.spool_pop() {
+            defmt::error!("spooled uplink not removed {:?}", e);
+            self.pending = false;
+        }
+    }
+}
+
+/// Where an entry is, and what it holds.
+#[derive(Clone, Copy)]
+struct Entry {
+    page: u32,
+    offset: u32,
+    fport: u8,
+    len: u8,
+    delivered: bool,
+    valid: bool,
+}
+impl Entry {
+    fn size(&self) -> u32 {
+        ENTRY_HEADER_SIZE + (self.len as u32).next_multiple_of(WORD)
+    }
+}
+
+/// Uplinks kept while the network can't be reached, in a ring of flash pages.
+///
+/// Entries are appended to the head page and only marked once delivered, a page is
+/// erased when the ring comes back around to it, dropping whatever it still holds. Every
+/// page is so erased once per turn of the ring whatever the traffic. Pages start with a
+/// sequence number, so that the oldest entries are found again after a reset, and
+/// entries carry a CRC so that one torn by a reset is skipped.
+pub struct Spool {
+    base: u32,
+    page_size: u32,
+    pages: u32,
+    mounted: bool,
+    /// Page appended to, `None` until the first entry.
+    head: Option<u32>,
+    seq: u32,
+    next: u32,
+}
+impl Spool {
+    pub const fn new(base: u32, page_size: u32, pages: u32) -> Self {
+        Self { base, page_size, pages, mounted: false, head: None, seq: 0, next: 0 }
+    }
+
+    pub fn push<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        fport: u8,
+        data: &[u8],
+    ) -> Result<(), SpoolError<F::Error>> {
+        if data.len() > MAX_ENTRY_SIZE {
+            return Err(SpoolError::TooLarge);
+        }
+        self.mount(flash)?;
+        let size = ENTRY_HEADER_SIZE + (data.len() as u32).next_multiple_of(WORD);
+        let head = match self.head {
+            Some(head) if self.next + size <= self.page_size => head,
+            head => self.advance(flash, head)?,
+        };
+        let offset = self.page_offset(head) + self.next;
+        let [crc_low, crc_high] = entry_crc(fport, data).to_le_bytes();
+        let len = data.len() as u8;
+        let header = [ENTRY_MARK, fport, len, crc_low, crc_high, ERASED, ERASED, ERASED];
+        // the header goes first, so that the space of a torn entry is skipped after a reset
+        flash.write(offset, &header).map_err(SpoolError::Flash)?;
+        self.next += size;
+        let mut body = [ERASED; MAX_ENTRY_SIZE.next_multiple_of(WORD as usize)];
+        body[..data.len()].copy_from_slice(data);
+        let body = &body[..(size - ENTRY_HEADER_SIZE) as usize];
+        flash.write(offset + ENTRY_HEADER_SIZE, body).map_err(SpoolError::Flash)
+    }
+
+    /// Copies the oldest undelivered entry into `buf`, returning its FPort and length.
+    pub fn peek<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        buf: &mut [u8],
+    ) -> Result<Option<(u8, usize)>, SpoolError<F::Error>> {
+        let Some(entry) = self.oldest(flash)? else {
+            return Ok(None);
+        };
+        let len = (entry.len as usize).min(buf.len());
+        flash
+            .read(self.page_offset(entry.page) + entry.offset + ENTRY_HEADER_SIZE, &mut buf[..len])
+            .map_err(SpoolError::Flash)?;
+        Ok(Some((entry.fport, len)))
+    }
+
+    /// Marks the entry [`Spool::peek`] returned as delivered.
+    pub fn pop<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), SpoolError<F::Error>> {
+        let Some(entry) = self.oldest(flash)? else {
+            return Ok(());
+        };
+        let marker = self.page_offset(entry.page) + entry.offset + WORD;
+        flash.write(marker, &[0; WORD as usize]).map_err(SpoolError::Flash)
+    }
+
+    /// Entries not delivered yet.
+    pub fn pending<F: NorFlash>(&mut self, flash: &mut F) -> Result<usize, SpoolError<F::Error>> {
+        let mut pending = 0;
+        self.scan(flash, |_| {
+            pending += 1;
+            false
+        })?;
+        Ok(pending)
+    }
+
+    fn oldest<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+    ) -> Result<Option<Entry>, SpoolError<F::Error>> {
+        let mut oldest = None;
+        self.scan(flash, |entry| {
+            oldest = Some(entry);
+            true
+        })?;
+        Ok(oldest)
+    }
+
+    /// Calls `f` on the undelivered entries from the oldest until it returns true.
+    fn scan<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        mut f: impl FnMut(Entry) -> bool,
+    ) -> Result<(), SpoolError<F::Error>> {
+        self.mount(flash)?;
+        let Some(head) = self.head else {
+            return Ok(());
+        };
+        for i in 1..=self.pages {
+            let page = (head + i) % self.pages;
+            if self.page_seq(flash, page)?.is_none() {
+                continue;
+            }
+            let mut offset = WORD;
+            while let Some(entry) = self.entry(flash, page, offset)? {
+                if entry.valid && !entry.delivered && f(entry) {
+                    return Ok(());
+                }
+                offset += entry.size();
+            }
+        }
+        Ok(())
+    }
+
+    fn page_offset(&self, page: u32) -> u32 {
+        self.base + page * self.page_size
+    }
+
+    fn page_seq<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+    ) -> Result<Option<u32>, SpoolError<F::Error>> {
+        let mut header = [0; WORD as usize];
+        flash.read(self.page_offset(page), &mut header).map_err(SpoolError::Flash)?;
+        Ok((header[0] == PAGE_MARK)
+            .then(|| u32::from_le_bytes([header[4], header[5], header[6], header[7]])))
+    }
+
+    fn entry<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        page: u32,
+        offset: u32,
+    ) -> Result<Option<Entry>, SpoolError<F::Error>> {
+        if offset + ENTRY_HEADER_SIZE > self.page_size {
+            return Ok(None);
+        }
+        let mut header = [0; ENTRY_HEADER_SIZE as usize];
+        let start = self.page_offset(page) + offset;
+        flash.read(start, &mut header).map_err(SpoolError::Flash)?;
+        if header[0] != ENTRY_MARK {
+            return Ok(None);
+        }
+        let mut entry = Entry {
+            page,
+            offset,
+            fport: header[1],
+            len: header[2],
+            delivered: header[WORD as usize] != ERASED,
+            valid: false,
+        };
+        if offset + entry.size() > self.page_size {
+            return Ok(None);
+        }
+        let mut data = [0; MAX_ENTRY_SIZE];
+        let data = &mut data[..entry.len as usize];
+        flash.read(start + ENTRY_HEADER_SIZE, data).map_err(SpoolError::Flash)?;
+        entry.valid = entry_crc(entry.fport, data).to_le_bytes() == header[3..5];
+        Ok(Some(entry))
+    }
+
+    fn mount<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), SpoolError<F::Error>> {
+        if self.mounted {
+            return Ok(());
+        }
+        for page in 0..self.pages {
+            match self.page_seq(flash, page)? {
+                Some(seq) if self.head.is_none() || seq > self.seq => {
+                    self.head = Some(page);
+                    self.seq = seq;
+                }
+                _ => {}
+            }
+        }
+        if let Some(head) = self.head {
+            self.next = WORD;
+            while let Some(entry) = self.entry(flash, head, self.next)? {
+                self.next += entry.size();
+            }
+        }
+        self.mounted = true;
+        Ok(())
+    }
+
+    /// Starts the page after `head`, dropping what it still holds.
+    fn advance<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        head: Option<u32>,
+    ) -> Result<u32, SpoolError<F::Error>> {
+        let page = head.map_or(0, |head| (head + 1) % self.pages);
+        let offset = self.page_offset(page);
+        if self.page_seq(flash, page)?.is_some() {
+            let mut dropped = 0;
+            let mut entry_offset = WORD;
+            while let Some(entry) = self.entry(flash, page, entry_offset)? {
+                dropped += (entry.valid && !entry.delivered) as u32;
+                entry_offset += entry.size();
+            }
+            if dropped > 0 {
+                defmt::warn!("spool full, {} oldest uplinks dropped", dropped);
+            }
+        }
+        flash.erase(offset, offset + self.page_size).map_err(SpoolError::Flash)?;
+        self.seq = self.seq.wrapping_add(1);
+        let mut header = [ERASED; WORD as usize];
+        header[0] = PAGE_MARK;
+        header[4..].copy_from_slice(&self.seq.to_le_bytes());
+        flash.write(offset, &header).map_err(SpoolError::Flash)?;
+        self.head = Some(page);
+        self.next = WORD;
+        Ok(page)
+    }
+}
+
+fn entry_crc(fport: u8, data: &[u8]) -> u16 {
+    let mut buf = [0; 2 + MAX_ENTRY_SIZE];
+    buf[0] = fport;
+    buf[1] = data.len() as u8;
+    buf[2..2 + data.len()].copy_from_slice(data);
+    crc16(&buf[..2 + data.len()])
+}
@@ -0,0 +1,62 @@
+//! Protection against a misconfigured network server flooding the device with downlinks,
+//! each keeping the radio on for another uplink to fetch the next one. Past
+//! [`MAX_DOWNLINKS`] within [`WINDOW`] downlinks are dropped unprocessed for [`HOLDOFF`],
+//! and the storm is flagged in the status uplink.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(600);
+const MAX_DOWNLINKS: u8 = 10;
+const HOLDOFF: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Admission {
+    Process,
+    /// The downlink starting a storm, to be dropped and reported.
+    StormStarted,
+    Drop,
+}
+
+struct StormGuard {
+    window_start: Instant,
+    downlinks: u8,
+    blocked_until: Option<Instant>,
+}
+
+static GUARD: Mutex<CriticalSectionRawMutex, RefCell<StormGuard>> =
+    Mutex::new(RefCell::new(StormGuard {
+        window_start: Instant::from_ticks(0),
+        downlinks: 0,
+        blocked_until: None,
+    }));
+
+/// Counts a received downlink and tells whether to act on it.
+pub fn admit(now: Instant) -> Admission {
+    GUARD.lock(|guard| {
+        let mut guard = guard.borrow_mut();
+        if now.saturating_duration_since(guard.window_start) >= WINDOW {
+            guard.window_start = now;
+            guard.downlinks = 0;
+        }
+        guard.downlinks = guard.downlinks.saturating_add(1);
+        match guard.blocked_until {
+            Some(until) if now < until => Admission::Drop,
+            _ if guard.downlinks > MAX_DOWNLINKS => {
+                guard.blocked_until = Some(now + HOLDOFF);
+                Admission::StormStarted
+            }
+            _ => {
+                guard.blocked_until = None;
+                Admission::Process
+            }
+        }
+    })
+}
+
+pub fn is_active() -> bool {
+    GUARD.lock(|guard| guard.borrow().blocked_until.is_some_and(|until| Instant::now() < until))
+}
@@ -0,0 +1,59 @@
+//! Restarts a task that stopped making progress, e.g. waiting on a peripheral that never
+//! answers, without resetting the device and losing the LoRaWAN session with it. Tasks
+//! send heartbeats saying how long they may take until the next one, and whoever runs a
+//! task drops it and starts it over once [`stalled`] completes.
+//!
+//! Only tasks stuck at an `.await` can be caught, one spinning without yielding holds up
+//! the executor this runs on as well. A panic still resets the device.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(usize)]
+pub enum Task {
+    /// The join and uplink loop in `main`
+    Application = 0,
+}
+const TASKS: usize = 1;
+/// Slack on top of what a task said it needs until its next heartbeat.
+const GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+struct Watch {
+    /// `None` until the first heartbeat
+    deadline: Option<Instant>,
+    restarts: u32,
+}
+
+static WATCHES: Mutex<CriticalSectionRawMutex, RefCell<[Watch; TASKS]>> =
+    Mutex::new(RefCell::new([Watch { deadline: None, restarts: 0 }; TASKS]));
+
+fn with_watch<R>(task: Task, f: impl FnOnce(&mut Watch) -> R) -> R {
+    WATCHES.lock(|watches| f(&mut watches.borrow_mut()[task as usize]))
+}
+
+/// Reports progress, the next heartbeat is due within `next_within`.
+pub fn heartbeat(task: Task, next_within: Duration) {
+    with_watch(task, |watch| watch.deadline = Some(Instant::now() + next_within + GRACE));
+}
+
+/// Completes once `task` missed a heartbeat.
+pub async fn stalled(task: Task) {
+    loop {
+        match with_watch(task, |watch| watch.deadline) {
+            Some(deadline) if Instant::now() >= deadline => break,
+            Some(deadline) => Timer::at(deadline).await,
+            None => Timer::after(GRACE).await,
+        }
+    }
+    let restarts = with_watch(task, |watch| {
+        watch.deadline = None;
+        watch.restarts += 1;
+        watch.restarts
+    });
+    defmt::error!("{:?} stalled, restarting it ({} restarts)", task, restarts);
+}
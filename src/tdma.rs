@@ -0,0 +1,80 @@
+//! Time division of uplinks for dense private networks: the network server gives each
+//! device a slot within a common period and routine uplinks wait for it, so that devices
+//! sharing a gateway don't collide. Alarms and other urgent uplinks go out at once.
+//!
+//! Slots are assigned by downlink on [`TDMA_PORT`] as `[period u16][offset u16]`, big
+//! endian and in seconds, and an empty downlink turns it off. A slot starts when the GPS
+//! time of [`clock_sync::timestamp`] is `offset` into the period, so slots only line up
+//! across devices once their clocks were set by the network.
+//!
+//! Routine uplinks are also kept a minimum spacing after the previous uplink, with or
+//! without slots, so that flushing a backlog doesn't take up a gateway's receive paths
+//! for long stretches.
+
+use embassy_time::{Duration, Instant};
+
+use crate::clock_sync;
+use crate::schema;
+
+pub const TDMA_PORT: u8 = schema::TDMA.port;
+const ASSIGNMENT_SIZE: usize = schema::TDMA.header_size();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct Assignment {
+    period: u32,
+    offset: u32,
+}
+
+#[derive(Debug, PartialEq, defmt::Format)]
+pub struct InvalidAssignment;
+
+pub struct Schedule {
+    assignment: Option<Assignment>,
+    /// Least time from the start of an uplink to the start of the next routine one
+    spacing: Duration,
+    last_uplink: Option<Instant>,
+}
+impl Schedule {
+    pub fn new(spacing: Duration) -> Self {
+        Self { assignment: None, spacing, last_uplink: None }
+    }
+
+    pub fn configure(&mut self, downlink: &[u8]) -> Result<(), InvalidAssignment> {
+        if downlink.is_empty() {
+            defmt::info!("TDMA off");
+            self.assignment = None;
+            return Ok(());
+        }
+        let downlink: &[u8; ASSIGNMENT_SIZE] =
+            downlink.try_into().map_err(|_| InvalidAssignment)?;
+        let period = u16::from_be_bytes([downlink[0], downlink[1]]) as u32;
+        let offset = u16::from_be_bytes([downlink[2], downlink[3]]) as u32;
+        if period == 0 || offset >= period {
+            return Err(InvalidAssignment);
+        }
+        let assignment = Assignment { period, offset };
+        defmt::info!("TDMA {:?}", assignment);
+        self.assignment = Some(assignment);
+        Ok(())
+    }
+
+    /// Start of the next slot, `now` within the first second of one. `None` without an
+    /// assignment.
+    pub fn next_slot(&self, now: Instant) -> Option<Instant> {
+        let Assignment { period, offset } = self.assignment?;
+        let gps_time = clock_sync::timestamp(now);
+        let wait = (offset + period - gps_time % period) % period;
+        Some(now + Duration::from_secs(wait as u64))
+    }
+
+    pub fn uplink_started(&mut self, at: Instant) {
+        self.last_uplink = Some(at);
+    }
+
+    /// When a routine uplink may start, the next slot after the spacing has passed.
+    /// `None` if it can go at once.
+    pub fn next_uplink(&self, now: Instant) -> Option<Instant> {
+        let earliest = self.last_uplink.map_or(now, |last| now.max(last + self.spacing));
+        self.next_slot(earliest).or((earliest > now).then_some(earliest))
+    }
+}
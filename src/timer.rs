@@ -1,14 +1,33 @@
+use core::cell::Cell;
 use core::convert::Infallible;
 
 use embassy_time::{Duration, Instant, Timer};
 use futures::Future;
 
+use crate::rx_schedule;
+
+/// Deadlines after a reset that open a receive window: RX1 and RX2.
+const RX_WINDOWS: u8 = 2;
+
 pub struct LoraTimer {
     start: Instant,
+    margin: Duration,
+    /// Deadlines asked for since the last reset.
+    deadlines: Cell<u8>,
 }
 impl LoraTimer {
     pub fn new() -> Self {
-        Self { start: Instant::now() }
+        Self { start: Instant::now(), margin: Duration::from_millis(0), deadlines: Cell::new(0) }
+    }
+
+    /// When the timer was last reset.
+    pub fn started(&self) -> Instant {
+        self.start
+    }
+
+    /// Fires the RX1 and RX2 deadlines after a transmission `margin` before they are due.
+    pub fn set_margin(&mut self, margin: Duration) {
+        self.margin = margin;
     }
 }
 impl Default for LoraTimer {
@@ -22,13 +41,25 @@ impl ::lorawan::device::timer::Timer for LoraTimer {
 
     fn reset(&mut self) {
         self.start = Instant::now();
+        self.deadlines.set(0);
     }
 
     type AtFuture<'a> = impl Future<Output = ()> + 'a;
 
     fn at<'a>(&self, millis: u64) -> Result<Self::AtFuture<'a>, Self::Error> {
         let start = self.start;
-        let fut = Timer::at(start + Duration::from_millis(millis));
-        Ok(fut)
+        let deadlines = self.deadlines.get();
+        self.deadlines.set(deadlines.saturating_add(1));
+        let margin = if deadlines < RX_WINDOWS {
+            self.margin
+        } else {
+            Duration::from_ticks(0)
+        };
+        let deadline = Duration::from_millis(millis).checked_sub(margin).unwrap_or_default();
+        let due = start + deadline;
+        Ok(async move {
+            Timer::at(due).await;
+            rx_schedule::window_due(due);
+        })
     }
 }
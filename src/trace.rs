@@ -0,0 +1,51 @@
+//! Timeline of radio and application activity for debugging timing between the
+//! application and the MAC. With the `trace` feature every event is printed over RTT
+//! as `trace <µs> <event>`, `tools/src/bin/trace_view.rs` turns a capture into a
+//! Chrome trace that Perfetto or chrome://tracing render as a timeline. Without the
+//! feature recording compiles to nothing.
+//!
+//! With the `markers` feature events also toggle GPIOs, see [`crate::markers`].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::energy::RadioState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TraceEvent {
+    TxStart,
+    TxEnd,
+    RxOpen,
+    RxClose,
+    SleepEnter,
+    SleepExit,
+}
+
+static RADIO_STATE: AtomicU8 = AtomicU8::new(RadioState::Idle as u8);
+
+#[inline(always)]
+pub fn record(event: TraceEvent) {
+    #[cfg(feature = "trace")]
+    defmt::println!("trace {=u64} {}", embassy_time::Instant::now().as_micros(), event);
+    #[cfg(feature = "markers")]
+    crate::markers::record(event);
+    #[cfg(not(any(feature = "trace", feature = "markers")))]
+    let _ = event;
+}
+
+/// Records the end of the previous radio state and the start of the new one.
+pub fn radio(state: RadioState) {
+    let previous = RADIO_STATE.swap(state as u8, Ordering::Relaxed);
+    if previous == state as u8 {
+        return;
+    }
+    if previous == RadioState::Tx as u8 {
+        record(TraceEvent::TxEnd);
+    } else if previous == RadioState::Rx as u8 {
+        record(TraceEvent::RxClose);
+    }
+    match state {
+        RadioState::Tx => record(TraceEvent::TxStart),
+        RadioState::Rx => record(TraceEvent::RxOpen),
+        RadioState::Idle => {}
+    }
+}
@@ -0,0 +1,173 @@
+//! Installing a firmware update received by FUOTA. The STM32WLE5 has a single flash bank
+//! and nothing runs before the application, so with the `fuota` feature the application
+//! is linked into the lower half of the flash and an update is staged in the upper half,
+//! below the provisioning and storage pages, which are never touched.
+//!
+//! Once an image is staged, [`stage`] checks it and records its size and CRC in the
+//! journal, activating it at the next reset. [`install`] finds the record at boot, checks
+//! the staged image again and copies it over the application from RAM with interrupts
+//! off, then resets into it. Losing power during the copy leaves no image to boot, which only a bootloader
+//! could avoid.
+
+use embassy_stm32::pac;
+
+use crate::device::DeviceNonVolatileStore;
+use crate::firmware::{self, ImageInfo, UpdateError};
+use crate::journal::RecordKey;
+
+const PAGE_SIZE: u32 = 2048;
+const FLASH_KEYR: u32 = 0x5800_4008;
+const FLASH_SR: u32 = 0x5800_4010;
+const FLASH_CR: u32 = 0x5800_4014;
+const SCB_AIRCR: u32 = 0xE000_ED0C;
+const SR_BUSY: u32 = 1 << 16 | 1 << 18;
+/// EOP and the error flags, cleared by writing them.
+const SR_CLEAR: u32 = 0x0000_C3FB;
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+const STAGED_SIZE: usize = 8;
+
+/// The first `size` bytes of the staging partition.
+fn staged_image(size: u32) -> &'static [u8] {
+    let (offset, _) = DeviceNonVolatileStore::<'_>::staging();
+    let start = (pac::FLASH_BASE as u32 + offset) as *const u8;
+    unsafe { core::slice::from_raw_parts(start, size as usize) }
+}
+
+fn record(store: &mut DeviceNonVolatileStore<'_>) -> Option<(u32, u32)> {
+    let mut staged = [0; STAGED_SIZE];
+    let Ok(STAGED_SIZE) = store.read_record(RecordKey::StagedImage, &mut staged) else {
+        return None;
+    };
+    let size = u32::from_le_bytes([staged[0], staged[1], staged[2], staged[3]]);
+    let crc = u32::from_le_bytes([staged[4], staged[5], staged[6], staged[7]]);
+    Some((size, crc))
+}
+
+/// Checks the `size` bytes staged, to be installed at the next reset.
+pub fn stage(store: &mut DeviceNonVolatileStore<'_>, size: u32) -> Result<ImageInfo, UpdateError> {
+    let (_, capacity) = DeviceNonVolatileStore::<'_>::staging();
+    if size > capacity {
+        return Err(UpdateError::TooLarge);
+    }
+    let image = staged_image(size);
+    let info = firmware::check_update(store, image)?;
+    let mut staged = [0; STAGED_SIZE];
+    staged[..4].copy_from_slice(&size.to_le_bytes());
+    staged[4..].copy_from_slice(&crc32(image).to_le_bytes());
+    store.write_record(RecordKey::StagedImage, &staged).map_err(UpdateError::Store)?;
+    defmt::info!("update {} staged, installed at the next reset", info.build_id());
+    Ok(info)
+}
+
+/// The update to be installed at the next reset, checked again.
+pub fn staged(store: &mut DeviceNonVolatileStore<'_>) -> Result<Option<ImageInfo>, UpdateError> {
+    let Some((size, crc)) = record(store) else {
+        return Ok(None);
+    };
+    let image = staged_image(size);
+    if crc32(image) != crc {
+        return Err(UpdateError::Corrupted);
+    }
+    firmware::check_update(store, image).map(Some)
+}
+
+/// Drops the staged update.
+pub fn discard(store: &mut DeviceNonVolatileStore<'_>) -> Result<(), UpdateError> {
+    store.write_record(RecordKey::StagedImage, &[]).map_err(UpdateError::Store)?;
+    store.erase_staging().map_err(UpdateError::Store)
+}
+
+/// Installs an update staged by [`stage`], never returning when there is one. Called at
+/// boot before the application touches the radio.
+pub fn install(store: &mut DeviceNonVolatileStore<'_>) {
+    let Some((size, crc)) = record(store) else {
+        return;
+    };
+    // cleared first, an install that fails half way must not be tried at every boot
+    if let Err(e) = store.write_record(RecordKey::StagedImage, &[]) {
+        defmt::error!("update not installed {:?}", e);
+        return;
+    }
+    let image = staged_image(size);
+    if crc32(image) != crc {
+        defmt::error!("staged update corrupted, not installed");
+        return;
+    }
+    defmt::info!("installing update of {} bytes", size);
+    cortex_m::interrupt::disable();
+    unsafe { copy_image(image.as_ptr() as u32, size) }
+}
+
+// plain loads and stores, calls into core could be left in flash
+macro_rules! read_register {
+    ($address:expr) => {{
+        let value: u32;
+        core::arch::asm!("ldr {}, [{}]", out(reg) value, in(reg) $address, options(nostack));
+        value
+    }};
+}
+macro_rules! write_register {
+    ($address:expr, $value:expr) => {
+        core::arch::asm!("str {}, [{}]", in(reg) $value, in(reg) $address, options(nostack))
+    };
+}
+macro_rules! wait_flash {
+    () => {
+        while read_register!(FLASH_SR) & SR_BUSY != 0 {}
+    };
+}
+
+/// Erases the application and programs the staged image over it. Runs from RAM and
+/// only touches registers, as the code in flash is gone once it starts.
+#[inline(never)]
+#[link_section = ".data.copy_image"]
+unsafe fn copy_image(from: u32, size: u32) -> ! {
+    wait_flash!();
+    if read_register!(FLASH_CR) & CR_LOCK != 0 {
+        write_register!(FLASH_KEYR, 0x4567_0123u32);
+        write_register!(FLASH_KEYR, 0xCDEF_89ABu32);
+    }
+    write_register!(FLASH_SR, SR_CLEAR);
+    let mut page = 0;
+    while page * PAGE_SIZE < size {
+        write_register!(FLASH_CR, CR_PER | page << 3);
+        write_register!(FLASH_CR, CR_PER | page << 3 | CR_STRT);
+        wait_flash!();
+        write_register!(FLASH_CR, 0u32);
+        page += 1;
+    }
+    write_register!(FLASH_CR, CR_PG);
+    // programmed a double word at a time
+    let to = pac::FLASH_BASE as u32;
+    let mut offset = 0;
+    while offset < size {
+        write_register!(to + offset, read_register!(from + offset));
+        write_register!(to + offset + 4, read_register!(from + offset + 4));
+        wait_flash!();
+        offset += 8;
+    }
+    write_register!(FLASH_CR, 0u32);
+    write_register!(SCB_AIRCR, 0x05FA_0004u32);
+    loop {
+        core::arch::asm!("nop");
+    }
+}
+
+/// CRC-32/ISO-HDLC, as zlib's.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
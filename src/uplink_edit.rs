@@ -0,0 +1,222 @@
+//! Edits to data uplinks on their way to the radio, for what the lorawan MAC doesn't let the
+//! board say itself: the LinkADRAns to a LinkADRReq beyond the PA goes out with the power
+//! bit NACKed per [`crate::link_adr`], once the main loop has put back the data rate, TX
+//! power and NbTrans the MAC took from it. The channel mask stays as the MAC applied it.
+//! Requests the MAC has no API for, a LinkCheckReq for [`crate::link_check`] and a
+//! DeviceTimeReq for [`crate::rtc`], are appended to FOpts, the MAC hands their answers to
+//! the [`lorawan::device::Device`] hooks. The ADR and ADRACKReq bits of FCtrl are the ones
+//! of [`crate::adr`], which the MAC can't be given.
+//!
+//! [`crate::iv::SubghzSpiDevice`] hands over each frame written to the radio buffer,
+//! [`crate::fopts`] edits it and the MIC is computed again with the NwkSKey. The key is
+//! derived here from the JoinAccept, decrypted as [`crate::channels`] does, and kept in the
+//! journal for a session restored from flash. The MIC of the frame as the MAC made it is
+//! checked first: with the key of another session, or upper FCntUp bits missed, the frame
+//! goes out as it is. A frame that got longer needs the payload length of the last
+//! SetPacketParams sent again, see [`packet_params`].
+
+use core::cell::RefCell;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+
+use crate::device::{DeviceNonVolatileStore, NonVolatileStoreError};
+use crate::fopts::{self, Edit, Edited};
+use crate::frames::MAX_FRAME_SIZE;
+use crate::journal::RecordKey;
+
+const MIC_SIZE: usize = 4;
+const KEY_SIZE: usize = 16;
+const UNCONFIRMED_DATA_UP: u8 = 2;
+const CONFIRMED_DATA_UP: u8 = 4;
+/// SetPacketParams for GFSK, LoRa takes 7.
+const MAX_PACKET_PARAMS_SIZE: usize = 10;
+
+struct State {
+    nwk_s_key: Option<[u8; KEY_SIZE]>,
+    /// FCntUp of the session as last saved, for the upper 16 bits frames don't carry.
+    fcnt_up: u32,
+    /// For the next data uplink, the ADR bits for every one after it as well.
+    edit: Edit,
+    /// The last SetPacketParams on its way to the radio.
+    packet_params: Vec<u8, MAX_PACKET_PARAMS_SIZE>,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    nwk_s_key: None,
+    fcnt_up: 0,
+    edit: Edit {
+        link_adr_nack: 0,
+        link_check_req: false,
+        device_time_req: false,
+        adr: None,
+        adr_ack_req: None,
+    },
+    packet_params: Vec::new(),
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.lock(|state| f(&mut state.borrow_mut()))
+}
+
+pub fn load(store: &mut DeviceNonVolatileStore<'_>) {
+    let mut nwk_s_key = [0; KEY_SIZE];
+    if let Ok(KEY_SIZE) = store.read_record(RecordKey::NwkSKey, &mut nwk_s_key) {
+        with_state(|state| state.nwk_s_key = Some(nwk_s_key));
+    }
+}
+
+/// Derives and persists the NwkSKey of the session started by `join_accept`, the answer to
+/// the JoinRequest with `dev_nonce`.
+pub fn joined(
+    store: &mut DeviceNonVolatileStore<'_>,
+    join_accept: &[u8],
+    dev_nonce: u16,
+    app_key: &[u8; 16],
+) -> Result<(), NonVolatileStoreError> {
+    let nwk_s_key = match join_accept {
+        [_, encrypted @ ..] if encrypted.len() >= 16 && encrypted.len() % 16 == 0 => {
+            // the network encrypts JoinAccepts with AES decrypt, so they are read with encrypt
+            let aes = Aes128::new(GenericArray::from_slice(app_key));
+            let mut block: [u8; 16] = encrypted[..16].try_into().unwrap();
+            aes.encrypt_block(GenericArray::from_mut_slice(&mut block));
+            // 0x01 | AppNonce | NetID | DevNonce | pad
+            let mut nwk_s_key = [0; KEY_SIZE];
+            nwk_s_key[0] = 0x01;
+            nwk_s_key[1..7].copy_from_slice(&block[..6]);
+            nwk_s_key[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+            aes.encrypt_block(GenericArray::from_mut_slice(&mut nwk_s_key));
+            Some(nwk_s_key)
+        }
+        _ => None,
+    };
+    with_state(|state| {
+        state.nwk_s_key = nwk_s_key;
+        state.fcnt_up = 0;
+    });
+    store.write_record(RecordKey::NwkSKey, nwk_s_key.as_ref().map_or(&[], |key| &key[..]))
+}
+
+/// From the session as the MAC saves or loads it.
+pub fn set_fcnt_up(fcnt_up: u32) {
+    with_state(|state| state.fcnt_up = fcnt_up);
+}
+
+/// Clears `status` bits in the LinkADRAns of the next uplink, see [`crate::link_adr::nack`].
+pub fn nack_link_adr(status: u8) {
+    with_state(|state| state.edit.link_adr_nack |= status);
+}
+
+/// Appends a LinkCheckReq to the next uplink.
+pub fn request_link_check() {
+    with_state(|state| state.edit.link_check_req = true);
+}
+
+/// Appends a DeviceTimeReq to the next uplink.
+pub fn request_device_time() {
+    with_state(|state| state.edit.device_time_req = true);
+}
+
+/// The ADR bits of the uplinks from the next one on, see [`crate::adr::update`].
+pub fn set_adr(adr: bool, adr_ack_req: bool) {
+    with_state(|state| {
+        state.edit.adr = Some(adr);
+        state.edit.adr_ack_req = Some(adr_ack_req);
+    });
+}
+
+/// From a SetPacketParams command.
+pub fn set_packet(command: &[u8]) {
+    if let Ok(command) = Vec::from_slice(command) {
+        with_state(|state| state.packet_params = command);
+    }
+}
+
+/// The last SetPacketParams with a payload length of `len`, for a frame edited to it.
+pub fn packet_params(len: usize) -> Option<Vec<u8, MAX_PACKET_PARAMS_SIZE>> {
+    let mut command = with_state(|state| state.packet_params.clone());
+    // where the packet type puts it, the commands differ in length
+    let index = match command.len() {
+        7 => 4,
+        MAX_PACKET_PARAMS_SIZE => 7,
+        _ => return None,
+    };
+    command[index] = u8::try_from(len).ok()?;
+    Some(command)
+}
+
+/// The data uplink `phy` as it should go out, `None` to send it as it is. What was to be
+/// edited is done with either way, the answers of the MAC only go out once.
+pub fn edit(phy: &[u8]) -> Option<Vec<u8, MAX_FRAME_SIZE>> {
+    if !matches!(phy.first()? >> 5, UNCONFIRMED_DATA_UP | CONFIRMED_DATA_UP) {
+        return None;
+    }
+    let (edit, nwk_s_key, fcnt_up) = with_state(|state| {
+        let edit = state.edit;
+        state.edit = Edit { adr: edit.adr, adr_ack_req: edit.adr_ack_req, ..Edit::default() };
+        (edit, state.nwk_s_key, state.fcnt_up)
+    });
+    if edit == Edit::default() {
+        return None;
+    }
+    let (message, mic) = phy.split_last_chunk::<MIC_SIZE>()?;
+    let mut buf = [0; MAX_FRAME_SIZE - MIC_SIZE];
+    buf.get_mut(..message.len())?.copy_from_slice(message);
+    let edited = fopts::edit(&mut buf, message.len(), &edit);
+    if edit.link_adr_nack != 0 && edited.is_none_or(|edited| edited.link_adr_nacked == 0) {
+        defmt::warn!("no LinkADRAns in FOpts to NACK");
+    }
+    if edit.link_check_req && edited.is_none_or(|edited| !edited.link_check_req) {
+        defmt::warn!("no room in FOpts for a LinkCheckReq");
+    }
+    if edit.device_time_req && edited.is_none_or(|edited| !edited.device_time_req) {
+        defmt::warn!("no room in FOpts for a DeviceTimeReq");
+    }
+    let Some(Edited { len, .. }) = edited
+        .filter(|edited| edited.link_adr_nacked > 0 || edited.fctrl || edited.len != message.len())
+    else {
+        return None;
+    };
+    let mut frame = Vec::<u8, MAX_FRAME_SIZE>::from_slice(&buf[..len]).ok()?;
+    let Some(nwk_s_key) = nwk_s_key else {
+        defmt::warn!("uplink not edited, no NwkSKey");
+        return None;
+    };
+    let dev_addr = u32::from_le_bytes([phy[1], phy[2], phy[3], phy[4]]);
+    let fcnt16 = u16::from_le_bytes([phy[6], phy[7]]) as u32;
+    let high = fcnt_up & !0xFFFF;
+    let Some(fcnt) = [high, high.wrapping_add(0x1_0000)]
+        .into_iter()
+        .map(|high| high | fcnt16)
+        .find(|fcnt| compute_mic(&nwk_s_key, dev_addr, *fcnt, message) == *mic)
+    else {
+        defmt::warn!("uplink not edited, its MIC doesn't match the NwkSKey");
+        return None;
+    };
+    let mic = compute_mic(&nwk_s_key, dev_addr, fcnt, &frame);
+    frame.extend_from_slice(&mic).ok()?;
+    Some(frame)
+}
+
+fn compute_mic(
+    nwk_s_key: &[u8; KEY_SIZE],
+    dev_addr: u32,
+    fcnt: u32,
+    message: &[u8],
+) -> [u8; MIC_SIZE] {
+    // B0 of an uplink
+    let mut block = [0; 16];
+    block[0] = 0x49;
+    block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    block[15] = message.len() as u8;
+    let mut cmac = <Cmac<Aes128> as Mac>::new_from_slice(nwk_s_key).unwrap();
+    cmac.update(&block);
+    cmac.update(message);
+    cmac.finalize().into_bytes()[..MIC_SIZE].try_into().unwrap()
+}
@@ -0,0 +1,88 @@
+//! Inputs that wake the device for an immediate uplink on [`WAKE_PORT`], for door contacts,
+//! panic buttons and the like. Pins are given [`PinFunction::WakeInput`] in the pin map;
+//! every edge is debounced by reading the level again once it settled, and reported as
+//! `[slot][state][timestamp]` unless the slot already reported within the minimum
+//! interval.
+//!
+//! [`PinFunction::WakeInput`]: crate::pin_map::PinFunction::WakeInput
+
+use embassy_stm32::exti::ExtiInput;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::clock_sync;
+use crate::payload;
+use crate::pin_map::SLOTS;
+use crate::schema;
+
+pub const WAKE_PORT: u8 = schema::WAKE.port;
+pub const WAKE_PAYLOAD_SIZE: usize = schema::WAKE.header_size();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct WakeConfig {
+    pub edge: Edge,
+    /// How long the level has to hold after an edge.
+    pub debounce: Duration,
+    /// Shortest time between two uplinks of the same slot, edges in between are dropped.
+    pub min_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct WakeEvent {
+    pub slot: u8,
+    pub high: bool,
+    /// on the network clock, see [`clock_sync::timestamp`]
+    pub timestamp: u32,
+}
+impl WakeEvent {
+    pub fn encode(&self) -> [u8; WAKE_PAYLOAD_SIZE] {
+        payload::wake(self.slot, self.high, self.timestamp)
+    }
+}
+
+static EVENTS: Channel<CriticalSectionRawMutex, WakeEvent, SLOTS> = Channel::new();
+
+#[embassy_executor::task(pool_size = SLOTS)]
+pub async fn watch(slot: usize, mut input: ExtiInput<'static>, config: WakeConfig) {
+    let mut high = input.is_high();
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        input.wait_for_any_edge().await;
+        Timer::after(config.debounce).await;
+        if input.is_high() == high {
+            continue;
+        }
+        high = !high;
+        let wanted = match config.edge {
+            Edge::Rising => high,
+            Edge::Falling => !high,
+            Edge::Both => true,
+        };
+        if !wanted {
+            continue;
+        }
+        if last_sent.is_some_and(|at| at.elapsed() < config.min_interval) {
+            defmt::warn!("wake input {} rate limited", slot);
+            continue;
+        }
+        let now = Instant::now();
+        last_sent = Some(now);
+        let event = WakeEvent { slot: slot as u8, high, timestamp: clock_sync::timestamp(now) };
+        if EVENTS.try_send(event).is_err() {
+            defmt::warn!("dropped {:?}", event);
+        }
+    }
+}
+
+/// Waits for the next debounced edge of a wake input.
+pub async fn next_event() -> WakeEvent {
+    EVENTS.receive().await
+}
@@ -0,0 +1,258 @@
+//! Minimal AES-128 and AES-CMAC (RFC 4493), enough to emulate the network side of
+//! LoRaWAN 1.0.x. Not constant time, do not use outside of tests and tooling.
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+pub type Block = [u8; 16];
+
+fn inv_sbox() -> [u8; 256] {
+    let mut inv = [0; 256];
+    for (i, s) in SBOX.iter().enumerate() {
+        inv[*s as usize] = i as u8;
+    }
+    inv
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1)
+        ^ if b & 0x80 != 0 {
+            0x1b
+        } else {
+            0
+        }
+}
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    p
+}
+
+pub struct Aes128 {
+    round_keys: [Block; 11],
+}
+
+impl Aes128 {
+    pub fn new(key: &Block) -> Self {
+        let mut w = [[0u8; 4]; 44];
+        for (i, word) in w.iter_mut().take(4).enumerate() {
+            word.copy_from_slice(&key[i * 4..i * 4 + 4]);
+        }
+        for i in 4..44 {
+            let mut t = w[i - 1];
+            if i % 4 == 0 {
+                t = [
+                    SBOX[t[1] as usize],
+                    SBOX[t[2] as usize],
+                    SBOX[t[3] as usize],
+                    SBOX[t[0] as usize],
+                ];
+                t[0] ^= RCON[i / 4 - 1];
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - 4][j] ^ t[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 11];
+        for (r, round_key) in round_keys.iter_mut().enumerate() {
+            for c in 0..4 {
+                round_key[c * 4..c * 4 + 4].copy_from_slice(&w[r * 4 + c]);
+            }
+        }
+        Self { round_keys }
+    }
+
+    pub fn encrypt(&self, block: &Block) -> Block {
+        let mut s = *block;
+        add_round_key(&mut s, &self.round_keys[0]);
+        for (round, round_key) in self.round_keys.iter().enumerate().skip(1) {
+            for b in s.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(&mut s);
+            if round != 10 {
+                mix_columns(&mut s);
+            }
+            add_round_key(&mut s, round_key);
+        }
+        s
+    }
+
+    pub fn decrypt(&self, block: &Block) -> Block {
+        let inv = inv_sbox();
+        let mut s = *block;
+        add_round_key(&mut s, &self.round_keys[10]);
+        for (round, round_key) in self.round_keys.iter().enumerate().take(10).rev() {
+            inv_shift_rows(&mut s);
+            for b in s.iter_mut() {
+                *b = inv[*b as usize];
+            }
+            add_round_key(&mut s, round_key);
+            if round != 0 {
+                inv_mix_columns(&mut s);
+            }
+        }
+        s
+    }
+}
+
+fn add_round_key(s: &mut Block, k: &Block) {
+    for (b, k) in s.iter_mut().zip(k) {
+        *b ^= k;
+    }
+}
+
+fn shift_rows(s: &mut Block) {
+    let t = *s;
+    for c in 0..4 {
+        for r in 0..4 {
+            s[c * 4 + r] = t[((c + r) % 4) * 4 + r];
+        }
+    }
+}
+
+fn inv_shift_rows(s: &mut Block) {
+    let t = *s;
+    for c in 0..4 {
+        for r in 0..4 {
+            s[((c + r) % 4) * 4 + r] = t[c * 4 + r];
+        }
+    }
+}
+
+fn mix_columns(s: &mut Block) {
+    for c in s.as_chunks_mut::<4>().0 {
+        let a = [c[0], c[1], c[2], c[3]];
+        c[0] = mul(a[0], 2) ^ mul(a[1], 3) ^ a[2] ^ a[3];
+        c[1] = a[0] ^ mul(a[1], 2) ^ mul(a[2], 3) ^ a[3];
+        c[2] = a[0] ^ a[1] ^ mul(a[2], 2) ^ mul(a[3], 3);
+        c[3] = mul(a[0], 3) ^ a[1] ^ a[2] ^ mul(a[3], 2);
+    }
+}
+
+fn inv_mix_columns(s: &mut Block) {
+    for c in s.as_chunks_mut::<4>().0 {
+        let a = [c[0], c[1], c[2], c[3]];
+        c[0] = mul(a[0], 14) ^ mul(a[1], 11) ^ mul(a[2], 13) ^ mul(a[3], 9);
+        c[1] = mul(a[0], 9) ^ mul(a[1], 14) ^ mul(a[2], 11) ^ mul(a[3], 13);
+        c[2] = mul(a[0], 13) ^ mul(a[1], 9) ^ mul(a[2], 14) ^ mul(a[3], 11);
+        c[3] = mul(a[0], 11) ^ mul(a[1], 13) ^ mul(a[2], 9) ^ mul(a[3], 14);
+    }
+}
+
+fn double(b: &Block) -> Block {
+    let mut out = [0u8; 16];
+    for i in 0..16 {
+        out[i] = b[i] << 1
+            | if i < 15 {
+                b[i + 1] >> 7
+            } else {
+                0
+            };
+    }
+    if b[0] & 0x80 != 0 {
+        out[15] ^= 0x87;
+    }
+    out
+}
+
+pub fn cmac(key: &Block, msg: &[u8]) -> Block {
+    let aes = Aes128::new(key);
+    let k1 = double(&aes.encrypt(&[0; 16]));
+    let k2 = double(&k1);
+    let blocks = msg.len().div_ceil(16).max(1);
+    let mut x = [0u8; 16];
+    for i in 0..blocks {
+        let chunk = &msg[i * 16..msg.len().min(i * 16 + 16)];
+        let mut block = [0u8; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        if i == blocks - 1 {
+            let subkey = if chunk.len() == 16 {
+                k1
+            } else {
+                block[chunk.len()] = 0x80;
+                k2
+            };
+            add_round_key(&mut block, &subkey);
+        }
+        add_round_key(&mut x, &block);
+        x = aes.encrypt(&x);
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn block(s: &str) -> Block {
+        hex(s).try_into().unwrap()
+    }
+
+    const RFC4493_KEY: &str = "2b7e1516 28aed2a6 abf71588 09cf4f3c";
+    const RFC4493_MSG: &str =
+        "6bc1bee2 2e409f96 e93d7e11 7393172a ae2d8a57 1e03ac9c 9eb76fac 45af8e51
+        30c81c46 a35ce411 e5fbc119 1a0a52ef f69f2445 df4f9b17 ad2b417b e66c3710";
+
+    #[test]
+    fn fips197_appendix_b() {
+        let aes = Aes128::new(&block("2b7e1516 28aed2a6 abf71588 09cf4f3c"));
+        let plaintext = block("3243f6a8 885a308d 313198a2 e0370734");
+        let ciphertext = block("3925841d 02dc09fb dc118597 196a0b32");
+        assert_eq!(aes.encrypt(&plaintext), ciphertext);
+        assert_eq!(aes.decrypt(&ciphertext), plaintext);
+    }
+
+    #[test]
+    fn fips197_appendix_c1() {
+        let aes = Aes128::new(&block("00010203 04050607 08090a0b 0c0d0e0f"));
+        let plaintext = block("00112233 44556677 8899aabb ccddeeff");
+        let ciphertext = block("69c4e0d8 6a7b0430 d8cdb780 70b4c55a");
+        assert_eq!(aes.encrypt(&plaintext), ciphertext);
+        assert_eq!(aes.decrypt(&ciphertext), plaintext);
+    }
+
+    #[test]
+    fn rfc4493_examples() {
+        let key = block(RFC4493_KEY);
+        let msg = hex(RFC4493_MSG);
+        for (len, mac) in [
+            (0, "bb1d6929 e9593728 7fa37d12 9b756746"),
+            (16, "070a16b4 6b4d4144 f79bdd9d d04a287c"),
+            (40, "dfa66747 de9ae630 30ca3261 1497c827"),
+            (64, "51f0bebf 7e3b9d92 fc497417 79363cfe"),
+        ] {
+            assert_eq!(cmac(&key, &msg[..len]), block(mac), "message of {len} bytes");
+        }
+    }
+}
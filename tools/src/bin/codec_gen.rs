@@ -0,0 +1,108 @@
+//! Emits a TTN v3 / ChirpStack v4 compatible JavaScript `decodeUplink` for the
+//! payloads described in the firmware's `schema.rs`.
+//!
+//! Usage: `cargo run --bin codec_gen > decoder.js`
+
+use std::fmt::Write;
+
+use lorawan_pilot_tools::schema::{Field, FieldKind, PayloadSchema, SCHEMAS};
+
+fn main() {
+    print!("{}", generate(SCHEMAS));
+}
+
+fn generate(schemas: &[PayloadSchema]) -> String {
+    let mut out = String::new();
+    writeln!(out, "// Generated by lorawan-pilot-tools codec_gen, do not edit.").unwrap();
+    writeln!(out, "function readField(bytes, offset, kind) {{").unwrap();
+    writeln!(out, "  switch (kind) {{").unwrap();
+    writeln!(out, "    case \"u8\": return bytes[offset];").unwrap();
+    writeln!(out, "    case \"u16\": return (bytes[offset] << 8) | bytes[offset + 1];").unwrap();
+    writeln!(
+        out,
+        "    case \"u32\": return ((bytes[offset] << 24) | (bytes[offset + 1] << 16) | \
+         (bytes[offset + 2] << 8) | bytes[offset + 3]) >>> 0;"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    case \"i32\": return (bytes[offset] << 24) | (bytes[offset + 1] << 16) | \
+         (bytes[offset + 2] << 8) | bytes[offset + 3];"
+    )
+    .unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "function readFields(bytes, offset, fields) {{").unwrap();
+    writeln!(out, "  var obj = {{}};").unwrap();
+    writeln!(out, "  for (var i = 0; i < fields.length; i++) {{").unwrap();
+    writeln!(out, "    var f = fields[i];").unwrap();
+    writeln!(out, "    var v = readField(bytes, offset, f.kind);").unwrap();
+    writeln!(out, "    obj[f.name] = f.names ? (f.names[v] || v) : v;").unwrap();
+    writeln!(out, "    offset += f.size;").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "  return obj;").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "var SCHEMAS = {{").unwrap();
+    for schema in schemas {
+        writeln!(out, "  {}: {{", schema.port).unwrap();
+        writeln!(out, "    name: \"{}\",", schema.name).unwrap();
+        writeln!(out, "    headerSize: {},", schema.header_size()).unwrap();
+        writeln!(out, "    itemSize: {},", schema.item_size()).unwrap();
+        writeln!(out, "    header: {},", fields(schema.header)).unwrap();
+        writeln!(out, "    item: {},", fields(schema.item)).unwrap();
+        writeln!(out, "  }},").unwrap();
+    }
+    writeln!(out, "}};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "function decodeUplink(input) {{").unwrap();
+    writeln!(out, "  var schema = SCHEMAS[input.fPort];").unwrap();
+    writeln!(out, "  if (!schema) {{").unwrap();
+    writeln!(out, "    return {{ errors: [\"unknown fPort \" + input.fPort] }};").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "  var bytes = input.bytes;").unwrap();
+    writeln!(out, "  if (bytes.length < schema.headerSize) {{").unwrap();
+    writeln!(out, "    return {{ errors: [\"payload too short for \" + schema.name] }};").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "  var data = readFields(bytes, 0, schema.header);").unwrap();
+    writeln!(out, "  data.type = schema.name;").unwrap();
+    writeln!(out, "  if (schema.itemSize > 0) {{").unwrap();
+    writeln!(out, "    data.items = [];").unwrap();
+    writeln!(
+        out,
+        "    for (var o = schema.headerSize; o + schema.itemSize <= bytes.length; o += schema.itemSize) {{"
+    )
+    .unwrap();
+    writeln!(out, "      data.items.push(readFields(bytes, o, schema.item));").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "  return {{ data: data }};").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn fields(fields: &[Field]) -> String {
+    let mut out = String::from("[");
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        let (kind, names) = match field.kind {
+            FieldKind::U8 => ("u8", None),
+            FieldKind::U16 => ("u16", None),
+            FieldKind::U32 => ("u32", None),
+            FieldKind::I32 => ("i32", None),
+            FieldKind::Enum(names) => ("u8", Some(names)),
+        };
+        write!(out, "{{ name: \"{}\", kind: \"{}\", size: {}", field.name, kind, field.kind.size())
+            .unwrap();
+        if let Some(names) = names {
+            let names: Vec<String> = names.iter().map(|n| format!("\"{n}\"")).collect();
+            write!(out, ", names: [{}]", names.join(", ")).unwrap();
+        }
+        out.push_str(" }");
+    }
+    out.push(']');
+    out
+}
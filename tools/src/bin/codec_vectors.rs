@@ -0,0 +1,157 @@
+//! Emits golden test vectors for the uplink decoders: for each case the FPort, the payload
+//! as the firmware's own encoders in `payload.rs` build it, and the object the decoder
+//! from `codec_gen` is expected to return for it. Backend decoders can be checked against
+//! the fixtures before they are deployed.
+//!
+//! Usage: `cargo run --bin codec_vectors > fixtures/codec_vectors.json`
+
+use std::fmt::Write;
+
+use lorawan_pilot_tools::payload::{self, Status};
+use lorawan_pilot_tools::schema::{self, Field, FieldKind, PayloadSchema};
+
+/// Never for the link times in the status.
+const NEVER: u32 = u32::MAX;
+
+struct Vector {
+    schema: PayloadSchema,
+    /// Field values in schema order, enums by index.
+    header: Vec<i64>,
+    items: Vec<Vec<i64>>,
+    bytes: Vec<u8>,
+}
+
+fn main() {
+    let vectors = vectors();
+    let mut out = String::from("[\n");
+    for (i, vector) in vectors.iter().enumerate() {
+        let separator = if i + 1 < vectors.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(out, "  {{").unwrap();
+        writeln!(out, "    \"name\": \"{}\",", vector.schema.name).unwrap();
+        writeln!(out, "    \"fPort\": {},", vector.schema.port).unwrap();
+        let hex: String = vector.bytes.iter().map(|b| format!("{b:02X}")).collect();
+        writeln!(out, "    \"bytes\": \"{hex}\",").unwrap();
+        write!(out, "    \"expected\": {{ {}", object(vector.schema.header, &vector.header))
+            .unwrap();
+        write!(out, ", \"type\": \"{}\"", vector.schema.name).unwrap();
+        if vector.schema.item_size() > 0 {
+            let items: Vec<String> = vector
+                .items
+                .iter()
+                .map(|item| format!("{{ {} }}", object(vector.schema.item, item)))
+                .collect();
+            write!(out, ", \"items\": [{}]", items.join(", ")).unwrap();
+        }
+        writeln!(out, " }}").unwrap();
+        writeln!(out, "  }}{separator}").unwrap();
+    }
+    out.push_str("]\n");
+    print!("{out}");
+}
+
+fn vectors() -> Vec<Vector> {
+    let status = Status {
+        boot_count: 12,
+        uptime: 3_456_789,
+        last_session: 86_400,
+        battery_permille: 873,
+        battery_days_left: 1_204,
+        flags: 0b0001_0100,
+        radio_temperature: -1_250,
+        radio_supply_mv: 3_280,
+        radio_errors: 1 << 8,
+        since_last_ack: 3_600,
+        since_last_downlink: NEVER,
+    };
+    let mut batch = vec![0; payload::BATCH_HEADER_SIZE + 2 * payload::BATCH_ITEM_SIZE];
+    payload::batch_header(&mut batch, 1_300_000_000, 2);
+    payload::batch_item(&mut batch[payload::BATCH_HEADER_SIZE..], 0, 0, 2_150);
+    let second = payload::BATCH_HEADER_SIZE + payload::BATCH_ITEM_SIZE;
+    payload::batch_item(&mut batch[second..], 1, 600, -1);
+    vec![
+        Vector {
+            schema: schema::ALARM,
+            header: vec![0, 1, 0, 6_150],
+            items: vec![],
+            bytes: payload::alarm(0, true, 0, 6_150).to_vec(),
+        },
+        Vector {
+            schema: schema::ALARM,
+            header: vec![3, 0, 2, -1_250],
+            items: vec![],
+            bytes: payload::alarm(3, false, 2, -1_250).to_vec(),
+        },
+        Vector {
+            schema: schema::MOTION,
+            header: vec![1, u32::MAX as i64],
+            items: vec![],
+            bytes: payload::motion(1, u32::MAX).to_vec(),
+        },
+        Vector {
+            schema: schema::GEOFENCE,
+            header: vec![1, 1, 526_843_000, 61_418_000],
+            items: vec![],
+            bytes: payload::geofence(1, true, 526_843_000, 61_418_000).to_vec(),
+        },
+        Vector {
+            schema: schema::GEOFENCE,
+            header: vec![0, 0, -338_688_000, 151_209_000],
+            items: vec![],
+            bytes: payload::geofence(0, false, -338_688_000, 151_209_000).to_vec(),
+        },
+        Vector {
+            schema: schema::BATCH,
+            header: vec![1_300_000_000, 2],
+            items: vec![vec![0, 0, 2_150], vec![1, 600, -1]],
+            bytes: batch,
+        },
+        Vector {
+            schema: schema::STATUS,
+            header: vec![
+                status.boot_count as i64,
+                status.uptime as i64,
+                status.last_session as i64,
+                status.battery_permille as i64,
+                status.battery_days_left as i64,
+                status.flags as i64,
+                status.radio_temperature as i64,
+                status.radio_supply_mv as i64,
+                status.radio_errors as i64,
+                status.since_last_ack as i64,
+                status.since_last_downlink as i64,
+            ],
+            items: vec![],
+            bytes: status.encode().to_vec(),
+        },
+        Vector {
+            schema: schema::ANTENNA,
+            header: vec![1, 1, -92, -118],
+            items: vec![],
+            bytes: payload::antenna(true, 1, -92, -118).to_vec(),
+        },
+        Vector {
+            schema: schema::WAKE,
+            header: vec![2, 1, 86_400],
+            items: vec![],
+            bytes: payload::wake(2, true, 86_400).to_vec(),
+        },
+    ]
+}
+
+/// The fields as the decoder returns them, enums by name.
+fn object(fields: &[Field], values: &[i64]) -> String {
+    assert_eq!(fields.len(), values.len(), "a value per field");
+    let members: Vec<String> = fields
+        .iter()
+        .zip(values)
+        .map(|(field, value)| match field.kind {
+            FieldKind::Enum(names) => format!("\"{}\": \"{}\"", field.name, names[*value as usize]),
+            _ => format!("\"{}\": {value}", field.name),
+        })
+        .collect();
+    members.join(", ")
+}
@@ -0,0 +1,48 @@
+//! Builds the downlink provisioning the payload key of `src/e2e.rs`, to be queued on
+//! port 16. The key is encrypted with the AppKey so the network operator never sees it.
+//!
+//! Usage: `e2e_key <DevEUI> <AppKey> <Key>` with a 32 byte key, or `e2e_key remove` for
+//! the empty downlink removing it.
+
+use lorawan_pilot_tools::aes::{cmac, Aes128};
+
+const DOMAIN: &[u8] = b"E2EKEY";
+const MIC_SIZE: usize = 4;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [dev_eui, app_key, key] = args.as_slice() else {
+        if args.len() == 1 && args[0] == "remove" {
+            println!();
+            return;
+        }
+        usage();
+    };
+    let (Some(mut dev_eui), Some(app_key), Some(key)) =
+        (parse_hex::<8>(dev_eui), parse_hex::<16>(app_key), parse_hex::<32>(key))
+    else {
+        usage();
+    };
+    // least significant byte first, as the device keeps it
+    dev_eui.reverse();
+    let aes = Aes128::new(&app_key);
+    let mut downlink: Vec<u8> =
+        key.as_chunks::<16>().0.iter().flat_map(|block| aes.encrypt(block)).collect();
+    let mic = cmac(&app_key, &[DOMAIN, &dev_eui, &downlink].concat());
+    downlink.extend_from_slice(&mic[..MIC_SIZE]);
+    println!("{}", downlink.iter().map(|b| format!("{b:02X}")).collect::<String>());
+}
+
+fn usage() -> ! {
+    eprintln!("usage: e2e_key <DevEUI> <AppKey> <Key> | e2e_key remove");
+    std::process::exit(1);
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> =
+        (0..N).map(|i| u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()).collect();
+    bytes?.try_into().ok()
+}
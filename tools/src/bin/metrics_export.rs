@@ -0,0 +1,35 @@
+//! Picks the `metrics <line>` dumps out of the RTT output of a device and writes each
+//! complete one to a file in the Prometheus text format, replacing the last, for
+//! node_exporter's textfile collector or anything else reading that format.
+//!
+//! Usage: `probe-rs run ... | cargo run --bin metrics_export -- device.prom`
+
+use std::io::BufRead;
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: metrics_export <output.prom>");
+        std::process::exit(2);
+    };
+    let temporary = format!("{path}.tmp");
+    let mut dump = String::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("failed to read stdin");
+        let Some((_, metric)) = line.split_once("metrics ") else {
+            continue;
+        };
+        if metric.trim() == "# EOF" {
+            // renamed into place so that a scrape never sees half a dump
+            std::fs::write(&temporary, &dump).expect("failed to write the dump");
+            std::fs::rename(&temporary, &path).expect("failed to replace the dump");
+            eprintln!(
+                "{} metrics written to {path}",
+                dump.lines().filter(|l| !l.starts_with('#')).count()
+            );
+            dump.clear();
+        } else {
+            dump.push_str(metric.trim_end());
+            dump.push('\n');
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Runs the network server emulator on hex encoded uplink PHYPayloads read from stdin,
+//! one per line, printing the resulting events and downlinks.
+//!
+//! Usage: `cargo run --bin ns_sim -- <AppKey hex> [MAC command hex]...`
+
+use std::io::BufRead;
+
+use lorawan_pilot_tools::network_server::{Event, NetworkServer};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(app_key) = args.next().and_then(|key| parse_hex(&key)) else {
+        eprintln!("usage: ns_sim <AppKey hex> [MAC command hex]...");
+        std::process::exit(2);
+    };
+    let Ok(app_key) = app_key.try_into() else {
+        eprintln!("AppKey must be 16 bytes");
+        std::process::exit(2);
+    };
+    let mut server = NetworkServer::new(app_key);
+    for command in args {
+        match parse_hex(&command) {
+            Some(command) => server.queue_mac_command(&command),
+            None => eprintln!("ignoring invalid MAC command {command}"),
+        }
+    }
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("failed to read stdin");
+        let Some(phy) = parse_hex(line.trim()) else {
+            println!("! invalid hex");
+            continue;
+        };
+        match server.handle_uplink(&phy) {
+            Ok((event, downlink)) => {
+                match event {
+                    Event::Joined { dev_eui, dev_nonce, dev_addr } => println!(
+                        "joined DevEUI {} DevNonce {dev_nonce} DevAddr {dev_addr:08X}",
+                        to_hex(&dev_eui.iter().rev().copied().collect::<Vec<_>>())
+                    ),
+                    Event::Uplink(uplink) => println!(
+                        "uplink FCnt {} FPort {:?} payload {} MAC {}",
+                        uplink.fcnt,
+                        uplink.fport,
+                        to_hex(&uplink.payload),
+                        to_hex(&uplink.mac_commands)
+                    ),
+                }
+                if let Some(downlink) = downlink {
+                    println!("> {}", to_hex(&downlink));
+                }
+            }
+            Err(e) => println!("! {e}"),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
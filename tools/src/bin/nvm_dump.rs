@@ -0,0 +1,28 @@
+//! Decodes a dump of the STORAGE region into JSON, for support cases where a device
+//! has to be inspected without a debug build.
+//!
+//! Takes either the raw bytes or the text printed by `probe-rs read b8 ...`:
+//! `probe-rs read --chip STM32WLE5JCIx b8 0x0803E800 6144 | cargo run --bin nvm_dump`
+
+use std::io::Read;
+
+use lorawan_pilot_tools::device_twin::DeviceTwin;
+use lorawan_pilot_tools::probe;
+
+fn main() {
+    let mut input = Vec::new();
+    match std::env::args().nth(1) {
+        Some(path) => input = std::fs::read(&path).unwrap_or_else(|e| panic!("{path}: {e}")),
+        None => {
+            std::io::stdin().read_to_end(&mut input).expect("failed to read stdin");
+        }
+    }
+    let dump = std::str::from_utf8(&input).ok().and_then(probe::parse_read_output).unwrap_or(input);
+    match DeviceTwin::parse(&dump) {
+        Ok(twin) => println!("{}", twin.to_json()),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
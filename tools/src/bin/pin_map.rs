@@ -0,0 +1,53 @@
+//! Builds the signed pin map downlink for `src/pin_map.rs`, to be queued on port 13.
+//!
+//! Usage: `pin_map <DevEUI> <AppKey> <PA0> <PA1> <PB4> <PB5>` with each pin one of
+//! `unused`, `pulse` or `relay`, e.g.
+//! `pin_map 0080E11505123456 2B7E151628AED2A6ABF7158809CF4F3C pulse unused relay unused`
+
+use lorawan_pilot_tools::aes::cmac;
+
+const VERSION: u8 = 1;
+const DOMAIN: &[u8] = b"PINMAP";
+const MIC_SIZE: usize = 4;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [dev_eui, app_key, functions @ ..] = args.as_slice() else {
+        usage();
+    };
+    let (Some(mut dev_eui), Some(app_key)) = (parse_hex::<8>(dev_eui), parse_hex::<16>(app_key))
+    else {
+        usage();
+    };
+    // least significant byte first, as the device keeps it
+    dev_eui.reverse();
+    if functions.len() != 4 {
+        usage();
+    }
+    let mut map = vec![VERSION];
+    for function in functions {
+        map.push(match function.as_str() {
+            "unused" => 0,
+            "pulse" => 1,
+            "relay" => 2,
+            _ => usage(),
+        });
+    }
+    let mic = cmac(&app_key, &[DOMAIN, &dev_eui, &map].concat());
+    map.extend_from_slice(&mic[..MIC_SIZE]);
+    println!("{}", map.iter().map(|b| format!("{b:02X}")).collect::<String>());
+}
+
+fn usage() -> ! {
+    eprintln!("usage: pin_map <DevEUI> <AppKey> <unused|pulse|relay> x4");
+    std::process::exit(1);
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> =
+        (0..N).map(|i| u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()).collect();
+    bytes?.try_into().ok()
+}
@@ -0,0 +1,97 @@
+//! Production line helper driving `probe-rs`: flashes the firmware, writes the device's
+//! credentials to the provisioning page, reads back what the device persisted and turns
+//! test modes on and off.
+//!
+//! Usage:
+//!   provision flash <elf>
+//!   provision credentials <dev_eui|uid> <app_eui> <app_key>
+//!   provision test-mode cw <frequency Hz> <power dBm> <seconds>
+//!   provision test-mode off
+//!   provision log
+//!
+//! The chip is taken from `PROBE_RS_CHIP`, by default the STM32WLE5JC.
+
+use lorawan_pilot_tools::device_twin::{DeviceTwin, STORAGE_SIZE};
+use lorawan_pilot_tools::probe::{self, Probe};
+use lorawan_pilot_tools::provisioning::{Provisioning, TestMode, PROVISIONING_ADDRESS, V2_SIZE};
+
+const STORAGE_ADDRESS: u32 = 0x0803_E800;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let chip = std::env::var("PROBE_RS_CHIP").unwrap_or_else(|_| probe::DEFAULT_CHIP.into());
+    let probe = Probe::new(chip);
+    if let Err(e) = run(&probe, &args) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(probe: &Probe, args: &[&str]) -> Result<(), String> {
+    match *args {
+        ["flash", elf] => probe.flash_elf(elf).map_err(|e| e.to_string()),
+        ["credentials", dev_eui, app_eui, app_key] => {
+            let provisioning = Provisioning {
+                dev_eui: parse_dev_eui(dev_eui)?,
+                app_eui: parse_hex(app_eui).ok_or("AppEUI is not 8 hex bytes")?,
+                app_key: parse_hex(app_key).ok_or("AppKey is not 16 hex bytes")?,
+                test_mode: None,
+            };
+            write(probe, &provisioning)
+        }
+        ["test-mode", "cw", frequency, power, seconds] => {
+            let test_mode = TestMode::ContinuousWave {
+                frequency: frequency.parse().map_err(|_| "invalid frequency")?,
+                power: power.parse().map_err(|_| "invalid power")?,
+                seconds: seconds.parse().map_err(|_| "invalid duration")?,
+            };
+            let provisioning = Provisioning { test_mode: Some(test_mode), ..read(probe)? };
+            write(probe, &provisioning)
+        }
+        ["test-mode", "off"] => {
+            let provisioning = Provisioning { test_mode: None, ..read(probe)? };
+            write(probe, &provisioning)
+        }
+        ["log"] => {
+            let dump = probe.read(STORAGE_ADDRESS, STORAGE_SIZE).map_err(|e| e.to_string())?;
+            let twin = DeviceTwin::parse(&dump).map_err(|e| e.to_string())?;
+            println!("{}", twin.to_json());
+            Ok(())
+        }
+        _ => Err("usage: provision flash|credentials|test-mode|log, see the source".into()),
+    }
+}
+
+fn read(probe: &Probe) -> Result<Provisioning, String> {
+    let page = probe.read(PROVISIONING_ADDRESS, V2_SIZE).map_err(|e| e.to_string())?;
+    Provisioning::from_bytes(&page).ok_or_else(|| "device is not provisioned".into())
+}
+
+/// Writes the page and resets the device so that it is picked up.
+fn write(probe: &Probe, provisioning: &Provisioning) -> Result<(), String> {
+    probe.write(PROVISIONING_ADDRESS, &provisioning.to_bytes()).map_err(|e| e.to_string())?;
+    if read(probe)? != *provisioning {
+        return Err("provisioning page did not read back".into());
+    }
+    probe.reset().map_err(|e| e.to_string())?;
+    println!("{provisioning:?}");
+    Ok(())
+}
+
+fn parse_dev_eui(s: &str) -> Result<Option<[u8; 8]>, String> {
+    match s {
+        "uid" => Ok(None),
+        eui => Ok(Some(parse_hex(eui).ok_or("DevEUI is not 8 hex bytes")?)),
+    }
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    let s = s.replace([':', '-'], "");
+    if s.len() != 2 * N {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> =
+        (0..N).map(|i| u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()).collect();
+    bytes?.try_into().ok()
+}
@@ -0,0 +1,49 @@
+//! Converts the `trace <µs> <event>` lines printed by firmware built with the `trace`
+//! feature into a Chrome trace, to be opened in Perfetto or chrome://tracing.
+//!
+//! Usage: `probe-rs run ... | cargo run --bin trace_view > trace.json`
+
+use std::io::BufRead;
+
+/// Track and span name for each event, and whether it begins the span.
+fn span(event: &str) -> Option<(u32, &'static str, bool)> {
+    Some(match event {
+        "TxStart" => (1, "tx", true),
+        "TxEnd" => (1, "tx", false),
+        "RxOpen" => (1, "rx", true),
+        "RxClose" => (1, "rx", false),
+        "SleepEnter" => (2, "sleep", true),
+        "SleepExit" => (2, "sleep", false),
+        _ => return None,
+    })
+}
+
+fn main() {
+    let mut events = Vec::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("failed to read stdin");
+        let mut words = line.split_whitespace().skip_while(|word| *word != "trace").skip(1);
+        let (Some(timestamp), Some(event)) = (words.next(), words.next()) else {
+            continue;
+        };
+        let (Ok(timestamp), Some((tid, name, begin))) = (timestamp.parse::<u64>(), span(event))
+        else {
+            eprintln!("ignoring {line}");
+            continue;
+        };
+        let phase = if begin {
+            "B"
+        } else {
+            "E"
+        };
+        events.push(format!(
+            r#"{{"name":"{name}","ph":"{phase}","ts":{timestamp},"pid":1,"tid":{tid}}}"#
+        ));
+    }
+    events
+        .push(r#"{"name":"thread_name","ph":"M","pid":1,"tid":1,"args":{"name":"radio"}}"#.into());
+    events.push(
+        r#"{"name":"thread_name","ph":"M","pid":1,"tid":2,"args":{"name":"application"}}"#.into(),
+    );
+    println!("{{\"traceEvents\":[\n{}\n]}}", events.join(",\n"));
+}
@@ -0,0 +1,255 @@
+//! Mirrors of what the firmware persists in its STORAGE region, decoded from a raw dump
+//! of that region, e.g. `probe-rs read --chip STM32WLE5JCIx b8 0x0803E800 6144`.
+//!
+//! The layouts follow `src/journal.rs` and the modules writing records to it. The session
+//! page, between the two journal pages, is written by the lorawan crate with its own codec
+//! and is kept as raw bytes.
+
+use std::fmt::{self, Write};
+
+pub const PAGE_SIZE: usize = 2048;
+pub const STORAGE_SIZE: usize = 3 * PAGE_SIZE;
+/// Encoded session at the start of the session page, see `DeviceNonVolatileStore`.
+const SESSION_SIZE: usize = 256;
+const RECORD_SIZE: usize = 32;
+const MAX_VALUE_SIZE: usize = RECORD_SIZE - 4;
+const ERASED: u8 = 0xFF;
+const HEADER_KEY: u8 = 0xFE;
+
+const BOOT_STATS: u8 = 0x01;
+const ENERGY_USED: u8 = 0x02;
+const RADIO_LAST_GOOD: u8 = 0x03;
+const RADIO_CANDIDATE: u8 = 0x04;
+const SETTINGS: u8 = 0x05;
+const MIN_SECURITY_VERSION: u8 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    WrongSize(usize),
+    NoJournal,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WrongSize(size) => {
+                write!(f, "dump is {size} bytes, expected {STORAGE_SIZE}")
+            }
+            Error::NoJournal => write!(f, "neither journal page has a valid header"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootStats {
+    pub boot_count: u32,
+    /// Seconds, including `session`
+    pub uptime: u32,
+    pub session: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RadioProfile {
+    pub high_power_pa: bool,
+    pub tcxo_mv: Option<u16>,
+    pub dcdc: bool,
+    pub rx_boost: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Seconds
+    pub report_interval: u32,
+    /// Seconds
+    pub sample_interval: u16,
+    pub compat_profile: &'static str,
+    pub rx_preamble_symbols: Option<u8>,
+    pub bench_mode: bool,
+}
+
+/// A record whose key or length the tool does not know about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownRecord {
+    pub key: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceTwin {
+    /// Raw session page contents, `None` when erased
+    pub session: Option<Vec<u8>>,
+    pub journal_seq: u32,
+    pub boot_stats: Option<BootStats>,
+    /// Microampere hours
+    pub energy_used: Option<u64>,
+    /// `None` also when hidden by an empty record after failing to join
+    pub radio_last_good: Option<RadioProfile>,
+    pub radio_candidate: Option<u8>,
+    pub settings: Option<Settings>,
+    pub min_security_version: Option<u32>,
+    pub unknown: Vec<UnknownRecord>,
+}
+impl DeviceTwin {
+    pub fn parse(dump: &[u8]) -> Result<Self, Error> {
+        if dump.len() != STORAGE_SIZE {
+            return Err(Error::WrongSize(dump.len()));
+        }
+        let session = &dump[PAGE_SIZE..PAGE_SIZE + SESSION_SIZE];
+        let mut twin = DeviceTwin {
+            session: session.iter().any(|b| *b != ERASED).then(|| session.to_vec()),
+            ..Default::default()
+        };
+        let pages = [&dump[..PAGE_SIZE], &dump[2 * PAGE_SIZE..]];
+        let (page, seq) = match (header_seq(pages[0]), header_seq(pages[1])) {
+            (Some(a), Some(b)) if b > a => (pages[1], b),
+            (Some(a), _) => (pages[0], a),
+            (None, Some(b)) => (pages[1], b),
+            (None, None) => return Err(Error::NoJournal),
+        };
+        twin.journal_seq = seq;
+        // later records replace earlier ones with the same key
+        for record in page.as_chunks::<RECORD_SIZE>().0.iter().skip(1) {
+            if record[0] == ERASED {
+                break;
+            }
+            if let Some(data) = value(record) {
+                twin.apply(record[0], data);
+            }
+        }
+        Ok(twin)
+    }
+
+    fn apply(&mut self, key: u8, data: &[u8]) {
+        match (key, data.len()) {
+            (BOOT_STATS, 12) => {
+                self.boot_stats = Some(BootStats {
+                    boot_count: le_u32(&data[0..4]),
+                    uptime: le_u32(&data[4..8]),
+                    session: le_u32(&data[8..12]),
+                })
+            }
+            (ENERGY_USED, 8) => {
+                self.energy_used = Some(u64::from_le_bytes(data.try_into().unwrap()))
+            }
+            (RADIO_LAST_GOOD, 0) => self.radio_last_good = None,
+            (RADIO_LAST_GOOD, 4) => {
+                self.radio_last_good = Some(RadioProfile {
+                    high_power_pa: data[0] != 0,
+                    tcxo_mv: (data[1] != 0).then(|| data[1] as u16 * 100),
+                    dcdc: data[2] != 0,
+                    rx_boost: data[3] != 0,
+                })
+            }
+            (RADIO_CANDIDATE, 1) => self.radio_candidate = Some(data[0]),
+            (SETTINGS, 9) => {
+                self.settings = Some(Settings {
+                    report_interval: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+                    sample_interval: u16::from_be_bytes([data[4], data[5]]),
+                    compat_profile: match data[6] {
+                        0 => "standard",
+                        1 => "loramac-node",
+                        _ => "unknown",
+                    },
+                    rx_preamble_symbols: (data[7] != 0).then_some(data[7]),
+                    bench_mode: data[8] != 0,
+                })
+            }
+            (MIN_SECURITY_VERSION, 4) => self.min_security_version = Some(le_u32(data)),
+            _ => {
+                self.unknown.retain(|record| record.key != key);
+                self.unknown.push(UnknownRecord { key, data: data.to_vec() })
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        let _ = write!(out, r#""journal_seq":{}"#, self.journal_seq);
+        let _ = write!(out, r#","session":{}"#, opt(&self.session, |s| format!("\"{}\"", hex(s))));
+        let _ = write!(
+            out,
+            r#","boot_stats":{}"#,
+            opt(&self.boot_stats, |b| format!(
+                r#"{{"boot_count":{},"uptime":{},"session":{}}}"#,
+                b.boot_count, b.uptime, b.session
+            ))
+        );
+        let _ = write!(out, r#","energy_used_uah":{}"#, opt(&self.energy_used, u64::to_string));
+        let _ = write!(
+            out,
+            r#","radio_last_good":{}"#,
+            opt(&self.radio_last_good, |p| format!(
+                r#"{{"high_power_pa":{},"tcxo_mv":{},"dcdc":{},"rx_boost":{}}}"#,
+                p.high_power_pa,
+                opt(&p.tcxo_mv, u16::to_string),
+                p.dcdc,
+                p.rx_boost
+            ))
+        );
+        let _ = write!(out, r#","radio_candidate":{}"#, opt(&self.radio_candidate, u8::to_string));
+        let _ = write!(
+            out,
+            r#","settings":{}"#,
+            opt(&self.settings, |s| format!(
+                r#"{{"report_interval":{},"sample_interval":{},"compat_profile":"{}","rx_preamble_symbols":{},"bench_mode":{}}}"#,
+                s.report_interval,
+                s.sample_interval,
+                s.compat_profile,
+                opt(&s.rx_preamble_symbols, u8::to_string),
+                s.bench_mode
+            ))
+        );
+        let _ = write!(
+            out,
+            r#","min_security_version":{}"#,
+            opt(&self.min_security_version, u32::to_string)
+        );
+        let unknown: Vec<String> = self
+            .unknown
+            .iter()
+            .map(|r| format!(r#"{{"key":{},"data":"{}"}}"#, r.key, hex(&r.data)))
+            .collect();
+        let _ = write!(out, r#","unknown":[{}]}}"#, unknown.join(","));
+        out
+    }
+}
+
+fn header_seq(page: &[u8]) -> Option<u32> {
+    let header = &page[..RECORD_SIZE];
+    (header[0] == HEADER_KEY && value(header).is_some()).then(|| le_u32(&header[2..6]))
+}
+
+/// The value of a record with a valid CRC.
+fn value(record: &[u8]) -> Option<&[u8]> {
+    let len = record[1] as usize;
+    (len <= MAX_VALUE_SIZE
+        && crc16(&record[..RECORD_SIZE - 2]).to_le_bytes() == record[RECORD_SIZE - 2..])
+        .then(|| &record[2..2 + len])
+}
+
+/// CRC-16/CCITT-FALSE, as in the journal.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn opt<T>(value: &Option<T>, f: impl Fn(&T) -> String) -> String {
+    value.as_ref().map_or_else(|| "null".into(), f)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
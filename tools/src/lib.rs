@@ -0,0 +1,15 @@
+//! Host side companion tools for the lorawan-pilot firmware.
+
+pub mod aes;
+pub mod device_twin;
+#[path = "../../src/fopts.rs"]
+pub mod fopts;
+#[path = "../../src/link_adr.rs"]
+pub mod link_adr;
+pub mod network_server;
+#[path = "../../src/payload.rs"]
+pub mod payload;
+pub mod probe;
+pub mod provisioning;
+#[path = "../../src/schema.rs"]
+pub mod schema;
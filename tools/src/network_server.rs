@@ -0,0 +1,620 @@
+//! A single device LoRaWAN 1.0.x network server emulator used as a test double: it
+//! answers join requests, validates uplink MICs and frame counters, decrypts payloads
+//! and builds downlinks carrying injected MAC commands and queued application data.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::aes::{cmac, Aes128, Block};
+
+const MTYPE_JOIN_REQUEST: u8 = 0b000;
+const MTYPE_JOIN_ACCEPT: u8 = 0b001;
+const MTYPE_UNCONFIRMED_UP: u8 = 0b010;
+const MTYPE_UNCONFIRMED_DOWN: u8 = 0b011;
+const MTYPE_CONFIRMED_UP: u8 = 0b100;
+const MAX_FOPTS_LEN: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    TooShort,
+    InvalidMic,
+    UnsupportedMessageType(u8),
+    NotJoined,
+    UnknownDevAddr(u32),
+    DevNonceReused(u16),
+    FrameCounterReplay { last: u32, received: u32 },
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooShort => write!(f, "frame too short"),
+            Error::InvalidMic => write!(f, "invalid MIC"),
+            Error::UnsupportedMessageType(mtype) => write!(f, "unsupported MType {mtype:#05b}"),
+            Error::NotJoined => write!(f, "data frame before join"),
+            Error::UnknownDevAddr(addr) => write!(f, "unknown DevAddr {addr:08X}"),
+            Error::DevNonceReused(nonce) => write!(f, "DevNonce {nonce} reused"),
+            Error::FrameCounterReplay { last, received } => {
+                write!(f, "FCntUp {received} not above {last}")
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub dev_addr: u32,
+    pub nwk_s_key: Block,
+    pub app_s_key: Block,
+    pub fcnt_up: Option<u32>,
+    pub fcnt_down: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uplink {
+    pub confirmed: bool,
+    pub adr: bool,
+    pub adr_ack_req: bool,
+    pub fcnt: u32,
+    pub fport: Option<u8>,
+    /// MAC commands from FOpts or from an FPort 0 payload
+    pub mac_commands: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Joined { dev_eui: [u8; 8], dev_nonce: u16, dev_addr: u32 },
+    Uplink(Uplink),
+}
+
+pub struct NetworkServer {
+    app_key: Block,
+    net_id: [u8; 3],
+    next_dev_addr: u32,
+    join_nonce: u32,
+    /// DLSettings, RxDelay and optional CFList sent in every join accept
+    pub dl_settings: u8,
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+    used_dev_nonces: Vec<u16>,
+    session: Option<Session>,
+    mac_commands: Vec<u8>,
+    downlinks: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl NetworkServer {
+    pub fn new(app_key: Block) -> Self {
+        Self {
+            app_key,
+            net_id: [0x13, 0x00, 0x00],
+            next_dev_addr: 0x2600_0001,
+            join_nonce: 0,
+            dl_settings: 0,
+            rx_delay: 1,
+            cf_list: None,
+            used_dev_nonces: Vec::new(),
+            session: None,
+            mac_commands: Vec::new(),
+            downlinks: VecDeque::new(),
+        }
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Queues a raw MAC command (CID followed by its payload) for the next downlink.
+    pub fn queue_mac_command(&mut self, command: &[u8]) {
+        self.mac_commands.extend_from_slice(command);
+    }
+
+    pub fn queue_downlink(&mut self, fport: u8, payload: &[u8]) {
+        self.downlinks.push_back((fport, payload.to_vec()));
+    }
+
+    /// Processes an uplink PHYPayload, returning what happened and the downlink to send
+    /// in RX1, if any.
+    pub fn handle_uplink(&mut self, phy: &[u8]) -> Result<(Event, Option<Vec<u8>>), Error> {
+        let mhdr = *phy.first().ok_or(Error::TooShort)?;
+        match mhdr >> 5 {
+            MTYPE_JOIN_REQUEST => self.handle_join_request(phy),
+            mtype @ (MTYPE_UNCONFIRMED_UP | MTYPE_CONFIRMED_UP) => {
+                let uplink = self.handle_data_uplink(phy, mtype == MTYPE_CONFIRMED_UP)?;
+                let downlink = self.build_downlink(uplink.confirmed);
+                Ok((Event::Uplink(uplink), downlink))
+            }
+            mtype => Err(Error::UnsupportedMessageType(mtype)),
+        }
+    }
+
+    fn handle_join_request(&mut self, phy: &[u8]) -> Result<(Event, Option<Vec<u8>>), Error> {
+        if phy.len() != 23 {
+            return Err(Error::TooShort);
+        }
+        let (msg, mic) = phy.split_at(19);
+        if cmac(&self.app_key, msg)[..4] != *mic {
+            return Err(Error::InvalidMic);
+        }
+        let mut dev_eui = [0; 8];
+        dev_eui.copy_from_slice(&phy[9..17]);
+        let dev_nonce = u16::from_le_bytes([phy[17], phy[18]]);
+        if self.used_dev_nonces.contains(&dev_nonce) {
+            return Err(Error::DevNonceReused(dev_nonce));
+        }
+        self.used_dev_nonces.push(dev_nonce);
+
+        self.join_nonce += 1;
+        let dev_addr = self.next_dev_addr;
+        self.next_dev_addr += 1;
+        let join_nonce = self.join_nonce.to_le_bytes();
+
+        let mut accept = vec![MTYPE_JOIN_ACCEPT << 5];
+        accept.extend_from_slice(&join_nonce[..3]);
+        accept.extend_from_slice(&self.net_id);
+        accept.extend_from_slice(&dev_addr.to_le_bytes());
+        accept.push(self.dl_settings);
+        accept.push(self.rx_delay);
+        if let Some(cf_list) = &self.cf_list {
+            accept.extend_from_slice(cf_list);
+        }
+        let mic = cmac(&self.app_key, &accept);
+        accept.extend_from_slice(&mic[..4]);
+        // the join accept is encrypted with an AES decrypt so the device only needs encrypt
+        let aes = Aes128::new(&self.app_key);
+        for block in accept[1..].as_chunks_mut::<16>().0 {
+            *block = aes.decrypt(block);
+        }
+
+        let derive = |prefix: u8| {
+            let mut block = [0u8; 16];
+            block[0] = prefix;
+            block[1..4].copy_from_slice(&join_nonce[..3]);
+            block[4..7].copy_from_slice(&self.net_id);
+            block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+            aes.encrypt(&block)
+        };
+        self.session = Some(Session {
+            dev_addr,
+            nwk_s_key: derive(0x01),
+            app_s_key: derive(0x02),
+            fcnt_up: None,
+            fcnt_down: 0,
+        });
+        Ok((Event::Joined { dev_eui, dev_nonce, dev_addr }, Some(accept)))
+    }
+
+    fn handle_data_uplink(&mut self, phy: &[u8], confirmed: bool) -> Result<Uplink, Error> {
+        if phy.len() < 12 {
+            return Err(Error::TooShort);
+        }
+        let session = self.session.as_mut().ok_or(Error::NotJoined)?;
+        let dev_addr = u32::from_le_bytes(phy[1..5].try_into().unwrap());
+        if dev_addr != session.dev_addr {
+            return Err(Error::UnknownDevAddr(dev_addr));
+        }
+        let fctrl = phy[5];
+        let fcnt16 = u16::from_le_bytes([phy[6], phy[7]]) as u32;
+        let fcnt = match session.fcnt_up {
+            Some(last) => {
+                let candidate = (last & 0xFFFF_0000) | fcnt16;
+                // only treat a smaller 16 bit counter as a roll over when far behind
+                if candidate <= last && last - candidate >= 0x8000 {
+                    candidate + 0x1_0000
+                } else {
+                    candidate
+                }
+            }
+            None => fcnt16,
+        };
+        let (msg, mic) = phy.split_at(phy.len() - 4);
+        if compute_mic(&session.nwk_s_key, 0, dev_addr, fcnt, msg) != *mic {
+            return Err(Error::InvalidMic);
+        }
+        if let Some(last) = session.fcnt_up.filter(|last| fcnt <= *last) {
+            return Err(Error::FrameCounterReplay { last, received: fcnt });
+        }
+        session.fcnt_up = Some(fcnt);
+
+        let fopts_len = (fctrl & 0x0F) as usize;
+        let fopts_end = 8 + fopts_len;
+        if msg.len() < fopts_end {
+            return Err(Error::TooShort);
+        }
+        let mut mac_commands = msg[8..fopts_end].to_vec();
+        let (fport, payload) = match msg.get(fopts_end) {
+            Some(&fport) => {
+                let key = if fport == 0 {
+                    &session.nwk_s_key
+                } else {
+                    &session.app_s_key
+                };
+                let payload = crypt_payload(key, 0, dev_addr, fcnt, &msg[fopts_end + 1..]);
+                if fport == 0 {
+                    mac_commands.extend_from_slice(&payload);
+                    (Some(0), Vec::new())
+                } else {
+                    (Some(fport), payload)
+                }
+            }
+            None => (None, Vec::new()),
+        };
+        Ok(Uplink {
+            confirmed,
+            adr: fctrl & 0x80 != 0,
+            adr_ack_req: fctrl & 0x40 != 0,
+            fcnt,
+            fport,
+            mac_commands,
+            payload,
+        })
+    }
+
+    fn build_downlink(&mut self, ack: bool) -> Option<Vec<u8>> {
+        if !ack && self.mac_commands.is_empty() && self.downlinks.is_empty() {
+            return None;
+        }
+        let session = self.session.as_mut()?;
+        let fopts_len = if self.mac_commands.len() <= MAX_FOPTS_LEN {
+            self.mac_commands.len()
+        } else {
+            0
+        };
+        let fopts: Vec<u8> = self.mac_commands.drain(..fopts_len).collect();
+        let app_data = self.downlinks.pop_front();
+        let fcnt = session.fcnt_down;
+        session.fcnt_down += 1;
+
+        let mut fctrl = fopts.len() as u8;
+        if ack {
+            fctrl |= 0x20;
+        }
+        if !self.mac_commands.is_empty() || !self.downlinks.is_empty() {
+            // FPending
+            fctrl |= 0x10;
+        }
+        let mut frame = vec![MTYPE_UNCONFIRMED_DOWN << 5];
+        frame.extend_from_slice(&session.dev_addr.to_le_bytes());
+        frame.push(fctrl);
+        frame.extend_from_slice(&(fcnt as u16).to_le_bytes());
+        frame.extend_from_slice(&fopts);
+        if let Some((fport, payload)) = app_data {
+            frame.push(fport);
+            let key = if fport == 0 {
+                &session.nwk_s_key
+            } else {
+                &session.app_s_key
+            };
+            frame.extend(crypt_payload(key, 1, session.dev_addr, fcnt, &payload));
+        } else if fopts.is_empty() && !self.mac_commands.is_empty() {
+            // too long for FOpts, send everything on FPort 0 instead
+            let commands = std::mem::take(&mut self.mac_commands);
+            frame.push(0);
+            frame.extend(crypt_payload(&session.nwk_s_key, 1, session.dev_addr, fcnt, &commands));
+            frame[5] &= !0x10;
+        }
+        let mic = compute_mic(&session.nwk_s_key, 1, session.dev_addr, fcnt, &frame);
+        frame.extend_from_slice(&mic);
+        Some(frame)
+    }
+}
+
+fn compute_mic(key: &Block, dir: u8, dev_addr: u32, fcnt: u32, msg: &[u8]) -> [u8; 4] {
+    let mut input = vec![0x49, 0, 0, 0, 0, dir];
+    input.extend_from_slice(&dev_addr.to_le_bytes());
+    input.extend_from_slice(&fcnt.to_le_bytes());
+    input.push(0);
+    input.push(msg.len() as u8);
+    input.extend_from_slice(msg);
+    cmac(key, &input)[..4].try_into().unwrap()
+}
+
+/// Encrypts or decrypts an FRMPayload, the operation is symmetric.
+pub fn crypt_payload(key: &Block, dir: u8, dev_addr: u32, fcnt: u32, data: &[u8]) -> Vec<u8> {
+    let aes = Aes128::new(key);
+    let mut out = Vec::with_capacity(data.len());
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let mut a = [0u8; 16];
+        a[0] = 0x01;
+        a[5] = dir;
+        a[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        a[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        a[15] = i as u8 + 1;
+        let s = aes.encrypt(&a);
+        out.extend(chunk.iter().zip(s.iter()).map(|(d, s)| d ^ s));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fopts, link_adr};
+
+    const APP_KEY: Block = [0x2B; 16];
+    const DEV_EUI: [u8; 8] = [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x00, 0x00, 0x01];
+    /// LinkADRReq for DR5, max EIRP, channels 0 to 2.
+    const LINK_ADR_REQ: [u8; 5] = [0x03, 0x50, 0x07, 0x00, 0x01];
+    const LINK_ADR_ANS: [u8; 2] = [0x03, 0x07];
+
+    /// The device side of a session, as the firmware derives it from the join accept.
+    struct TestDevice {
+        dev_addr: u32,
+        nwk_s_key: Block,
+        app_s_key: Block,
+        fcnt_up: u32,
+    }
+
+    fn join_request(dev_nonce: u16) -> Vec<u8> {
+        let mut phy = vec![MTYPE_JOIN_REQUEST << 5];
+        phy.extend_from_slice(&[0; 8]);
+        phy.extend_from_slice(&DEV_EUI);
+        phy.extend_from_slice(&dev_nonce.to_le_bytes());
+        let mic = cmac(&APP_KEY, &phy);
+        phy.extend_from_slice(&mic[..4]);
+        phy
+    }
+
+    fn accept(dev_nonce: u16, phy: &[u8]) -> TestDevice {
+        let aes = Aes128::new(&APP_KEY);
+        let mut accept = phy.to_vec();
+        for block in accept[1..].as_chunks_mut::<16>().0 {
+            *block = aes.encrypt(block);
+        }
+        let (msg, mic) = accept.split_at(accept.len() - 4);
+        assert_eq!(cmac(&APP_KEY, msg)[..4], *mic, "join accept MIC");
+        let derive = |prefix: u8| {
+            let mut block = [0u8; 16];
+            block[0] = prefix;
+            block[1..7].copy_from_slice(&msg[1..7]);
+            block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+            aes.encrypt(&block)
+        };
+        TestDevice {
+            dev_addr: u32::from_le_bytes(msg[7..11].try_into().unwrap()),
+            nwk_s_key: derive(0x01),
+            app_s_key: derive(0x02),
+            fcnt_up: 0,
+        }
+    }
+
+    impl TestDevice {
+        fn uplink(&mut self, confirmed: bool, fopts: &[u8], fport: u8, payload: &[u8]) -> Vec<u8> {
+            let mtype = if confirmed {
+                MTYPE_CONFIRMED_UP
+            } else {
+                MTYPE_UNCONFIRMED_UP
+            };
+            let fcnt = self.fcnt_up;
+            self.fcnt_up += 1;
+            let mut phy = vec![mtype << 5];
+            phy.extend_from_slice(&self.dev_addr.to_le_bytes());
+            phy.push(0x80 | fopts.len() as u8);
+            phy.extend_from_slice(&(fcnt as u16).to_le_bytes());
+            phy.extend_from_slice(fopts);
+            phy.push(fport);
+            phy.extend(crypt_payload(&self.app_s_key, 0, self.dev_addr, fcnt, payload));
+            let mic = compute_mic(&self.nwk_s_key, 0, self.dev_addr, fcnt, &phy);
+            phy.extend_from_slice(&mic);
+            phy
+        }
+
+        /// FCtrl, FOpts, FPort and the decrypted FRMPayload of a downlink.
+        fn downlink(&self, phy: &[u8]) -> (u8, Vec<u8>, Option<u8>, Vec<u8>) {
+            let (msg, mic) = phy.split_at(phy.len() - 4);
+            let fcnt = u16::from_le_bytes([msg[6], msg[7]]) as u32;
+            assert_eq!(compute_mic(&self.nwk_s_key, 1, self.dev_addr, fcnt, msg), *mic);
+            let fctrl = msg[5];
+            let fopts_end = 8 + (fctrl & 0x0F) as usize;
+            let fopts = msg[8..fopts_end].to_vec();
+            let Some(&fport) = msg.get(fopts_end) else {
+                return (fctrl, fopts, None, Vec::new());
+            };
+            let key = if fport == 0 {
+                &self.nwk_s_key
+            } else {
+                &self.app_s_key
+            };
+            let payload = crypt_payload(key, 1, self.dev_addr, fcnt, &msg[fopts_end + 1..]);
+            (fctrl, fopts, Some(fport), payload)
+        }
+
+        /// The status bits the firmware NACKs in the MAC's LinkADRAns to a LinkADRReq, for
+        /// a PA doing at most `supported` dBm.
+        fn link_adr_nack(&self, req: &[u8], supported: i8) -> u8 {
+            assert_eq!(req[0], 0x03, "LinkADRReq");
+            // EU868, TXPower 0 is the max EIRP of 16 dBm and each step 2 dB below it
+            let tx_power = match req[1] & 0x0F {
+                0x0F => None,
+                index => Some(16 - 2 * index as i8),
+            };
+            link_adr::nack(tx_power, supported)
+        }
+
+        /// An uplink of the MAC as the firmware sends it, edited with the MIC computed again.
+        fn edited(&self, phy: &[u8], edit: &fopts::Edit) -> Vec<u8> {
+            let len = phy.len() - 4;
+            let mut frame = phy[..len].to_vec();
+            frame.resize(len + MAX_FOPTS_LEN, 0);
+            let edited = fopts::edit(&mut frame, len, edit).unwrap();
+            frame.truncate(edited.len);
+            let fcnt = u16::from_le_bytes([frame[6], frame[7]]) as u32;
+            let mic = compute_mic(&self.nwk_s_key, 0, self.dev_addr, fcnt, &frame);
+            frame.extend_from_slice(&mic);
+            frame
+        }
+    }
+
+    fn joined(server: &mut NetworkServer, dev_nonce: u16) -> TestDevice {
+        let (event, accept_phy) = server.handle_uplink(&join_request(dev_nonce)).unwrap();
+        let device = accept(dev_nonce, &accept_phy.unwrap());
+        assert_eq!(event, Event::Joined { dev_eui: DEV_EUI, dev_nonce, dev_addr: device.dev_addr });
+        device
+    }
+
+    #[test]
+    fn join_uplink_downlink_mac_loop() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 7);
+        let session = server.session().unwrap();
+        assert_eq!((session.nwk_s_key, session.app_s_key), (device.nwk_s_key, device.app_s_key));
+
+        server.queue_mac_command(&LINK_ADR_REQ);
+        server.queue_downlink(5, b"config");
+        let (event, downlink) =
+            server.handle_uplink(&device.uplink(true, &[], 2, b"hello")).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert_eq!(
+            (uplink.fcnt, uplink.fport, uplink.payload.as_slice()),
+            (0, Some(2), &b"hello"[..])
+        );
+        assert!(uplink.confirmed && uplink.adr);
+
+        let (fctrl, fopts, fport, payload) = device.downlink(&downlink.unwrap());
+        assert_ne!(fctrl & 0x20, 0, "ACK");
+        assert_eq!(fctrl & 0x10, 0, "nothing pending");
+        assert_eq!(fopts, LINK_ADR_REQ);
+        assert_eq!((fport, payload.as_slice()), (Some(5), &b"config"[..]));
+
+        let (event, downlink) =
+            server.handle_uplink(&device.uplink(false, &LINK_ADR_ANS, 2, b"again")).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert_eq!(uplink.mac_commands, LINK_ADR_ANS);
+        assert_eq!(downlink, None);
+        assert_eq!(server.session().unwrap().fcnt_down, 1);
+    }
+
+    #[test]
+    fn link_adr_req_beyond_the_pa_is_nacked() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 3);
+
+        // 16 dBm on a board doing 14
+        server.queue_mac_command(&LINK_ADR_REQ);
+        let (_, downlink) = server.handle_uplink(&device.uplink(false, &[], 1, b"")).unwrap();
+        let (_, fopts, _, _) = device.downlink(&downlink.unwrap());
+        let edit =
+            fopts::Edit { link_adr_nack: device.link_adr_nack(&fopts, 14), ..Default::default() };
+        let phy = device.uplink(false, &LINK_ADR_ANS, 1, b"");
+        let (event, _) = server.handle_uplink(&device.edited(&phy, &edit)).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert_eq!(uplink.mac_commands, [0x03, 0x07 & !link_adr::POWER_ACK]);
+
+        // 14 dBm, and keeping the current power
+        for req in [[0x03, 0x51, 0x07, 0x00, 0x01], [0x03, 0x5F, 0x07, 0x00, 0x01]] {
+            server.queue_mac_command(&req);
+            let (_, downlink) = server.handle_uplink(&device.uplink(false, &[], 1, b"")).unwrap();
+            let (_, fopts, _, _) = device.downlink(&downlink.unwrap());
+            let edit = fopts::Edit {
+                link_adr_nack: device.link_adr_nack(&fopts, 14),
+                ..Default::default()
+            };
+            let phy = device.uplink(false, &LINK_ADR_ANS, 1, b"");
+            let (event, _) = server.handle_uplink(&device.edited(&phy, &edit)).unwrap();
+            let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+            assert_eq!(uplink.mac_commands, LINK_ADR_ANS);
+        }
+    }
+
+    #[test]
+    fn link_check_req_is_appended_to_fopts() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 5);
+        let edit = fopts::Edit { link_check_req: true, ..Default::default() };
+
+        let phy = device.uplink(false, &LINK_ADR_ANS, 2, b"report");
+        let (event, _) = server.handle_uplink(&device.edited(&phy, &edit)).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert_eq!(uplink.mac_commands, [0x03, 0x07, fopts::LINK_CHECK_REQ]);
+        assert_eq!((uplink.fport, uplink.payload.as_slice()), (Some(2), &b"report"[..]));
+
+        // five DevStatusAns fill FOpts
+        let full = [0x06, 0xFE, 0x10].repeat(5);
+        let phy = device.uplink(false, &full, 2, b"report");
+        let mut frame = phy[..phy.len() - 4].to_vec();
+        let len = frame.len();
+        frame.resize(len + 1, 0);
+        let edited = fopts::edit(&mut frame, len, &edit).unwrap();
+        assert!(!edited.link_check_req);
+        assert_eq!(edited.len, len);
+    }
+
+    #[test]
+    fn device_time_req_follows_a_link_check_req() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 6);
+        let edit =
+            fopts::Edit { link_check_req: true, device_time_req: true, ..Default::default() };
+
+        let phy = device.uplink(false, &[], 2, b"");
+        let (event, _) = server.handle_uplink(&device.edited(&phy, &edit)).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert_eq!(uplink.mac_commands, [fopts::LINK_CHECK_REQ, fopts::DEVICE_TIME_REQ]);
+
+        // room for one more, the DeviceTimeReq stays out
+        let nearly_full =
+            [0x06, 0xFE, 0x10].repeat(4).into_iter().chain([0x02, 0x02]).collect::<Vec<_>>();
+        let phy = device.uplink(false, &nearly_full, 2, b"");
+        let mut frame = phy[..phy.len() - 4].to_vec();
+        let len = frame.len();
+        frame.resize(len + 2, 0);
+        let edited = fopts::edit(&mut frame, len, &edit).unwrap();
+        assert!(edited.link_check_req && !edited.device_time_req);
+        assert_eq!(edited.len, len + 1);
+    }
+
+    #[test]
+    fn adr_bits_follow_the_adr_control() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 8);
+
+        let off = fopts::Edit { adr: Some(false), adr_ack_req: Some(false), ..Default::default() };
+        let phy = device.uplink(false, &[], 2, b"off");
+        let (event, _) = server.handle_uplink(&device.edited(&phy, &off)).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert!(!uplink.adr && !uplink.adr_ack_req);
+
+        let backoff =
+            fopts::Edit { adr: Some(true), adr_ack_req: Some(true), ..Default::default() };
+        let phy = device.uplink(false, &[], 2, b"on");
+        let (event, _) = server.handle_uplink(&device.edited(&phy, &backoff)).unwrap();
+        let Event::Uplink(uplink) = event else { panic!("expected an uplink") };
+        assert!(uplink.adr && uplink.adr_ack_req);
+        assert_eq!(uplink.payload, b"on");
+    }
+
+    #[test]
+    fn rejects_replays_and_reused_dev_nonces() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 1);
+        let uplink = device.uplink(false, &[], 1, b"once");
+        server.handle_uplink(&uplink).unwrap();
+        assert_eq!(
+            server.handle_uplink(&uplink),
+            Err(Error::FrameCounterReplay { last: 0, received: 0 })
+        );
+        assert_eq!(server.handle_uplink(&join_request(1)), Err(Error::DevNonceReused(1)));
+    }
+
+    #[test]
+    fn mac_commands_too_long_for_fopts_wait_for_the_next_downlink() {
+        let mut server = NetworkServer::new(APP_KEY);
+        let mut device = joined(&mut server, 2);
+        let commands: Vec<u8> = LINK_ADR_REQ.repeat(4);
+        server.queue_mac_command(&commands);
+        server.queue_downlink(5, b"data");
+
+        // FOpts can't hold them and FPort 0 is taken by the application data
+        let (_, downlink) = server.handle_uplink(&device.uplink(false, &[], 1, b"")).unwrap();
+        let (fctrl, fopts, fport, payload) = device.downlink(&downlink.unwrap());
+        assert!(fopts.is_empty());
+        assert_eq!((fport, payload.as_slice()), (Some(5), &b"data"[..]));
+        assert_ne!(fctrl & 0x10, 0, "FPending for the held back commands");
+
+        let (_, downlink) = server.handle_uplink(&device.uplink(false, &[], 1, b"")).unwrap();
+        let (fctrl, fopts, fport, payload) = device.downlink(&downlink.unwrap());
+        assert!(fopts.is_empty());
+        assert_eq!((fport, payload), (Some(0), commands));
+        assert_eq!(fctrl & 0x10, 0, "nothing pending");
+    }
+}
@@ -0,0 +1,79 @@
+//! Thin wrapper around the `probe-rs` command line tool.
+
+use std::fmt;
+use std::process::Command;
+
+pub const DEFAULT_CHIP: &str = "STM32WLE5JCIx";
+
+#[derive(Debug)]
+pub enum Error {
+    Spawn(std::io::Error),
+    Failed { command: String, stderr: String },
+    UnexpectedOutput(String),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spawn(e) => write!(f, "failed to run probe-rs: {e}"),
+            Error::Failed { command, stderr } => write!(f, "probe-rs {command} failed: {stderr}"),
+            Error::UnexpectedOutput(output) => write!(f, "unexpected probe-rs output: {output}"),
+        }
+    }
+}
+
+pub struct Probe {
+    chip: String,
+}
+impl Probe {
+    pub fn new(chip: impl Into<String>) -> Self {
+        Self { chip: chip.into() }
+    }
+
+    fn run(&self, command: &str, args: &[&str]) -> Result<String, Error> {
+        let output = Command::new("probe-rs")
+            .arg(command)
+            .args(["--chip", &self.chip])
+            .args(args)
+            .output()
+            .map_err(Error::Spawn)?;
+        if !output.status.success() {
+            return Err(Error::Failed {
+                command: command.into(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().into(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into())
+    }
+
+    pub fn flash_elf(&self, elf: &str) -> Result<(), Error> {
+        self.run("download", &[elf]).map(drop)
+    }
+
+    /// Erases the pages covered and writes `data` at `address`.
+    pub fn write(&self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("lorawan-pilot-{address:08x}.bin"));
+        std::fs::write(&path, data).map_err(Error::Spawn)?;
+        let base = format!("0x{address:08x}");
+        let path = path.to_string_lossy();
+        self.run("download", &["--binary-format", "bin", "--base-address", &base, &path]).map(drop)
+    }
+
+    pub fn read(&self, address: u32, len: usize) -> Result<Vec<u8>, Error> {
+        let output = self.run("read", &["b8", &format!("0x{address:08x}"), &len.to_string()])?;
+        match parse_read_output(&output) {
+            Some(data) if data.len() == len => Ok(data),
+            _ => Err(Error::UnexpectedOutput(output)),
+        }
+    }
+
+    pub fn reset(&self) -> Result<(), Error> {
+        self.run("reset", &[]).map(drop)
+    }
+}
+
+/// Whitespace separated hex bytes as printed by `probe-rs read b8`, optionally `0x` prefixed.
+pub fn parse_read_output(text: &str) -> Option<Vec<u8>> {
+    text.split_whitespace()
+        .map(|word| u8::from_str_radix(word.trim_start_matches("0x"), 16).ok())
+        .collect()
+}
@@ -0,0 +1,78 @@
+//! Host side of the provisioning page read by `src/provisioning.rs`.
+//!
+//! EUIs are given most significant byte first, as printed on labels, and stored reversed.
+
+use crate::device_twin::crc16;
+
+pub const PROVISIONING_ADDRESS: u32 = 0x0803_E000;
+pub const PAGE_SIZE: usize = 50;
+const MAGIC: u32 = 0x564F_5250;
+const VERSION: u8 = 1;
+/// Pages of version 2 append an ABP session the firmware ignores, the rest of them is
+/// read as a page of version 1.
+pub const V2_SIZE: usize = 86;
+const TEST_MODE_CW: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestMode {
+    ContinuousWave { frequency: u32, power: i8, seconds: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Provisioning {
+    /// `None` to use the EUI derived from the chip's unique ID
+    pub dev_eui: Option<[u8; 8]>,
+    pub app_eui: [u8; 8],
+    pub app_key: [u8; 16],
+    pub test_mode: Option<TestMode>,
+}
+impl Provisioning {
+    pub fn to_bytes(&self) -> [u8; PAGE_SIZE] {
+        let mut page = [0; PAGE_SIZE];
+        page[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        page[4] = VERSION;
+        if let Some(TestMode::ContinuousWave { frequency, power, seconds }) = self.test_mode {
+            page[5] = TEST_MODE_CW;
+            page[6..8].copy_from_slice(&seconds.to_le_bytes());
+            page[8..12].copy_from_slice(&frequency.to_le_bytes());
+            page[12] = power as u8;
+        }
+        page[16..24].copy_from_slice(&reversed(self.dev_eui.unwrap_or([0xFF; 8])));
+        page[24..32].copy_from_slice(&reversed(self.app_eui));
+        page[32..48].copy_from_slice(&self.app_key);
+        let crc = crc16(&page[..PAGE_SIZE - 2]);
+        page[PAGE_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// From the first [`V2_SIZE`] bytes of the page.
+    pub fn from_bytes(page: &[u8]) -> Option<Self> {
+        let page: &[u8; V2_SIZE] = page.try_into().ok()?;
+        let size = match page[4] {
+            VERSION => PAGE_SIZE,
+            2 => V2_SIZE,
+            _ => return None,
+        };
+        if page[..4] != MAGIC.to_le_bytes()
+            || page[size - 2..size] != crc16(&page[..size - 2]).to_le_bytes()
+        {
+            return None;
+        }
+        let dev_eui = reversed(page[16..24].try_into().unwrap());
+        Some(Self {
+            dev_eui: (dev_eui != [0xFF; 8]).then_some(dev_eui),
+            app_eui: reversed(page[24..32].try_into().unwrap()),
+            app_key: page[32..48].try_into().unwrap(),
+            test_mode: (page[5] == TEST_MODE_CW).then(|| TestMode::ContinuousWave {
+                frequency: u32::from_le_bytes(page[8..12].try_into().unwrap()),
+                power: page[12] as i8,
+                seconds: u16::from_le_bytes([page[6], page[7]]),
+            }),
+        })
+    }
+}
+
+fn reversed(mut eui: [u8; 8]) -> [u8; 8] {
+    eui.reverse();
+    eui
+}